@@ -6,6 +6,7 @@ use gpt::mbr::ProtectiveMBR;
 use gpt::partition_types::EFI;
 use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::{env, fs, io};
 use std::{io::Seek, io::SeekFrom};
@@ -13,6 +14,11 @@ use tempfile::NamedTempFile;
 
 type Files = BTreeMap<&'static str, PathBuf>;
 
+const CPIO_DIRECTORY: u32 = 0o040755;
+const CPIO_REGULAR: u32 = 0o100644;
+const CPIO_EXECUTABLE: u32 = 0o100755;
+const CPIO_SYMLINK: u32 = 0o120777;
+
 fn main() -> Result<()> {
     let env_path = env::var("CARGO_BIN_FILE_KERNEL")?;
     let kernel_path = Path::new(&env_path);
@@ -20,21 +26,142 @@ fn main() -> Result<()> {
     let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
     let assets_dir = manifest_dir.join("assets");
 
+    let workspace_dir = manifest_dir
+        .parent()
+        .ok_or_else(|| anyhow!("Failed to get parent directory"))?;
+
+    // Listing any input disables the default of rerunning on every package
+    // change, so the script itself has to be named too.
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", assets_dir.display());
+
+    let initramfs = NamedTempFile::new()?;
+    create_initramfs(workspace_dir, &assets_dir.join("rootfs"), initramfs.path())
+        .context("Failed to build initramfs")?;
+
     let mut files = BTreeMap::new();
     files.insert("kernel", kernel_path.to_path_buf());
     files.insert("efi/boot/bootx64.efi", assets_dir.join("BOOTX64.EFI"));
     files.insert("limine.conf", assets_dir.join("limine.conf"));
+    files.insert("initramfs.cpio", initramfs.path().to_path_buf());
 
-    let img_path = manifest_dir
-        .parent()
-        .ok_or_else(|| anyhow!("Failed to get parent directory"))?
-        .join("TrashOS.img");
-    build_img(files, &img_path).expect("Failed to build UEFI disk image");
+    let img_path = workspace_dir.join("TrashOS.img");
+    build_img(files, &img_path).context("Failed to build UEFI disk image")?;
     println!("cargo:rustc-env=IMG_PATH={}", img_path.to_str().unwrap());
 
     Ok(())
 }
 
+// Packs the rootfs asset directory plus every boybox binary under /bin into a
+// newc cpio archive, which the kernel unpacks into its tmpfs root.
+fn create_initramfs(workspace_dir: &Path, rootfs: &Path, out_path: &Path) -> Result<()> {
+    let mut archive = Cpio::new(File::create(out_path)?);
+    if rootfs.is_dir() {
+        archive.add_tree(rootfs, "")?;
+    }
+
+    let bin_dir = workspace_dir.join("target/x86_64-unknown-none/release");
+    archive.add("bin", CPIO_DIRECTORY, &[])?;
+
+    let sources = workspace_dir.join("apps/boybox/src/bin");
+    println!("cargo:rerun-if-changed={}", sources.display());
+
+    for entry in fs::read_dir(&sources)? {
+        let path = entry?.path();
+        let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        let binary_path = bin_dir.join(name);
+        println!("cargo:rerun-if-changed={}", binary_path.display());
+
+        let binary = fs::read(&binary_path)
+            .with_context(|| format!("Missing boybox binary {name}, build boybox first"))?;
+        archive.add(&format!("bin/{name}"), CPIO_EXECUTABLE, &binary)?;
+    }
+
+    archive.finish()?;
+    Ok(())
+}
+
+struct Cpio<W: Write> {
+    writer: W,
+    inode: u32,
+}
+
+impl<W: Write> Cpio<W> {
+    fn new(writer: W) -> Self {
+        Self { writer, inode: 1 }
+    }
+
+    fn add_tree(&mut self, dir: &Path, prefix: &str) -> Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+            let path = entry.path();
+            let file_type = fs::symlink_metadata(&path)?.file_type();
+
+            if file_type.is_dir() {
+                self.add(&name, CPIO_DIRECTORY, &[])?;
+                self.add_tree(&path, &format!("{name}/"))?;
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(&path)?;
+                self.add(&name, CPIO_SYMLINK, target.to_string_lossy().as_bytes())?;
+            } else {
+                self.add(&name, CPIO_REGULAR, &fs::read(&path)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add(&mut self, name: &str, mode: u32, data: &[u8]) -> io::Result<()> {
+        let links = if mode == CPIO_DIRECTORY { 2 } else { 1 };
+        let name_size = name.len() + 1;
+        let fields = [
+            self.inode,
+            mode,
+            0,
+            0,
+            links,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name_size as u32,
+            0,
+        ];
+        self.inode += 1;
+
+        let mut header = String::from("070701");
+        fields
+            .iter()
+            .for_each(|field| header.push_str(&format!("{field:08X}")));
+
+        self.writer.write_all(header.as_bytes())?;
+        self.writer.write_all(name.as_bytes())?;
+        self.writer.write_all(&[0])?;
+        self.pad(header.len() + name_size)?;
+        self.writer.write_all(data)?;
+        self.pad(data.len())
+    }
+
+    fn pad(&mut self, length: usize) -> io::Result<()> {
+        let padding = length.next_multiple_of(4) - length;
+        self.writer.write_all(&[0; 3][..padding])
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.inode = 0;
+        self.add("TRAILER!!!", 0, &[])?;
+        self.writer.flush()
+    }
+}
+
 fn build_img(files: Files, image_path: &Path) -> Result<()> {
     let fat_partition = NamedTempFile::new()?;
     create_fat(&files, fat_partition.path())?;
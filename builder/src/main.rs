@@ -1,8 +1,19 @@
 use anyhow::Result;
 use argh::{FromArgValue, FromArgs};
 use ovmf_prebuilt::{Arch, FileType, Prebuilt, Source};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::process::Command;
+use trashcrypt::{DEFAULT_ITERATIONS, Header, KEY_SIZE, Xts};
+
+const ENCRYPTED_DISK_SIZE: u64 = 64 * 1024 * 1024;
+const ENCRYPTED_SECTOR_SIZE: usize = 512;
+const RAID_DISK_SIZE: u64 = 64 * 1024 * 1024;
+const SWAP_DISK_SIZE: u64 = 64 * 1024 * 1024;
+const SWAP_PAGE_SIZE: usize = 4096;
+const SWAP_PARTITION_START: u64 = 2048;
+const MBR_LINUX_SWAP: u8 = 0x82;
 
 #[derive(FromArgs)]
 #[argh(description = "TrashOS kernel builder and runner")]
@@ -28,6 +39,22 @@ struct Args {
     #[argh(default = "StorageDevice::Nvme")]
     #[argh(description = "boot device")]
     storage: StorageDevice,
+
+    #[argh(option, short = 'i')]
+    #[argh(description = "attach an ISO image as a SATA CD-ROM")]
+    cdrom: Option<String>,
+
+    #[argh(option, short = 'e')]
+    #[argh(description = "attach a fresh encrypted virtio disk with this passphrase")]
+    encrypted_disk: Option<String>,
+
+    #[argh(switch, short = 'r')]
+    #[argh(description = "attach blank NVMe, SATA and virtio disks for RAID testing")]
+    raid_disks: bool,
+
+    #[argh(switch, short = 'p')]
+    #[argh(description = "attach a virtio disk with a swap partition")]
+    swap_disk: bool,
 }
 
 #[derive(Debug, Default)]
@@ -98,6 +125,58 @@ fn main() -> Result<()> {
         }
     }
 
+    if let Some(cdrom) = args.cdrom {
+        cmd.arg("-device").arg("ahci,id=cdrom-ahci");
+        cmd.arg("-device")
+            .arg("ide-cd,drive=cdrom,bus=cdrom-ahci.0");
+        cmd.args([
+            "-drive",
+            &format!("if=none,media=cdrom,id=cdrom,file={cdrom}"),
+        ]);
+    }
+
+    if let Some(passphrase) = args.encrypted_disk {
+        let path = Path::new("target/encrypted.img");
+        create_encrypted_disk(path, &passphrase)?;
+
+        cmd.arg("-device").arg("virtio-blk-pci,drive=crypt");
+        cmd.args([
+            "-drive",
+            &format!("if=none,format=raw,id=crypt,file={}", path.display()),
+        ]);
+    }
+
+    if args.raid_disks {
+        let devices = [
+            "nvme,drive=raid0,serial=raid0",
+            "ide-hd,drive=raid1,bus=raid-ahci.0",
+            "virtio-blk-pci,drive=raid2",
+        ];
+        cmd.arg("-device").arg("ahci,id=raid-ahci");
+
+        for (index, device) in devices.into_iter().enumerate() {
+            let path = format!("target/raid{index}.img");
+            create_blank_disk(Path::new(&path), RAID_DISK_SIZE)?;
+
+            cmd.arg("-device").arg(device);
+            cmd.args([
+                "-drive",
+                &format!("if=none,format=raw,id=raid{index},file={path}"),
+            ]);
+        }
+    }
+
+    if args.swap_disk {
+        let path = Path::new("target/swap.img");
+        create_swap_disk(path)?;
+
+        cmd.arg("-device").arg("virtio-blk-pci,drive=swap");
+        cmd.args([
+            "-drive",
+            &format!("if=none,format=raw,id=swap,file={}", path.display()),
+        ]);
+    }
+
     let param = "if=none,format=raw,id=disk";
     cmd.args(["-drive", &format!("{param},file={}", img_path.display())]);
 
@@ -110,3 +189,77 @@ fn main() -> Result<()> {
     cmd.spawn()?.wait()?;
     Ok(())
 }
+
+// The payload is all zeroes, so a fresh disk decrypts to a blank device.
+fn create_encrypted_disk(path: &Path, passphrase: &str) -> Result<()> {
+    let mut master_key = [0; KEY_SIZE];
+    let mut salt = [0; 32];
+    let mut uuid = [0; 16];
+    getrandom::fill(&mut master_key)?;
+    getrandom::fill(&mut salt)?;
+    getrandom::fill(&mut uuid)?;
+
+    let header = Header::new(
+        passphrase.as_bytes(),
+        &master_key,
+        salt,
+        uuid,
+        DEFAULT_ITERATIONS,
+        ENCRYPTED_SECTOR_SIZE as u32,
+    )?;
+    let cipher = Xts::new(&master_key);
+
+    let mut disk = BufWriter::new(File::create(path)?);
+    let mut header_area = vec![0; header.payload_offset as usize];
+    header_area[..trashcrypt::HEADER_SIZE].copy_from_slice(&header.encode());
+    disk.write_all(&header_area)?;
+
+    let sectors = (ENCRYPTED_DISK_SIZE - header.payload_offset) / ENCRYPTED_SECTOR_SIZE as u64;
+    let mut sector = [0; ENCRYPTED_SECTOR_SIZE];
+    for index in 0..sectors {
+        sector.fill(0);
+        cipher.encrypt_sector(index, &mut sector);
+        disk.write_all(&sector)?;
+    }
+
+    disk.flush()?;
+    println!("Encrypted disk: {path:?}");
+    Ok(())
+}
+
+// Existing images are kept so arrays survive between runs.
+fn create_blank_disk(path: &Path, size: u64) -> Result<()> {
+    if !path.exists() {
+        File::create(path)?.set_len(size)?;
+    }
+    Ok(())
+}
+
+// A single MBR partition of the swap type, holding a Linux swap header so the
+// kernel accepts it. Swapped out pages don't outlive a run.
+fn create_swap_disk(path: &Path) -> Result<()> {
+    let sectors = SWAP_DISK_SIZE / 512 - SWAP_PARTITION_START;
+
+    let mut mbr = [0u8; 512];
+    let entry = &mut mbr[446..462];
+    entry[4] = MBR_LINUX_SWAP;
+    entry[8..12].copy_from_slice(&(SWAP_PARTITION_START as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+    mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let last_page = sectors * 512 / SWAP_PAGE_SIZE as u64 - 1;
+    let mut header = [0u8; SWAP_PAGE_SIZE];
+    header[1024..1028].copy_from_slice(&1u32.to_le_bytes());
+    header[1028..1032].copy_from_slice(&(last_page as u32).to_le_bytes());
+    getrandom::fill(&mut header[1036..1052])?;
+    header[SWAP_PAGE_SIZE - 10..].copy_from_slice(b"SWAPSPACE2");
+
+    let mut disk = File::create(path)?;
+    disk.set_len(SWAP_DISK_SIZE)?;
+    disk.write_all(&mbr)?;
+    disk.seek(SeekFrom::Start(SWAP_PARTITION_START * 512))?;
+    disk.write_all(&header)?;
+
+    println!("Swap disk: {path:?}");
+    Ok(())
+}
@@ -0,0 +1,66 @@
+#![no_std]
+
+//! On-disk format of ISO 9660 volumes, shared by the kernel driver and the
+//! host tests.
+//!
+//! Besides the plain primary volume descriptor this understands the Joliet
+//! supplementary descriptor (UCS-2 names) and the Rock Ridge extensions
+//! carried in the system use area of each directory record (POSIX names,
+//! attributes, symlinks, timestamps and relocated directories).
+
+extern crate alloc;
+
+pub mod record;
+pub mod volume;
+
+mod rock_ridge;
+
+#[cfg(test)]
+mod tests;
+
+use alloc::vec::Vec;
+use thiserror::Error;
+
+pub use record::{DirectoryRecord, Extent, PosixAttributes};
+pub use volume::Volume;
+
+pub const SECTOR_SIZE: u64 = 2048;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatError {
+    #[error("Missing ISO 9660 identifier")]
+    NotIso9660,
+    #[error("Invalid {0}")]
+    Invalid(&'static str),
+}
+
+pub type FormatResult<T> = Result<T, FormatError>;
+
+// Source of volume bytes, so the parser can sit on any kind of device.
+pub trait Reader {
+    type Error: From<FormatError>;
+
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+impl Reader for Vec<u8> {
+    type Error = FormatError;
+
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> FormatResult<()> {
+        let source = usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.get(start..start.checked_add(buffer.len())?))
+            .ok_or(FormatError::Invalid("read beyond the end of the image"))?;
+
+        buffer.copy_from_slice(source);
+        Ok(())
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
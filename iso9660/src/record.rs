@@ -0,0 +1,104 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use time::error::ComponentRange;
+use time::{Date, Month, PrimitiveDateTime, Time, UtcOffset};
+
+use crate::SECTOR_SIZE;
+
+pub const MIN_RECORD_LENGTH: usize = 34;
+
+const FLAG_DIRECTORY: u8 = 1 << 1;
+const FLAG_MULTI_EXTENT: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub block: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PosixAttributes {
+    pub mode: u32,
+    pub links: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DirectoryRecord {
+    pub name: String,
+    pub extents: Vec<Extent>,
+    pub size: u64,
+    pub directory: bool,
+    pub multi_extent: bool,
+    pub modified: i64,
+    pub location: u64,
+    pub posix: Option<PosixAttributes>,
+    pub symlink: Option<String>,
+    pub child_link: Option<u32>,
+    pub relocated: bool,
+}
+
+impl DirectoryRecord {
+    // Directories are identified by their extent so every path to one (the
+    // parent's entry, its own "." record) agrees, files by their record.
+    pub fn inode(&self) -> u64 {
+        match (self.directory, self.extents.first()) {
+            (true, Some(extent)) => extent.block as u64 * SECTOR_SIZE,
+            _ => self.location,
+        }
+    }
+
+    pub(crate) fn parse(raw: &[u8], name: String, location: u64) -> Self {
+        let flags = raw[25];
+        let length = crate::read_u32(raw, 10);
+
+        Self {
+            name,
+            extents: alloc::vec![Extent {
+                block: crate::read_u32(raw, 2),
+                length,
+            }],
+            size: length as u64,
+            directory: flags & FLAG_DIRECTORY != 0,
+            multi_extent: flags & FLAG_MULTI_EXTENT != 0,
+            modified: timestamp(&raw[18..25]),
+            location,
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) fn timestamp(raw: &[u8]) -> i64 {
+    let convert = || -> Result<i64, ComponentRange> {
+        let date =
+            Date::from_calendar_date(1900 + raw[0] as i32, Month::try_from(raw[1])?, raw[2])?;
+        let time = Time::from_hms(raw[3], raw[4], raw[5])?;
+        let offset = UtcOffset::from_whole_seconds(raw[6] as i8 as i32 * 15 * 60)?;
+        Ok(PrimitiveDateTime::new(date, time)
+            .assume_offset(offset)
+            .unix_timestamp())
+    };
+
+    convert().unwrap_or(0)
+}
+
+pub(crate) fn long_timestamp(raw: &[u8]) -> i64 {
+    let digits = |range: core::ops::Range<usize>| {
+        raw[range].iter().fold(0u32, |value, digit| {
+            value * 10 + digit.wrapping_sub(b'0') as u32
+        })
+    };
+
+    let short = [
+        (digits(0..4).saturating_sub(1900)) as u8,
+        digits(4..6) as u8,
+        digits(6..8) as u8,
+        digits(8..10) as u8,
+        digits(10..12) as u8,
+        digits(12..14) as u8,
+        raw[16],
+    ];
+
+    timestamp(&short)
+}
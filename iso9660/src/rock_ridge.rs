@@ -0,0 +1,131 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec;
+
+use crate::record::{DirectoryRecord, PosixAttributes, long_timestamp, timestamp};
+use crate::{FormatError, Reader, Volume, read_u32};
+
+// Linux gives up after the same number of continuation areas per record.
+const MAX_CONTINUATIONS: usize = 32;
+
+// Returns the number of bytes to skip before the SUSP entries of every record
+// when the root's "." record starts with an SP entry.
+pub(crate) fn detect(record: &[u8]) -> Option<usize> {
+    let length = record[0] as usize;
+    let name_length = record[32] as usize;
+    let system_use = 33 + name_length + name_length.is_multiple_of(2) as usize;
+
+    let entry = &record[system_use.min(length)..length];
+    let is_sp = entry.len() >= 7 && &entry[..2] == b"SP" && entry[4..6] == [0xbe, 0xef];
+    is_sp.then(|| entry[6] as usize)
+}
+
+pub(crate) fn apply<R: Reader>(
+    volume: &Volume<R>,
+    record: &mut DirectoryRecord,
+    area: &[u8],
+) -> Result<(), R::Error> {
+    let mut name = None::<String>;
+    let mut link = None::<String>;
+    let mut link_continued = false;
+    let mut continuations = 0;
+    let mut areas = VecDeque::from([area.to_vec()]);
+
+    while let Some(area) = areas.pop_front() {
+        let mut offset = 0;
+
+        while offset + 4 <= area.len() {
+            let length = area[offset + 2] as usize;
+            if length < 4 || offset + length > area.len() {
+                break;
+            }
+
+            let entry = &area[offset..offset + length];
+            offset += length;
+
+            match &entry[..2] {
+                b"PX" if length >= 36 => {
+                    record.posix = Some(PosixAttributes {
+                        mode: read_u32(entry, 4),
+                        links: read_u32(entry, 12),
+                        uid: read_u32(entry, 20),
+                        gid: read_u32(entry, 28),
+                    });
+                }
+                // Skip the "." and ".." alternate names
+                b"NM" if length >= 5 && entry[4] & 0x06 == 0 => {
+                    let part = String::from_utf8_lossy(&entry[5..]);
+                    name.get_or_insert_default().push_str(&part);
+                }
+                b"SL" if length >= 5 => {
+                    let link = link.get_or_insert_default();
+                    let mut position = 5;
+
+                    while position + 2 <= entry.len() {
+                        let (flags, size) = (entry[position], entry[position + 1] as usize);
+                        let end = (position + 2 + size).min(entry.len());
+                        let content = &entry[position + 2..end];
+
+                        if !link.is_empty() && !link_continued && !link.ends_with('/') {
+                            link.push('/');
+                        }
+
+                        match flags & 0x0e {
+                            0x02 => link.push('.'),
+                            0x04 => link.push_str(".."),
+                            0x08 => link.push('/'),
+                            _ => link.push_str(&String::from_utf8_lossy(content)),
+                        }
+
+                        link_continued = flags & 0x01 != 0;
+                        position = end;
+                    }
+                }
+                b"TF" if length >= 5 => {
+                    let flags = entry[4];
+                    let size = if flags & 0x80 != 0 { 17 } else { 7 };
+                    let start = 5 + (flags & 0x01) as usize * size;
+
+                    if flags & 0x02 != 0 && start + size <= entry.len() {
+                        let raw = &entry[start..start + size];
+                        record.modified = match size {
+                            17 => long_timestamp(raw),
+                            _ => timestamp(raw),
+                        };
+                    }
+                }
+                b"CL" if length >= 12 => record.child_link = Some(read_u32(entry, 4)),
+                b"RE" => record.relocated = true,
+                b"CE" if length >= 28 => {
+                    // Crafted images can chain areas into a cycle or ask for
+                    // arbitrarily large ones.
+                    continuations += 1;
+                    if continuations > MAX_CONTINUATIONS {
+                        return Err(FormatError::Invalid("Rock Ridge continuation chain").into());
+                    }
+
+                    let block = read_u32(entry, 4) as u64;
+                    let offset = read_u32(entry, 12) as u64;
+                    let size = read_u32(entry, 20) as u64;
+                    if offset + size > volume.logical_block_size() {
+                        return Err(FormatError::Invalid("Rock Ridge continuation area").into());
+                    }
+
+                    let mut continuation = vec![0; size as usize];
+                    let position = block * volume.logical_block_size() + offset;
+                    volume.reader().read_bytes(position, &mut continuation)?;
+                    areas.push_back(continuation);
+                }
+                b"ST" => break,
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(name) = name {
+        record.name = name;
+    }
+    record.symlink = link;
+
+    Ok(())
+}
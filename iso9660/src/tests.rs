@@ -0,0 +1,352 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{DirectoryRecord, FormatError, SECTOR_SIZE, Volume};
+
+const DIRECTORY: u8 = 1 << 1;
+const HELLO: &[u8] = b"hello world";
+
+fn put_both_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    data[offset + 2..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn put_both_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    data[offset + 4..offset + 8].copy_from_slice(&value.to_be_bytes());
+}
+
+fn image(sectors: Vec<(u32, Vec<u8>)>) -> Vec<u8> {
+    let count = sectors
+        .iter()
+        .map(|(index, _)| index + 1)
+        .max()
+        .unwrap_or(0);
+    let mut image = vec![0; count as usize * SECTOR_SIZE as usize];
+
+    for (index, data) in sectors {
+        let start = index as usize * SECTOR_SIZE as usize;
+        image[start..start + data.len()].copy_from_slice(&data);
+    }
+
+    image
+}
+
+fn record(name: &[u8], block: u32, length: u32, flags: u8, system_use: &[u8]) -> Vec<u8> {
+    let padding = name.len().is_multiple_of(2) as usize;
+    let length_total = 33 + name.len() + padding + system_use.len();
+    let mut raw = vec![0; length_total + length_total % 2];
+
+    raw[0] = raw.len() as u8;
+    put_both_u32(&mut raw, 2, block);
+    put_both_u32(&mut raw, 10, length);
+    raw[18..25].copy_from_slice(&[124, 1, 2, 3, 4, 5, 0]);
+    raw[25] = flags;
+    put_both_u16(&mut raw, 28, 1);
+    raw[32] = name.len() as u8;
+    raw[33..33 + name.len()].copy_from_slice(name);
+    raw[33 + name.len() + padding..][..system_use.len()].copy_from_slice(system_use);
+    raw
+}
+
+fn directory(block: u32, parent: u32, system_use: &[u8], records: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = record(&[0], block, SECTOR_SIZE as u32, DIRECTORY, system_use);
+    data.extend(record(&[1], parent, SECTOR_SIZE as u32, DIRECTORY, &[]));
+    records.iter().for_each(|record| data.extend(record));
+    data
+}
+
+fn descriptor(kind: u8, root: u32, path_table: (u32, usize), joliet: bool) -> Vec<u8> {
+    let mut data = vec![0; SECTOR_SIZE as usize];
+    data[0] = kind;
+    data[1..6].copy_from_slice(b"CD001");
+    data[6] = 1;
+    if joliet {
+        data[88..91].copy_from_slice(b"%/E");
+    }
+    put_both_u16(&mut data, 128, SECTOR_SIZE as u16);
+    put_both_u32(&mut data, 132, path_table.1 as u32);
+    data[140..144].copy_from_slice(&path_table.0.to_le_bytes());
+
+    let root = record(&[0], root, SECTOR_SIZE as u32, DIRECTORY, &[]);
+    data[156..156 + root.len()].copy_from_slice(&root);
+    data
+}
+
+fn terminator() -> Vec<u8> {
+    let mut data = vec![0; 7];
+    data[0] = 255;
+    data[1..6].copy_from_slice(b"CD001");
+    data[6] = 1;
+    data
+}
+
+fn path_table(entries: &[(&[u8], u32, u16)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for &(name, block, parent) in entries {
+        data.push(name.len() as u8);
+        data.push(0);
+        data.extend(block.to_le_bytes());
+        data.extend(parent.to_le_bytes());
+        data.extend(name);
+        if name.len() % 2 != 0 {
+            data.push(0);
+        }
+    }
+    data
+}
+
+fn ucs2(name: &str) -> Vec<u8> {
+    name.encode_utf16().flat_map(u16::to_be_bytes).collect()
+}
+
+fn susp(signature: &[u8; 2], payload: &[u8]) -> Vec<u8> {
+    let mut entry = vec![signature[0], signature[1], 4 + payload.len() as u8, 1];
+    entry.extend(payload);
+    entry
+}
+
+fn sp() -> Vec<u8> {
+    susp(b"SP", &[0xbe, 0xef, 0])
+}
+
+fn nm(name: &str) -> Vec<u8> {
+    let mut payload = vec![0];
+    payload.extend(name.as_bytes());
+    susp(b"NM", &payload)
+}
+
+fn px(mode: u32, links: u32, uid: u32, gid: u32) -> Vec<u8> {
+    let mut payload = vec![0; 32];
+    for (index, value) in [mode, links, uid, gid].into_iter().enumerate() {
+        put_both_u32(&mut payload, index * 8, value);
+    }
+    susp(b"PX", &payload)
+}
+
+fn sl(components: &[&str]) -> Vec<u8> {
+    let mut payload = vec![0];
+    for component in components {
+        payload.extend([0, component.len() as u8]);
+        payload.extend(component.as_bytes());
+    }
+    susp(b"SL", &payload)
+}
+
+fn ce(block: u32, offset: u32, size: u32) -> Vec<u8> {
+    let mut payload = vec![0; 24];
+    put_both_u32(&mut payload, 0, block);
+    put_both_u32(&mut payload, 8, offset);
+    put_both_u32(&mut payload, 16, size);
+    susp(b"CE", &payload)
+}
+
+fn names(records: &[DirectoryRecord]) -> Vec<&str> {
+    records.iter().map(|record| record.name.as_str()).collect()
+}
+
+// Primary tree with 8.3 names plus an optional Joliet tree over the same
+// file data.
+fn plain_image(joliet: bool) -> Vec<u8> {
+    let primary_table = path_table(&[(&[0], 21, 1), (b"SUBDIR", 22, 1)]);
+    let joliet_name = ucs2("Sub Directory");
+    let joliet_table = path_table(&[(&[0], 23, 1), (&joliet_name, 24, 1)]);
+
+    let mut sectors = vec![
+        (16, descriptor(1, 21, (19, primary_table.len()), false)),
+        (18, terminator()),
+        (19, primary_table),
+        (
+            21,
+            directory(
+                21,
+                21,
+                &[],
+                &[
+                    record(b"README.TXT;1", 25, HELLO.len() as u32, 0, &[]),
+                    record(b"SUBDIR", 22, SECTOR_SIZE as u32, DIRECTORY, &[]),
+                ],
+            ),
+        ),
+        (
+            22,
+            directory(22, 21, &[], &[record(b"NESTED.TXT;1", 26, 6, 0, &[])]),
+        ),
+        (25, HELLO.to_vec()),
+        (26, b"nested".to_vec()),
+    ];
+
+    if joliet {
+        sectors.extend([
+            (17, descriptor(2, 23, (20, joliet_table.len()), true)),
+            (20, joliet_table),
+            (
+                23,
+                directory(
+                    23,
+                    23,
+                    &[],
+                    &[
+                        record(&ucs2("A long file name.txt;1"), 25, 11, 0, &[]),
+                        record(&joliet_name, 24, SECTOR_SIZE as u32, DIRECTORY, &[]),
+                    ],
+                ),
+            ),
+            (
+                24,
+                directory(24, 23, &[], &[record(&ucs2("nested.txt;1"), 26, 6, 0, &[])]),
+            ),
+        ]);
+    } else {
+        sectors.push((17, terminator()));
+    }
+
+    image(sectors)
+}
+
+// Primary tree carrying Rock Ridge entries, with `continuation` stored at
+// offset 100 of block 22 and referenced by the last root entry.
+fn rock_ridge_image(split: &[u8], continuation: Vec<u8>) -> Vec<u8> {
+    let table = path_table(&[(&[0], 19, 1), (b"SUBDIR", 20, 1)]);
+
+    let file = [nm("file with a long name.txt"), px(0o100640, 1, 1000, 100)].concat();
+    let link = [nm("link"), sl(&["subdir", "nested"]), px(0o120777, 1, 0, 0)].concat();
+    let subdir = nm("subdir");
+    let nested = nm("nested");
+
+    let mut area = vec![0; 100];
+    area.extend(continuation);
+
+    image(vec![
+        (16, descriptor(1, 19, (18, table.len()), false)),
+        (17, terminator()),
+        (18, table),
+        (
+            19,
+            directory(
+                19,
+                19,
+                &sp(),
+                &[
+                    record(b"FILE.TXT;1", 21, HELLO.len() as u32, 0, &file),
+                    record(b"LINK.;1", 0, 0, 0, &link),
+                    record(b"SUBDIR", 20, SECTOR_SIZE as u32, DIRECTORY, &subdir),
+                    record(b"SPLIT.TXT;1", 21, HELLO.len() as u32, 0, split),
+                ],
+            ),
+        ),
+        (
+            20,
+            directory(20, 19, &[], &[record(b"NESTED.;1", 21, 11, 0, &nested)]),
+        ),
+        (21, HELLO.to_vec()),
+        (22, area),
+    ])
+}
+
+#[test]
+fn rejects_non_iso_images() {
+    let result = Volume::open(vec![0; 32 * SECTOR_SIZE as usize]);
+    assert_eq!(result.err(), Some(FormatError::NotIso9660));
+}
+
+#[test]
+fn reads_primary_tree() {
+    let volume = Volume::open(plain_image(false)).unwrap();
+    assert!(!volume.joliet() && !volume.rock_ridge());
+
+    let entries = volume.read_directory(volume.root()).unwrap();
+    assert_eq!(names(&entries), ["readme.txt", "subdir"]);
+
+    let mut buffer = [0; 32];
+    let read = volume.read_file(&entries[0].extents, entries[0].size, 6, &mut buffer);
+    assert_eq!(&buffer[..read.unwrap()], b"world");
+
+    // Directories are found through the path table
+    let subdir = volume.lookup(volume.root(), "subdir").unwrap().unwrap();
+    assert!(subdir.directory);
+    assert_eq!(subdir.inode(), entries[1].inode());
+    let nested = volume.read_directory(&subdir).unwrap();
+    assert_eq!(names(&nested), ["nested.txt"]);
+    assert!(volume.lookup(volume.root(), "missing").unwrap().is_none());
+}
+
+#[test]
+fn prefers_joliet_names() {
+    let volume = Volume::open(plain_image(true)).unwrap();
+    assert!(volume.joliet());
+
+    let entries = volume.read_directory(volume.root()).unwrap();
+    assert_eq!(names(&entries), ["A long file name.txt", "Sub Directory"]);
+
+    let subdir = volume
+        .lookup(volume.root(), "Sub Directory")
+        .unwrap()
+        .unwrap();
+    let nested = volume.read_directory(&subdir).unwrap();
+    assert_eq!(names(&nested), ["nested.txt"]);
+
+    let mut buffer = [0; 6];
+    volume
+        .read_file(&nested[0].extents, nested[0].size, 0, &mut buffer)
+        .unwrap();
+    assert_eq!(&buffer, b"nested");
+}
+
+#[test]
+fn applies_rock_ridge_entries() {
+    let continuation = [nm("split-name.txt"), px(0o100600, 1, 7, 7)].concat();
+    let split = ce(22, 100, continuation.len() as u32);
+    let volume = Volume::open(rock_ridge_image(&split, continuation)).unwrap();
+    assert!(volume.rock_ridge() && !volume.joliet());
+
+    let entries = volume.read_directory(volume.root()).unwrap();
+    assert_eq!(
+        names(&entries),
+        [
+            "file with a long name.txt",
+            "link",
+            "subdir",
+            "split-name.txt"
+        ]
+    );
+
+    let posix = entries[0].posix.unwrap();
+    assert_eq!((posix.mode, posix.uid, posix.gid), (0o100640, 1000, 100));
+    assert_eq!(entries[1].symlink.as_deref(), Some("subdir/nested"));
+    assert_eq!(entries[3].posix.map(|posix| posix.mode), Some(0o100600));
+
+    let subdir = volume.lookup(volume.root(), "subdir").unwrap().unwrap();
+    let nested = volume.read_directory(&subdir).unwrap();
+    assert_eq!(names(&nested), ["nested"]);
+}
+
+#[test]
+fn rejects_cyclic_continuation_areas() {
+    // The continuation area points back at itself
+    let split = ce(22, 100, 28);
+    let volume = Volume::open(rock_ridge_image(&split, ce(22, 100, 28))).unwrap();
+
+    let result = volume.read_directory(volume.root());
+    let expected = FormatError::Invalid("Rock Ridge continuation chain");
+    assert_eq!(result.err(), Some(expected));
+}
+
+#[test]
+fn rejects_oversized_continuation_areas() {
+    let split = ce(22, 100, SECTOR_SIZE as u32);
+    let volume = Volume::open(rock_ridge_image(&split, Vec::new())).unwrap();
+
+    let result = volume.read_directory(volume.root());
+    let expected = FormatError::Invalid("Rock Ridge continuation area");
+    assert_eq!(result.err(), Some(expected));
+}
+
+#[test]
+fn decodes_record_timestamps() {
+    let volume = Volume::open(plain_image(false)).unwrap();
+    let entries = volume.read_directory(volume.root()).unwrap();
+
+    // 2024-01-02 03:04:05 UTC
+    assert_eq!(entries[0].modified, 1_704_164_645);
+}
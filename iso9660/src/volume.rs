@@ -0,0 +1,333 @@
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::record::{DirectoryRecord, Extent, MIN_RECORD_LENGTH};
+use crate::{FormatError, Reader, SECTOR_SIZE, read_u16, read_u32, rock_ridge};
+
+const VOLUME_DESCRIPTOR_START: u64 = 16;
+const MAX_VOLUME_DESCRIPTORS: u64 = 64;
+const STANDARD_IDENTIFIER: &[u8] = b"CD001";
+const ROOT_RECORD_OFFSET: usize = 156;
+
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+const JOLIET_ESCAPES: [&[u8]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+struct PathTableEntry {
+    block: u32,
+    parent: u16,
+    name: String,
+}
+
+pub struct Volume<R> {
+    reader: R,
+    logical_block_size: u64,
+    joliet: bool,
+    rock_ridge: Option<usize>,
+    path_table: Vec<PathTableEntry>,
+    root: DirectoryRecord,
+}
+
+impl<R: Reader> Volume<R> {
+    pub fn open(reader: R) -> Result<Self, R::Error> {
+        let mut volume = Self {
+            reader,
+            logical_block_size: SECTOR_SIZE,
+            joliet: false,
+            rock_ridge: None,
+            path_table: Vec::new(),
+            root: DirectoryRecord::default(),
+        };
+
+        let mut primary = None;
+        let mut supplementary = None;
+
+        for index in VOLUME_DESCRIPTOR_START..VOLUME_DESCRIPTOR_START + MAX_VOLUME_DESCRIPTORS {
+            let mut descriptor = vec![0; SECTOR_SIZE as usize];
+            volume
+                .reader
+                .read_bytes(index * SECTOR_SIZE, &mut descriptor)?;
+
+            if &descriptor[1..6] != STANDARD_IDENTIFIER {
+                return Err(FormatError::NotIso9660.into());
+            }
+
+            match descriptor[0] {
+                DESCRIPTOR_PRIMARY => primary = Some(descriptor),
+                DESCRIPTOR_SUPPLEMENTARY if is_joliet(&descriptor) => {
+                    supplementary = Some(descriptor)
+                }
+                DESCRIPTOR_TERMINATOR => break,
+                _ => {}
+            }
+        }
+
+        let primary = primary.ok_or(FormatError::Invalid("primary volume descriptor"))?;
+        volume.logical_block_size = read_u16(&primary, 128) as u64;
+        if volume.logical_block_size == 0 {
+            return Err(FormatError::Invalid("logical block size").into());
+        }
+
+        let root = volume.parse_record(&primary[ROOT_RECORD_OFFSET..][..34], 0)?;
+        let extent = root.extents[0];
+        let dot = volume.read_block(extent.block, volume.logical_block_size as usize)?;
+        if (dot[0] as usize) < MIN_RECORD_LENGTH {
+            return Err(FormatError::Invalid("directory record length").into());
+        }
+        volume.rock_ridge = rock_ridge::detect(&dot);
+
+        // Rock Ridge carries POSIX names and attributes on the primary tree,
+        // Joliet only provides long Unicode names, so prefer the former.
+        let descriptor = match supplementary {
+            Some(descriptor) if volume.rock_ridge.is_none() => {
+                volume.joliet = true;
+                descriptor
+            }
+            _ => primary,
+        };
+
+        volume.root = volume.parse_record(&descriptor[ROOT_RECORD_OFFSET..][..34], 0)?;
+        volume.load_path_table(&descriptor)?;
+
+        Ok(volume)
+    }
+
+    pub fn reader(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn root(&self) -> &DirectoryRecord {
+        &self.root
+    }
+
+    pub fn logical_block_size(&self) -> u64 {
+        self.logical_block_size
+    }
+
+    pub fn joliet(&self) -> bool {
+        self.joliet
+    }
+
+    pub fn rock_ridge(&self) -> bool {
+        self.rock_ridge.is_some()
+    }
+
+    pub fn read_file(
+        &self,
+        extents: &[Extent],
+        size: u64,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, R::Error> {
+        let end = (offset + buffer.len() as u64).min(size);
+        let mut position = offset;
+        let mut extent_start = 0;
+
+        for extent in extents.iter() {
+            let extent_end = extent_start + extent.length as u64;
+
+            if position < end && position < extent_end {
+                let length = (end.min(extent_end) - position) as usize;
+                let done = (position - offset) as usize;
+                let device_offset =
+                    extent.block as u64 * self.logical_block_size + (position - extent_start);
+
+                self.reader
+                    .read_bytes(device_offset, &mut buffer[done..done + length])?;
+                position += length as u64;
+            }
+
+            extent_start = extent_end;
+        }
+
+        Ok(position.saturating_sub(offset) as usize)
+    }
+
+    pub fn read_directory(
+        &self,
+        directory: &DirectoryRecord,
+    ) -> Result<Vec<DirectoryRecord>, R::Error> {
+        let extent = directory
+            .extents
+            .first()
+            .ok_or(FormatError::Invalid("directory without extent"))?;
+
+        let data = self.read_block(extent.block, extent.length as usize)?;
+        let base = extent.block as u64 * self.logical_block_size;
+        let sector = self.logical_block_size as usize;
+
+        let mut entries: Vec<DirectoryRecord> = Vec::new();
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let length = data[offset] as usize;
+            if length == 0 {
+                offset = (offset / sector + 1) * sector;
+                continue;
+            }
+
+            if length < MIN_RECORD_LENGTH || offset + length > data.len() {
+                return Err(FormatError::Invalid("directory record length").into());
+            }
+
+            let raw = &data[offset..offset + length];
+            let location = base + offset as u64;
+            offset += length;
+
+            if raw[32] == 1 && matches!(raw[33], 0 | 1) {
+                continue;
+            }
+
+            let mut entry = self.parse_record(raw, location)?;
+            if entry.relocated {
+                continue;
+            }
+
+            if let Some(block) = entry.child_link {
+                let target = self.read_dot(block)?;
+                entry.extents = target.extents;
+                entry.size = target.size;
+                entry.directory = true;
+            }
+
+            match entries.last_mut() {
+                Some(last) if last.multi_extent && last.name == entry.name => {
+                    last.extents.extend(entry.extents);
+                    last.size += entry.size;
+                    last.multi_extent = entry.multi_extent;
+                }
+                _ => entries.push(entry),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn lookup(
+        &self,
+        directory: &DirectoryRecord,
+        name: &str,
+    ) -> Result<Option<DirectoryRecord>, R::Error> {
+        // The path table only knows the primary names, which Rock Ridge
+        // replaces.
+        if self.rock_ridge.is_none()
+            && let Some(block) = self.find_directory(directory, name)
+        {
+            let mut record = self.read_dot(block)?;
+            record.name = name.to_string();
+            return Ok(Some(record));
+        }
+
+        Ok(self
+            .read_directory(directory)?
+            .into_iter()
+            .find(|record| record.name == name))
+    }
+}
+
+impl<R: Reader> Volume<R> {
+    fn read_block(&self, block: u32, length: usize) -> Result<Vec<u8>, R::Error> {
+        let mut buffer = vec![0; length];
+        self.reader
+            .read_bytes(block as u64 * self.logical_block_size, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn read_dot(&self, block: u32) -> Result<DirectoryRecord, R::Error> {
+        let data = self.read_block(block, self.logical_block_size as usize)?;
+        let length = data[0] as usize;
+        if length < MIN_RECORD_LENGTH {
+            return Err(FormatError::Invalid("directory record length").into());
+        }
+
+        self.parse_record(&data[..length], block as u64 * self.logical_block_size)
+    }
+
+    fn parse_record(&self, raw: &[u8], location: u64) -> Result<DirectoryRecord, R::Error> {
+        let name_length = raw[32] as usize;
+        if MIN_RECORD_LENGTH - 1 + name_length > raw.len() {
+            return Err(FormatError::Invalid("directory record name").into());
+        }
+
+        let name = self.decode_name(&raw[33..33 + name_length]);
+        let mut record = DirectoryRecord::parse(raw, name, location);
+
+        if let Some(skip) = self.rock_ridge {
+            let system_use = 33 + name_length + name_length.is_multiple_of(2) as usize + skip;
+            if system_use < raw.len() {
+                rock_ridge::apply(self, &mut record, &raw[system_use..])?;
+            }
+        }
+
+        Ok(record)
+    }
+
+    fn decode_name(&self, raw: &[u8]) -> String {
+        if self.joliet {
+            let units = raw
+                .chunks_exact(2)
+                .map(|unit| u16::from_be_bytes([unit[0], unit[1]]));
+            let name = char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect::<String>();
+            return strip_version(&name).to_string();
+        }
+
+        let name = String::from_utf8_lossy(raw);
+        strip_version(&name)
+            .trim_end_matches('.')
+            .to_ascii_lowercase()
+    }
+
+    fn load_path_table(&mut self, descriptor: &[u8]) -> Result<(), R::Error> {
+        let size = read_u32(descriptor, 132) as usize;
+        let block = read_u32(descriptor, 140);
+        let data = self.read_block(block, size)?;
+
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let name_length = data[offset] as usize;
+            let end = offset + 8 + name_length;
+            if name_length == 0 || end > data.len() {
+                return Err(FormatError::Invalid("path table entry").into());
+            }
+
+            self.path_table.push(PathTableEntry {
+                block: read_u32(&data, offset + 2),
+                parent: read_u16(&data, offset + 6),
+                name: self.decode_name(&data[offset + 8..end]),
+            });
+
+            offset = end + name_length % 2;
+        }
+
+        Ok(())
+    }
+
+    fn find_directory(&self, parent: &DirectoryRecord, name: &str) -> Option<u32> {
+        let parent_block = parent.extents.first()?.block;
+        let parent_index = self
+            .path_table
+            .iter()
+            .position(|entry| entry.block == parent_block)?;
+
+        self.path_table
+            .iter()
+            .skip(1)
+            .find(|entry| entry.parent as usize == parent_index + 1 && entry.name == name)
+            .map(|entry| entry.block)
+    }
+}
+
+fn strip_version(name: &str) -> &str {
+    name.rsplit_once(';').map_or(name, |(name, _)| name)
+}
+
+fn is_joliet(descriptor: &[u8]) -> bool {
+    JOLIET_ESCAPES
+        .iter()
+        .any(|escape| &descriptor[88..91] == *escape)
+}
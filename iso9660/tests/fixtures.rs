@@ -0,0 +1,60 @@
+use iso9660::{DirectoryRecord, FormatError, Volume};
+
+fn open(name: &str) -> Volume<Vec<u8>> {
+    let path = format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+    let image = std::fs::read(&path).unwrap_or_else(|err| panic!("{path}: {err}"));
+    Volume::open(image).unwrap()
+}
+
+fn lookup(volume: &Volume<Vec<u8>>, path: &str) -> Result<DirectoryRecord, FormatError> {
+    let mut record = volume.root().clone();
+    for component in path.split('/') {
+        record = volume.lookup(&record, component)?.expect(component);
+    }
+    Ok(record)
+}
+
+fn read(volume: &Volume<Vec<u8>>, path: &str) -> Vec<u8> {
+    let record = lookup(volume, path).unwrap();
+    let mut buffer = vec![0; record.size as usize];
+    let read = volume.read_file(&record.extents, record.size, 0, &mut buffer);
+    assert_eq!(read, Ok(buffer.len()));
+    buffer
+}
+
+#[test]
+#[ignore = "needs the images from tests/fixtures/generate.sh"]
+fn genisoimage_joliet() {
+    let volume = open("joliet.iso");
+    assert!(volume.joliet() && !volume.rock_ridge());
+
+    let mut names = volume
+        .read_directory(volume.root())
+        .unwrap()
+        .into_iter()
+        .map(|record| record.name)
+        .collect::<Vec<_>>();
+    names.sort();
+    // Without Rock Ridge genisoimage leaves the symlink out
+    assert_eq!(names, ["A long file name.txt", "Sub Directory"]);
+
+    assert_eq!(read(&volume, "A long file name.txt"), b"hello world\n");
+    assert_eq!(read(&volume, "Sub Directory/nested/file.txt"), b"nested\n");
+}
+
+#[test]
+#[ignore = "needs the images from tests/fixtures/generate.sh"]
+fn xorriso_rock_ridge() {
+    let volume = open("rockridge.iso");
+    assert!(volume.rock_ridge());
+
+    let file = lookup(&volume, "A long file name.txt").unwrap();
+    assert_eq!(file.posix.map(|posix| posix.mode & 0o7777), Some(0o640));
+    assert_eq!(read(&volume, "Sub Directory/nested/file.txt"), b"nested\n");
+
+    let link = lookup(&volume, "link").unwrap();
+    assert_eq!(
+        link.symlink.as_deref(),
+        Some("Sub Directory/nested/file.txt")
+    );
+}
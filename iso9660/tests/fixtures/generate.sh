@@ -0,0 +1,17 @@
+#!/bin/sh
+# Rebuilds the fixture images read by tests/fixtures.rs. Needs genisoimage and
+# xorriso; the output is deterministic apart from the volume timestamps.
+set -eu
+
+cd "$(dirname "$0")"
+tree=$(mktemp -d)
+trap 'rm -rf "$tree"' EXIT
+
+mkdir -p "$tree/Sub Directory/nested"
+printf 'hello world\n' > "$tree/A long file name.txt"
+printf 'nested\n' > "$tree/Sub Directory/nested/file.txt"
+ln -s "Sub Directory/nested/file.txt" "$tree/link"
+chmod 0640 "$tree/A long file name.txt"
+
+genisoimage -quiet -J -V JOLIET -o joliet.iso "$tree"
+xorriso -as mkisofs -quiet -R -V ROCKRIDGE -o rockridge.iso "$tree"
@@ -1,13 +1,13 @@
 use acpi::aml::AmlError;
 use acpi::{PciAddress, PhysicalMapping};
 use core::ptr::NonNull;
-use x86_64::PhysAddr;
 use x86_64::instructions::port::Port;
-use x86_64::structures::paging::PhysFrame;
+use x86_64::structures::paging::{PageSize, Size4KiB};
 use x86_64::structures::port::{PortRead, PortWrite};
+use x86_64::{PhysAddr, VirtAddr};
 
 use crate::mem::convert_physical_to_virtual;
-use crate::mem::{KERNEL_PAGE_TABLE, MappingType, MemoryManager};
+use crate::mem::{MMIO_ALLOCATOR, MappingType};
 
 #[derive(Clone)]
 pub struct AcpiHandler;
@@ -60,29 +60,29 @@ impl acpi::Handler for AcpiHandler {
         size: usize,
     ) -> PhysicalMapping<Self, T> {
         let address = PhysAddr::new(physical_address as u64);
-        let virtual_address = convert_physical_to_virtual(address);
 
-        <MemoryManager>::map_range_to(
-            virtual_address,
-            PhysFrame::containing_address(address),
-            size as u64,
-            MappingType::KernelData.flags(),
-            &mut KERNEL_PAGE_TABLE.lock(),
-        )
-        .unwrap();
+        let virtual_address = MMIO_ALLOCATOR
+            .lock()
+            .map(address, size as u64, MappingType::KernelData)
+            .unwrap();
 
         let virtual_start = NonNull::new_unchecked(virtual_address.as_mut_ptr());
+        let page_offset = address.as_u64() % Size4KiB::SIZE;
+        let mapped_length = (page_offset + size as u64).next_multiple_of(Size4KiB::SIZE);
 
         PhysicalMapping {
             physical_start: physical_address,
             virtual_start,
             region_length: size,
-            mapped_length: size,
+            mapped_length: mapped_length as usize,
             handler: self.clone(),
         }
     }
 
-    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
+    fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {
+        let virtual_address = VirtAddr::from_ptr(region.virtual_start.as_ptr());
+        MMIO_ALLOCATOR.lock().unmap(virtual_address).unwrap();
+    }
 
     aml_io!([], u8, (address: usize));
     aml_io!([], u16, (address: usize));
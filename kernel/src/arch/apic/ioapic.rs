@@ -1,29 +1,22 @@
+use acpi::platform::interrupt::{Polarity, TriggerMode};
 use spin::{Lazy, Mutex};
-use x2apic::ioapic::{IoApic, RedirectionTableEntry};
+use x2apic::ioapic::{IoApic, IrqFlags, RedirectionTableEntry};
 use x86_64::PhysAddr;
-use x86_64::structures::paging::PhysFrame;
 
 use crate::arch::acpi::ACPI;
 use crate::arch::interrupts::InterruptIndex;
-use crate::mem::convert_physical_to_virtual;
-use crate::mem::{KERNEL_PAGE_TABLE, MappingType, MemoryManager};
+use crate::mem::{MMIO_ALLOCATOR, MappingType};
 
-use super::lapic::LAPIC;
+use super::lapic::current_id;
 
 const IOAPIC_INTERRUPT_INDEX_OFFSET: u8 = 32;
 
 pub static IOAPIC: Lazy<Mutex<IoApic>> = Lazy::new(|| unsafe {
     let physical_address = PhysAddr::new(ACPI.apic.io_apics[0].address as u64);
-    let virtual_address = convert_physical_to_virtual(physical_address);
-
-    <MemoryManager>::map_range_to(
-        virtual_address,
-        PhysFrame::containing_address(physical_address),
-        0x1000,
-        MappingType::KernelData.flags(),
-        &mut KERNEL_PAGE_TABLE.lock(),
-    )
-    .unwrap();
+    let virtual_address = MMIO_ALLOCATOR
+        .lock()
+        .map(physical_address, 0x1000, MappingType::Uncached)
+        .unwrap();
 
     let mut ioapic = IoApic::new(virtual_address.as_u64());
     ioapic.init(IOAPIC_INTERRUPT_INDEX_OFFSET);
@@ -40,9 +33,45 @@ pub enum IrqVector {
 
 pub unsafe fn ioapic_add_entry(irq: IrqVector, vector: InterruptIndex) {
     let mut entry = RedirectionTableEntry::default();
-    entry.set_dest(LAPIC.lock().id() as u8);
+    entry.set_dest(current_id() as u8);
     entry.set_vector(vector as u8);
     let mut ioapic = IOAPIC.lock();
     ioapic.set_table_entry(irq as u8, entry);
     ioapic.enable_irq(irq as u8);
 }
+
+// PCI INTx lines are level-triggered and active low unless the MADT overrides
+// the legacy IRQ the firmware routed them to. Returns false when the IRQ has
+// no IOAPIC pin.
+pub unsafe fn ioapic_add_pci_entry(line: u8, vector: InterruptIndex) -> bool {
+    let source_override = ACPI
+        .apic
+        .interrupt_source_overrides
+        .iter()
+        .find(|source_override| source_override.isa_source == line);
+
+    let mut flags = IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE;
+    let mut irq = line as u32;
+    if let Some(source_override) = source_override {
+        irq = source_override.global_system_interrupt;
+        if matches!(source_override.polarity, Polarity::ActiveHigh) {
+            flags.remove(IrqFlags::LOW_ACTIVE);
+        }
+        if matches!(source_override.trigger_mode, TriggerMode::Edge) {
+            flags.remove(IrqFlags::LEVEL_TRIGGERED);
+        }
+    }
+
+    let mut ioapic = IOAPIC.lock();
+    if irq > ioapic.max_table_entry() as u32 {
+        return false;
+    }
+
+    let mut entry = RedirectionTableEntry::default();
+    entry.set_flags(flags);
+    entry.set_dest(current_id() as u8);
+    entry.set_vector(vector as u8);
+    ioapic.set_table_entry(irq as u8, entry);
+    ioapic.enable_irq(irq as u8);
+    true
+}
@@ -1,4 +1,4 @@
-use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use core::time::Duration;
 use x86_64::instructions::port::Port;
 
@@ -6,20 +6,27 @@ use derive_more::Deref;
 use spin::{Lazy, Mutex};
 use x2apic::lapic::{LocalApic, LocalApicBuilder, TimerMode};
 use x86_64::PhysAddr;
-use x86_64::structures::paging::PhysFrame;
+use x86_64::registers::model_specific::Msr;
 
 use crate::arch::acpi::ACPI;
 use crate::arch::interrupts::InterruptIndex;
 use crate::drivers::hpet::HPET;
-use crate::mem::convert_physical_to_virtual;
-use crate::mem::{KERNEL_PAGE_TABLE, MappingType, MemoryManager};
+use crate::mem::{MMIO_ALLOCATOR, MappingType};
 
 const TIMER_FREQUENCY_HZ: u32 = 200;
 const TIMER_CALIBRATION_ITERATION: u32 = 50;
 
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const X2APIC_ID: u32 = 0x802;
+const XAPIC_ID_OFFSET: u64 = 0x20;
+
 pub static APIC_INIT: AtomicBool = AtomicBool::new(false);
 pub static LAPIC_TIMER_INITIAL: AtomicU32 = AtomicU32::new(0);
 
+// Virtual address of the xAPIC ID register, or zero in x2APIC mode.
+static XAPIC_ID_REGISTER: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Deref)]
 pub struct LockedLocalApic(Mutex<LocalApic>);
 
@@ -28,16 +35,10 @@ unsafe impl Sync for LockedLocalApic {}
 
 pub static LAPIC: Lazy<LockedLocalApic> = Lazy::new(|| unsafe {
     let physical_address = PhysAddr::new(ACPI.apic.local_apic_address);
-    let virtual_address = convert_physical_to_virtual(physical_address);
-
-    <MemoryManager>::map_range_to(
-        virtual_address,
-        PhysFrame::containing_address(physical_address),
-        0x1000,
-        MappingType::KernelData.flags(),
-        &mut KERNEL_PAGE_TABLE.lock(),
-    )
-    .unwrap();
+    let virtual_address = MMIO_ALLOCATOR
+        .lock()
+        .map(physical_address, 0x1000, MappingType::Uncached)
+        .unwrap();
 
     let mut lapic = LocalApicBuilder::new()
         .timer_vector(InterruptIndex::Timer as usize)
@@ -51,6 +52,11 @@ pub static LAPIC: Lazy<LockedLocalApic> = Lazy::new(|| unsafe {
 
     lapic.enable();
 
+    if unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_X2APIC_ENABLE == 0 {
+        let register = virtual_address.as_u64() + XAPIC_ID_OFFSET;
+        XAPIC_ID_REGISTER.store(register, Ordering::Release);
+    }
+
     LockedLocalApic(Mutex::new(lapic))
 });
 
@@ -60,6 +66,17 @@ pub fn end_of_interrupt() {
     }
 }
 
+// Reads the ID register directly so interrupt handlers and the scheduler
+// never contend on the LAPIC lock.
+pub fn current_id() -> u32 {
+    Lazy::force(&LAPIC);
+
+    match XAPIC_ID_REGISTER.load(Ordering::Acquire) {
+        0 => unsafe { Msr::new(X2APIC_ID).read() as u32 },
+        register => unsafe { core::ptr::read_volatile(register as *const u32) >> 24 },
+    }
+}
+
 pub unsafe fn disable_pic() {
     Port::<u8>::new(0x21).write(0xff);
     Port::<u8>::new(0xa1).write(0xff);
@@ -4,9 +4,9 @@ mod ioapic;
 mod lapic;
 
 use super::interrupts::InterruptIndex;
-pub use ioapic::{IrqVector, ioapic_add_entry};
+pub use ioapic::{IrqVector, ioapic_add_entry, ioapic_add_pci_entry};
 pub use lapic::{APIC_INIT, LAPIC, LAPIC_TIMER_INITIAL};
-pub use lapic::{disable_pic, end_of_interrupt};
+pub use lapic::{current_id, disable_pic, end_of_interrupt};
 
 pub fn init() {
     unsafe {
@@ -1,13 +1,20 @@
+use alloc::collections::BTreeMap;
+use core::mem::variant_count;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Lazy;
 use x86_64::VirtAddr;
 use x86_64::instructions::port::Port;
 use x86_64::registers::control::Cr2;
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::PageFaultErrorCode;
 
+use super::apic;
 use super::gdt::DOUBLE_FAULT_IST_INDEX;
+use super::smp::LAPIC_IDS;
 use crate::drivers::term::SCANCODE_QUEUE;
+use crate::mem::handle_swap_fault;
 use crate::tasks::scheduler::SCHEDULER;
 use crate::tasks::timer::TIMER;
 
@@ -22,6 +29,43 @@ pub enum InterruptIndex {
     Keyboard,
     Mouse,
     HpetTimer,
+    TlbShootdown,
+    Ahci,
+    Virtio,
+    Nvme,
+}
+
+impl InterruptIndex {
+    pub const ALL: [InterruptIndex; variant_count::<InterruptIndex>()] = [
+        InterruptIndex::Timer,
+        InterruptIndex::ApicError,
+        InterruptIndex::ApicSpurious,
+        InterruptIndex::Keyboard,
+        InterruptIndex::Mouse,
+        InterruptIndex::HpetTimer,
+        InterruptIndex::TlbShootdown,
+        InterruptIndex::Ahci,
+        InterruptIndex::Virtio,
+        InterruptIndex::Nvme,
+    ];
+}
+
+type InterruptCounters = [AtomicU64; variant_count::<InterruptIndex>()];
+
+// Indexed by LAPIC ID, then by vector relative to the first InterruptIndex.
+pub static INTERRUPT_COUNTS: Lazy<BTreeMap<u32, InterruptCounters>> = Lazy::new(|| {
+    LAPIC_IDS
+        .iter()
+        .map(|&lapic_id| (lapic_id, core::array::from_fn(|_| AtomicU64::new(0))))
+        .collect()
+});
+
+fn count_interrupt(index: InterruptIndex) {
+    let lapic_id = apic::current_id();
+    if let Some(counters) = INTERRUPT_COUNTS.get(&lapic_id) {
+        let slot = (index as u8 - INTERRUPT_INDEX_OFFSET) as usize;
+        counters[slot].fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
@@ -40,6 +84,10 @@ pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt);
     idt[InterruptIndex::Mouse as u8].set_handler_fn(mouse_interrupt);
     idt[InterruptIndex::HpetTimer as u8].set_handler_fn(hpet_timer_interrupt);
+    idt[InterruptIndex::TlbShootdown as u8].set_handler_fn(tlb_shootdown_interrupt);
+    idt[InterruptIndex::Ahci as u8].set_handler_fn(ahci_interrupt);
+    idt[InterruptIndex::Virtio as u8].set_handler_fn(virtio_interrupt);
+    idt[InterruptIndex::Nvme as u8].set_handler_fn(nvme_interrupt);
 
     unsafe {
         idt.double_fault
@@ -53,6 +101,7 @@ pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
 #[unsafe(naked)]
 pub extern "x86-interrupt" fn timer_interrupt(_frame: InterruptStackFrame) {
     fn timer_handler(context: VirtAddr) -> VirtAddr {
+        count_interrupt(InterruptIndex::Timer);
         super::apic::end_of_interrupt();
         SCHEDULER.lock().schedule(context)
     }
@@ -69,20 +118,47 @@ pub extern "x86-interrupt" fn timer_interrupt(_frame: InterruptStackFrame) {
 }
 
 extern "x86-interrupt" fn lapic_error(_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::ApicError);
     super::apic::end_of_interrupt();
     log::error!("Local APIC error!");
 }
 
 extern "x86-interrupt" fn spurious_interrupt(_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::ApicSpurious);
     super::apic::end_of_interrupt();
     log::debug!("Received spurious interrupt!");
 }
 
 extern "x86-interrupt" fn hpet_timer_interrupt(_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::HpetTimer);
     TIMER.lock().wakeup();
     super::apic::end_of_interrupt();
 }
 
+extern "x86-interrupt" fn tlb_shootdown_interrupt(_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::TlbShootdown);
+    super::shootdown::handle_interrupt();
+    super::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn ahci_interrupt(_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::Ahci);
+    crate::drivers::ahci::handle_interrupt();
+    super::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn virtio_interrupt(_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::Virtio);
+    crate::drivers::virtio::blk::handle_interrupt();
+    super::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn nvme_interrupt(_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::Nvme);
+    crate::drivers::nvme::handle_interrupt();
+    super::apic::end_of_interrupt();
+}
+
 extern "x86-interrupt" fn segment_not_present(frame: InterruptStackFrame, code: u64) {
     log::error!("Exception: Segment Not Present\n{frame:#?}");
     log::error!("Error Code: {code:#x}");
@@ -111,18 +187,29 @@ extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, code: u64) ->
 }
 
 extern "x86-interrupt" fn keyboard_interrupt(_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::Keyboard);
     super::apic::end_of_interrupt();
     let scancode = unsafe { Port::new(0x60).read() };
     SCANCODE_QUEUE.force_push(scancode);
 }
 
 extern "x86-interrupt" fn mouse_interrupt(_frame: InterruptStackFrame) {
+    count_interrupt(InterruptIndex::Mouse);
     let packet = unsafe { Port::new(0x60).read() };
     crate::drivers::mouse::MOUSE.lock().process_packet(packet);
     super::apic::end_of_interrupt();
 }
 
 extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, code: PageFaultErrorCode) {
+    if let Ok(address) = Cr2::read() {
+        let preemptible = frame.cpu_flags.contains(RFlags::INTERRUPT_FLAG);
+        if !code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+            && handle_swap_fault(address, preemptible)
+        {
+            return;
+        }
+    }
+
     log::warn!("Exception: Page Fault\n{frame:#?}");
     log::warn!("Error Code: {code:#x}");
     match Cr2::read() {
@@ -1,8 +1,11 @@
 use apic::{APIC_INIT, LAPIC, LAPIC_TIMER_INITIAL};
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicU64, Ordering};
 use limine::mp::Cpu;
+use x86_64::instructions::random::RdRand;
+use x86_64::instructions::tlb;
 use x86_64::registers::control::{Cr0, Cr4};
 use x86_64::registers::control::{Cr0Flags, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
 
 use crate::syscall;
 use crate::tasks::scheduler::SCHEDULER_INIT;
@@ -13,8 +16,14 @@ pub mod acpi;
 pub mod apic;
 pub mod gdt;
 pub mod interrupts;
+pub mod shootdown;
 pub mod smp;
 
+const IA32_PAT: u32 = 0x277;
+
+// PA0..PA7: WB, WC, UC-, UC, WB, WC, UC-, UC
+const PAT_LAYOUT: u64 = 0x0007_0106_0007_0106;
+
 pub fn init_sse() {
     let mut cr0 = Cr0::read();
     cr0.remove(Cr0Flags::EMULATE_COPROCESSOR);
@@ -27,11 +36,34 @@ pub fn init_sse() {
     unsafe { Cr4::write(cr4) };
 }
 
+pub fn init_pat() {
+    unsafe {
+        Msr::new(IA32_PAT).write(PAT_LAYOUT);
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    }
+    tlb::flush_all();
+}
+
+// Falls back to a scrambled TSC on CPUs without RDRAND, which is fine for
+// identifiers but not for secrets.
+pub fn random_u64() -> u64 {
+    static FALLBACK: AtomicU64 = AtomicU64::new(0);
+
+    match RdRand::new().and_then(|rdrand| rdrand.get_u64()) {
+        Some(value) => value,
+        None => {
+            let counter = FALLBACK.fetch_add(1, Ordering::Relaxed);
+            unsafe { core::arch::x86_64::_rdtsc() }.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ counter
+        }
+    }
+}
+
 unsafe extern "C" fn ap_entry(smp_info: &Cpu) -> ! {
     CPUS.write().load(smp_info.lapic_id);
     IDT.load();
 
     init_sse();
+    init_pat();
 
     while !APIC_INIT.load(Ordering::SeqCst) {
         core::hint::spin_loop()
@@ -46,6 +78,7 @@ unsafe extern "C" fn ap_entry(smp_info: &Cpu) -> ! {
     while !SCHEDULER_INIT.load(Ordering::SeqCst) {
         core::hint::spin_loop()
     }
+    shootdown::init();
     x86_64::instructions::interrupts::enable();
     log::debug!("Application Processor {} started", smp_info.id);
 
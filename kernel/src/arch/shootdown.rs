@@ -0,0 +1,138 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crossbeam_queue::ArrayQueue;
+use spin::Lazy;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr, align_up};
+
+use super::apic::{self, LAPIC};
+use super::interrupts::InterruptIndex;
+use super::smp::LAPIC_IDS;
+
+const QUEUE_CAPACITY: usize = 16;
+const FLUSH_ALL_THRESHOLD: u64 = 64;
+const KERNEL_SPACE_START: u64 = 0xffff_8000_0000_0000;
+
+static QUEUES: Lazy<BTreeMap<u32, ShootdownQueue>> = Lazy::new(|| {
+    LAPIC_IDS
+        .iter()
+        .map(|&lapic_id| (lapic_id, ShootdownQueue::default()))
+        .collect()
+});
+
+struct ShootdownRequest {
+    range: Range<VirtAddr>,
+    cr3: PhysAddr,
+    pending: Arc<AtomicUsize>,
+}
+
+struct ShootdownQueue {
+    online: AtomicBool,
+    requests: ArrayQueue<ShootdownRequest>,
+}
+
+impl ShootdownQueue {
+    fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for ShootdownQueue {
+    fn default() -> Self {
+        Self {
+            online: AtomicBool::new(false),
+            requests: ArrayQueue::new(QUEUE_CAPACITY),
+        }
+    }
+}
+
+pub fn init() {
+    let lapic_id = apic::current_id();
+    QUEUES[&lapic_id].online.store(true, Ordering::SeqCst);
+    tlb::flush_all();
+}
+
+pub fn shootdown(range: Range<VirtAddr>, cr3: PhysAddr) {
+    interrupts::without_interrupts(|| {
+        if should_flush(&range, cr3) {
+            flush_range(&range);
+        }
+
+        if !QUEUES.values().any(ShootdownQueue::is_online) {
+            return;
+        }
+
+        let current_id = apic::current_id();
+        let targets = QUEUES
+            .iter()
+            .filter(|(lapic_id, queue)| **lapic_id != current_id && queue.is_online())
+            .collect::<Vec<_>>();
+
+        if targets.is_empty() {
+            return;
+        }
+
+        let pending = Arc::new(AtomicUsize::new(targets.len()));
+        for (&lapic_id, queue) in targets {
+            let mut request = ShootdownRequest {
+                range: range.clone(),
+                cr3,
+                pending: pending.clone(),
+            };
+
+            while let Err(rejected) = queue.requests.push(request) {
+                request = rejected;
+                handle_requests(current_id);
+                core::hint::spin_loop();
+            }
+
+            unsafe {
+                LAPIC
+                    .lock()
+                    .send_ipi(InterruptIndex::TlbShootdown as u8, lapic_id);
+            }
+        }
+
+        while pending.load(Ordering::SeqCst) > 0 {
+            handle_requests(current_id);
+            core::hint::spin_loop();
+        }
+    });
+}
+
+pub fn handle_interrupt() {
+    let lapic_id = apic::current_id();
+    handle_requests(lapic_id);
+}
+
+fn handle_requests(lapic_id: u32) {
+    while let Some(request) = QUEUES[&lapic_id].requests.pop() {
+        if should_flush(&request.range, request.cr3) {
+            flush_range(&request.range);
+        }
+        request.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn should_flush(range: &Range<VirtAddr>, cr3: PhysAddr) -> bool {
+    range.start.as_u64() >= KERNEL_SPACE_START || Cr3::read().0.start_address() == cr3
+}
+
+fn flush_range(range: &Range<VirtAddr>) {
+    let start = range.start.align_down(Size4KiB::SIZE).as_u64();
+    let end = align_up(range.end.as_u64(), Size4KiB::SIZE);
+
+    if (end - start) / Size4KiB::SIZE > FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+        return;
+    }
+
+    for address in (start..end).step_by(Size4KiB::SIZE as usize) {
+        tlb::flush(VirtAddr::new(address));
+    }
+}
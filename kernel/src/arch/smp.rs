@@ -1,4 +1,5 @@
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use limine::request::MpRequest;
 use spin::{Lazy, RwLock};
 
@@ -14,6 +15,11 @@ pub static BSP_LAPIC_ID: Lazy<u32> = Lazy::new(|| {
     response.bsp_lapic_id()
 });
 
+pub static LAPIC_IDS: Lazy<Vec<u32>> = Lazy::new(|| {
+    let response = MP_REQUEST.get_response().unwrap();
+    response.cpus().iter().map(|cpu| cpu.lapic_id).collect()
+});
+
 pub static CPUS: Lazy<RwLock<Cpus>> = Lazy::new(RwLock::default);
 
 pub struct Cpus(BTreeMap<u32, CpuInfo>);
@@ -1,5 +1,7 @@
 use vcell::VolatileCell as Volatile;
 
+pub const PRDT_ENTRY_COUNT: usize = 248;
+
 #[repr(C)]
 pub struct CommandHeader {
     pub flags: u16,
@@ -14,7 +16,7 @@ pub struct CommandTable {
     pub cfis: [u8; 64],
     pub acmd: [u8; 16],
     pub reserved: [u8; 48],
-    pub prdt: [PrdtEntry; 1],
+    pub prdt: [PrdtEntry; PRDT_ENTRY_COUNT],
 }
 
 #[repr(C)]
@@ -1,23 +1,60 @@
+use alloc::format;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bit_field::BitField;
+use core::ops::Range;
+use core::slice;
+use core::time::Duration;
+use spin::Mutex;
 use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
 
-use super::cmd::{CommandHeader, CommandTable, FisRegH2D};
-use super::hba::{HbaMemory, HbaPort};
+use super::cmd::{CommandHeader, CommandTable, FisRegH2D, PRDT_ENTRY_COUNT};
+use super::hba::{HbaMemory, HbaPort, PORT_IE_DEFAULT, PORT_IS_ERROR};
+use super::hba::{TFD_STATUS_BSY, TFD_STATUS_DRQ, TFD_STATUS_ERR};
 use super::identify::{Identify, IdentifyData};
-use crate::mem::DmaManager;
+use crate::drivers::hpet::HPET;
+use crate::io::block::{BlockDeviceError, BlockDeviceResult};
+use crate::io::request::{BlockOperation, BlockRequest, DmaLimits, DmaTransfer, PendingRequest};
+use crate::mem::{DmaManager, convert_virtual_to_physical};
 
 pub const BLOCK_SIZE: usize = 512;
+pub const ATAPI_BLOCK_SIZE: usize = 2048;
+const MAX_COMMAND_SIZE: usize = 1024 * 1024;
+const MAX_PRD_SIZE: usize = 4 * 1024 * 1024;
+const COMMAND_SLOTS: usize = 32;
 const FIS_TYPE_REG_H2D: u8 = 0x27;
 const CMD_READ_DMA_EXT: u8 = 0x25;
 const CMD_WRITE_DMA_EXT: u8 = 0x35;
+const CMD_READ_FPDMA_QUEUED: u8 = 0x60;
+const CMD_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const CMD_DATA_SET_MANAGEMENT: u8 = 0x06;
 const CMD_IDENTIFY_DEVICE: u8 = 0xEC;
+const CMD_PACKET: u8 = 0xA0;
+const CMD_IDENTIFY_PACKET_DEVICE: u8 = 0xA1;
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const UNIT_READY_RETRIES: usize = 3;
+const TRIM_RANGE_MAX: u64 = 0xFFFF;
+const COMRESET_DURATION: Duration = Duration::from_millis(1);
+const LINK_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct Ahci {
-    pub data: &'static mut [u8],
-    pub port: &'static HbaPort,
-    pub cmd_list: &'static [CommandHeader],
-    pub cmd_table: &'static mut CommandTable,
+    hba: &'static HbaMemory,
+    port: &'static HbaPort,
+    port_index: usize,
+    cmd_list: &'static mut [CommandHeader],
+    cmd_tables: Vec<&'static mut CommandTable>,
+    fis_area: VirtAddr,
+    data: &'static mut [u8],
+    slots: Vec<Option<Arc<PendingRequest>>>,
+    queue_depth: usize,
+    block_size: usize,
+    ncq: bool,
+    atapi: bool,
+    offline: bool,
 }
 
 unsafe impl Send for Ahci {}
@@ -33,54 +70,485 @@ impl Ahci {
 
         (0..hba_memory.support_port_count())
             .filter(|&port_num| hba_memory.port_active(port_num))
-            .flat_map(|port_num| hba_memory.get_port(port_num))
-            .map(|port| unsafe { port.init_ahci() })
+            .flat_map(|port_num| Some((port_num, hba_memory.get_port(port_num)?)))
+            .map(|(port_num, port)| unsafe { Self::init_port(hba_memory, port, port_num) })
             .collect()
     }
 
     pub fn identity(&mut self) -> IdentifyData {
-        unsafe {
-            self.execute_command(CMD_IDENTIFY_DEVICE, 0);
-            (&*(self.data.as_ptr() as *const Identify)).into()
+        let command = if self.atapi {
+            CMD_IDENTIFY_PACKET_DEVICE
+        } else {
+            CMD_IDENTIFY_DEVICE
+        };
+
+        let buffer = self.data_buffer(BLOCK_SIZE);
+        let result = self
+            .wait_idle()
+            .and_then(|_| self.execute_polled(command, Some(buffer)));
+
+        if let Err(err) = result {
+            log::warn!("AHCI port {} identify failed: {err}", self.port_index);
+        }
+
+        let mut identify: IdentifyData =
+            unsafe { (&*(self.data.as_ptr() as *const Identify)).into() };
+        if self.atapi {
+            identify.ncq_supported = false;
+            (identify.block_count, identify.block_size) = match self.read_capacity() {
+                Ok(capacity) => capacity,
+                Err(err) => {
+                    log::warn!("AHCI port {} has no readable media: {err}", self.port_index);
+                    (0, ATAPI_BLOCK_SIZE)
+                }
+            };
         }
+
+        identify
     }
 
-    pub fn read_block(&mut self, start_sector: u64, buffer: &mut [u8]) {
-        unsafe { self.execute_command(CMD_READ_DMA_EXT, start_sector) }
-        let length = buffer.len().min(BLOCK_SIZE);
-        buffer.copy_from_slice(&self.data[..length]);
+    pub fn is_atapi(&self) -> bool {
+        self.atapi
     }
 
-    pub fn write_block(&mut self, start_sector: u64, buffer: &[u8]) {
-        let length = buffer.len().min(BLOCK_SIZE);
-        self.data[..length].copy_from_slice(&buffer[..length]);
-        unsafe { self.execute_command(CMD_WRITE_DMA_EXT, start_sector) }
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth
     }
 
-    unsafe fn execute_command(&mut self, command: u8, start_sector: u64) {
-        let cmd_table = &mut *self.cmd_table;
-        let fis = &mut *(cmd_table.cfis.as_mut_ptr() as *mut FisRegH2D);
-        fis.fis_type = FIS_TYPE_REG_H2D;
-        fis.cflags = 1 << 7;
-        fis.command = command;
+    pub fn flush(&mut self) -> BlockDeviceResult<()> {
+        if self.atapi {
+            return Ok(());
+        }
+
+        self.wait_idle()?;
+        self.execute_polled(CMD_FLUSH_CACHE_EXT, None)
+    }
+
+    pub fn trim(&mut self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.wait_idle()?;
+
+        let mut block_id = range.start;
+        while block_id < range.end {
+            // Each 8-byte entry holds a 48-bit LBA and a 16-bit sector count.
+            let entries = unsafe {
+                slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut u64, BLOCK_SIZE / 8)
+            };
+            entries.fill(0);
+
+            for entry in entries.iter_mut() {
+                if block_id >= range.end {
+                    break;
+                }
+                let count = (range.end - block_id).min(TRIM_RANGE_MAX);
+                *entry = block_id | (count << 48);
+                block_id += count;
+            }
 
-        fis.device = match command {
-            CMD_READ_DMA_EXT | CMD_WRITE_DMA_EXT => 1 << 6,
-            _ => 0,
+            let buffer = self.data_buffer(BLOCK_SIZE);
+            self.prepare_slot(0, CMD_DATA_SET_MANAGEMENT, 0, 1, &[buffer], true);
+            self.run_polled()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_interrupt(&mut self) {
+        if self.hba.interrupt_status.get().get_bit(self.port_index) {
+            self.process_completions();
+            self.hba.interrupt_status.set(1 << self.port_index);
+        }
+    }
+
+    pub fn process_completions(&mut self) {
+        if self.take_error().is_some() {
+            return;
+        }
+
+        let active = self.port.sata_active.get() | self.port.command_issue.get();
+        for (slot, pending) in self.slots.iter_mut().enumerate() {
+            if !active.get_bit(slot) {
+                if let Some(pending) = pending.take() {
+                    pending.finish(Ok(()));
+                }
+            }
+        }
+    }
+
+    pub fn submit(device: &Mutex<Self>, request: BlockRequest) {
+        let block_size = interrupts::without_interrupts(|| device.lock().block_size);
+        let commands = match plan_commands(&request, block_size) {
+            Ok(commands) if !commands.is_empty() => commands,
+            Ok(_) => return request.complete(Ok(())),
+            Err(err) => return request.complete(Err(err)),
         };
 
-        fis.sector_count = if command == CMD_IDENTIFY_DEVICE { 0 } else { 1 };
-        fis.set_lba(start_sector);
+        let write = request.operation == BlockOperation::Write;
+        let pending = PendingRequest::new(request, commands.len());
+
+        for command in commands.iter() {
+            loop {
+                let issued = interrupts::without_interrupts(|| {
+                    let mut ahci = device.lock();
+                    if ahci.offline {
+                        return Some(Err(ahci.offline_error()));
+                    }
+                    ahci.process_completions();
+
+                    let slot = ahci.free_slot()?;
+                    ahci.issue(slot, command, write, pending.clone());
+                    Some(Ok(()))
+                });
+
+                match issued {
+                    Some(Ok(())) => break,
+                    Some(Err(err)) => {
+                        pending.finish(Err(err));
+                        break;
+                    }
+                    None => {}
+                }
+
+                if interrupts::are_enabled() {
+                    crate::syscall::r#yield();
+                } else {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+}
+
+impl Ahci {
+    unsafe fn init_port(hba: &'static HbaMemory, port: &'static HbaPort, index: usize) -> Self {
+        port.stop_cmd();
+
+        let (cmd_list_pa, cmd_list_va) = DmaManager::allocate(size_of::<CommandHeader>());
+        let (fis_pa, fis_va) = DmaManager::allocate(DmaManager::UNIT_SIZE);
+        let (_, data_va) = DmaManager::allocate(BLOCK_SIZE);
+        let atapi = port.is_atapi_device();
+
+        port.command_list_base_address.set(cmd_list_pa.as_u64());
+        port.fis_base_address.set(fis_pa.as_u64());
+
+        let cmd_list_ptr = cmd_list_va.as_mut_ptr::<CommandHeader>();
+        let cmd_list = unsafe { slice::from_raw_parts_mut(cmd_list_ptr, COMMAND_SLOTS) };
+
+        let slot_count = hba.command_slot_count();
+        let cmd_tables = cmd_list
+            .iter_mut()
+            .take(slot_count)
+            .map(|header| {
+                let (cmd_table_pa, cmd_table_va) = DmaManager::allocate(size_of::<CommandTable>());
+                header.command_table_base_address = cmd_table_pa.as_u64();
+                unsafe { &mut *cmd_table_va.as_mut_ptr::<CommandTable>() }
+            })
+            .collect();
+
+        port.clear_errors();
+        port.interrupt_enable.set(PORT_IE_DEFAULT);
+        port.command.set(*port.command.get().set_bit(24, atapi));
+        port.start_cmd();
+
+        let mut ahci = Self {
+            hba,
+            port,
+            port_index: index,
+            cmd_list,
+            cmd_tables,
+            fis_area: fis_va,
+            data: unsafe { slice::from_raw_parts_mut(data_va.as_mut_ptr(), BLOCK_SIZE) },
+            slots: (0..slot_count).map(|_| None).collect(),
+            queue_depth: 1,
+            block_size: BLOCK_SIZE,
+            ncq: false,
+            atapi,
+            offline: false,
+        };
+
+        let identify = ahci.identity();
+        ahci.block_size = identify.block_size;
+        if hba.supports_ncq() && identify.ncq_supported {
+            ahci.ncq = true;
+            ahci.queue_depth = identify.queue_depth.min(slot_count);
+        }
+
+        ahci
+    }
+
+    fn data_buffer(&self, length: usize) -> (u64, usize) {
+        let address = convert_virtual_to_physical(VirtAddr::from_ptr(self.data.as_ptr()));
+        (address.as_u64(), length)
+    }
+
+    fn read_capacity(&mut self) -> BlockDeviceResult<(u64, usize)> {
+        // The first command after a reset or media change reports a unit
+        // attention condition, so retry until the drive settles.
+        let mut result = Ok(());
+        for _ in 0..UNIT_READY_RETRIES {
+            result = self.execute_packet(&packet(SCSI_TEST_UNIT_READY, 0, 0), None);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result?;
+
+        let buffer = self.data_buffer(8);
+        self.execute_packet(&packet(SCSI_READ_CAPACITY, 0, 0), Some(buffer))?;
+
+        let last_block = u32::from_be_bytes(self.data[0..4].try_into().unwrap());
+        let block_size = u32::from_be_bytes(self.data[4..8].try_into().unwrap());
+        Ok((last_block as u64 + 1, block_size as usize))
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        let active = self.port.sata_active.get() | self.port.command_issue.get();
+        (0..self.queue_depth).find(|&slot| self.slots[slot].is_none() && !active.get_bit(slot))
+    }
+
+    fn offline_error(&self) -> BlockDeviceError {
+        BlockDeviceError::IoError(format!("AHCI port {} is offline", self.port_index))
+    }
+
+    fn wait_idle(&mut self) -> BlockDeviceResult<()> {
+        if self.offline {
+            return Err(self.offline_error());
+        }
+
+        while self.slots.iter().any(Option::is_some) {
+            self.process_completions();
+            core::hint::spin_loop();
+        }
+
+        match self.take_error() {
+            Some((status, error)) => Err(BlockDeviceError::Ata { status, error }),
+            None => Ok(()),
+        }
+    }
+
+    fn take_error(&mut self) -> Option<(u8, u8)> {
+        let interrupt_status = self.port.interrupt_status.get();
+        self.port.interrupt_status.set(interrupt_status);
+
+        let task_file = self.port.task_file_data.get();
+        if interrupt_status & PORT_IS_ERROR == 0 && task_file & TFD_STATUS_ERR == 0 {
+            return None;
+        }
+
+        self.recover(task_file);
+        Some((
+            task_file.get_bits(0..8) as u8,
+            task_file.get_bits(8..16) as u8,
+        ))
+    }
+
+    fn recover(&mut self, task_file: u32) {
+        log::warn!(
+            "AHCI port {} error (task file: {task_file:#x}), restarting port",
+            self.port_index
+        );
+
+        // Commands in flight are lost once the port is stopped
+        for pending in self.slots.iter_mut().filter_map(Option::take) {
+            pending.finish(Err(BlockDeviceError::Ata {
+                status: task_file.get_bits(0..8) as u8,
+                error: task_file.get_bits(8..16) as u8,
+            }));
+        }
+
+        self.port.stop_cmd();
+        self.port.clear_errors();
+
+        if task_file & (TFD_STATUS_BSY | TFD_STATUS_DRQ) != 0 {
+            let control = &self.port.sata_control;
+            control.set(*control.get().set_bits(0..4, 1));
+            wait_until(COMRESET_DURATION, || false);
+            control.set(*control.get().set_bits(0..4, 0));
+
+            if !wait_until(LINK_TIMEOUT, || self.port.device_connected()) {
+                log::error!(
+                    "AHCI port {} link did not come back after reset, taking it offline",
+                    self.port_index
+                );
+                self.offline = true;
+                return;
+            }
+            self.port.clear_errors();
+        }
+
+        self.port.start_cmd();
+    }
+
+    fn execute_polled(
+        &mut self,
+        command: u8,
+        buffer: Option<(u64, usize)>,
+    ) -> BlockDeviceResult<()> {
+        let prdt = buffer.into_iter().collect::<Vec<_>>();
+        let sectors = prdt.iter().map(|&(_, length)| length / BLOCK_SIZE).sum();
+        self.prepare_slot(0, command, 0, sectors, &prdt, false);
+        self.run_polled()
+    }
+
+    fn execute_packet(
+        &mut self,
+        cdb: &[u8; 12],
+        buffer: Option<(u64, usize)>,
+    ) -> BlockDeviceResult<()> {
+        let prdt = buffer.into_iter().collect::<Vec<_>>();
+        self.prepare_slot(0, CMD_PACKET, 0, 0, &prdt, false);
+        self.cmd_tables[0].acmd[..cdb.len()].copy_from_slice(cdb);
+        self.run_polled()
+    }
+
+    fn run_polled(&mut self) -> BlockDeviceResult<()> {
+        if self.offline {
+            return Err(self.offline_error());
+        }
 
         self.port.command_issue.set(1 << 0);
-        while self.port.command_issue.get().get_bit(0) {}
+        while self.port.command_issue.get().get_bit(0) {
+            if self.port.interrupt_status.get() & PORT_IS_ERROR != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+
+        match self.take_error() {
+            Some((status, error)) => Err(BlockDeviceError::Ata { status, error }),
+            None => Ok(()),
+        }
+    }
+
+    fn issue(
+        &mut self,
+        slot: usize,
+        command: &DmaTransfer,
+        write: bool,
+        pending: Arc<PendingRequest>,
+    ) {
+        let opcode = match (self.ncq, write) {
+            (true, false) => CMD_READ_FPDMA_QUEUED,
+            (true, true) => CMD_WRITE_FPDMA_QUEUED,
+            (false, false) => CMD_READ_DMA_EXT,
+            (false, true) => CMD_WRITE_DMA_EXT,
+        };
+
+        let (block_id, sectors) = (command.block_id, command.block_count);
+        if self.atapi {
+            self.prepare_slot(slot, CMD_PACKET, 0, 0, &command.segments, false);
+            let cdb = packet(SCSI_READ_10, block_id as u32, sectors as u16);
+            self.cmd_tables[slot].acmd[..cdb.len()].copy_from_slice(&cdb);
+        } else {
+            self.prepare_slot(slot, opcode, block_id, sectors, &command.segments, write);
+        }
+        self.slots[slot] = Some(pending);
+
+        if self.ncq {
+            self.port.sata_active.set(1 << slot);
+        }
+        self.port.command_issue.set(1 << slot);
+    }
+
+    fn prepare_slot(
+        &mut self,
+        slot: usize,
+        command: u8,
+        block_id: u64,
+        sectors: usize,
+        prdt: &[(u64, usize)],
+        write: bool,
+    ) {
+        let header = &mut self.cmd_list[slot];
+        header.flags = (size_of::<FisRegH2D>() / size_of::<u32>()) as u16;
+        header.flags.set_bit(5, command == CMD_PACKET);
+        header.flags.set_bit(6, write);
+        header.prdt_length = prdt.len() as u16;
+        header.prd_byte_count.set(0);
+
+        let cmd_table = &mut *self.cmd_tables[slot];
+        for (entry, &(address, length)) in cmd_table.prdt.iter_mut().zip(prdt) {
+            entry.data_base_address = address;
+            entry.reserved = 0;
+            entry.byte_count_i = (length - 1) as u32;
+        }
+
+        cmd_table.cfis.fill(0);
+        cmd_table.acmd.fill(0);
+        let fis = unsafe { &mut *(cmd_table.cfis.as_mut_ptr() as *mut FisRegH2D) };
+        fis.fis_type = FIS_TYPE_REG_H2D;
+        fis.cflags = 1 << 7;
+        fis.command = command;
+        fis.set_lba(block_id);
+
+        match command {
+            CMD_READ_FPDMA_QUEUED | CMD_WRITE_FPDMA_QUEUED => {
+                fis.feature_lo = sectors as u8;
+                fis.feature_hi = (sectors >> 8) as u8;
+                fis.sector_count = (slot << 3) as u16;
+                fis.device = 1 << 6;
+            }
+            CMD_READ_DMA_EXT | CMD_WRITE_DMA_EXT => {
+                fis.sector_count = sectors as u16;
+                fis.device = 1 << 6;
+            }
+            CMD_DATA_SET_MANAGEMENT => {
+                fis.feature_lo = 1;
+                fis.sector_count = sectors as u16;
+                fis.device = 1 << 6;
+            }
+            CMD_FLUSH_CACHE_EXT => fis.device = 1 << 6,
+            CMD_PACKET => fis.feature_lo = !prdt.is_empty() as u8,
+            _ => {}
+        }
     }
 }
 
 impl Drop for Ahci {
     fn drop(&mut self) {
+        self.port.stop_cmd();
+
+        for cmd_table in self.cmd_tables.iter() {
+            DmaManager::deallocate(VirtAddr::from_ptr(&**cmd_table));
+        }
         DmaManager::deallocate(VirtAddr::from_ptr(self.cmd_list.as_ptr()));
-        DmaManager::deallocate(VirtAddr::from_ptr(self.cmd_table));
+        DmaManager::deallocate(self.fis_area);
         DmaManager::deallocate(VirtAddr::from_ptr(self.data.as_ptr()));
     }
 }
+
+fn wait_until(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let start = HPET.elapsed();
+    while !condition() {
+        if HPET.elapsed() - start >= timeout {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+fn packet(operation: u8, block_id: u32, length: u16) -> [u8; 12] {
+    let mut cdb = [0; 12];
+    cdb[0] = operation;
+    cdb[2..6].copy_from_slice(&block_id.to_be_bytes());
+    cdb[7..9].copy_from_slice(&length.to_be_bytes());
+    cdb
+}
+
+fn plan_commands(request: &BlockRequest, block_size: usize) -> BlockDeviceResult<Vec<DmaTransfer>> {
+    if request
+        .segments
+        .iter()
+        .any(|segment| segment.buffer as usize % 2 != 0)
+    {
+        return Err(BlockDeviceError::InvalidInput);
+    }
+
+    request.dma_transfers(&DmaLimits {
+        block_size,
+        max_segments: PRDT_ENTRY_COUNT,
+        max_segment_size: MAX_PRD_SIZE,
+        max_transfer_size: MAX_COMMAND_SIZE,
+        page_aligned: false,
+    })
+}
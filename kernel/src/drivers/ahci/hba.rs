@@ -1,16 +1,28 @@
 use bit_field::BitField;
-use core::slice;
 use vcell::VolatileCell as Volatile;
 
-use super::cmd::{CommandHeader, CommandTable, FisRegH2D};
-use super::driver::Ahci;
-use crate::mem::DmaManager;
-
-const BLOCK_SIZE: usize = 512;
 const SATA_SIG_ATAPI: u32 = 0xEB140101;
 const SATA_SIG_SEMB: u32 = 0xC33C0101;
 const SATA_SIG_PM: u32 = 0x96690101;
 
+pub const PORT_IS_DHRS: u32 = 1 << 0;
+pub const PORT_IS_PSS: u32 = 1 << 1;
+pub const PORT_IS_DSS: u32 = 1 << 2;
+pub const PORT_IS_SDBS: u32 = 1 << 3;
+pub const PORT_IS_DPS: u32 = 1 << 5;
+pub const PORT_IS_IFS: u32 = 1 << 27;
+pub const PORT_IS_HBDS: u32 = 1 << 28;
+pub const PORT_IS_HBFS: u32 = 1 << 29;
+pub const PORT_IS_TFES: u32 = 1 << 30;
+
+pub const PORT_IS_ERROR: u32 = PORT_IS_IFS | PORT_IS_HBDS | PORT_IS_HBFS | PORT_IS_TFES;
+pub const PORT_IE_DEFAULT: u32 =
+    PORT_IS_DHRS | PORT_IS_PSS | PORT_IS_DSS | PORT_IS_SDBS | PORT_IS_DPS | PORT_IS_ERROR;
+
+pub const TFD_STATUS_ERR: u32 = 1 << 0;
+pub const TFD_STATUS_DRQ: u32 = 1 << 3;
+pub const TFD_STATUS_BSY: u32 = 1 << 7;
+
 #[repr(C)]
 pub struct HbaMemory {
     pub capability: Volatile<u32>,
@@ -39,12 +51,27 @@ impl HbaMemory {
         self.capability.get().get_bits(0..5) as usize + 1
     }
 
+    pub fn command_slot_count(&self) -> usize {
+        self.capability.get().get_bits(8..13) as usize + 1
+    }
+
+    pub fn supports_ncq(&self) -> bool {
+        self.capability.get().get_bit(30)
+    }
+
+    pub fn enable_interrupts(&self) {
+        self.interrupt_status.set(u32::MAX);
+        let control = &self.global_host_control;
+        control.set(*control.get().set_bit(1, true));
+    }
+
     pub fn get_port(&self, port_num: usize) -> Option<&HbaPort> {
         let hba_ptr = self as *const _ as usize;
         let port_address = hba_ptr + 0x100 + 0x80 * port_num;
 
         let port = unsafe { &*(port_address as *const HbaPort) };
-        (port.device_connected() && port.is_sata_device()).then_some(port)
+        let supported = port.is_sata_device() || port.is_atapi_device();
+        (port.device_connected() && supported).then_some(port)
     }
 }
 
@@ -82,6 +109,11 @@ impl HbaPort {
         while command.get().get_bit(15) || command.get().get_bit(14) {}
     }
 
+    pub fn clear_errors(&self) {
+        self.sata_error.set(u32::MAX);
+        self.interrupt_status.set(u32::MAX);
+    }
+
     pub fn is_sata_device(&self) -> bool {
         !matches!(
             self.signature.get(),
@@ -89,43 +121,12 @@ impl HbaPort {
         )
     }
 
+    pub fn is_atapi_device(&self) -> bool {
+        self.signature.get() == SATA_SIG_ATAPI
+    }
+
     pub fn device_connected(&self) -> bool {
         let status = self.sata_status.get();
         status.get_bits(8..12) == 1 && status.get_bits(0..4) == 3
     }
 }
-
-impl HbaPort {
-    pub unsafe fn init_ahci(&'static self) -> Ahci {
-        self.stop_cmd();
-
-        let (cmd_list_pa, cmd_list_va) = DmaManager::allocate(size_of::<CommandHeader>());
-        let (cmd_table_pa, cmd_table_va) = DmaManager::allocate(size_of::<CommandTable>());
-        let (data_pa, data_va) = DmaManager::allocate(BLOCK_SIZE);
-
-        self.command_list_base_address.set(cmd_list_pa.as_u64());
-
-        let cmd_list_ptr = cmd_list_va.as_mut_ptr::<CommandHeader>();
-        let cmd_list_size = DmaManager::UNIT_SIZE / size_of::<CommandHeader>();
-        let cmd_list = unsafe { slice::from_raw_parts_mut(cmd_list_ptr, cmd_list_size) };
-
-        let cmd_header = &mut cmd_list[0];
-        cmd_header.command_table_base_address = cmd_table_pa.as_u64();
-        cmd_header.flags = (size_of::<FisRegH2D>() / size_of::<u32>()) as u16;
-        cmd_header.prdt_length = 1;
-
-        let cmd_table = &mut *cmd_table_va.as_mut_ptr::<CommandTable>();
-        let prdt = &mut cmd_table.prdt[0];
-        prdt.data_base_address = data_pa.as_u64();
-        prdt.byte_count_i = (BLOCK_SIZE - 1) as u32;
-
-        self.start_cmd();
-
-        Ahci {
-            cmd_list,
-            cmd_table,
-            data: unsafe { slice::from_raw_parts_mut(data_va.as_mut_ptr(), BLOCK_SIZE) },
-            port: self,
-        }
-    }
-}
@@ -8,8 +8,13 @@ pub struct Identify {
     _2: [u16; 3],
     pub firmware_revision: [u8; 8],
     pub model: [u8; 40],
-    _3: [u16; 53],
+    _3: [u16; 28],
+    pub queue_depth: u16,
+    pub sata_capabilities: u16,
+    _4: [u16; 23],
     pub lba48_sectors: u64,
+    _5: [u16; 65],
+    pub data_set_management: u16,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -18,6 +23,10 @@ pub struct IdentifyData {
     pub firmware_revision: String,
     pub model_number: String,
     pub block_count: u64,
+    pub block_size: usize,
+    pub queue_depth: usize,
+    pub ncq_supported: bool,
+    pub trim_supported: bool,
 }
 
 impl From<&Identify> for IdentifyData {
@@ -35,6 +44,10 @@ impl From<&Identify> for IdentifyData {
             firmware_revision: parse(&info.firmware_revision),
             model_number: parse(&info.model),
             block_count: info.lba48_sectors,
+            block_size: super::BLOCK_SIZE,
+            queue_depth: (info.queue_depth & 0x1f) as usize + 1,
+            ncq_supported: info.sata_capabilities & (1 << 8) != 0,
+            trim_supported: info.data_set_management & (1 << 0) != 0,
         }
     }
 }
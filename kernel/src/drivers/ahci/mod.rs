@@ -1,32 +1,55 @@
 use alloc::{sync::Arc, vec::Vec};
 use identify::IdentifyData;
 use pci_types::device_type::DeviceType;
-use spin::{Lazy, Mutex};
+use spin::{Lazy, Mutex, RwLock};
 use x86_64::PhysAddr;
-use x86_64::structures::paging::PhysFrame;
+use x86_64::instructions::interrupts;
 
 use super::pcie::PCI_DEVICES;
-use crate::mem::convert_physical_to_virtual;
-use crate::mem::{KERNEL_PAGE_TABLE, MappingType, MemoryManager};
+use crate::arch::apic::ioapic_add_pci_entry;
+use crate::arch::interrupts::InterruptIndex;
+use crate::arch::smp::BSP_LAPIC_ID;
+use crate::io::block::BlockDeviceResult;
+use crate::io::request::{BlockRequest, BlockRequestHandle};
+use crate::mem::{MMIO_ALLOCATOR, MappingType};
 
 pub mod cmd;
 pub mod driver;
 pub mod hba;
 pub mod identify;
 
-pub use driver::{Ahci, BLOCK_SIZE};
+pub use driver::{ATAPI_BLOCK_SIZE, Ahci, BLOCK_SIZE};
 pub use hba::HbaMemory;
 
+static PORTS: RwLock<Vec<Arc<Mutex<Ahci>>>> = RwLock::new(Vec::new());
+
 pub struct AhciBlockDevice {
     pub device: Arc<Mutex<Ahci>>,
     pub identify: IdentifyData,
+    pub atapi: bool,
+}
+
+impl AhciBlockDevice {
+    pub fn execute(&self, request: BlockRequest) -> BlockDeviceResult<()> {
+        BlockRequestHandle::submit(self, request).wait_with(|| {
+            interrupts::without_interrupts(|| self.device.lock().process_completions())
+        })
+    }
 }
 
 impl AhciManager {
     pub fn iter(&self) -> impl Iterator<Item = AhciBlockDevice> {
-        self.0.iter().map(|device| AhciBlockDevice {
-            device: device.clone(),
-            identify: device.lock().identity(),
+        self.0.iter().map(|device| {
+            let (identify, atapi) = interrupts::without_interrupts(|| {
+                let mut device = device.lock();
+                (device.identity(), device.is_atapi())
+            });
+
+            AhciBlockDevice {
+                device: device.clone(),
+                identify,
+                atapi,
+            }
         })
     }
 }
@@ -43,22 +66,37 @@ pub static AHCI: Lazy<AhciManager> = Lazy::new(|| {
             };
             let (address, size) = bar.unwrap_mem();
             let physical_address = PhysAddr::new(address as u64);
-            let virtual_address = convert_physical_to_virtual(physical_address);
+            let virtual_address = MMIO_ALLOCATOR
+                .lock()
+                .map(physical_address, size as u64, MappingType::Uncached)
+                .unwrap();
 
-            <MemoryManager>::map_range_to(
-                virtual_address,
-                PhysFrame::containing_address(physical_address),
-                size as u64,
-                MappingType::KernelData.flags(),
-                &mut KERNEL_PAGE_TABLE.lock(),
-            )
-            .unwrap();
+            let interrupts_enabled = device.enable_msi(InterruptIndex::Ahci as u8, *BSP_LAPIC_ID)
+                || device.enable_intx().is_some_and(|line| unsafe {
+                    ioapic_add_pci_entry(line, InterruptIndex::Ahci)
+                });
+            if !interrupts_enabled {
+                log::warn!("AHCI controller {device} has no usable interrupt, polling only");
+            }
 
             for ahci_device in Ahci::new(virtual_address) {
-                connections.push(Arc::new(Mutex::new(ahci_device)));
+                let ahci_device = Arc::new(Mutex::new(ahci_device));
+                interrupts::without_interrupts(|| PORTS.write().push(ahci_device.clone()));
+                connections.push(ahci_device);
+            }
+
+            if interrupts_enabled {
+                let hba_memory = unsafe { &*virtual_address.as_ptr::<HbaMemory>() };
+                hba_memory.enable_interrupts();
             }
         }
     }
 
     AhciManager(connections)
 });
+
+pub fn handle_interrupt() {
+    for port in PORTS.read().iter() {
+        port.lock().handle_interrupt();
+    }
+}
@@ -1,5 +1,8 @@
 use limine::request::FramebufferRequest;
 use os_terminal::{DrawTarget, Rgb};
+use x86_64::VirtAddr;
+
+use crate::mem::{KERNEL_PAGE_TABLE, MappingType, MemoryManager};
 
 #[used]
 #[unsafe(link_section = ".requests")]
@@ -47,13 +50,32 @@ impl Default for Display {
                 | ((color.2 as u32) << shifts.2)
         };
 
+        let buffer_size = frame_buffer.pitch() * frame_buffer.height();
+
         Self {
             shifts,
             convert_color,
             width: frame_buffer.width() as usize,
             height: frame_buffer.height() as usize,
-            buffer: frame_buffer.addr() as *mut u32,
+            buffer: Self::map_buffer(frame_buffer.addr(), buffer_size),
             stride: frame_buffer.pitch() as usize / size_of::<u32>(),
         }
     }
 }
+
+impl Display {
+    // Limine hands the framebuffer out through the HHDM, so that mapping is
+    // switched to write-combining in place. A separate WC alias would leave
+    // the same memory mapped write-back too, which is undefined.
+    fn map_buffer(address: *mut u8, length: u64) -> *mut u32 {
+        MemoryManager::protect_range(
+            VirtAddr::from_ptr(address),
+            length,
+            MappingType::WriteCombining.flags(),
+            &mut KERNEL_PAGE_TABLE.lock(),
+        )
+        .unwrap();
+
+        address.cast()
+    }
+}
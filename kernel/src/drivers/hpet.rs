@@ -3,25 +3,17 @@ use core::ptr;
 use core::time::Duration;
 use spin::Lazy;
 use x86_64::PhysAddr;
-use x86_64::structures::paging::PhysFrame;
 
 use crate::arch::acpi::ACPI;
 use crate::arch::apic::IrqVector;
-use crate::mem::convert_physical_to_virtual;
-use crate::mem::{KERNEL_PAGE_TABLE, MappingType, MemoryManager};
+use crate::mem::{MMIO_ALLOCATOR, MappingType};
 
 pub static HPET: Lazy<Hpet> = Lazy::new(|| {
     let physical_address = PhysAddr::new(ACPI.hpet_info.base_address as u64);
-    let virtual_address = convert_physical_to_virtual(physical_address);
-
-    <MemoryManager>::map_range_to(
-        virtual_address,
-        PhysFrame::containing_address(physical_address),
-        0x1000,
-        MappingType::KernelData.flags(),
-        &mut KERNEL_PAGE_TABLE.lock(),
-    )
-    .unwrap();
+    let virtual_address = MMIO_ALLOCATOR
+        .lock()
+        .map(physical_address, 0x1000, MappingType::Uncached)
+        .unwrap();
 
     Hpet::new(virtual_address.as_u64())
 });
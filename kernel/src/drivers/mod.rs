@@ -9,4 +9,5 @@ pub mod rtc;
 pub mod serial;
 pub mod speaker;
 pub mod term;
+pub mod virtio;
 pub mod xhci;
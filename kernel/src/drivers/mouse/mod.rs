@@ -4,7 +4,7 @@ mod packet;
 mod ports;
 
 pub use driver::{MOUSE, MOUSE_BUFFER};
-pub use event::MouseEvent;
+pub use event::{MouseButton, MouseEvent};
 
 pub fn init() {
     let mut mouse = MOUSE.lock();
@@ -0,0 +1,181 @@
+use bit_field::BitField;
+
+use crate::io::block::{BlockDeviceError, BlockDeviceResult};
+
+pub const ADMIN_DELETE_SQ: u8 = 0x00;
+pub const ADMIN_CREATE_SQ: u8 = 0x01;
+pub const ADMIN_GET_LOG_PAGE: u8 = 0x02;
+pub const ADMIN_DELETE_CQ: u8 = 0x04;
+pub const ADMIN_CREATE_CQ: u8 = 0x05;
+pub const ADMIN_IDENTIFY: u8 = 0x06;
+pub const ADMIN_SET_FEATURES: u8 = 0x09;
+pub const ADMIN_NAMESPACE_MANAGEMENT: u8 = 0x0D;
+pub const ADMIN_NAMESPACE_ATTACHMENT: u8 = 0x15;
+
+pub const IO_FLUSH: u8 = 0x00;
+pub const IO_WRITE: u8 = 0x01;
+pub const IO_READ: u8 = 0x02;
+pub const IO_WRITE_ZEROES: u8 = 0x08;
+pub const IO_DATASET_MANAGEMENT: u8 = 0x09;
+
+pub const CNS_NAMESPACE: u32 = 0x00;
+pub const CNS_CONTROLLER: u32 = 0x01;
+pub const CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+
+pub const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+pub const LOG_SMART: u32 = 0x02;
+pub const LOG_FIRMWARE_SLOTS: u32 = 0x03;
+
+// Selects the create/attach or delete/detach form of the namespace commands.
+pub const SELECT_CREATE: u32 = 0;
+pub const SELECT_DELETE: u32 = 1;
+
+pub const GLOBAL_NAMESPACE: u32 = 0xFFFF_FFFF;
+
+const ATTRIBUTE_DEALLOCATE: u32 = 1 << 2;
+
+const QUEUE_PHYSICALLY_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS_ENABLED: u32 = 1 << 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Command {
+    pub opcode: u8,
+    pub flags: u8,
+    pub command_id: u16,
+    pub namespace_id: u32,
+    _reserved: u64,
+    pub metadata: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+impl Command {
+    pub fn new(opcode: u8, namespace_id: u32) -> Self {
+        Self {
+            opcode,
+            namespace_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn identify(cns: u32, namespace_id: u32) -> Self {
+        Self {
+            cdw10: cns,
+            ..Self::new(ADMIN_IDENTIFY, namespace_id)
+        }
+    }
+
+    pub fn get_log_page(page: u32, namespace_id: u32, length: usize) -> Self {
+        let last_dword = (length / 4 - 1) as u32;
+        Self {
+            cdw10: page | last_dword.get_bits(0..16) << 16,
+            cdw11: last_dword.get_bits(16..32),
+            ..Self::new(ADMIN_GET_LOG_PAGE, namespace_id)
+        }
+    }
+
+    pub fn set_features(feature: u32, value: u32) -> Self {
+        Self {
+            cdw10: feature,
+            cdw11: value,
+            ..Self::new(ADMIN_SET_FEATURES, 0)
+        }
+    }
+
+    pub fn create_completion_queue(id: u16, size: u16, vector: Option<u16>) -> Self {
+        let interrupts = match vector {
+            Some(vector) => (vector as u32) << 16 | QUEUE_INTERRUPTS_ENABLED,
+            None => 0,
+        };
+
+        Self {
+            cdw10: ((size as u32 - 1) << 16) | id as u32,
+            cdw11: interrupts | QUEUE_PHYSICALLY_CONTIGUOUS,
+            ..Self::new(ADMIN_CREATE_CQ, 0)
+        }
+    }
+
+    pub fn create_submission_queue(id: u16, size: u16, completion_queue: u16) -> Self {
+        Self {
+            cdw10: ((size as u32 - 1) << 16) | id as u32,
+            cdw11: (completion_queue as u32) << 16 | QUEUE_PHYSICALLY_CONTIGUOUS,
+            ..Self::new(ADMIN_CREATE_SQ, 0)
+        }
+    }
+
+    pub fn delete_queue(opcode: u8, id: u16) -> Self {
+        Self {
+            cdw10: id as u32,
+            ..Self::new(opcode, 0)
+        }
+    }
+
+    pub fn namespace_management(select: u32, namespace_id: u32) -> Self {
+        Self {
+            cdw10: select,
+            ..Self::new(ADMIN_NAMESPACE_MANAGEMENT, namespace_id)
+        }
+    }
+
+    pub fn namespace_attachment(select: u32, namespace_id: u32) -> Self {
+        Self {
+            cdw10: select,
+            ..Self::new(ADMIN_NAMESPACE_ATTACHMENT, namespace_id)
+        }
+    }
+
+    // Carries a single range, whose number is zero-based in cdw10.
+    pub fn deallocate(namespace_id: u32) -> Self {
+        Self {
+            cdw11: ATTRIBUTE_DEALLOCATE,
+            ..Self::new(IO_DATASET_MANAGEMENT, namespace_id)
+        }
+    }
+
+    pub fn transfer(opcode: u8, namespace_id: u32, block_id: u64, block_count: usize) -> Self {
+        Self {
+            cdw10: block_id as u32,
+            cdw11: (block_id >> 32) as u32,
+            cdw12: block_count as u32 - 1,
+            ..Self::new(opcode, namespace_id)
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Completion {
+    pub result: u32,
+    _reserved: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub command_id: u16,
+    pub status: u16,
+}
+
+impl Completion {
+    pub fn phase(&self) -> bool {
+        self.status.get_bit(0)
+    }
+
+    pub fn result(&self) -> BlockDeviceResult<u32> {
+        let status_code = self.status.get_bits(1..9) as u8;
+        let status_type = self.status.get_bits(9..12) as u8;
+
+        match (status_type, status_code) {
+            (0, 0) => Ok(self.result),
+            _ => Err(BlockDeviceError::Nvme {
+                status_type,
+                status_code,
+            }),
+        }
+    }
+}
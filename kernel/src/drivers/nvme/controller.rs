@@ -0,0 +1,555 @@
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::ops::Range;
+use core::slice;
+use core::time::Duration;
+use spin::Mutex;
+use vcell::VolatileCell as Volatile;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+
+use super::IO_QUEUE_SIZE;
+use super::command::{ADMIN_DELETE_CQ, CNS_ACTIVE_NAMESPACES, CNS_CONTROLLER};
+use super::command::{CNS_NAMESPACE, Command, FEATURE_NUMBER_OF_QUEUES, GLOBAL_NAMESPACE};
+use super::command::{IO_DATASET_MANAGEMENT, IO_FLUSH, IO_READ, IO_WRITE, IO_WRITE_ZEROES};
+use super::command::{LOG_FIRMWARE_SLOTS, LOG_SMART, SELECT_CREATE, SELECT_DELETE};
+use super::identify::{ControllerIdentify, IDENTIFY_SIZE, Namespace, read_u32};
+use super::log_page::{FirmwareSlots, LOG_PAGE_SIZE, SmartLog};
+use super::queue::QueuePair;
+use crate::arch::apic;
+use crate::arch::interrupts::InterruptIndex;
+use crate::arch::smp::CPUS;
+use crate::drivers::hpet::HPET;
+use crate::drivers::pcie::PciDevice;
+use crate::io::block::{BlockCapabilities, BlockDeviceError, BlockDeviceResult, DeviceDetails};
+use crate::io::request::{BlockOperation, BlockRequest, DmaLimits, DmaTransfer, PendingRequest};
+use crate::mem::DmaManager;
+
+const PAGE_SIZE: usize = DmaManager::UNIT_SIZE;
+const ADMIN_QUEUE_SIZE: u16 = 32;
+// Keeps every PRP list within a single page.
+const MAX_TRANSFER_SIZE: usize = 1024 * 1024;
+// Dataset Management ranges count blocks in 32 bits, Write Zeroes in 16.
+const MAX_DEALLOCATE_BLOCKS: u64 = u32::MAX as u64;
+const MAX_WRITE_ZEROES_BLOCKS: u64 = 1 << 16;
+const TIMEOUT_UNIT: Duration = Duration::from_millis(500);
+
+const CC_ENABLE: u32 = 1 << 0;
+// 64-byte submission and 16-byte completion entries, as powers of two.
+const CC_QUEUE_ENTRY_SIZES: u32 = (6 << 16) | (4 << 20);
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+#[repr(C)]
+pub struct Registers {
+    pub capabilities: Volatile<u64>,
+    pub version: Volatile<u32>,
+    pub interrupt_mask_set: Volatile<u32>,
+    pub interrupt_mask_clear: Volatile<u32>,
+    pub configuration: Volatile<u32>,
+    _reserved: u32,
+    pub status: Volatile<u32>,
+    pub subsystem_reset: Volatile<u32>,
+    pub admin_queue_attributes: Volatile<u32>,
+    pub admin_submission_queue: Volatile<u64>,
+    pub admin_completion_queue: Volatile<u64>,
+}
+
+impl Registers {
+    pub fn max_queue_entries(&self) -> u16 {
+        (self.capabilities.get().get_bits(0..16) as u16).saturating_add(1)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        TIMEOUT_UNIT * self.capabilities.get().get_bits(24..32).max(1) as u32
+    }
+
+    pub fn doorbell_stride(&self) -> u64 {
+        4 << self.capabilities.get().get_bits(32..36)
+    }
+
+    pub fn min_page_size(&self) -> usize {
+        1 << (12 + self.capabilities.get().get_bits(48..52))
+    }
+}
+
+struct IoQueue {
+    cpu: u32,
+    interrupts: bool,
+    pair: Mutex<QueuePair>,
+}
+
+impl IoQueue {
+    // Completions are reaped by the interrupt routed to the owning CPU, or
+    // by whoever waits when no interrupt is going to arrive.
+    fn poll(&self) {
+        if !self.interrupts || !interrupts::are_enabled() {
+            interrupts::without_interrupts(|| self.pair.lock().process_completions());
+        }
+    }
+}
+
+pub struct Controller {
+    registers: &'static Registers,
+    base: VirtAddr,
+    admin: Mutex<QueuePair>,
+    queues: Vec<IoQueue>,
+    identify: ControllerIdentify,
+    timeout: Duration,
+}
+
+unsafe impl Send for Controller {}
+unsafe impl Sync for Controller {}
+
+impl Controller {
+    pub fn new(pci: &PciDevice, base: VirtAddr) -> BlockDeviceResult<Self> {
+        let registers = unsafe { &*base.as_ptr::<Registers>() };
+        if registers.min_page_size() != PAGE_SIZE {
+            return Err(BlockDeviceError::IoError(format!(
+                "NVMe controller {pci} does not support {PAGE_SIZE} byte pages"
+            )));
+        }
+
+        let timeout = registers.timeout();
+        registers.configuration.set(0);
+        if !wait_until(timeout, || registers.status.get() & CSTS_READY == 0) {
+            return Err(BlockDeviceError::IoError(format!(
+                "NVMe controller {pci} did not reset"
+            )));
+        }
+
+        let admin = QueuePair::new(0, ADMIN_QUEUE_SIZE, base, registers.doorbell_stride());
+        let last_entry = ADMIN_QUEUE_SIZE as u32 - 1;
+        registers
+            .admin_queue_attributes
+            .set(last_entry << 16 | last_entry);
+        registers
+            .admin_submission_queue
+            .set(admin.submission_address().as_u64());
+        registers
+            .admin_completion_queue
+            .set(admin.completion_address().as_u64());
+        registers
+            .configuration
+            .set(CC_QUEUE_ENTRY_SIZES | CC_ENABLE);
+
+        let ready = wait_until(timeout, || {
+            registers.status.get() & (CSTS_READY | CSTS_FATAL) != 0
+        });
+        if !ready || registers.status.get() & CSTS_FATAL != 0 {
+            return Err(BlockDeviceError::IoError(format!(
+                "NVMe controller {pci} failed to become ready"
+            )));
+        }
+
+        let mut controller = Self {
+            registers,
+            base,
+            admin: Mutex::new(admin),
+            queues: Vec::new(),
+            identify: ControllerIdentify::default(),
+            timeout,
+        };
+
+        let data = controller.controller_data()?;
+        controller.identify = ControllerIdentify::parse(&data);
+        controller.create_io_queues(pci)?;
+
+        Ok(controller)
+    }
+
+    pub fn identify(&self) -> &ControllerIdentify {
+        &self.identify
+    }
+
+    pub fn capabilities(&self) -> BlockCapabilities {
+        let mut capabilities = BlockCapabilities::empty();
+        if self.identify.dataset_management {
+            capabilities |= BlockCapabilities::DISCARD;
+        }
+        if self.identify.write_zeroes {
+            capabilities |= BlockCapabilities::WRITE_ZEROES;
+        }
+        capabilities
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        interrupts::without_interrupts(|| {
+            self.queues
+                .iter()
+                .map(|queue| queue.pair.lock().size() as usize - 1)
+                .sum()
+        })
+    }
+
+    pub fn namespaces(&self) -> BlockDeviceResult<Vec<Namespace>> {
+        let list = self.admin_read(Command::identify(CNS_ACTIVE_NAMESPACES, 0))?;
+        let ids = (0..IDENTIFY_SIZE / 4)
+            .map(|index| read_u32(&list, index * 4))
+            .take_while(|&id| id != 0);
+
+        let mut namespaces = Vec::new();
+        for id in ids {
+            let namespace = self.namespace(id)?;
+            if namespace.block_count > 0 {
+                namespaces.push(namespace);
+            }
+        }
+
+        Ok(namespaces)
+    }
+
+    pub fn namespace(&self, id: u32) -> BlockDeviceResult<Namespace> {
+        Ok(Namespace::parse(id, &self.namespace_data(id)?))
+    }
+
+    pub fn controller_data(&self) -> BlockDeviceResult<Vec<u8>> {
+        self.admin_read(Command::identify(CNS_CONTROLLER, 0))
+    }
+
+    pub fn namespace_data(&self, id: u32) -> BlockDeviceResult<Vec<u8>> {
+        self.admin_read(Command::identify(CNS_NAMESPACE, id))
+    }
+
+    pub fn smart_log(&self) -> BlockDeviceResult<SmartLog> {
+        let command = Command::get_log_page(LOG_SMART, GLOBAL_NAMESPACE, LOG_PAGE_SIZE);
+        let mut data = vec![0; LOG_PAGE_SIZE];
+        self.admin_transfer(command, &mut data)?;
+        Ok(SmartLog::parse(&data))
+    }
+
+    pub fn firmware_slots(&self) -> BlockDeviceResult<FirmwareSlots> {
+        let command = Command::get_log_page(LOG_FIRMWARE_SLOTS, GLOBAL_NAMESPACE, LOG_PAGE_SIZE);
+        let mut data = vec![0; LOG_PAGE_SIZE];
+        self.admin_transfer(command, &mut data)?;
+        Ok(FirmwareSlots::parse(&data))
+    }
+
+    pub fn details(&self) -> DeviceDetails {
+        let smart = self
+            .smart_log()
+            .inspect_err(|err| log::warn!("Failed to read the NVMe SMART log: {err}"))
+            .ok();
+        let firmware = self
+            .firmware_slots()
+            .ok()
+            .and_then(|slots| slots.active_revision().map(ToString::to_string))
+            .unwrap_or_else(|| self.identify.firmware_revision.clone());
+
+        DeviceDetails {
+            model: self.identify.model_number.clone(),
+            serial: self.identify.serial_number.clone(),
+            firmware,
+            temperature: smart.map(|smart| smart.temperature),
+            percentage_used: smart.map(|smart| smart.percentage_used),
+            power_on_hours: smart.map(|smart| smart.power_on_hours),
+        }
+    }
+
+    // Returns the ID of the new namespace, which still has to be attached
+    // before it shows up in the active list.
+    pub fn create_namespace(&self, block_count: u64, format: u8) -> BlockDeviceResult<u32> {
+        self.check_namespace_management()?;
+
+        let mut data = vec![0; IDENTIFY_SIZE];
+        data[0..8].copy_from_slice(&block_count.to_le_bytes());
+        data[8..16].copy_from_slice(&block_count.to_le_bytes());
+        data[26] = format;
+
+        let command = Command::namespace_management(SELECT_CREATE, 0);
+        self.admin_transfer(command, &mut data)
+    }
+
+    pub fn delete_namespace(&self, id: u32) -> BlockDeviceResult<()> {
+        self.check_namespace_management()?;
+        self.admin(Command::namespace_management(SELECT_DELETE, id))
+            .map(|_| ())
+    }
+
+    pub fn attach_namespace(&self, id: u32) -> BlockDeviceResult<()> {
+        self.change_attachment(SELECT_CREATE, id)
+    }
+
+    pub fn detach_namespace(&self, id: u32) -> BlockDeviceResult<()> {
+        self.change_attachment(SELECT_DELETE, id)
+    }
+
+    pub fn admin(&self, command: Command) -> BlockDeviceResult<u32> {
+        let start = HPET.elapsed();
+        let timeout = self.timeout;
+        self.admin
+            .lock()
+            .execute_polled(command, || HPET.elapsed() - start >= timeout)
+    }
+
+    pub fn admin_read(&self, command: Command) -> BlockDeviceResult<Vec<u8>> {
+        let mut data = vec![0; IDENTIFY_SIZE];
+        self.admin_transfer(command, &mut data)?;
+        Ok(data)
+    }
+
+    // Runs an admin command on a DMA page holding `data`, which is copied
+    // back whatever the direction of the transfer.
+    pub fn admin_transfer(&self, mut command: Command, data: &mut [u8]) -> BlockDeviceResult<u32> {
+        let (physical_address, virtual_address) = DmaManager::allocate(data.len());
+        let page =
+            unsafe { slice::from_raw_parts_mut(virtual_address.as_mut_ptr::<u8>(), data.len()) };
+        page.copy_from_slice(data);
+        command.prp1 = physical_address.as_u64();
+
+        let result = self.admin(command);
+        data.copy_from_slice(page);
+
+        DmaManager::deallocate(virtual_address);
+        result
+    }
+
+    pub fn submit(&self, namespace: &Namespace, request: BlockRequest) {
+        let transfers = match request.operation {
+            BlockOperation::Flush if !self.identify.volatile_write_cache => {
+                return request.complete(Ok(()));
+            }
+            BlockOperation::Flush => Ok(vec![DmaTransfer {
+                block_id: 0,
+                block_count: 0,
+                segments: Vec::new(),
+            }]),
+            BlockOperation::Read | BlockOperation::Write => {
+                request.dma_transfers(&self.limits(namespace))
+            }
+            BlockOperation::Discard if self.identify.dataset_management => {
+                Ok(range_transfers(request.range(), MAX_DEALLOCATE_BLOCKS))
+            }
+            BlockOperation::WriteZeroes if self.identify.write_zeroes => {
+                Ok(range_transfers(request.range(), MAX_WRITE_ZEROES_BLOCKS))
+            }
+            _ => Err(BlockDeviceError::Unsupported),
+        };
+
+        let transfers = match transfers {
+            Ok(transfers) if !transfers.is_empty() => transfers,
+            Ok(_) => return request.complete(Ok(())),
+            Err(err) => return request.complete(Err(err)),
+        };
+
+        let opcode = match request.operation {
+            BlockOperation::Read => IO_READ,
+            BlockOperation::Write => IO_WRITE,
+            BlockOperation::Flush => IO_FLUSH,
+            BlockOperation::Discard => IO_DATASET_MANAGEMENT,
+            BlockOperation::WriteZeroes => IO_WRITE_ZEROES,
+        };
+        let pending = PendingRequest::new(request, transfers.len());
+        let queue = self.local_queue();
+
+        for transfer in transfers.iter() {
+            let command = transfer_command(opcode, namespace, transfer);
+            let pages = prp_entries(&transfer.segments);
+
+            loop {
+                let issued = interrupts::without_interrupts(|| {
+                    let mut pair = queue.pair.lock();
+                    let slot = pair.free_slot()?;
+                    match opcode {
+                        IO_DATASET_MANAGEMENT => pair.issue_range(
+                            slot,
+                            command,
+                            transfer.block_id,
+                            transfer.block_count as u32,
+                            pending.clone(),
+                        ),
+                        _ => pair.issue(slot, command, &pages, pending.clone()),
+                    }
+                    Some(())
+                });
+
+                if issued.is_some() {
+                    break;
+                }
+
+                queue.poll();
+                if interrupts::are_enabled() {
+                    crate::syscall::r#yield();
+                } else {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+
+    pub fn poll(&self) {
+        for queue in self.queues.iter() {
+            queue.poll();
+        }
+    }
+
+    pub fn handle_interrupt(&self, cpu: u32) {
+        for queue in self.queues.iter() {
+            if queue.interrupts && queue.cpu == cpu {
+                queue.pair.lock().process_completions();
+            }
+        }
+    }
+}
+
+impl Controller {
+    fn create_io_queues(&mut self, pci: &PciDevice) -> BlockDeviceResult<()> {
+        let cpus: Vec<u32> = CPUS.read().iter_id().copied().collect();
+        let wanted = cpus.len() as u32 - 1;
+        let granted = self.admin(Command::set_features(
+            FEATURE_NUMBER_OF_QUEUES,
+            wanted << 16 | wanted,
+        ))?;
+
+        let count = cpus
+            .len()
+            .min(granted.get_bits(0..16) as usize + 1)
+            .min(granted.get_bits(16..32) as usize + 1);
+        let size = IO_QUEUE_SIZE.min(self.registers.max_queue_entries());
+
+        for (index, &cpu) in cpus.iter().take(count).enumerate() {
+            let id = index as u16 + 1;
+
+            // MSI-X entry 0 belongs to the admin queue, which is polled, so
+            // each I/O queue gets the entry after it routed to its CPU.
+            let entry = index as u16 + 1;
+            let interrupts = pci.enable_msix(entry, InterruptIndex::Nvme as u8, cpu);
+            if !interrupts {
+                log::warn!("NVMe controller {pci} has no MSI-X entry for queue {id}, polling only");
+            }
+
+            let pair = QueuePair::new(id, size, self.base, self.registers.doorbell_stride());
+
+            let mut command =
+                Command::create_completion_queue(id, size, interrupts.then_some(entry));
+            command.prp1 = pair.completion_address().as_u64();
+            self.admin(command)?;
+
+            let mut command = Command::create_submission_queue(id, size, id);
+            command.prp1 = pair.submission_address().as_u64();
+            if let Err(err) = self.admin(command) {
+                let _ = self.admin(Command::delete_queue(ADMIN_DELETE_CQ, id));
+                return Err(err);
+            }
+
+            self.queues.push(IoQueue {
+                cpu,
+                interrupts,
+                pair: Mutex::new(pair),
+            });
+        }
+
+        match self.queues.is_empty() {
+            true => Err(BlockDeviceError::IoError(format!(
+                "NVMe controller {pci} granted no I/O queues"
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    fn check_namespace_management(&self) -> BlockDeviceResult<()> {
+        match self.identify.namespace_management {
+            true => Ok(()),
+            false => Err(BlockDeviceError::Unsupported),
+        }
+    }
+
+    fn change_attachment(&self, select: u32, id: u32) -> BlockDeviceResult<()> {
+        self.check_namespace_management()?;
+
+        // A controller list naming only this controller.
+        let mut data = vec![0; IDENTIFY_SIZE];
+        data[0..2].copy_from_slice(&1u16.to_le_bytes());
+        data[2..4].copy_from_slice(&self.identify.controller_id.to_le_bytes());
+
+        let command = Command::namespace_attachment(select, id);
+        self.admin_transfer(command, &mut data).map(|_| ())
+    }
+
+    // CPUs without a queue of their own share one, whose completions are
+    // still reaped on the CPU that owns it.
+    fn local_queue(&self) -> &IoQueue {
+        let cpu = apic::current_id();
+        self.queues
+            .iter()
+            .find(|queue| queue.cpu == cpu)
+            .unwrap_or_else(|| &self.queues[cpu as usize % self.queues.len()])
+    }
+
+    fn limits(&self, namespace: &Namespace) -> DmaLimits {
+        let max_transfer_size = match self.identify.max_transfer_shift {
+            0 => MAX_TRANSFER_SIZE,
+            shift => MAX_TRANSFER_SIZE.min(PAGE_SIZE << shift),
+        };
+
+        DmaLimits {
+            block_size: namespace.block_size,
+            max_segments: max_transfer_size / PAGE_SIZE + 1,
+            max_segment_size: max_transfer_size,
+            max_transfer_size,
+            page_aligned: true,
+        }
+    }
+}
+
+fn transfer_command(opcode: u8, namespace: &Namespace, transfer: &DmaTransfer) -> Command {
+    match opcode {
+        IO_FLUSH => return Command::new(IO_FLUSH, namespace.id),
+        IO_DATASET_MANAGEMENT => return Command::deallocate(namespace.id),
+        _ => {}
+    }
+
+    Command::transfer(
+        opcode,
+        namespace.id,
+        transfer.block_id,
+        transfer.block_count,
+    )
+}
+
+fn range_transfers(range: Range<u64>, max_blocks: u64) -> Vec<DmaTransfer> {
+    let mut transfers = Vec::new();
+    let mut block_id = range.start;
+
+    while block_id < range.end {
+        let block_count = (range.end - block_id).min(max_blocks);
+        transfers.push(DmaTransfer {
+            block_id,
+            block_count: block_count as usize,
+            segments: Vec::new(),
+        });
+        block_id += block_count;
+    }
+
+    transfers
+}
+
+// One entry per page touched, the first one possibly at an offset.
+fn prp_entries(segments: &[(u64, usize)]) -> Vec<u64> {
+    let mut entries = Vec::new();
+
+    for &(address, length) in segments {
+        let end = address + length as u64;
+        let mut page = address;
+        while page < end {
+            entries.push(page);
+            page = (page & !(PAGE_SIZE as u64 - 1)) + PAGE_SIZE as u64;
+        }
+    }
+
+    entries
+}
+
+fn wait_until(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let start = HPET.elapsed();
+    while !condition() {
+        if HPET.elapsed() - start >= timeout {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
@@ -0,0 +1,73 @@
+use alloc::string::{String, ToString};
+use bit_field::BitField;
+
+pub const IDENTIFY_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Default)]
+pub struct ControllerIdentify {
+    pub serial_number: String,
+    pub model_number: String,
+    pub firmware_revision: String,
+    // Maximum data transfer size as a power of two of the minimum page
+    // size, zero when unlimited.
+    pub max_transfer_shift: u8,
+    pub controller_id: u16,
+    pub namespace_management: bool,
+    pub namespace_count: u32,
+    pub dataset_management: bool,
+    pub write_zeroes: bool,
+    pub volatile_write_cache: bool,
+}
+
+impl ControllerIdentify {
+    pub fn parse(data: &[u8]) -> Self {
+        let parse = |range: core::ops::Range<usize>| {
+            String::from_utf8_lossy(&data[range]).trim().to_string()
+        };
+
+        Self {
+            serial_number: parse(4..24),
+            model_number: parse(24..64),
+            firmware_revision: parse(64..72),
+            max_transfer_shift: data[77],
+            controller_id: read_u16(data, 78),
+            namespace_management: read_u16(data, 256).get_bit(3),
+            namespace_count: read_u32(data, 516),
+            dataset_management: read_u16(data, 520).get_bit(2),
+            write_zeroes: read_u16(data, 520).get_bit(3),
+            volatile_write_cache: data[525].get_bit(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Namespace {
+    pub id: u32,
+    pub block_size: usize,
+    pub block_count: u64,
+}
+
+impl Namespace {
+    pub fn parse(id: u32, data: &[u8]) -> Self {
+        let format = data[26].get_bits(0..4) as usize;
+        let lba_format = read_u32(data, 128 + format * 4);
+
+        Self {
+            id,
+            block_size: 1 << lba_format.get_bits(16..24),
+            block_count: read_u64(data, 0),
+        }
+    }
+}
+
+pub fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,67 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::identify::{read_u16, read_u64};
+
+pub const LOG_PAGE_SIZE: usize = 512;
+
+const KELVIN_OFFSET: i32 = 273;
+const FIRMWARE_SLOTS: usize = 7;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmartLog {
+    pub critical_warning: u8,
+    // Composite temperature in degrees Celsius.
+    pub temperature: i16,
+    pub available_spare: u8,
+    pub percentage_used: u8,
+    pub power_on_hours: u64,
+    pub unsafe_shutdowns: u64,
+    pub media_errors: u64,
+}
+
+impl SmartLog {
+    // Counters are 128 bits wide, only the low half is kept.
+    pub fn parse(data: &[u8]) -> Self {
+        Self {
+            critical_warning: data[0],
+            temperature: (read_u16(data, 1) as i32 - KELVIN_OFFSET) as i16,
+            available_spare: data[3],
+            percentage_used: data[5],
+            power_on_hours: read_u64(data, 128),
+            unsafe_shutdowns: read_u64(data, 144),
+            media_errors: read_u64(data, 160),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FirmwareSlots {
+    pub active: usize,
+    // Revision held by each slot, starting with slot 1.
+    pub revisions: Vec<Option<String>>,
+}
+
+impl FirmwareSlots {
+    pub fn parse(data: &[u8]) -> Self {
+        let revisions = (0..FIRMWARE_SLOTS)
+            .map(|slot| {
+                let raw = &data[8 + slot * 8..16 + slot * 8];
+                let revision = String::from_utf8_lossy(raw)
+                    .trim_end_matches(['\0', ' '])
+                    .to_string();
+                (!revision.is_empty()).then_some(revision)
+            })
+            .collect();
+
+        Self {
+            active: (data[0] & 0x07) as usize,
+            revisions,
+        }
+    }
+
+    pub fn active_revision(&self) -> Option<&str> {
+        let slot = self.active.checked_sub(1)?;
+        self.revisions.get(slot)?.as_deref()
+    }
+}
@@ -0,0 +1,96 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use pci_types::device_type::DeviceType;
+use spin::{Lazy, RwLock};
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts;
+
+use super::pcie::PCI_DEVICES;
+use crate::arch::apic;
+use crate::io::block::BlockDeviceResult;
+use crate::io::request::{BlockRequest, BlockRequestHandle};
+use crate::mem::{MMIO_ALLOCATOR, MappingType};
+
+pub mod command;
+pub mod controller;
+pub mod identify;
+pub mod log_page;
+pub mod queue;
+
+pub use controller::Controller;
+pub use identify::Namespace;
+pub use log_page::{FirmwareSlots, SmartLog};
+
+pub const IO_QUEUE_SIZE: u16 = 64;
+
+static CONTROLLERS: RwLock<Vec<Arc<Controller>>> = RwLock::new(Vec::new());
+
+pub struct NvmeBlockDevice {
+    pub controller: Arc<Controller>,
+    pub namespace: Namespace,
+}
+
+impl NvmeBlockDevice {
+    pub fn execute(&self, request: BlockRequest) -> BlockDeviceResult<()> {
+        BlockRequestHandle::submit(self, request).wait_with(|| self.controller.poll())
+    }
+}
+
+pub struct NvmeManager(Vec<Arc<Controller>>);
+
+impl NvmeManager {
+    pub fn iter(&self) -> impl Iterator<Item = Vec<NvmeBlockDevice>> {
+        self.0.iter().map(|controller| {
+            let namespaces = controller.namespaces().unwrap_or_else(|err| {
+                log::warn!("Failed to list NVMe namespaces: {err}");
+                Vec::new()
+            });
+
+            namespaces
+                .into_iter()
+                .map(|namespace| NvmeBlockDevice {
+                    controller: controller.clone(),
+                    namespace,
+                })
+                .collect()
+        })
+    }
+}
+
+pub static NVME: Lazy<NvmeManager> = Lazy::new(|| {
+    let mut controllers = Vec::new();
+
+    for device in PCI_DEVICES.lock().iter() {
+        if device.device_type == DeviceType::NvmeController {
+            let Some(bar) = device.bars[0] else {
+                continue;
+            };
+            let (address, size) = bar.unwrap_mem();
+            let physical_address = PhysAddr::new(address as u64);
+            let virtual_address = MMIO_ALLOCATOR
+                .lock()
+                .map(physical_address, size as u64, MappingType::Uncached)
+                .unwrap();
+
+            let controller = match Controller::new(device, virtual_address) {
+                Ok(controller) => Arc::new(controller),
+                Err(err) => {
+                    log::warn!("Failed to initialize NVMe controller {device}: {err}");
+                    continue;
+                }
+            };
+
+            interrupts::without_interrupts(|| CONTROLLERS.write().push(controller.clone()));
+            controllers.push(controller);
+        }
+    }
+
+    NvmeManager(controllers)
+});
+
+pub fn handle_interrupt() {
+    let cpu = apic::current_id();
+    for controller in CONTROLLERS.read().iter() {
+        controller.handle_interrupt(cpu);
+    }
+}
@@ -0,0 +1,241 @@
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use vcell::VolatileCell as Volatile;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::command::{Command, Completion};
+use crate::io::block::{BlockDeviceError, BlockDeviceResult};
+use crate::io::request::PendingRequest;
+use crate::mem::DmaManager;
+
+const DOORBELL_BASE: u64 = 0x1000;
+
+#[repr(C)]
+struct DatasetRange {
+    attributes: u32,
+    block_count: u32,
+    block_id: u64,
+}
+
+pub struct QueuePair {
+    id: u16,
+    size: u16,
+    submissions: (PhysAddr, VirtAddr),
+    completions: (PhysAddr, VirtAddr),
+    submission_doorbell: &'static Volatile<u32>,
+    completion_doorbell: &'static Volatile<u32>,
+    tail: u16,
+    head: u16,
+    phase: bool,
+    // Indexed by command ID. A queue of N entries holds at most N - 1
+    // commands, so one ID is never handed out.
+    slots: Vec<Option<Arc<PendingRequest>>>,
+    // Page of each command ID holding its PRP list or Dataset Management
+    // ranges, allocated the first time it is needed.
+    pages: Vec<Option<(PhysAddr, VirtAddr)>>,
+    // Polled commands each get a new ID, so a completion arriving after its
+    // command timed out is never taken for the next one.
+    polled_id: u16,
+}
+
+unsafe impl Send for QueuePair {}
+unsafe impl Sync for QueuePair {}
+
+impl QueuePair {
+    pub fn new(id: u16, size: u16, registers: VirtAddr, doorbell_stride: u64) -> Self {
+        let submissions = DmaManager::allocate(size as usize * size_of::<Command>());
+        let completions = DmaManager::allocate(size as usize * size_of::<Completion>());
+        unsafe {
+            completions
+                .1
+                .as_mut_ptr::<Completion>()
+                .write_bytes(0, size as usize);
+        }
+
+        let doorbell = |index: u64| {
+            let address = registers + DOORBELL_BASE + index * doorbell_stride;
+            unsafe { &*address.as_ptr::<Volatile<u32>>() }
+        };
+
+        Self {
+            id,
+            size,
+            submissions,
+            completions,
+            submission_doorbell: doorbell(2 * id as u64),
+            completion_doorbell: doorbell(2 * id as u64 + 1),
+            tail: 0,
+            head: 0,
+            phase: true,
+            slots: (0..size - 1).map(|_| None).collect(),
+            pages: (0..size - 1).map(|_| None).collect(),
+            polled_id: 0,
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn submission_address(&self) -> PhysAddr {
+        self.submissions.0
+    }
+
+    pub fn completion_address(&self) -> PhysAddr {
+        self.completions.0
+    }
+
+    pub fn free_slot(&self) -> Option<u16> {
+        self.slots
+            .iter()
+            .position(Option::is_none)
+            .map(|slot| slot as u16)
+    }
+
+    // The first page goes in PRP1, PRP2 holds either the second page or a
+    // list of all the remaining ones.
+    pub fn issue(
+        &mut self,
+        slot: u16,
+        mut command: Command,
+        pages: &[u64],
+        pending: Arc<PendingRequest>,
+    ) {
+        command.prp1 = pages.first().copied().unwrap_or(0);
+        command.prp2 = match pages.len() {
+            0 | 1 => 0,
+            2 => pages[1],
+            _ => {
+                let (physical_address, virtual_address) = self.page(slot);
+                unsafe {
+                    let list = virtual_address.as_mut_ptr::<u64>();
+                    list.copy_from_nonoverlapping(pages[1..].as_ptr(), pages.len() - 1);
+                }
+                physical_address.as_u64()
+            }
+        };
+
+        self.slots[slot as usize] = Some(pending);
+        self.push(slot, command);
+    }
+
+    pub fn issue_range(
+        &mut self,
+        slot: u16,
+        mut command: Command,
+        block_id: u64,
+        block_count: u32,
+        pending: Arc<PendingRequest>,
+    ) {
+        let (physical_address, virtual_address) = self.page(slot);
+        unsafe {
+            virtual_address
+                .as_mut_ptr::<DatasetRange>()
+                .write_volatile(DatasetRange {
+                    attributes: 0,
+                    block_count,
+                    block_id,
+                });
+        }
+        command.prp1 = physical_address.as_u64();
+
+        self.slots[slot as usize] = Some(pending);
+        self.push(slot, command);
+    }
+
+    pub fn process_completions(&mut self) {
+        while let Some(completion) = self.pop() {
+            let slot = completion.command_id as usize;
+            if let Some(pending) = self.slots.get_mut(slot).and_then(Option::take) {
+                pending.finish(completion.result().map(|_| ()));
+            }
+        }
+    }
+
+    // Only used on the admin queue, which runs one command at a time.
+    pub fn execute_polled(
+        &mut self,
+        command: Command,
+        mut expired: impl FnMut() -> bool,
+    ) -> BlockDeviceResult<u32> {
+        let command_id = self.polled_id;
+        self.polled_id = self.polled_id.wrapping_add(1);
+        self.push(command_id, command);
+
+        loop {
+            match self.pop() {
+                Some(completion) if completion.command_id == command_id => {
+                    return completion.result();
+                }
+                Some(_) => {}
+                None if expired() => {
+                    return Err(BlockDeviceError::IoError(format!(
+                        "NVMe queue {} command {:#x} timed out",
+                        self.id, command.opcode
+                    )));
+                }
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    pub fn fail_all(&mut self, error: impl Fn() -> BlockDeviceError) {
+        for pending in self.slots.iter_mut().filter_map(Option::take) {
+            pending.finish(Err(error()));
+        }
+    }
+}
+
+impl QueuePair {
+    fn page(&mut self, slot: u16) -> (PhysAddr, VirtAddr) {
+        *self.pages[slot as usize]
+            .get_or_insert_with(|| DmaManager::allocate(DmaManager::UNIT_SIZE))
+    }
+
+    fn push(&mut self, slot: u16, mut command: Command) {
+        command.command_id = slot;
+
+        unsafe {
+            let entry = self.submissions.1.as_mut_ptr::<Command>();
+            entry.add(self.tail as usize).write_volatile(command);
+        }
+
+        self.tail = (self.tail + 1) % self.size;
+        self.submission_doorbell.set(self.tail as u32);
+    }
+
+    fn pop(&mut self) -> Option<Completion> {
+        let completion = unsafe {
+            let entry = self.completions.1.as_ptr::<Completion>();
+            entry.add(self.head as usize).read_volatile()
+        };
+
+        if completion.phase() != self.phase {
+            return None;
+        }
+
+        self.head = (self.head + 1) % self.size;
+        if self.head == 0 {
+            self.phase = !self.phase;
+        }
+        self.completion_doorbell.set(self.head as u32);
+
+        Some(completion)
+    }
+}
+
+impl Drop for QueuePair {
+    fn drop(&mut self) {
+        self.fail_all(|| BlockDeviceError::DeviceNotFound);
+        DmaManager::deallocate(self.submissions.1);
+        DmaManager::deallocate(self.completions.1);
+        for &(_, virtual_address) in self.pages.iter().flatten() {
+            DmaManager::deallocate(virtual_address);
+        }
+    }
+}
@@ -1,31 +1,61 @@
 use acpi::platform::PciConfigRegions;
 use alloc::alloc::Global;
 use alloc::vec::Vec;
+use bit_field::BitField;
 use capability::PciCapability;
 use core::fmt::Display;
 use core::{fmt, ptr};
 use device_type::DeviceType;
 use pci_types::*;
 use spin::{Lazy, Mutex};
-use x86_64::structures::paging::PhysFrame;
 use x86_64::{PhysAddr, VirtAddr};
 
 use crate::arch::acpi::ACPI;
-use crate::mem::convert_physical_to_virtual;
-use crate::mem::{KERNEL_PAGE_TABLE, MappingType, MemoryManager};
+use crate::mem::{MMIO_ALLOCATOR, MappingType};
+
+const MSI_CAPABILITY_ID: u8 = 0x05;
+const MSIX_CAPABILITY_ID: u8 = 0x11;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSI_ADDRESS_BASE: u32 = 0xfee0_0000;
+
+pub static PCI_ACCESS: Lazy<PciAccess<'static>> = Lazy::new(|| PciAccess::new(&ACPI.pci_regions));
 
 pub static PCI_DEVICES: Lazy<Mutex<Vec<PciDevice>>> = Lazy::new(|| {
-    let access = PciAccess::new(&ACPI.pci_regions);
-    let devices = PciResolver::resolve(access);
+    let devices = PciResolver::resolve(&PCI_ACCESS);
     devices.iter().for_each(|device| log::info!("{device}"));
     Mutex::new(devices)
 });
 
-pub struct PciAccess<'a>(&'a PciConfigRegions<Global>);
+pub struct PciAccess<'a> {
+    regions: &'a PciConfigRegions<Global>,
+    windows: Vec<(u16, PhysAddr, VirtAddr, u64)>,
+}
 
 impl<'a> PciAccess<'a> {
     pub fn new(regions: &'a PciConfigRegions<Global>) -> Self {
-        Self(regions)
+        let windows = regions
+            .regions
+            .iter()
+            .map(|region| {
+                let segment = region.pci_segment_group;
+                let physical_address = regions
+                    .physical_address(segment, region.bus_number_start, 0, 0)
+                    .expect("Invalid PCI region");
+
+                let physical_address = PhysAddr::new(physical_address);
+                let bus_count = (region.bus_number_end - region.bus_number_start) as u64 + 1;
+                let length = bus_count << 20;
+
+                let virtual_address = MMIO_ALLOCATOR
+                    .lock()
+                    .map(physical_address, length, MappingType::Uncached)
+                    .unwrap();
+
+                (segment, physical_address, virtual_address, length)
+            })
+            .collect();
+
+        Self { regions, windows }
     }
 
     pub fn mmio_address(&self, address: PciAddress, offset: u16) -> VirtAddr {
@@ -37,24 +67,23 @@ impl<'a> PciAccess<'a> {
         );
 
         let physical_address = self
-            .0
+            .regions
             .physical_address(segment, bus, device, function)
             .expect("Invalid PCI address")
             + offset as u64;
 
         let physical_address = PhysAddr::new(physical_address);
-        let virtual_address = convert_physical_to_virtual(physical_address);
-
-        <MemoryManager>::map_range_to(
-            virtual_address,
-            PhysFrame::containing_address(physical_address),
-            0x1000,
-            MappingType::KernelData.flags(),
-            &mut KERNEL_PAGE_TABLE.lock(),
-        )
-        .unwrap();
 
-        virtual_address
+        let (_, window_start, virtual_address, _) = self
+            .windows
+            .iter()
+            .find(|(window_segment, window_start, _, length)| {
+                *window_segment == segment
+                    && (*window_start..*window_start + *length).contains(&physical_address)
+            })
+            .expect("PCI address outside of mapped regions");
+
+        *virtual_address + (physical_address - *window_start)
     }
 }
 
@@ -70,7 +99,7 @@ impl ConfigRegionAccess for PciAccess<'_> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PciDevice {
     pub address: PciAddress,
     pub vendor_id: VendorId,
@@ -81,6 +110,131 @@ pub struct PciDevice {
     pub bars: [Option<Bar>; MAX_BARS],
 }
 
+impl PciDevice {
+    pub fn read_config(&self, offset: u16) -> u32 {
+        unsafe { PCI_ACCESS.read(self.address, offset) }
+    }
+
+    pub fn write_config(&self, offset: u16, value: u32) {
+        unsafe { PCI_ACCESS.write(self.address, offset, value) }
+    }
+
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u16)> + '_ {
+        let mut offset = match self.read_config(0x04).get_bit(20) {
+            true => self.read_config(0x34).get_bits(0..8) as u16 & !0x3,
+            false => 0,
+        };
+
+        core::iter::from_fn(move || {
+            if offset == 0 {
+                return None;
+            }
+
+            let (current, header) = (offset, self.read_config(offset));
+            offset = header.get_bits(8..16) as u16 & !0x3;
+            Some((header.get_bits(0..8) as u8, current))
+        })
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .find(|&(capability, _)| capability == id)
+            .map(|(_, offset)| offset)
+    }
+
+    pub fn enable_msi(&self, vector: u8, lapic_id: u32) -> bool {
+        let Some(offset) = self.find_capability(MSI_CAPABILITY_ID) else {
+            return false;
+        };
+
+        let mut header = self.read_config(offset);
+        let data_offset = if header.get_bit(23) {
+            self.write_config(offset + 8, 0);
+            offset + 12
+        } else {
+            offset + 8
+        };
+
+        self.write_config(offset + 4, MSI_ADDRESS_BASE | (lapic_id << 12));
+        self.write_config(data_offset, vector as u32);
+
+        header.set_bits(20..23, 0);
+        header.set_bit(16, true);
+        self.write_config(offset, header);
+        self.set_intx_disabled(true);
+
+        true
+    }
+
+    pub fn enable_msix(&self, entry: u16, vector: u8, lapic_id: u32) -> bool {
+        let Some(offset) = self.find_capability(MSIX_CAPABILITY_ID) else {
+            return false;
+        };
+
+        let mut header = self.read_config(offset);
+        if entry as u32 > header.get_bits(16..27) {
+            return false;
+        }
+
+        let table = self.read_config(offset + 4);
+        let Some(bar) = self.bars[table.get_bits(0..3) as usize] else {
+            return false;
+        };
+        if matches!(bar, Bar::Io { .. }) {
+            return false;
+        }
+
+        let (address, _) = bar.unwrap_mem();
+        let entry_offset = (table & !0x7) as u64 + entry as u64 * MSIX_ENTRY_SIZE;
+        let physical_address = PhysAddr::new(address as u64 + entry_offset);
+
+        let mut mmio = MMIO_ALLOCATOR.lock();
+        let Ok(virtual_address) =
+            mmio.map(physical_address, MSIX_ENTRY_SIZE, MappingType::Uncached)
+        else {
+            return false;
+        };
+
+        let entry = virtual_address.as_mut_ptr::<u32>();
+        unsafe {
+            ptr::write_volatile(entry, MSI_ADDRESS_BASE | (lapic_id << 12));
+            ptr::write_volatile(entry.add(1), 0);
+            ptr::write_volatile(entry.add(2), vector as u32);
+            ptr::write_volatile(entry.add(3), 0);
+        }
+        let _ = mmio.unmap(virtual_address);
+
+        header.set_bit(30, false);
+        header.set_bit(31, true);
+        self.write_config(offset, header);
+        self.set_intx_disabled(true);
+
+        true
+    }
+
+    // Returns the legacy IRQ the firmware routed the INTx pin to, if any.
+    pub fn enable_intx(&self) -> Option<u8> {
+        let interrupt = self.read_config(0x3c);
+        let (line, pin) = (interrupt.get_bits(0..8) as u8, interrupt.get_bits(8..16));
+        if pin == 0 || line == 0xff {
+            return None;
+        }
+
+        self.set_intx_disabled(false);
+        Some(line)
+    }
+}
+
+impl PciDevice {
+    // Only the command half of the register is written back, as the status
+    // half is write-one-to-clear.
+    fn set_intx_disabled(&self, disabled: bool) {
+        let mut command = self.read_config(0x04).get_bits(0..16);
+        command.set_bit(10, disabled);
+        self.write_config(0x04, command);
+    }
+}
+
 impl Display for PciDevice {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -98,18 +252,18 @@ impl Display for PciDevice {
 }
 
 pub struct PciResolver<'a> {
-    access: PciAccess<'a>,
+    access: &'a PciAccess<'a>,
     devices: Vec<PciDevice>,
 }
 
 impl<'a> PciResolver<'a> {
-    fn resolve(access: PciAccess<'a>) -> Vec<PciDevice> {
+    fn resolve(access: &'a PciAccess<'a>) -> Vec<PciDevice> {
         let mut resolver = Self {
             access,
             devices: Vec::new(),
         };
 
-        for region in resolver.access.0.regions.iter() {
+        for region in resolver.access.regions.regions.iter() {
             resolver.scan_segment(region.pci_segment_group);
         }
 
@@ -120,7 +274,7 @@ impl<'a> PciResolver<'a> {
         self.scan_bus(segment, 0);
 
         let address = PciAddress::new(segment, 0, 0, 0);
-        if PciHeader::new(address).has_multiple_functions(&self.access) {
+        if PciHeader::new(address).has_multiple_functions(self.access) {
             (1..8).for_each(|i| self.scan_bus(segment, i));
         }
     }
@@ -131,7 +285,7 @@ impl<'a> PciResolver<'a> {
             self.scan_function(segment, bus, device, 0);
 
             let header = PciHeader::new(address);
-            if header.has_multiple_functions(&self.access) {
+            if header.has_multiple_functions(self.access) {
                 (1..8).for_each(|function| {
                     self.scan_function(segment, bus, device, function);
                 });
@@ -143,8 +297,8 @@ impl<'a> PciResolver<'a> {
         let address = PciAddress::new(segment, bus, device, function);
         let header = PciHeader::new(address);
 
-        let (vendor_id, device_id) = header.id(&self.access);
-        let (revision, class, sub_class, interface) = header.revision_and_class(&self.access);
+        let (vendor_id, device_id) = header.id(self.access);
+        let (revision, class, sub_class, interface) = header.revision_and_class(self.access);
 
         if vendor_id == 0xffff {
             return;
@@ -159,7 +313,7 @@ impl<'a> PciResolver<'a> {
                     skip_next = false;
                     continue;
                 }
-                let bar = header.bar(index as u8, &self.access);
+                let bar = header.bar(index as u8, self.access);
                 if let Some(Bar::Memory64 { .. }) = bar {
                     skip_next = true;
                 }
@@ -169,27 +323,27 @@ impl<'a> PciResolver<'a> {
             bars
         };
 
-        match header.header_type(&self.access) {
+        match header.header_type(self.access) {
             HeaderType::Endpoint => {
-                let mut endpoint_header = EndpointHeader::from_header(header, &self.access)
+                let mut endpoint_header = EndpointHeader::from_header(header, self.access)
                     .expect("Invalid endpoint header");
 
                 let bars = endpoint_bars(&endpoint_header);
                 let device_type = DeviceType::from((class, sub_class));
 
-                endpoint_header.capabilities(&self.access).for_each(
-                    |capability| match capability {
+                endpoint_header
+                    .capabilities(self.access)
+                    .for_each(|capability| match capability {
                         PciCapability::Msi(msi) => {
-                            msi.set_enabled(true, &self.access);
+                            msi.set_enabled(true, self.access);
                         }
                         PciCapability::MsiX(mut msix) => {
-                            msix.set_enabled(true, &self.access);
+                            msix.set_enabled(true, self.access);
                         }
                         _ => {}
-                    },
-                );
+                    });
 
-                endpoint_header.update_command(&self.access, |command| {
+                endpoint_header.update_command(self.access, |command| {
                     command
                         | CommandRegister::BUS_MASTER_ENABLE
                         | CommandRegister::IO_ENABLE
@@ -209,11 +363,11 @@ impl<'a> PciResolver<'a> {
                 self.devices.push(device);
             }
             HeaderType::PciPciBridge => {
-                let bridge_header = PciPciBridgeHeader::from_header(header, &self.access)
+                let bridge_header = PciPciBridgeHeader::from_header(header, self.access)
                     .expect("Invalid PCI-PCI bridge header");
 
-                let start_bus = bridge_header.secondary_bus_number(&self.access);
-                let end_bus = bridge_header.subordinate_bus_number(&self.access);
+                let start_bus = bridge_header.secondary_bus_number(self.access);
+                let end_bus = bridge_header.subordinate_bus_number(self.access);
                 (start_bus..=end_bus).for_each(|bus_id| self.scan_bus(segment, bus_id));
             }
             _ => {}
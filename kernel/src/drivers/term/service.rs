@@ -9,6 +9,7 @@ use os_terminal::{MouseInput, Terminal};
 use spin::Lazy;
 
 use super::writer::TerminalWriter;
+use crate::drivers::hpet::HPET;
 use crate::drivers::mouse::{MOUSE_BUFFER, MouseEvent};
 use crate::drivers::{display::Display, speaker::SPEAKER};
 use crate::syscall::r#yield;
@@ -56,6 +57,12 @@ pub fn terminal_thread() {
     terminal.set_bell_handler(|| SPEAKER.lock().beep(750, Duration::from_millis(100)));
     terminal.set_pty_writer(Box::new(|s: String| TerminalWriter.write_str(&s).unwrap()));
 
+    // The first flush redraws every cell, which makes it a handy benchmark
+    // for framebuffer write performance.
+    let start = HPET.elapsed();
+    terminal.flush();
+    log::info!("Full terminal redraw took {:?}", HPET.elapsed() - start);
+
     loop {
         terminal_event(&mut terminal);
         terminal_flush(&mut terminal);
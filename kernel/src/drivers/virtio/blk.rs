@@ -0,0 +1,495 @@
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::{Lazy, Mutex, RwLock};
+use vcell::VolatileCell as Volatile;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::queue::{Buffer, QUEUE_SIZE_MAX, VirtQueue};
+use super::{NO_VECTOR, VIRTIO_VENDOR_ID, VirtioPci};
+use crate::arch::interrupts::InterruptIndex;
+use crate::arch::smp::BSP_LAPIC_ID;
+use crate::drivers::pcie::PCI_DEVICES;
+use crate::io::block::{BlockCapabilities, BlockDeviceError, BlockDeviceResult};
+use crate::io::request::{BlockOperation, BlockRequest, BlockRequestHandle};
+use crate::io::request::{DmaLimits, DmaTransfer, PendingRequest};
+use crate::mem::DmaManager;
+
+const VIRTIO_BLK_DEVICE_IDS: [u16; 2] = [0x1001, 0x1042];
+const SECTOR_SIZE: usize = 512;
+const MAX_TRANSFER_SIZE: usize = 1024 * 1024;
+const HEADER_STRIDE: usize = 32;
+
+const FEATURE_SIZE_MAX: u64 = 1 << 1;
+const FEATURE_SEG_MAX: u64 = 1 << 2;
+const FEATURE_RO: u64 = 1 << 5;
+const FEATURE_BLK_SIZE: u64 = 1 << 6;
+const FEATURE_FLUSH: u64 = 1 << 9;
+const FEATURE_DISCARD: u64 = 1 << 13;
+const FEATURE_WRITE_ZEROES: u64 = 1 << 14;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const REQUEST_DISCARD: u32 = 11;
+const REQUEST_WRITE_ZEROES: u32 = 13;
+
+const STATUS_OK: u8 = 0;
+
+#[repr(C)]
+pub struct BlkConfig {
+    pub capacity: Volatile<u64>,
+    pub size_max: Volatile<u32>,
+    pub seg_max: Volatile<u32>,
+    pub cylinders: Volatile<u16>,
+    pub heads: Volatile<u8>,
+    pub sectors: Volatile<u8>,
+    pub blk_size: Volatile<u32>,
+    pub physical_block_exp: Volatile<u8>,
+    pub alignment_offset: Volatile<u8>,
+    pub min_io_size: Volatile<u16>,
+    pub opt_io_size: Volatile<u32>,
+    pub writeback: Volatile<u8>,
+    _unused: u8,
+    pub num_queues: Volatile<u16>,
+    pub max_discard_sectors: Volatile<u32>,
+    pub max_discard_seg: Volatile<u32>,
+    pub discard_sector_alignment: Volatile<u32>,
+    pub max_write_zeroes_sectors: Volatile<u32>,
+    pub max_write_zeroes_seg: Volatile<u32>,
+    pub write_zeroes_may_unmap: Volatile<u8>,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+#[repr(C)]
+struct RangeSegment {
+    sector: u64,
+    sector_count: u32,
+    flags: u32,
+}
+
+pub struct VirtioBlk {
+    transport: VirtioPci,
+    queue: VirtQueue,
+    headers: (PhysAddr, VirtAddr),
+    ranges: (PhysAddr, VirtAddr),
+    pending: Vec<Option<Arc<PendingRequest>>>,
+    block_size: usize,
+    block_count: u64,
+    limits: DmaLimits,
+    read_only: bool,
+    flush: bool,
+    capabilities: BlockCapabilities,
+    max_discard_blocks: u64,
+    max_write_zeroes_blocks: u64,
+}
+
+impl VirtioBlk {
+    pub fn new(transport: VirtioPci, msix_enabled: bool) -> Option<Self> {
+        let wanted = FEATURE_SIZE_MAX
+            | FEATURE_SEG_MAX
+            | FEATURE_RO
+            | FEATURE_BLK_SIZE
+            | FEATURE_FLUSH
+            | FEATURE_DISCARD
+            | FEATURE_WRITE_ZEROES;
+        let features = transport.initialize(wanted)?;
+
+        let Some(config) = transport.device_config::<BlkConfig>() else {
+            transport.fail();
+            return None;
+        };
+
+        let vector = if msix_enabled { 0 } else { NO_VECTOR };
+        let Some(queue) = transport.setup_queue(0, QUEUE_SIZE_MAX, vector) else {
+            transport.fail();
+            return None;
+        };
+
+        if msix_enabled && transport.queue_vector(0) == NO_VECTOR {
+            log::warn!("virtio-blk device rejected its MSI-X vector, polling only");
+        }
+
+        let block_size = match features & FEATURE_BLK_SIZE != 0 {
+            true => config.blk_size.get() as usize,
+            false => SECTOR_SIZE,
+        };
+
+        // Every request needs a header and a status descriptor around its data.
+        let mut max_segments = queue.size() as usize - 2;
+        if features & FEATURE_SEG_MAX != 0 && config.seg_max.get() != 0 {
+            max_segments = max_segments.min(config.seg_max.get() as usize);
+        }
+
+        let max_segment_size = match features & FEATURE_SIZE_MAX != 0 {
+            true if config.size_max.get() != 0 => config.size_max.get() as usize,
+            _ => MAX_TRANSFER_SIZE,
+        };
+
+        let mut capabilities = BlockCapabilities::empty();
+        if features & FEATURE_DISCARD != 0 {
+            capabilities |= BlockCapabilities::DISCARD;
+        }
+        if features & FEATURE_WRITE_ZEROES != 0 {
+            capabilities |= BlockCapabilities::WRITE_ZEROES;
+        }
+
+        let sectors_per_block = (block_size / SECTOR_SIZE) as u64;
+        let max_blocks = |sectors: u32| match sectors {
+            0 => u32::MAX as u64 / sectors_per_block,
+            _ => (sectors as u64 / sectors_per_block).max(1),
+        };
+
+        let headers = DmaManager::allocate(queue.size() as usize * HEADER_STRIDE);
+        let ranges = DmaManager::allocate(queue.size() as usize * size_of::<RangeSegment>());
+        let pending = (0..queue.size()).map(|_| None).collect();
+
+        transport.driver_ok();
+
+        Some(Self {
+            transport,
+            queue,
+            headers,
+            ranges,
+            pending,
+            block_size,
+            block_count: config.capacity.get() * SECTOR_SIZE as u64 / block_size as u64,
+            limits: DmaLimits {
+                block_size,
+                max_segments,
+                max_segment_size,
+                max_transfer_size: MAX_TRANSFER_SIZE,
+                page_aligned: false,
+            },
+            read_only: features & FEATURE_RO != 0,
+            flush: features & FEATURE_FLUSH != 0,
+            capabilities,
+            max_discard_blocks: max_blocks(config.max_discard_sectors.get()),
+            max_write_zeroes_blocks: max_blocks(config.max_write_zeroes_sectors.get()),
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn capabilities(&self) -> BlockCapabilities {
+        self.capabilities
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue.size() as usize / 3
+    }
+
+    pub fn handle_interrupt(&mut self) {
+        self.transport.read_isr();
+        self.process_completions();
+    }
+
+    pub fn process_completions(&mut self) {
+        while let Some((head, _)) = self.queue.pop_used() {
+            let status = unsafe { self.status(head).1.as_ptr::<u8>().read_volatile() };
+            let result = match status {
+                STATUS_OK => Ok(()),
+                _ => Err(BlockDeviceError::IoError(format!(
+                    "virtio-blk request failed with status {status}"
+                ))),
+            };
+
+            if let Some(pending) = self.pending[head as usize].take() {
+                pending.finish(result);
+            }
+        }
+    }
+
+    pub fn submit(device: &Mutex<Self>, request: BlockRequest) {
+        let (limits, flush, read_only, max_discard, max_write_zeroes) =
+            interrupts::without_interrupts(|| {
+                let device = device.lock();
+                (
+                    device.limits,
+                    device.flush,
+                    device.read_only,
+                    device.max_discard_blocks,
+                    device.max_write_zeroes_blocks,
+                )
+            });
+
+        let transfers = match request.operation {
+            BlockOperation::Flush if !flush => return request.complete(Ok(())),
+            BlockOperation::Flush => Ok(vec![DmaTransfer {
+                block_id: 0,
+                block_count: 0,
+                segments: Vec::new(),
+            }]),
+            BlockOperation::Write | BlockOperation::Discard | BlockOperation::WriteZeroes
+                if read_only =>
+            {
+                Err(BlockDeviceError::ReadOnly)
+            }
+            BlockOperation::Discard => Ok(range_transfers(request.range(), max_discard)),
+            BlockOperation::WriteZeroes => Ok(range_transfers(request.range(), max_write_zeroes)),
+            _ => request.dma_transfers(&limits),
+        };
+
+        let transfers = match transfers {
+            Ok(transfers) if !transfers.is_empty() => transfers,
+            Ok(_) => return request.complete(Ok(())),
+            Err(err) => return request.complete(Err(err)),
+        };
+
+        let request_type = match request.operation {
+            BlockOperation::Read => REQUEST_IN,
+            BlockOperation::Write => REQUEST_OUT,
+            BlockOperation::Flush => REQUEST_FLUSH,
+            BlockOperation::Discard => REQUEST_DISCARD,
+            BlockOperation::WriteZeroes => REQUEST_WRITE_ZEROES,
+        };
+        let pending = PendingRequest::new(request, transfers.len());
+
+        for transfer in transfers.iter() {
+            loop {
+                let issued = interrupts::without_interrupts(|| {
+                    let mut device = device.lock();
+                    device.process_completions();
+                    device.issue(request_type, transfer, pending.clone())
+                });
+
+                if issued {
+                    break;
+                }
+
+                if interrupts::are_enabled() {
+                    crate::syscall::r#yield();
+                } else {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+}
+
+impl VirtioBlk {
+    fn header(&self, head: u16) -> (PhysAddr, VirtAddr) {
+        let offset = (head as usize * HEADER_STRIDE) as u64;
+        (self.headers.0 + offset, self.headers.1 + offset)
+    }
+
+    fn range_segment(&self, head: u16) -> (PhysAddr, VirtAddr) {
+        let offset = (head as usize * size_of::<RangeSegment>()) as u64;
+        (self.ranges.0 + offset, self.ranges.1 + offset)
+    }
+
+    fn status(&self, head: u16) -> (PhysAddr, VirtAddr) {
+        let (physical, virtual_address) = self.header(head);
+        let offset = size_of::<RequestHeader>() as u64;
+        (physical + offset, virtual_address + offset)
+    }
+
+    fn issue(
+        &mut self,
+        request_type: u32,
+        transfer: &DmaTransfer,
+        pending: Arc<PendingRequest>,
+    ) -> bool {
+        let ranged = matches!(request_type, REQUEST_DISCARD | REQUEST_WRITE_ZEROES);
+        let descriptors = match ranged {
+            true => 3,
+            false => transfer.segments.len() + 2,
+        };
+        if self.queue.free_count() < descriptors {
+            return false;
+        }
+
+        let sectors_per_block = (self.block_size / SECTOR_SIZE) as u64;
+        let mut sector = transfer.block_id * sectors_per_block;
+
+        // Header slots are indexed by the chain's head descriptor, which is
+        // always the first free one.
+        let head = self.queue.next_head();
+        let (header_physical, header_virtual) = self.header(head);
+        let (status_physical, status_virtual) = self.status(head);
+
+        let mut buffers = Vec::with_capacity(descriptors);
+        buffers.push(Buffer {
+            address: header_physical.as_u64(),
+            length: size_of::<RequestHeader>() as u32,
+            writable: false,
+        });
+
+        if ranged {
+            let (range_physical, range_virtual) = self.range_segment(head);
+            unsafe {
+                range_virtual
+                    .as_mut_ptr::<RangeSegment>()
+                    .write_volatile(RangeSegment {
+                        sector,
+                        sector_count: (transfer.block_count as u64 * sectors_per_block) as u32,
+                        flags: 0,
+                    });
+            }
+            buffers.push(Buffer {
+                address: range_physical.as_u64(),
+                length: size_of::<RangeSegment>() as u32,
+                writable: false,
+            });
+            sector = 0;
+        } else {
+            buffers.extend(transfer.segments.iter().map(|&(address, length)| Buffer {
+                address,
+                length: length as u32,
+                writable: request_type == REQUEST_IN,
+            }));
+        }
+
+        buffers.push(Buffer {
+            address: status_physical.as_u64(),
+            length: 1,
+            writable: true,
+        });
+
+        unsafe {
+            header_virtual
+                .as_mut_ptr::<RequestHeader>()
+                .write_volatile(RequestHeader {
+                    request_type,
+                    reserved: 0,
+                    sector,
+                });
+            status_virtual.as_mut_ptr::<u8>().write_volatile(0xFF);
+        }
+
+        let Some(head) = self.queue.add(&buffers) else {
+            return false;
+        };
+        self.pending[head as usize] = Some(pending);
+        self.queue.notify();
+
+        true
+    }
+}
+
+impl Drop for VirtioBlk {
+    fn drop(&mut self) {
+        DmaManager::deallocate(self.headers.1);
+        DmaManager::deallocate(self.ranges.1);
+    }
+}
+
+fn range_transfers(range: Range<u64>, max_blocks: u64) -> Vec<DmaTransfer> {
+    let mut transfers = Vec::new();
+    let mut block_id = range.start;
+
+    while block_id < range.end {
+        let block_count = (range.end - block_id).min(max_blocks);
+        transfers.push(DmaTransfer {
+            block_id,
+            block_count: block_count as usize,
+            segments: Vec::new(),
+        });
+        block_id += block_count;
+    }
+
+    transfers
+}
+
+static DEVICES: RwLock<Vec<Arc<Mutex<VirtioBlk>>>> = RwLock::new(Vec::new());
+
+pub struct VirtioBlockDevice {
+    pub device: Arc<Mutex<VirtioBlk>>,
+    pub block_size: usize,
+    pub block_count: u64,
+    pub read_only: bool,
+    pub capabilities: BlockCapabilities,
+}
+
+impl VirtioBlockDevice {
+    pub fn execute(&self, request: BlockRequest) -> BlockDeviceResult<()> {
+        BlockRequestHandle::submit(self, request).wait_with(|| {
+            interrupts::without_interrupts(|| self.device.lock().process_completions())
+        })
+    }
+}
+
+pub struct VirtioBlkManager(Vec<Arc<Mutex<VirtioBlk>>>);
+
+impl VirtioBlkManager {
+    pub fn iter(&self) -> impl Iterator<Item = VirtioBlockDevice> {
+        self.0.iter().map(|device| {
+            let (block_size, block_count, read_only, capabilities) =
+                interrupts::without_interrupts(|| {
+                    let device = device.lock();
+                    (
+                        device.block_size(),
+                        device.block_count(),
+                        device.is_read_only(),
+                        device.capabilities(),
+                    )
+                });
+
+            VirtioBlockDevice {
+                device: device.clone(),
+                block_size,
+                block_count,
+                read_only,
+                capabilities,
+            }
+        })
+    }
+}
+
+pub static VIRTIO_BLK: Lazy<VirtioBlkManager> = Lazy::new(|| {
+    let mut devices = Vec::new();
+
+    for device in PCI_DEVICES.lock().iter() {
+        if device.vendor_id != VIRTIO_VENDOR_ID
+            || !VIRTIO_BLK_DEVICE_IDS.contains(&device.device_id)
+        {
+            continue;
+        }
+
+        let Some(transport) = VirtioPci::new(device) else {
+            log::warn!("virtio-blk device {device} has no modern PCI transport");
+            continue;
+        };
+
+        let msix_enabled = device.enable_msix(0, InterruptIndex::Virtio as u8, *BSP_LAPIC_ID);
+        if !msix_enabled {
+            log::warn!("virtio-blk device {device} has no MSI-X support, polling only");
+        }
+
+        let Some(blk) = VirtioBlk::new(transport, msix_enabled) else {
+            log::warn!("Failed to initialize virtio-blk device {device}");
+            continue;
+        };
+
+        let blk = Arc::new(Mutex::new(blk));
+        interrupts::without_interrupts(|| DEVICES.write().push(blk.clone()));
+        devices.push(blk);
+    }
+
+    VirtioBlkManager(devices)
+});
+
+pub fn handle_interrupt() {
+    for device in DEVICES.read().iter() {
+        device.lock().handle_interrupt();
+    }
+}
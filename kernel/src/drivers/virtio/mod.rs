@@ -0,0 +1,232 @@
+use bit_field::BitField;
+use pci_types::Bar;
+use vcell::VolatileCell as Volatile;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::pcie::PciDevice;
+use crate::mem::{MMIO_ALLOCATOR, MappingType};
+
+pub mod blk;
+pub mod queue;
+
+pub use blk::{VIRTIO_BLK, VirtioBlk, VirtioBlockDevice};
+pub use queue::VirtQueue;
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+const VENDOR_CAPABILITY_ID: u8 = 0x09;
+const CFG_TYPE_COMMON: u32 = 1;
+const CFG_TYPE_NOTIFY: u32 = 2;
+const CFG_TYPE_ISR: u32 = 3;
+const CFG_TYPE_DEVICE: u32 = 4;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+#[repr(C)]
+pub struct CommonConfig {
+    pub device_feature_select: Volatile<u32>,
+    pub device_feature: Volatile<u32>,
+    pub driver_feature_select: Volatile<u32>,
+    pub driver_feature: Volatile<u32>,
+    pub msix_config: Volatile<u16>,
+    pub num_queues: Volatile<u16>,
+    pub device_status: Volatile<u8>,
+    pub config_generation: Volatile<u8>,
+    pub queue_select: Volatile<u16>,
+    pub queue_size: Volatile<u16>,
+    pub queue_msix_vector: Volatile<u16>,
+    pub queue_enable: Volatile<u16>,
+    pub queue_notify_off: Volatile<u16>,
+    pub queue_desc_lo: Volatile<u32>,
+    pub queue_desc_hi: Volatile<u32>,
+    pub queue_driver_lo: Volatile<u32>,
+    pub queue_driver_hi: Volatile<u32>,
+    pub queue_device_lo: Volatile<u32>,
+    pub queue_device_hi: Volatile<u32>,
+}
+
+pub struct VirtioPci {
+    common: &'static CommonConfig,
+    notify_base: VirtAddr,
+    notify_multiplier: u32,
+    isr: &'static Volatile<u8>,
+    device: VirtAddr,
+}
+
+unsafe impl Send for VirtioPci {}
+unsafe impl Sync for VirtioPci {}
+
+impl VirtioPci {
+    pub fn new(device: &PciDevice) -> Option<Self> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut config = None;
+
+        for (_, offset) in device
+            .capabilities()
+            .filter(|&(id, _)| id == VENDOR_CAPABILITY_ID)
+        {
+            let cfg_type = device.read_config(offset).get_bits(24..32);
+            let bar = device.read_config(offset + 4).get_bits(0..8) as usize;
+            let region_offset = device.read_config(offset + 8) as u64;
+
+            let Some(address) = map_bar(device, bar) else {
+                continue;
+            };
+            let address = address + region_offset;
+
+            match cfg_type {
+                CFG_TYPE_COMMON if common.is_none() => common = Some(address),
+                CFG_TYPE_NOTIFY if notify.is_none() => {
+                    notify = Some((address, device.read_config(offset + 16)))
+                }
+                CFG_TYPE_ISR if isr.is_none() => isr = Some(address),
+                CFG_TYPE_DEVICE if config.is_none() => config = Some(address),
+                _ => {}
+            }
+        }
+
+        let (notify_base, notify_multiplier) = notify?;
+        Some(Self {
+            common: unsafe { &*common?.as_ptr::<CommonConfig>() },
+            notify_base,
+            notify_multiplier,
+            isr: unsafe { &*isr?.as_ptr::<Volatile<u8>>() },
+            device: config.unwrap_or(VirtAddr::zero()),
+        })
+    }
+
+    pub fn initialize(&self, features: u64) -> Option<u64> {
+        self.common.device_status.set(0);
+        while self.common.device_status.get() != 0 {
+            core::hint::spin_loop();
+        }
+
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let negotiated = self.device_features() & (features | FEATURE_VERSION_1);
+        if negotiated & FEATURE_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return None;
+        }
+
+        self.common.driver_feature_select.set(0);
+        self.common.driver_feature.set(negotiated as u32);
+        self.common.driver_feature_select.set(1);
+        self.common.driver_feature.set((negotiated >> 32) as u32);
+
+        self.add_status(STATUS_FEATURES_OK);
+        if self.common.device_status.get() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return None;
+        }
+
+        self.common.msix_config.set(NO_VECTOR);
+        Some(negotiated)
+    }
+
+    pub fn setup_queue(&self, index: u16, max_size: u16, msix_vector: u16) -> Option<VirtQueue> {
+        self.common.queue_select.set(index);
+
+        let size = self.common.queue_size.get().min(max_size);
+        if size == 0 {
+            return None;
+        }
+
+        let notify_offset = self.common.queue_notify_off.get() as u64;
+        let notify = self.notify_base + notify_offset * self.notify_multiplier as u64;
+        let queue = VirtQueue::new(index, size, notify);
+
+        self.common.queue_size.set(size);
+        set_address(
+            &self.common.queue_desc_lo,
+            &self.common.queue_desc_hi,
+            queue.descriptor_address(),
+        );
+        set_address(
+            &self.common.queue_driver_lo,
+            &self.common.queue_driver_hi,
+            queue.driver_address(),
+        );
+        set_address(
+            &self.common.queue_device_lo,
+            &self.common.queue_device_hi,
+            queue.device_address(),
+        );
+
+        self.common.queue_msix_vector.set(msix_vector);
+        self.common.queue_enable.set(1);
+
+        Some(queue)
+    }
+
+    pub fn queue_vector(&self, index: u16) -> u16 {
+        self.common.queue_select.set(index);
+        self.common.queue_msix_vector.get()
+    }
+
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    pub fn read_isr(&self) -> u8 {
+        self.isr.get()
+    }
+
+    pub fn device_config<T>(&self) -> Option<&'static T> {
+        match self.device.is_null() {
+            true => None,
+            false => Some(unsafe { &*self.device.as_ptr::<T>() }),
+        }
+    }
+}
+
+impl VirtioPci {
+    fn add_status(&self, status: u8) {
+        let current = self.common.device_status.get();
+        self.common.device_status.set(current | status);
+    }
+
+    fn device_features(&self) -> u64 {
+        self.common.device_feature_select.set(0);
+        let low = self.common.device_feature.get() as u64;
+        self.common.device_feature_select.set(1);
+        let high = self.common.device_feature.get() as u64;
+        (high << 32) | low
+    }
+}
+
+fn map_bar(device: &PciDevice, index: usize) -> Option<VirtAddr> {
+    let bar = (*device.bars.get(index)?)?;
+    if matches!(bar, Bar::Io { .. }) {
+        return None;
+    }
+
+    let (address, size) = bar.unwrap_mem();
+    MMIO_ALLOCATOR
+        .lock()
+        .map(
+            PhysAddr::new(address as u64),
+            size as u64,
+            MappingType::Uncached,
+        )
+        .ok()
+}
+
+fn set_address(low: &Volatile<u32>, high: &Volatile<u32>, address: PhysAddr) {
+    low.set(address.as_u64() as u32);
+    high.set((address.as_u64() >> 32) as u32);
+}
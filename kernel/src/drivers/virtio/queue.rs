@@ -0,0 +1,228 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{Ordering, fence};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::mem::DmaManager;
+
+pub const QUEUE_SIZE_MAX: u16 = 128;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElement {
+    id: u32,
+    length: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: u64,
+    pub length: u32,
+    pub writable: bool,
+}
+
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    physical_address: PhysAddr,
+    virtual_address: VirtAddr,
+    notify: VirtAddr,
+    free_head: u16,
+    free_count: u16,
+    last_used: u16,
+}
+
+unsafe impl Send for VirtQueue {}
+unsafe impl Sync for VirtQueue {}
+
+impl VirtQueue {
+    pub fn new(index: u16, size: u16, notify: VirtAddr) -> Self {
+        let size = size.min(QUEUE_SIZE_MAX);
+        let (physical_address, virtual_address) = DmaManager::allocate(DmaManager::UNIT_SIZE);
+
+        let queue = Self {
+            index,
+            size,
+            physical_address,
+            virtual_address,
+            notify,
+            free_head: 0,
+            free_count: size,
+            last_used: 0,
+        };
+
+        unsafe {
+            core::ptr::write_bytes(virtual_address.as_mut_ptr::<u8>(), 0, DmaManager::UNIT_SIZE);
+            for index in 0..size {
+                let descriptor = Descriptor {
+                    next: (index + 1) % size,
+                    ..Default::default()
+                };
+                write_volatile(queue.descriptor(index), descriptor);
+            }
+        }
+
+        queue
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.free_count as usize
+    }
+
+    pub fn next_head(&self) -> u16 {
+        self.free_head
+    }
+
+    pub fn descriptor_address(&self) -> PhysAddr {
+        self.physical_address
+    }
+
+    pub fn driver_address(&self) -> PhysAddr {
+        self.physical_address + self.driver_offset() as u64
+    }
+
+    pub fn device_address(&self) -> PhysAddr {
+        self.physical_address + self.device_offset() as u64
+    }
+
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+
+        for (position, buffer) in buffers.iter().enumerate() {
+            let descriptor = self.descriptor(index);
+            let next = unsafe { read_volatile(descriptor).next };
+
+            let mut flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            if position + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+
+            unsafe {
+                write_volatile(
+                    descriptor,
+                    Descriptor {
+                        address: buffer.address,
+                        length: buffer.length,
+                        flags,
+                        next,
+                    },
+                );
+            }
+
+            self.free_head = next;
+            index = next;
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let available = self.available_index();
+        unsafe {
+            let available_index = read_volatile(available);
+            write_volatile(self.available_ring(available_index % self.size), head);
+            fence(Ordering::SeqCst);
+            write_volatile(available, available_index.wrapping_add(1));
+        }
+
+        Some(head)
+    }
+
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { write_volatile(self.notify.as_mut_ptr::<u16>(), self.index) };
+    }
+
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index = unsafe { read_volatile(self.used_index()) };
+        if used_index == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        let element = unsafe { read_volatile(self.used_ring(self.last_used % self.size)) };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = element.id as u16;
+        let mut index = head;
+        loop {
+            let descriptor = unsafe { read_volatile(self.descriptor(index)) };
+            self.free_count += 1;
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = descriptor.next;
+        }
+
+        unsafe {
+            let descriptor = self.descriptor(index);
+            let mut last = read_volatile(descriptor);
+            last.next = self.free_head;
+            write_volatile(descriptor, last);
+        }
+        self.free_head = head;
+
+        Some((head, element.length))
+    }
+}
+
+impl VirtQueue {
+    fn driver_offset(&self) -> usize {
+        size_of::<Descriptor>() * self.size as usize
+    }
+
+    fn device_offset(&self) -> usize {
+        (self.driver_offset() + 4 + 2 * self.size as usize + 2).next_multiple_of(4)
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        unsafe {
+            self.virtual_address
+                .as_mut_ptr::<Descriptor>()
+                .add(index as usize)
+        }
+    }
+
+    fn available_index(&self) -> *mut u16 {
+        (self.virtual_address + self.driver_offset() as u64 + 2).as_mut_ptr()
+    }
+
+    fn available_ring(&self, slot: u16) -> *mut u16 {
+        (self.virtual_address + self.driver_offset() as u64 + 4 + 2 * slot as u64).as_mut_ptr()
+    }
+
+    fn used_index(&self) -> *mut u16 {
+        (self.virtual_address + self.device_offset() as u64 + 2).as_mut_ptr()
+    }
+
+    fn used_ring(&self, slot: u16) -> *mut UsedElement {
+        (self.virtual_address
+            + self.device_offset() as u64
+            + 4
+            + size_of::<UsedElement>() as u64 * slot as u64)
+            .as_mut_ptr()
+    }
+}
+
+impl Drop for VirtQueue {
+    fn drop(&mut self) {
+        DmaManager::deallocate(self.virtual_address);
+    }
+}
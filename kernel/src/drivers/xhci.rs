@@ -1,12 +1,10 @@
 use core::num::NonZeroUsize;
 use pci_types::device_type::DeviceType;
-use x86_64::PhysAddr;
-use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
 use xhci::Registers;
 use xhci::accessor::Mapper;
 
-use crate::mem::convert_physical_to_virtual;
-use crate::mem::{KERNEL_PAGE_TABLE, MappingType, MemoryManager};
+use crate::mem::{MMIO_ALLOCATOR, MappingType};
 
 use super::pcie::PCI_DEVICES;
 
@@ -16,21 +14,19 @@ pub struct XHCIMapper;
 impl Mapper for XHCIMapper {
     unsafe fn map(&mut self, physical_start: usize, length: usize) -> NonZeroUsize {
         let physical_address = PhysAddr::new(physical_start as u64);
-        let virtual_address = convert_physical_to_virtual(physical_address);
 
-        MemoryManager::map_range_to(
-            virtual_address,
-            PhysFrame::containing_address(physical_address),
-            length as u64,
-            MappingType::KernelData.flags(),
-            &mut KERNEL_PAGE_TABLE.lock(),
-        )
-        .unwrap();
+        let virtual_address = MMIO_ALLOCATOR
+            .lock()
+            .map(physical_address, length as u64, MappingType::Uncached)
+            .unwrap();
 
         NonZeroUsize::new(virtual_address.as_u64() as usize).unwrap()
     }
 
-    fn unmap(&mut self, _virt_start: usize, _bytes: usize) {}
+    fn unmap(&mut self, virt_start: usize, _bytes: usize) {
+        let virtual_address = VirtAddr::new(virt_start as u64);
+        MMIO_ALLOCATOR.lock().unmap(virtual_address).unwrap();
+    }
 }
 
 pub fn test_xhci() {
@@ -0,0 +1,423 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::str::from_utf8;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::{Lazy, RwLock};
+use x86_64::instructions::interrupts;
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, Metadata};
+use crate::arch::random_u64;
+use crate::drivers::mouse::{MOUSE_BUFFER, MouseButton, MouseEvent};
+use crate::drivers::rtc::RtcDateTime;
+use crate::drivers::serial::SERIAL;
+use crate::drivers::speaker::SPEAKER;
+use crate::io::block::{BlockDevice, BlockDeviceError};
+use crate::io::manager::{DEVICE_MANAGER, DeviceEvent};
+
+const ROOT_INODE: u64 = 1;
+const FIRST_BLOCK_INODE: u64 = 64;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+
+const MOUSE_EVENT_SIZE: usize = 8;
+
+// Weak references keep devfs from holding on to unregistered devices.
+struct BlockEntry {
+    inode: u64,
+    device: Weak<dyn BlockDevice>,
+}
+
+static BLOCK_DEVICES: Lazy<RwLock<BTreeMap<String, BlockEntry>>> = Lazy::new(RwLock::default);
+static NEXT_BLOCK_INODE: AtomicU64 = AtomicU64::new(FIRST_BLOCK_INODE);
+
+// Devices registered so far are replayed to the listener on subscription.
+pub fn init() {
+    DEVICE_MANAGER.write().subscribe(handle_device_event);
+}
+
+fn handle_device_event(event: &DeviceEvent) {
+    match event {
+        DeviceEvent::Registered(info) => {
+            let entry = block_entry(&info.device);
+            BLOCK_DEVICES.write().insert(info.name.clone(), entry);
+        }
+        DeviceEvent::Unregistered(info) => {
+            BLOCK_DEVICES.write().remove(&info.name);
+        }
+    }
+}
+
+fn block_entry(device: &Arc<dyn BlockDevice>) -> BlockEntry {
+    BlockEntry {
+        inode: NEXT_BLOCK_INODE.fetch_add(1, Ordering::Relaxed),
+        device: Arc::downgrade(device),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CharDevice {
+    Null,
+    Zero,
+    Random,
+    Terminal,
+    Serial,
+    Mouse,
+    Speaker,
+    Rtc,
+}
+
+impl CharDevice {
+    const ALL: [CharDevice; 8] = [
+        CharDevice::Null,
+        CharDevice::Zero,
+        CharDevice::Random,
+        CharDevice::Terminal,
+        CharDevice::Serial,
+        CharDevice::Mouse,
+        CharDevice::Speaker,
+        CharDevice::Rtc,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            CharDevice::Null => "null",
+            CharDevice::Zero => "zero",
+            CharDevice::Random => "random",
+            CharDevice::Terminal => "tty",
+            CharDevice::Serial => "ttyS0",
+            CharDevice::Mouse => "mouse",
+            CharDevice::Speaker => "speaker",
+            CharDevice::Rtc => "rtc",
+        }
+    }
+
+    fn inode(&self) -> u64 {
+        ROOT_INODE + 1 + *self as u64
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|device| device.name() == name)
+    }
+}
+
+pub struct DevFs;
+
+impl DevFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self)
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(DevRoot)
+    }
+}
+
+struct DevRoot;
+
+impl Inode for DevRoot {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: ROOT_INODE,
+            file_type: FileType::Directory,
+            size: 0,
+            mode: S_IFDIR | 0o755,
+            uid: 0,
+            gid: 0,
+            links: 2,
+            modified: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        if let Some(device) = CharDevice::from_name(name) {
+            return Ok(Arc::new(CharNode(device)));
+        }
+
+        let devices = BLOCK_DEVICES.read();
+        let entry = devices.get(name).ok_or(FsError::NotFound)?;
+        Ok(Arc::new(BlockNode {
+            inode: entry.inode,
+            device: entry.device.clone(),
+        }))
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        let mut entries = CharDevice::ALL
+            .iter()
+            .map(|device| DirEntry {
+                name: device.name().to_string(),
+                inode: device.inode(),
+                file_type: FileType::CharDevice,
+            })
+            .collect::<Vec<_>>();
+
+        entries.extend(BLOCK_DEVICES.read().iter().map(|(name, entry)| DirEntry {
+            name: name.clone(),
+            inode: entry.inode,
+            file_type: FileType::BlockDevice,
+        }));
+
+        Ok(entries)
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> FsResult<usize> {
+        Err(FsError::IsADirectory)
+    }
+}
+
+struct CharNode(CharDevice);
+
+impl Inode for CharNode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.0.inode(),
+            file_type: FileType::CharDevice,
+            size: 0,
+            mode: S_IFCHR | 0o666,
+            uid: 0,
+            gid: 0,
+            links: 1,
+            modified: 0,
+        }
+    }
+
+    fn lookup(&self, _name: &str) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        match self.0 {
+            CharDevice::Null | CharDevice::Terminal | CharDevice::Speaker => Ok(0),
+            CharDevice::Zero => {
+                buffer.fill(0);
+                Ok(buffer.len())
+            }
+            CharDevice::Random => {
+                for chunk in buffer.chunks_mut(8) {
+                    chunk.copy_from_slice(&random_u64().to_le_bytes()[..chunk.len()]);
+                }
+                Ok(buffer.len())
+            }
+            CharDevice::Serial => Ok(read_serial(buffer)),
+            CharDevice::Mouse => Ok(read_mouse(buffer)),
+            CharDevice::Rtc => {
+                let text = render_time()?;
+                let start = (offset as usize).min(text.len());
+                let length = buffer.len().min(text.len() - start);
+                buffer[..length].copy_from_slice(&text.as_bytes()[start..start + length]);
+                Ok(length)
+            }
+        }
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> FsResult<usize> {
+        match self.0 {
+            CharDevice::Null | CharDevice::Zero | CharDevice::Random => Ok(buffer.len()),
+            CharDevice::Terminal => {
+                crate::print!("{}", String::from_utf8_lossy(buffer));
+                Ok(buffer.len())
+            }
+            CharDevice::Serial => {
+                interrupts::without_interrupts(|| {
+                    let mut serial = SERIAL.lock();
+                    buffer.iter().for_each(|&byte| serial.send(byte));
+                });
+                Ok(buffer.len())
+            }
+            CharDevice::Speaker => {
+                play_tones(buffer)?;
+                Ok(buffer.len())
+            }
+            CharDevice::Mouse | CharDevice::Rtc => Err(FsError::ReadOnly),
+        }
+    }
+}
+
+fn read_serial(buffer: &mut [u8]) -> usize {
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL.lock();
+        buffer
+            .iter_mut()
+            .map_while(|byte| serial.try_receive().ok().map(|value| *byte = value))
+            .count()
+    })
+}
+
+// Each event is 8 bytes: kind (0 moved, 1 scroll, 2 pressed, 3 released),
+// button (0 left, 1 right, 2 middle), two padding bytes, then two
+// little-endian i16 values holding dx/dy or the scroll delta.
+fn read_mouse(buffer: &mut [u8]) -> usize {
+    let mut written = 0;
+
+    for record in buffer.chunks_exact_mut(MOUSE_EVENT_SIZE) {
+        let Some(event) = MOUSE_BUFFER.pop() else {
+            break;
+        };
+
+        let button_index = |button: MouseButton| match button {
+            MouseButton::Left => 0,
+            MouseButton::Right => 1,
+            MouseButton::Middle => 2,
+        };
+
+        let (kind, button, first, second) = match event {
+            MouseEvent::Moved(dx, dy) => (0, 0, dx, dy),
+            MouseEvent::Scroll(delta) => (1, 0, delta.clamp(-32768, 32767) as i16, 0),
+            MouseEvent::Pressed(button) => (2, button_index(button), 0, 0),
+            MouseEvent::Released(button) => (3, button_index(button), 0, 0),
+        };
+
+        record[..4].copy_from_slice(&[kind, button, 0, 0]);
+        record[4..6].copy_from_slice(&first.to_le_bytes());
+        record[6..8].copy_from_slice(&second.to_le_bytes());
+        written += MOUSE_EVENT_SIZE;
+    }
+
+    written
+}
+
+fn render_time() -> FsResult<String> {
+    let time = RtcDateTime::default()
+        .to_datetime()
+        .map_err(|_| FsError::Corrupted("invalid RTC time"))?;
+
+    Ok(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}\n",
+        time.year(),
+        time.month() as u8,
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    ))
+}
+
+// Accepts whitespace-separated "frequency duration_ms" pairs, so
+// `echo 440 200 > /dev/speaker` plays a short A4.
+fn play_tones(buffer: &[u8]) -> FsResult<()> {
+    let text = from_utf8(buffer).map_err(|_| FsError::InvalidArgument)?;
+    let values = text
+        .split_whitespace()
+        .map(|value| value.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| FsError::InvalidArgument)?;
+
+    if values.len() % 2 != 0 || values.chunks(2).any(|pair| pair[0] == 0) {
+        return Err(FsError::InvalidArgument);
+    }
+
+    for pair in values.chunks(2) {
+        let duration = Duration::from_millis(pair[1] as u64);
+        SPEAKER.lock().beep(pair[0], duration);
+    }
+    Ok(())
+}
+
+struct BlockNode {
+    inode: u64,
+    device: Weak<dyn BlockDevice>,
+}
+
+impl BlockNode {
+    fn device(&self) -> FsResult<Arc<dyn BlockDevice>> {
+        self.device
+            .upgrade()
+            .ok_or(FsError::Device(BlockDeviceError::DeviceNotFound))
+    }
+}
+
+impl Inode for BlockNode {
+    fn metadata(&self) -> Metadata {
+        let size = self.device().map_or(0, |device| {
+            device.block_count() * device.block_size() as u64
+        });
+
+        Metadata {
+            inode: self.inode,
+            file_type: FileType::BlockDevice,
+            size,
+            mode: S_IFBLK | 0o660,
+            uid: 0,
+            gid: 0,
+            links: 1,
+            modified: 0,
+        }
+    }
+
+    fn lookup(&self, _name: &str) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let device = self.device()?;
+        let block_size = device.block_size() as u64;
+        let end = (offset + buffer.len() as u64).min(device.block_count() * block_size);
+        let mut block = vec![0; block_size as usize];
+        let mut position = offset;
+
+        while position < end {
+            let block_offset = (position % block_size) as usize;
+            let length = (block_size - position % block_size).min(end - position) as usize;
+            let done = (position - offset) as usize;
+
+            device.read_block(position / block_size, &mut block)?;
+            buffer[done..done + length]
+                .copy_from_slice(&block[block_offset..block_offset + length]);
+            position += length as u64;
+        }
+
+        Ok(end.saturating_sub(offset) as usize)
+    }
+
+    // Partial blocks are read back first so neighbouring bytes survive.
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> FsResult<usize> {
+        let device = self.device()?;
+        let block_size = device.block_size() as u64;
+        let end = (offset + buffer.len() as u64).min(device.block_count() * block_size);
+        let mut block = vec![0; block_size as usize];
+        let mut position = offset;
+
+        while position < end {
+            let block_id = position / block_size;
+            let block_offset = (position % block_size) as usize;
+            let length = (block_size - position % block_size).min(end - position) as usize;
+            let done = (position - offset) as usize;
+
+            if length < block_size as usize {
+                device.read_block(block_id, &mut block)?;
+            }
+            block[block_offset..block_offset + length]
+                .copy_from_slice(&buffer[done..done + length]);
+            device.write_block(block_id, &block)?;
+            position += length as u64;
+        }
+
+        Ok(end.saturating_sub(offset) as usize)
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        self.device()?.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::tmpfs::TmpFs;
+use super::{FileSystem, FileType, FsError, FsResult, Inode, VFS};
+
+const INITRAMFS_MODULE: &str = "initramfs";
+
+const NEWC_MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+struct CpioEntry<'a> {
+    name: &'a str,
+    mode: u32,
+    data: &'a [u8],
+}
+
+pub fn init() {
+    let Some(archive) = crate::modules::find(INITRAMFS_MODULE).next() else {
+        log::warn!("No initramfs module, root filesystem is not mounted");
+        return;
+    };
+
+    let filesystem = TmpFs::new();
+    let result = unpack(&filesystem.root(), archive)
+        .and_then(|count| VFS.write().mount("/", filesystem).map(|_| count));
+
+    match result {
+        Ok(count) => log::info!("Unpacked {count} initramfs entries"),
+        Err(err) => log::error!("Failed to load initramfs: {err}"),
+    }
+}
+
+pub fn unpack(root: &Arc<dyn Inode>, archive: &[u8]) -> FsResult<usize> {
+    let entries = parse(archive)?;
+
+    for entry in entries.iter() {
+        let mut components = entry
+            .name
+            .split('/')
+            .filter(|component| !component.is_empty() && *component != ".")
+            .collect::<Vec<_>>();
+        let Some(name) = components.pop() else {
+            continue;
+        };
+
+        let mut parent = root.clone();
+        for component in components {
+            parent = match parent.lookup(component) {
+                Ok(inode) => inode,
+                Err(FsError::NotFound) => parent.create(component, FileType::Directory, 0o755)?,
+                Err(err) => return Err(err),
+            };
+        }
+
+        match entry.mode & S_IFMT {
+            S_IFDIR => match parent.create(name, FileType::Directory, entry.mode) {
+                Ok(_) | Err(FsError::AlreadyExists) => {}
+                Err(err) => return Err(err),
+            },
+            S_IFREG => {
+                let file = parent.create(name, FileType::Regular, entry.mode)?;
+                file.write_at(0, entry.data)?;
+            }
+            S_IFLNK => {
+                let target = core::str::from_utf8(entry.data)
+                    .map_err(|_| FsError::Corrupted("symlink target is not UTF-8"))?;
+                parent.symlink(name, target)?;
+            }
+            _ => log::debug!("Skipping special file {} in initramfs", entry.name),
+        }
+    }
+
+    Ok(entries.len())
+}
+
+fn parse(archive: &[u8]) -> FsResult<Vec<CpioEntry<'_>>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let header = archive
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(FsError::Corrupted("truncated cpio header"))?;
+        if &header[..6] != NEWC_MAGIC {
+            return Err(FsError::Unsupported("not a newc cpio archive"));
+        }
+
+        let field = |index: usize| {
+            let raw = &header[6 + index * 8..6 + (index + 1) * 8];
+            core::str::from_utf8(raw)
+                .ok()
+                .and_then(|raw| u32::from_str_radix(raw, 16).ok())
+                .ok_or(FsError::Corrupted("invalid cpio header field"))
+        };
+
+        let mode = field(1)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = offset + HEADER_SIZE;
+        let name = archive
+            .get(name_start..name_start + name_size.saturating_sub(1))
+            .and_then(|raw| core::str::from_utf8(raw).ok())
+            .ok_or(FsError::Corrupted("invalid cpio entry name"))?;
+
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = archive
+            .get(data_start..data_start + file_size)
+            .ok_or(FsError::Corrupted("truncated cpio entry"))?;
+        offset = (data_start + file_size).next_multiple_of(4);
+
+        if name == TRAILER {
+            return Ok(entries);
+        }
+        entries.push(CpioEntry { name, mode, data });
+    }
+}
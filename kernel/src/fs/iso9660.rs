@@ -0,0 +1,226 @@
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use iso9660::{DirectoryRecord, Extent, FormatError, Reader};
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, Metadata};
+use crate::io::block::BlockDevice;
+use crate::mem::{PAGE_CACHE, PageBacking, new_filesystem_id};
+
+impl From<FormatError> for FsError {
+    fn from(err: FormatError) -> Self {
+        match err {
+            FormatError::NotIso9660 => FsError::Unsupported("missing ISO 9660 identifier"),
+            FormatError::Invalid(what) => FsError::Corrupted(what),
+        }
+    }
+}
+
+struct Device(Arc<dyn BlockDevice>);
+
+impl Reader for Device {
+    type Error = FsError;
+
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> FsResult<()> {
+        let block_size = self.0.block_size() as u64;
+        let mut block = vec![0; block_size as usize];
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let (block_id, block_offset) = (position / block_size, position % block_size);
+            let remaining = buffer.len() - done;
+
+            if block_offset == 0 && remaining as u64 >= block_size {
+                let length = remaining - remaining % block_size as usize;
+                self.0
+                    .read_block(block_id, &mut buffer[done..done + length])?;
+                done += length;
+                continue;
+            }
+
+            self.0.read_block(block_id, &mut block)?;
+            let length = (block_size - block_offset).min(remaining as u64) as usize;
+            let start = block_offset as usize;
+            buffer[done..done + length].copy_from_slice(&block[start..start + length]);
+            done += length;
+        }
+
+        Ok(())
+    }
+}
+
+struct Volume {
+    format: iso9660::Volume<Device>,
+    cache_id: u64,
+}
+
+pub struct Iso9660 {
+    volume: Arc<Volume>,
+}
+
+impl Iso9660 {
+    pub fn mount(device: Arc<dyn BlockDevice>) -> FsResult<Arc<Self>> {
+        let format = iso9660::Volume::open(Device(device))?;
+
+        log::info!(
+            "Mounted ISO 9660 volume (joliet: {}, rock ridge: {})",
+            format.joliet(),
+            format.rock_ridge()
+        );
+
+        let volume = Volume {
+            format,
+            cache_id: new_filesystem_id(),
+        };
+        Ok(Arc::new(Self {
+            volume: Arc::new(volume),
+        }))
+    }
+}
+
+impl FileSystem for Iso9660 {
+    fn name(&self) -> &'static str {
+        "iso9660"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(IsoInode {
+            volume: self.volume.clone(),
+            record: self.volume.format.root().clone(),
+        })
+    }
+}
+
+impl Drop for Volume {
+    fn drop(&mut self) {
+        PAGE_CACHE.invalidate_filesystem(self.cache_id);
+    }
+}
+
+// Holds the volume weakly, so cached pages don't keep an unmounted device
+// busy.
+struct IsoFile {
+    volume: Weak<Volume>,
+    extents: Vec<Extent>,
+    size: u64,
+}
+
+impl PageBacking for IsoFile {
+    fn read_page(&self, offset: u64, buffer: &mut [u8]) -> FsResult<()> {
+        let volume = self.volume.upgrade().ok_or(FsError::NotFound)?;
+        buffer.fill(0);
+        volume
+            .format
+            .read_file(&self.extents, self.size, offset, buffer)?;
+        Ok(())
+    }
+}
+
+pub struct IsoInode {
+    volume: Arc<Volume>,
+    record: DirectoryRecord,
+}
+
+impl IsoInode {
+    fn child(&self, record: DirectoryRecord) -> Arc<dyn Inode> {
+        Arc::new(IsoInode {
+            volume: self.volume.clone(),
+            record,
+        })
+    }
+}
+
+impl Inode for IsoInode {
+    fn metadata(&self) -> Metadata {
+        let record = &self.record;
+        let (default_mode, default_links) = match record.directory {
+            true => (0o040555, 2),
+            false => (0o100444, 1),
+        };
+
+        Metadata {
+            inode: record.inode(),
+            file_type: file_type(record),
+            size: record
+                .symlink
+                .as_ref()
+                .map_or(record.size, |link| link.len() as u64),
+            mode: record.posix.map_or(default_mode, |posix| posix.mode),
+            uid: record.posix.map_or(0, |posix| posix.uid),
+            gid: record.posix.map_or(0, |posix| posix.gid),
+            links: record.posix.map_or(default_links, |posix| posix.links),
+            modified: record.modified,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        if !self.record.directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        self.volume
+            .format
+            .lookup(&self.record, name)?
+            .map(|record| self.child(record))
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        if !self.record.directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let entries = self.volume.format.read_directory(&self.record)?;
+        Ok(entries
+            .into_iter()
+            .map(|record| DirEntry {
+                inode: record.inode(),
+                file_type: file_type(&record),
+                name: record.name,
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        if self.record.directory {
+            return Err(FsError::IsADirectory);
+        }
+
+        let length = self.record.size.saturating_sub(offset);
+        let length = length.min(buffer.len() as u64) as usize;
+        if length == 0 {
+            return Ok(0);
+        }
+
+        let backing: Arc<dyn PageBacking> = Arc::new(IsoFile {
+            volume: Arc::downgrade(&self.volume),
+            extents: self.record.extents.clone(),
+            size: self.record.size,
+        });
+
+        let read = PAGE_CACHE.read(
+            self.volume.cache_id,
+            self.record.inode(),
+            offset,
+            &mut buffer[..length],
+            &backing,
+        )?;
+        Ok(read)
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        self.record.symlink.clone().ok_or(FsError::InvalidArgument)
+    }
+}
+
+fn file_type(record: &DirectoryRecord) -> FileType {
+    if record.symlink.is_some() {
+        FileType::Symlink
+    } else if record.directory {
+        FileType::Directory
+    } else {
+        FileType::Regular
+    }
+}
@@ -0,0 +1,192 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use thiserror::Error;
+
+use crate::io::block::BlockDeviceError;
+use crate::mem::PageCacheError;
+
+pub mod devfs;
+pub mod initramfs;
+pub mod iso9660;
+pub mod procfs;
+pub mod tmpfs;
+pub mod trashfs;
+pub mod vfs;
+
+pub use devfs::DevFs;
+pub use procfs::ProcFs;
+pub use tmpfs::TmpFs;
+pub use vfs::{VFS, Vfs};
+
+const TMP_SIZE_LIMIT: u64 = 32 * 1024 * 1024;
+const MEDIA_DIRECTORY: &str = "/media";
+
+#[derive(Error, Debug)]
+pub enum FsError {
+    #[error("No such file or directory")]
+    NotFound,
+    #[error("Not a directory")]
+    NotADirectory,
+    #[error("Is a directory")]
+    IsADirectory,
+    #[error("Read-only filesystem")]
+    ReadOnly,
+    #[error("Invalid path")]
+    InvalidPath,
+    #[error("Invalid argument")]
+    InvalidArgument,
+    #[error("Too many levels of symbolic links")]
+    TooManyLinks,
+    #[error("Mount point is busy")]
+    Busy,
+    #[error("File exists")]
+    AlreadyExists,
+    #[error("Directory not empty")]
+    NotEmpty,
+    #[error("No space left on device")]
+    NoSpace,
+    #[error("Invalid cross-device link")]
+    CrossDevice,
+    #[error("Corrupted filesystem: {0}")]
+    Corrupted(&'static str),
+    #[error("Unsupported filesystem: {0}")]
+    Unsupported(&'static str),
+    #[error("Out of memory")]
+    OutOfMemory,
+    #[error("Device error: {0}")]
+    Device(#[from] BlockDeviceError),
+}
+
+impl From<PageCacheError> for FsError {
+    fn from(error: PageCacheError) -> Self {
+        match error {
+            PageCacheError::FrameAllocationFailed => FsError::OutOfMemory,
+            PageCacheError::Backing(err) => err,
+        }
+    }
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub inode: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub links: u32,
+    pub modified: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+pub trait Inode: Send + Sync + Any {
+    fn metadata(&self) -> Metadata;
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>>;
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>>;
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize>;
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> FsResult<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType, _mode: u32) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(&self, _name: &str, _target: &Arc<dyn Inode>, _new_name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    // Makes completed writes durable. Memory-backed inodes have nothing to do.
+    fn sync(&self) -> FsResult<()> {
+        Ok(())
+    }
+}
+
+pub fn init() {
+    initramfs::init();
+    devfs::init();
+
+    let mounts: [(&str, u32, Arc<dyn FileSystem>); 3] = [
+        ("tmp", 0o1777, TmpFs::with_limit(TMP_SIZE_LIMIT)),
+        ("dev", 0o755, DevFs::new()),
+        ("proc", 0o555, ProcFs::new()),
+    ];
+
+    for (name, mode, filesystem) in mounts {
+        if let Err(err) = mount_directory(name, mode, filesystem) {
+            log::warn!("Failed to mount /{name}: {err}");
+        }
+    }
+}
+
+fn mount_directory(name: &str, mode: u32, filesystem: Arc<dyn FileSystem>) -> FsResult<()> {
+    let mut vfs = VFS.write();
+    match vfs.lookup("/")?.create(name, FileType::Directory, mode) {
+        Ok(_) | Err(FsError::AlreadyExists) => {}
+        Err(err) => return Err(err),
+    }
+    vfs.mount(&format!("/{name}"), filesystem)
+}
+
+// Removable media show up under /media/<device name>.
+pub fn mount_media(name: &str, filesystem: Arc<dyn FileSystem>) -> FsResult<()> {
+    let mut vfs = VFS.write();
+    let media = match vfs.lookup("/")?.create("media", FileType::Directory, 0o755) {
+        Ok(media) => media,
+        Err(FsError::AlreadyExists) => vfs.lookup(MEDIA_DIRECTORY)?,
+        Err(err) => return Err(err),
+    };
+
+    match media.create(name, FileType::Directory, 0o555) {
+        Ok(_) | Err(FsError::AlreadyExists) => {}
+        Err(err) => return Err(err),
+    }
+    vfs.mount(&format!("{MEDIA_DIRECTORY}/{name}"), filesystem)
+}
+
+pub fn unmount_media(name: &str) -> FsResult<()> {
+    VFS.write().unmount(&format!("{MEDIA_DIRECTORY}/{name}"))?;
+    Ok(())
+}
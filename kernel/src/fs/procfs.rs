@@ -0,0 +1,409 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, Metadata};
+use crate::arch::interrupts::{INTERRUPT_COUNTS, InterruptIndex};
+use crate::arch::smp::CPUS;
+use crate::drivers::hpet::HPET;
+use crate::io::manager::{DEVICE_MANAGER, DeviceKind};
+use crate::io::raid::MemberState;
+use crate::mem::FRAME_ALLOCATOR;
+use crate::tasks::process::{Process, ProcessId};
+
+const ROOT_INODE: u64 = 1;
+const FIRST_PROCESS_INODE: u64 = 0x1000;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProcEntry {
+    MemInfo,
+    CpuInfo,
+    Interrupts,
+    Uptime,
+    Partitions,
+    MdStat,
+    Status(ProcessId),
+}
+
+impl ProcEntry {
+    const GLOBAL: [ProcEntry; 6] = [
+        ProcEntry::MemInfo,
+        ProcEntry::CpuInfo,
+        ProcEntry::Interrupts,
+        ProcEntry::Uptime,
+        ProcEntry::Partitions,
+        ProcEntry::MdStat,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            ProcEntry::MemInfo => "meminfo",
+            ProcEntry::CpuInfo => "cpuinfo",
+            ProcEntry::Interrupts => "interrupts",
+            ProcEntry::Uptime => "uptime",
+            ProcEntry::Partitions => "partitions",
+            ProcEntry::MdStat => "mdstat",
+            ProcEntry::Status(_) => "status",
+        }
+    }
+
+    fn inode(&self) -> u64 {
+        match self {
+            ProcEntry::MemInfo => ROOT_INODE + 1,
+            ProcEntry::CpuInfo => ROOT_INODE + 2,
+            ProcEntry::Interrupts => ROOT_INODE + 3,
+            ProcEntry::Uptime => ROOT_INODE + 4,
+            ProcEntry::Partitions => ROOT_INODE + 5,
+            ProcEntry::MdStat => ROOT_INODE + 6,
+            ProcEntry::Status(pid) => process_inode(*pid) + 1,
+        }
+    }
+
+    fn render(&self) -> FsResult<String> {
+        match self {
+            ProcEntry::MemInfo => Ok(render_meminfo()),
+            ProcEntry::CpuInfo => Ok(render_cpuinfo()),
+            ProcEntry::Interrupts => Ok(render_interrupts()),
+            ProcEntry::Uptime => Ok(render_uptime()),
+            ProcEntry::Partitions => Ok(render_partitions()),
+            ProcEntry::MdStat => Ok(render_mdstat()),
+            ProcEntry::Status(pid) => render_status(*pid),
+        }
+    }
+}
+
+// Each process gets a pair of inodes: its directory and its status file.
+fn process_inode(pid: ProcessId) -> u64 {
+    FIRST_PROCESS_INODE + pid.0 * 2
+}
+
+fn find_process(pid: ProcessId) -> Option<Arc<RwLock<Process>>> {
+    interrupts::without_interrupts(|| {
+        Process::all()
+            .into_iter()
+            .find(|process| process.read().id == pid)
+    })
+}
+
+pub struct ProcFs;
+
+impl ProcFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self)
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(ProcRoot)
+    }
+}
+
+fn directory_metadata(inode: u64) -> Metadata {
+    Metadata {
+        inode,
+        file_type: FileType::Directory,
+        size: 0,
+        mode: S_IFDIR | 0o555,
+        uid: 0,
+        gid: 0,
+        links: 2,
+        modified: 0,
+    }
+}
+
+struct ProcRoot;
+
+impl Inode for ProcRoot {
+    fn metadata(&self) -> Metadata {
+        directory_metadata(ROOT_INODE)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        if let Some(entry) = ProcEntry::GLOBAL.iter().find(|entry| entry.name() == name) {
+            return Ok(Arc::new(ProcFile(*entry)));
+        }
+
+        let pid = name.parse().map(ProcessId).map_err(|_| FsError::NotFound)?;
+        find_process(pid).ok_or(FsError::NotFound)?;
+        Ok(Arc::new(ProcDir(pid)))
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        let mut entries = ProcEntry::GLOBAL
+            .iter()
+            .map(|entry| DirEntry {
+                name: entry.name().to_string(),
+                inode: entry.inode(),
+                file_type: FileType::Regular,
+            })
+            .collect::<Vec<_>>();
+
+        let pids = interrupts::without_interrupts(|| {
+            Process::all()
+                .iter()
+                .map(|process| process.read().id)
+                .collect::<Vec<_>>()
+        });
+
+        entries.extend(pids.into_iter().map(|pid| DirEntry {
+            name: pid.0.to_string(),
+            inode: process_inode(pid),
+            file_type: FileType::Directory,
+        }));
+
+        Ok(entries)
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> FsResult<usize> {
+        Err(FsError::IsADirectory)
+    }
+}
+
+struct ProcDir(ProcessId);
+
+impl Inode for ProcDir {
+    fn metadata(&self) -> Metadata {
+        directory_metadata(process_inode(self.0))
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let entry = ProcEntry::Status(self.0);
+        if name != entry.name() {
+            return Err(FsError::NotFound);
+        }
+        Ok(Arc::new(ProcFile(entry)))
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        let entry = ProcEntry::Status(self.0);
+        Ok(Vec::from([DirEntry {
+            name: entry.name().to_string(),
+            inode: entry.inode(),
+            file_type: FileType::Regular,
+        }]))
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> FsResult<usize> {
+        Err(FsError::IsADirectory)
+    }
+}
+
+// Contents are rendered on every read, so sizes are reported as zero.
+struct ProcFile(ProcEntry);
+
+impl Inode for ProcFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.0.inode(),
+            file_type: FileType::Regular,
+            size: 0,
+            mode: S_IFREG | 0o444,
+            uid: 0,
+            gid: 0,
+            links: 1,
+            modified: 0,
+        }
+    }
+
+    fn lookup(&self, _name: &str) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let text = self.0.render()?;
+        let start = (offset as usize).min(text.len());
+        let length = buffer.len().min(text.len() - start);
+        buffer[..length].copy_from_slice(&text.as_bytes()[start..start + length]);
+        Ok(length)
+    }
+}
+
+fn render_meminfo() -> String {
+    let (total_frames, free_frames) = interrupts::without_interrupts(|| {
+        let allocator = FRAME_ALLOCATOR.lock();
+        (allocator.total_frames(), allocator.free_frames())
+    });
+
+    let total = total_frames * 4;
+    let free = free_frames * 4;
+    format!(
+        "MemTotal: {total:>12} kB\nMemFree:  {free:>12} kB\nMemUsed:  {:>12} kB\n",
+        total - free
+    )
+}
+
+fn render_cpuinfo() -> String {
+    let vendor = {
+        let result = __cpuid(0);
+        let bytes = [result.ebx, result.edx, result.ecx].map(u32::to_le_bytes);
+        String::from_utf8_lossy(bytes.as_flattened()).into_owned()
+    };
+
+    let brand = {
+        let bytes = [0x8000_0002, 0x8000_0003, 0x8000_0004]
+            .map(|leaf| {
+                let result = __cpuid(leaf);
+                [result.eax, result.ebx, result.ecx, result.edx].map(u32::to_le_bytes)
+            })
+            .as_flattened()
+            .as_flattened()
+            .to_vec();
+        String::from_utf8_lossy(&bytes)
+            .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+            .to_string()
+    };
+
+    let signature = __cpuid(1).eax;
+    let base_family = (signature >> 8) & 0xf;
+    let base_model = (signature >> 4) & 0xf;
+    let family = match base_family {
+        0xf => base_family + ((signature >> 20) & 0xff),
+        _ => base_family,
+    };
+    let model = match base_family {
+        0x6 | 0xf => base_model | (((signature >> 16) & 0xf) << 4),
+        _ => base_model,
+    };
+
+    // CPUID runs on the reading CPU; the system is assumed to be homogeneous.
+    let mut text = String::new();
+    for (index, lapic_id) in CPUS.read().iter_id().enumerate() {
+        let _ = writeln!(text, "processor\t: {index}");
+        let _ = writeln!(text, "vendor_id\t: {vendor}");
+        let _ = writeln!(text, "cpu family\t: {family}");
+        let _ = writeln!(text, "model\t\t: {model}");
+        let _ = writeln!(text, "model name\t: {brand}");
+        let _ = writeln!(text, "stepping\t: {}", signature & 0xf);
+        let _ = writeln!(text, "apicid\t\t: {lapic_id}\n");
+    }
+    text
+}
+
+fn render_interrupts() -> String {
+    let mut text = String::from("    ");
+    for index in 0..INTERRUPT_COUNTS.len() {
+        let _ = write!(text, " {:>10}", format!("CPU{index}"));
+    }
+    text.push('\n');
+
+    for (slot, index) in InterruptIndex::ALL.iter().enumerate() {
+        let _ = write!(text, "{:>3}:", *index as u8);
+        for counters in INTERRUPT_COUNTS.values() {
+            let _ = write!(text, " {:>10}", counters[slot].load(Ordering::Relaxed));
+        }
+        let _ = writeln!(text, "  {index:?}");
+    }
+    text
+}
+
+fn render_uptime() -> String {
+    let elapsed = HPET.elapsed();
+    format!(
+        "{}.{:02}\n",
+        elapsed.as_secs(),
+        elapsed.subsec_millis() / 10
+    )
+}
+
+fn render_partitions() -> String {
+    let mut text = String::from("#blocks name\n\n");
+    for info in DEVICE_MANAGER.read().iter() {
+        let size = info.device.block_count() * info.device.block_size() as u64;
+        let _ = writeln!(text, "{:>7} {}", size / 1024, info.name);
+    }
+    text
+}
+
+// Faulty members are flagged (F) and resyncing ones (R); mirrors also get a
+// [UU_] style map of their members like Linux prints.
+fn render_mdstat() -> String {
+    let manager = DEVICE_MANAGER.read();
+    let mut text = String::new();
+
+    for info in manager.iter() {
+        let DeviceKind::Raid { level, members } = &info.kind else {
+            continue;
+        };
+        let states = manager.mirror(info.id).map(|mirror| mirror.member_states());
+
+        let _ = write!(text, "{} : {level}", info.name);
+        for (index, id) in members.iter().enumerate() {
+            let name = manager.get(*id).map_or("?", |member| member.name.as_str());
+            let flag = match states.as_ref().ok().map(|states| states[index]) {
+                Some(MemberState::Faulty) => "(F)",
+                Some(MemberState::Syncing) => "(R)",
+                _ => "",
+            };
+            let _ = write!(text, " {name}[{index}]{flag}");
+        }
+
+        let _ = write!(text, "\n      {} blocks", info.device.block_count());
+        if let Ok(states) = states {
+            let active = states.iter().filter(|state| **state == MemberState::Active);
+            let map = states
+                .iter()
+                .map(|state| {
+                    if *state == MemberState::Active {
+                        'U'
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>();
+            let _ = write!(text, " [{}/{}] [{map}]", states.len(), active.count());
+        }
+        text.push('\n');
+    }
+    text
+}
+
+fn render_status(pid: ProcessId) -> FsResult<String> {
+    let process = find_process(pid).ok_or(FsError::NotFound)?;
+
+    Ok(interrupts::without_interrupts(|| {
+        let process = process.read();
+        let threads = process
+            .threads
+            .iter()
+            .map(|thread| {
+                let thread = thread.read();
+                (thread.id.0, thread.sleeping)
+            })
+            .collect::<Vec<_>>();
+
+        let state = if threads.iter().all(|(_, sleeping)| *sleeping) {
+            "S (sleeping)"
+        } else {
+            "R (running)"
+        };
+
+        let mut text = String::new();
+        let _ = writeln!(text, "Name:\t{}", process.name);
+        let _ = writeln!(text, "Pid:\t{}", process.id.0);
+        let _ = writeln!(text, "State:\t{state}");
+        let _ = writeln!(text, "Threads:\t{}", threads.len());
+        for (id, sleeping) in threads {
+            let state = if sleeping { "sleeping" } else { "running" };
+            let _ = writeln!(text, "Thread {id}:\t{state}");
+        }
+        text
+    }))
+}
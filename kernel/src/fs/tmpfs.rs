@@ -0,0 +1,441 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+use x86_64::structures::paging::{PageSize, PhysFrame, Size4KiB};
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, Metadata};
+use crate::mem::{FRAME_ALLOCATOR, ensure_free_frames, frame_data};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+struct Superblock {
+    next_inode: AtomicU64,
+    used_pages: AtomicUsize,
+    max_pages: usize,
+    rename_lock: Mutex<()>,
+}
+
+impl Superblock {
+    fn allocate_page(&self) -> FsResult<PhysFrame> {
+        self.used_pages
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used < self.max_pages).then_some(used + 1)
+            })
+            .map_err(|_| FsError::NoSpace)?;
+
+        ensure_free_frames(1);
+        let Some(frame) = FRAME_ALLOCATOR.lock().allocate_frames(1) else {
+            self.used_pages.fetch_sub(1, Ordering::AcqRel);
+            return Err(FsError::NoSpace);
+        };
+
+        frame_data(frame).fill(0);
+        Ok(frame)
+    }
+
+    fn free_pages(&self, frames: impl Iterator<Item = PhysFrame>) {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        for frame in frames {
+            frame_allocator.deallocate_frames(frame, 1);
+            self.used_pages.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+#[derive(Default)]
+struct FileData {
+    size: u64,
+    pages: BTreeMap<u64, PhysFrame>,
+}
+
+enum Node {
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+    Regular(FileData),
+    Symlink(String),
+}
+
+impl Node {
+    fn not_regular(&self) -> FsError {
+        match self {
+            Node::Directory(_) => FsError::IsADirectory,
+            _ => FsError::InvalidArgument,
+        }
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        matches!(self, Node::Directory(children) if children.is_empty())
+    }
+}
+
+pub struct TmpFs {
+    superblock: Arc<Superblock>,
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    pub fn new() -> Arc<Self> {
+        Self::with_max_pages(usize::MAX)
+    }
+
+    pub fn with_limit(max_bytes: u64) -> Arc<Self> {
+        Self::with_max_pages((max_bytes / PAGE_SIZE) as usize)
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.superblock.used_pages.load(Ordering::Acquire) as u64 * PAGE_SIZE
+    }
+
+    fn with_max_pages(max_pages: usize) -> Arc<Self> {
+        let superblock = Arc::new(Superblock {
+            next_inode: AtomicU64::new(1),
+            used_pages: AtomicUsize::new(0),
+            max_pages,
+            rename_lock: Mutex::new(()),
+        });
+
+        let root = TmpInode::new(
+            &superblock,
+            Node::Directory(BTreeMap::new()),
+            FileType::Directory,
+            0o755,
+        );
+        Arc::new(Self { superblock, root })
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+// The file type never changes, so it is kept outside the lock and directory
+// listings never have to lock their children.
+pub struct TmpInode {
+    superblock: Arc<Superblock>,
+    inode: u64,
+    file_type: FileType,
+    mode: u32,
+    links: AtomicU32,
+    node: RwLock<Node>,
+}
+
+impl TmpInode {
+    fn new(superblock: &Arc<Superblock>, node: Node, file_type: FileType, mode: u32) -> Arc<Self> {
+        Arc::new(Self {
+            superblock: superblock.clone(),
+            inode: superblock.next_inode.fetch_add(1, Ordering::Relaxed),
+            file_type,
+            mode: mode & 0o7777,
+            links: AtomicU32::new(1),
+            node: RwLock::new(node),
+        })
+    }
+
+    fn insert(
+        &self,
+        name: &str,
+        node: Node,
+        file_type: FileType,
+        mode: u32,
+    ) -> FsResult<Arc<dyn Inode>> {
+        check_name(name)?;
+
+        let mut current = self.node.write();
+        let Node::Directory(children) = &mut *current else {
+            return Err(FsError::NotADirectory);
+        };
+
+        if children.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let inode = TmpInode::new(&self.superblock, node, file_type, mode);
+        children.insert(name.to_string(), inode.clone());
+        Ok(inode)
+    }
+
+    fn child(&self, name: &str) -> FsResult<Arc<TmpInode>> {
+        match &*self.node.read() {
+            Node::Directory(children) => children.get(name).cloned().ok_or(FsError::NotFound),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn contains(&self, inode: u64) -> bool {
+        let children = match &*self.node.read() {
+            Node::Directory(children) => children.values().cloned().collect::<Vec<_>>(),
+            _ => return false,
+        };
+
+        children
+            .iter()
+            .any(|child| child.inode == inode || child.contains(inode))
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Metadata {
+        let (format, size) = match &*self.node.read() {
+            Node::Directory(children) => (S_IFDIR, children.len() as u64),
+            Node::Regular(data) => (S_IFREG, data.size),
+            Node::Symlink(target) => (S_IFLNK, target.len() as u64),
+        };
+
+        let links = self.links.load(Ordering::Acquire);
+        Metadata {
+            inode: self.inode,
+            file_type: self.file_type,
+            size,
+            mode: format | self.mode,
+            uid: 0,
+            gid: 0,
+            links: match self.file_type {
+                FileType::Directory if links > 0 => 2,
+                _ => links,
+            },
+            modified: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        self.child(name).map(|inode| inode as Arc<dyn Inode>)
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        let node = self.node.read();
+        let Node::Directory(children) = &*node else {
+            return Err(FsError::NotADirectory);
+        };
+
+        Ok(children
+            .iter()
+            .map(|(name, inode)| DirEntry {
+                name: name.clone(),
+                inode: inode.inode,
+                file_type: inode.file_type,
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let node = self.node.read();
+        let data = match &*node {
+            Node::Regular(data) => data,
+            node => return Err(node.not_regular()),
+        };
+
+        let end = offset.saturating_add(buffer.len() as u64).min(data.size);
+        let mut position = offset;
+
+        while position < end {
+            let page_offset = (position % PAGE_SIZE) as usize;
+            let length = (PAGE_SIZE - position % PAGE_SIZE).min(end - position) as usize;
+            let done = (position - offset) as usize;
+            let target = &mut buffer[done..done + length];
+
+            match data.pages.get(&(position / PAGE_SIZE)) {
+                Some(&frame) => {
+                    target.copy_from_slice(&frame_data(frame)[page_offset..page_offset + length])
+                }
+                None => target.fill(0),
+            }
+            position += length as u64;
+        }
+
+        Ok(end.saturating_sub(offset) as usize)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> FsResult<usize> {
+        let mut node = self.node.write();
+        let data = match &mut *node {
+            Node::Regular(data) => data,
+            node => return Err(node.not_regular()),
+        };
+
+        let end = offset
+            .checked_add(buffer.len() as u64)
+            .ok_or(FsError::InvalidArgument)?;
+        let mut position = offset;
+
+        while position < end {
+            let index = position / PAGE_SIZE;
+            let frame = match data.pages.get(&index) {
+                Some(&frame) => frame,
+                None => match self.superblock.allocate_page() {
+                    Ok(frame) => *data.pages.entry(index).or_insert(frame),
+                    Err(_) if position > offset => break,
+                    Err(err) => return Err(err),
+                },
+            };
+
+            let page_offset = (position % PAGE_SIZE) as usize;
+            let length = (PAGE_SIZE - position % PAGE_SIZE).min(end - position) as usize;
+            let done = (position - offset) as usize;
+            frame_data(frame)[page_offset..page_offset + length]
+                .copy_from_slice(&buffer[done..done + length]);
+
+            position += length as u64;
+            data.size = data.size.max(position);
+        }
+
+        Ok((position - offset) as usize)
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        match &*self.node.read() {
+            Node::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType, mode: u32) -> FsResult<Arc<dyn Inode>> {
+        let node = match file_type {
+            FileType::Directory => Node::Directory(BTreeMap::new()),
+            FileType::Regular => Node::Regular(FileData::default()),
+            _ => return Err(FsError::InvalidArgument),
+        };
+        self.insert(name, node, file_type, mode)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> FsResult<Arc<dyn Inode>> {
+        let node = Node::Symlink(target.to_string());
+        self.insert(name, node, FileType::Symlink, 0o777)
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        let mut node = self.node.write();
+        let data = match &mut *node {
+            Node::Regular(data) => data,
+            node => return Err(node.not_regular()),
+        };
+
+        if size < data.size {
+            let removed = data.pages.split_off(&size.div_ceil(PAGE_SIZE));
+            self.superblock.free_pages(removed.into_values());
+
+            if let Some(&frame) = data.pages.get(&(size / PAGE_SIZE)) {
+                frame_data(frame)[(size % PAGE_SIZE) as usize..].fill(0);
+            }
+        }
+
+        data.size = size;
+        Ok(())
+    }
+
+    // Open handles keep their Arc, so an unlinked file stays readable until
+    // the last one is dropped.
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        let mut node = self.node.write();
+        let Node::Directory(children) = &mut *node else {
+            return Err(FsError::NotADirectory);
+        };
+
+        let child = children.get(name).ok_or(FsError::NotFound)?;
+        if child.file_type == FileType::Directory && !child.node.read().is_empty_directory() {
+            return Err(FsError::NotEmpty);
+        }
+
+        let child = children.remove(name).unwrap();
+        child.links.store(0, Ordering::Release);
+        Ok(())
+    }
+
+    fn rename(&self, name: &str, target: &Arc<dyn Inode>, new_name: &str) -> FsResult<()> {
+        let target = (target.as_ref() as &dyn Any)
+            .downcast_ref::<TmpInode>()
+            .filter(|target| Arc::ptr_eq(&self.superblock, &target.superblock))
+            .ok_or(FsError::CrossDevice)?;
+        check_name(new_name)?;
+
+        // Only one rename runs at a time, which makes locking two directories
+        // safe and keeps the subtree check below stable.
+        let _guard = self.superblock.rename_lock.lock();
+
+        let inode = self.child(name)?;
+        if inode.file_type == FileType::Directory
+            && (inode.inode == target.inode || inode.contains(target.inode))
+        {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let mut source = self.node.write();
+        let still_linked = matches!(&*source, Node::Directory(children)
+            if children.get(name).is_some_and(|child| Arc::ptr_eq(child, &inode)));
+        if !still_linked {
+            return Err(FsError::NotFound);
+        }
+
+        let mut destination = match core::ptr::eq(self, target) {
+            true => None,
+            false => Some(target.node.write()),
+        };
+
+        let destination_children = match destination.as_deref_mut().unwrap_or(&mut *source) {
+            Node::Directory(children) => children,
+            _ => return Err(FsError::NotADirectory),
+        };
+
+        if let Some(existing) = destination_children.get(new_name) {
+            if Arc::ptr_eq(existing, &inode) {
+                return Ok(());
+            }
+            // Replacing the source directory itself, which is never empty.
+            if core::ptr::eq(existing.as_ref(), self) {
+                return Err(FsError::NotEmpty);
+            }
+            check_replace(&inode, existing)?;
+        }
+
+        if let Some(replaced) = destination_children.insert(new_name.to_string(), inode) {
+            replaced.links.store(0, Ordering::Release);
+        }
+
+        if let Node::Directory(source_children) = &mut *source {
+            source_children.remove(name);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        if let Node::Regular(data) = self.node.get_mut() {
+            let pages = core::mem::take(&mut data.pages);
+            self.superblock.free_pages(pages.into_values());
+        }
+    }
+}
+
+fn check_name(name: &str) -> FsResult<()> {
+    match name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        true => Err(FsError::InvalidPath),
+        false => Ok(()),
+    }
+}
+
+fn check_replace(inode: &TmpInode, existing: &TmpInode) -> FsResult<()> {
+    match (inode.file_type, existing.file_type) {
+        (FileType::Directory, FileType::Directory) => {
+            match existing.node.read().is_empty_directory() {
+                true => Ok(()),
+                false => Err(FsError::NotEmpty),
+            }
+        }
+        (FileType::Directory, _) => Err(FsError::NotADirectory),
+        (_, FileType::Directory) => Err(FsError::IsADirectory),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,202 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use trashfs::btree::{MAX_DEPTH, name_hash, split_leaf};
+use trashfs::{Child, DirRecord, Inode, Node};
+
+use super::volume::{Transaction, Volume, format_error};
+use crate::fs::{FsError, FsResult};
+
+impl Volume {
+    fn read_node(&self, transaction: &Transaction, owner: u64, block: u64) -> FsResult<Node> {
+        let data = self.read(transaction, block)?;
+        Node::decode(owner, &data).map_err(format_error)
+    }
+
+    fn write_node(
+        &self,
+        transaction: &mut Transaction,
+        owner: u64,
+        block: u64,
+        node: &Node,
+    ) -> FsResult<()> {
+        let data = node.encode(owner).map_err(format_error)?;
+        self.write(transaction, block, data);
+        Ok(())
+    }
+
+    pub fn create_tree(&mut self, transaction: &mut Transaction, owner: u64) -> FsResult<u64> {
+        let (block, _) = self.allocate_blocks(transaction, 0, 1)?;
+        self.write_node(transaction, owner, block, &Node::Leaf(Vec::new()))?;
+        Ok(block)
+    }
+
+    pub fn find_entry(
+        &self,
+        transaction: &Transaction,
+        owner: u64,
+        directory: &Inode,
+        name: &str,
+    ) -> FsResult<Option<DirRecord>> {
+        let hash = name_hash(name);
+        let mut block = directory.tree_root;
+
+        loop {
+            match self.read_node(transaction, owner, block)? {
+                Node::Leaf(records) => {
+                    return Ok(records.into_iter().find(|record| record.name == name));
+                }
+                Node::Internal { children, .. } => {
+                    block = children[Node::child_index(&children, hash)].block;
+                }
+            }
+        }
+    }
+
+    pub fn entries(
+        &self,
+        transaction: &Transaction,
+        owner: u64,
+        directory: &Inode,
+    ) -> FsResult<Vec<DirRecord>> {
+        let mut entries = Vec::new();
+        let mut pending = vec![directory.tree_root];
+
+        while let Some(block) = pending.pop() {
+            match self.read_node(transaction, owner, block)? {
+                Node::Leaf(records) => entries.extend(records),
+                Node::Internal { children, .. } => {
+                    pending.extend(children.iter().rev().map(|child| child.block));
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn insert_entry(
+        &mut self,
+        transaction: &mut Transaction,
+        owner: u64,
+        directory: &mut Inode,
+        record: DirRecord,
+    ) -> FsResult<()> {
+        let root = directory.tree_root;
+        if let Some(sibling) = self.insert_into(transaction, owner, root, record)? {
+            let level = self.read_node(transaction, owner, root)?.level() + 1;
+            if level > MAX_DEPTH {
+                return Err(FsError::NoSpace);
+            }
+
+            let children = vec![
+                Child {
+                    key: 0,
+                    block: root,
+                },
+                sibling,
+            ];
+            let (new_root, _) = self.allocate_blocks(transaction, root, 1)?;
+            let node = Node::Internal { level, children };
+            self.write_node(transaction, owner, new_root, &node)?;
+            directory.tree_root = new_root;
+        }
+
+        directory.size += 1;
+        Ok(())
+    }
+
+    // Returns the new right sibling when the node at `block` had to split.
+    fn insert_into(
+        &mut self,
+        transaction: &mut Transaction,
+        owner: u64,
+        block: u64,
+        record: DirRecord,
+    ) -> FsResult<Option<Child>> {
+        let mut node = self.read_node(transaction, owner, block)?;
+        match &mut node {
+            Node::Leaf(records) => {
+                let index = records
+                    .binary_search_by(|existing| existing.key().cmp(&record.key()))
+                    .err()
+                    .ok_or(FsError::AlreadyExists)?;
+                records.insert(index, record);
+            }
+            Node::Internal { children, .. } => {
+                let index = Node::child_index(children, record.hash);
+                let child = children[index].block;
+                if let Some(sibling) = self.insert_into(transaction, owner, child, record)? {
+                    children.insert(index + 1, sibling);
+                }
+            }
+        }
+
+        if node.fits() {
+            self.write_node(transaction, owner, block, &node)?;
+            return Ok(None);
+        }
+
+        let right = match &mut node {
+            Node::Leaf(records) => Node::Leaf(split_leaf(records).ok_or(FsError::NoSpace)?),
+            Node::Internal { level, children } => Node::Internal {
+                level: *level,
+                children: children.split_off(children.len() / 2),
+            },
+        };
+
+        let key = right.min_key().unwrap_or_default();
+        let (sibling, _) = self.allocate_blocks(transaction, block, 1)?;
+        self.write_node(transaction, owner, block, &node)?;
+        self.write_node(transaction, owner, sibling, &right)?;
+        Ok(Some(Child {
+            key,
+            block: sibling,
+        }))
+    }
+
+    // Leaves are allowed to become empty; the tree is never rebalanced.
+    pub fn remove_entry(
+        &mut self,
+        transaction: &mut Transaction,
+        owner: u64,
+        directory: &mut Inode,
+        name: &str,
+    ) -> FsResult<DirRecord> {
+        let hash = name_hash(name);
+        let mut block = directory.tree_root;
+
+        let (mut records, block) = loop {
+            match self.read_node(transaction, owner, block)? {
+                Node::Leaf(records) => break (records, block),
+                Node::Internal { children, .. } => {
+                    block = children[Node::child_index(&children, hash)].block;
+                }
+            }
+        };
+
+        let index = records
+            .iter()
+            .position(|record| record.name == name)
+            .ok_or(FsError::NotFound)?;
+        let record = records.remove(index);
+
+        self.write_node(transaction, owner, block, &Node::Leaf(records))?;
+        directory.size -= 1;
+        Ok(record)
+    }
+
+    pub fn free_tree(
+        &mut self,
+        transaction: &mut Transaction,
+        owner: u64,
+        directory: &Inode,
+    ) -> FsResult<()> {
+        let mut pending = vec![directory.tree_root];
+        while let Some(block) = pending.pop() {
+            if let Node::Internal { children, .. } = self.read_node(transaction, owner, block)? {
+                pending.extend(children.iter().map(|child| child.block));
+            }
+            self.free_blocks(transaction, block, 1);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use trashfs::{BLOCK_SIZE, Extent, Inode};
+
+use super::volume::{Transaction, Volume};
+use crate::fs::FsResult;
+
+const BLOCK_BYTES: u64 = BLOCK_SIZE as u64;
+
+fn map_block(extents: &[Extent], logical: u64) -> Option<u64> {
+    extents.iter().find_map(|extent| extent.map(logical))
+}
+
+// Number of unmapped blocks from `logical` up to the next extent.
+fn hole_length(extents: &[Extent], logical: u64) -> u64 {
+    extents
+        .iter()
+        .map(|extent| extent.logical)
+        .find(|&start| start > logical)
+        .map_or(u64::MAX, |start| start - logical)
+}
+
+fn insert_extent(extents: &mut Vec<Extent>, extent: Extent) {
+    let index = extents.partition_point(|existing| existing.logical < extent.logical);
+    if let Some(previous) = index.checked_sub(1).map(|index| &mut extents[index]) {
+        let contiguous = previous.end() == extent.logical
+            && previous.start + previous.length as u64 == extent.start;
+        if contiguous && previous.length.checked_add(extent.length).is_some() {
+            previous.length += extent.length;
+            return;
+        }
+    }
+    extents.insert(index, extent);
+}
+
+impl Volume {
+    pub fn read_file(
+        &self,
+        inode: &Inode,
+        extents: &[Extent],
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> FsResult<usize> {
+        let end = inode.size.min(offset + buffer.len() as u64);
+        let mut position = offset;
+        let mut block = vec![0; BLOCK_SIZE];
+
+        while position < end {
+            let within = (position % BLOCK_BYTES) as usize;
+            let length = (BLOCK_SIZE - within).min((end - position) as usize);
+            let done = (position - offset) as usize;
+            let target = &mut buffer[done..done + length];
+
+            match map_block(extents, position / BLOCK_BYTES) {
+                Some(physical) if length == BLOCK_SIZE => self.read_raw(physical, target)?,
+                Some(physical) => {
+                    self.read_raw(physical, &mut block)?;
+                    target.copy_from_slice(&block[within..within + length]);
+                }
+                None => target.fill(0),
+            }
+            position += length as u64;
+        }
+
+        Ok(position.saturating_sub(offset) as usize)
+    }
+
+    // File data is written in place right away; only the allocation and the
+    // inode update go through the transaction.
+    pub fn write_file(
+        &mut self,
+        transaction: &mut Transaction,
+        number: u64,
+        inode: &mut Inode,
+        offset: u64,
+        data: &[u8],
+    ) -> FsResult<usize> {
+        let mut extents = self.extents(transaction, number, inode)?;
+        let end = offset + data.len() as u64;
+        let mut position = offset;
+        let mut block = vec![0; BLOCK_SIZE];
+        // Logical blocks allocated by this write, which still hold whatever
+        // was on disk before.
+        let mut allocated = Vec::new();
+
+        while position < end {
+            let logical = position / BLOCK_BYTES;
+            let physical = match map_block(&extents, logical) {
+                Some(physical) => physical,
+                None => {
+                    let wanted =
+                        (end.div_ceil(BLOCK_BYTES) - logical).min(hole_length(&extents, logical));
+                    let goal = extents
+                        .iter()
+                        .rev()
+                        .find(|extent| extent.logical < logical)
+                        .map_or(0, |extent| extent.start + extent.length as u64);
+                    let (start, length) =
+                        self.allocate_blocks(transaction, goal, wanted.min(u32::MAX as u64))?;
+                    let extent = Extent {
+                        logical,
+                        start,
+                        length: length as u32,
+                    };
+                    insert_extent(&mut extents, extent);
+                    allocated.push(logical..logical + length);
+                    start
+                }
+            };
+            let fresh = allocated.iter().any(|range| range.contains(&logical));
+
+            let within = (position % BLOCK_BYTES) as usize;
+            let length = (BLOCK_SIZE - within).min((end - position) as usize);
+            let done = (position - offset) as usize;
+
+            if length == BLOCK_SIZE {
+                self.write_raw(physical, &data[done..done + length])?;
+            } else {
+                match fresh {
+                    true => block.fill(0),
+                    false => self.read_raw(physical, &mut block)?,
+                }
+                block[within..within + length].copy_from_slice(&data[done..done + length]);
+                self.write_raw(physical, &block)?;
+            }
+            position += length as u64;
+        }
+
+        inode.size = inode.size.max(end);
+        self.set_extents(transaction, number, inode, &extents)?;
+        Ok(data.len())
+    }
+
+    pub fn truncate_file(
+        &mut self,
+        transaction: &mut Transaction,
+        number: u64,
+        inode: &mut Inode,
+        size: u64,
+    ) -> FsResult<()> {
+        if size >= inode.size {
+            // Blocks past the old end are holes, but the old last block may
+            // still carry stale bytes after it.
+            let extents = self.extents(transaction, number, inode)?;
+            self.zero_tail(&extents, inode.size)?;
+            inode.size = size;
+            return Ok(());
+        }
+
+        let kept_blocks = size.div_ceil(BLOCK_BYTES);
+        let mut extents = self.extents(transaction, number, inode)?;
+        let mut freed = Vec::new();
+
+        extents.retain_mut(|extent| {
+            if extent.logical >= kept_blocks {
+                freed.push((extent.start, extent.length as u64));
+                return false;
+            }
+            if extent.end() > kept_blocks {
+                let kept = (kept_blocks - extent.logical) as u32;
+                freed.push((extent.start + kept as u64, (extent.length - kept) as u64));
+                extent.length = kept;
+            }
+            true
+        });
+
+        for (start, count) in freed {
+            self.free_blocks(transaction, start, count);
+        }
+
+        self.zero_tail(&extents, size)?;
+        inode.size = size;
+        self.set_extents(transaction, number, inode, &extents)
+    }
+
+    // Bytes past the end of the file must read back as zeroes if the file
+    // grows, so clear the tail of the last partial block.
+    fn zero_tail(&self, extents: &[Extent], size: u64) -> FsResult<()> {
+        if size.is_multiple_of(BLOCK_BYTES) {
+            return Ok(());
+        }
+
+        if let Some(physical) = map_block(extents, size / BLOCK_BYTES) {
+            let mut block = vec![0; BLOCK_SIZE];
+            self.read_raw(physical, &mut block)?;
+            block[(size % BLOCK_BYTES) as usize..].fill(0);
+            self.write_raw(physical, &block)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,582 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use trashfs::inode::INODE_SIZE;
+use trashfs::{BLOCK_SIZE, DirRecord, FileKind, Inode as DiskInode, NAME_MAX, ROOT_INODE};
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, Metadata};
+use crate::drivers::rtc::RtcDateTime;
+use crate::io::block::BlockDevice;
+use crate::mem::{PAGE_CACHE, PageBacking, new_filesystem_id};
+use volume::{Transaction, Volume};
+
+mod directory;
+mod file;
+mod volume;
+
+struct Shared {
+    volume: Mutex<Volume>,
+    cache_id: u64,
+    // Inodes handed out to the VFS, so an unlinked inode can stay alive until
+    // its last user is gone.
+    inodes: Mutex<BTreeMap<u64, Weak<TrashInode>>>,
+}
+
+impl Shared {
+    // Runs `operation` as one journaled transaction, rolling back allocations
+    // when it fails.
+    fn transaction<T>(
+        &self,
+        operation: impl FnOnce(&mut Volume, &mut Transaction) -> FsResult<T>,
+    ) -> FsResult<T> {
+        let mut volume = self.volume.lock();
+        let mut transaction = Transaction::default();
+
+        match operation(&mut volume, &mut transaction) {
+            Ok(value) => volume.commit(transaction).map(|_| value),
+            Err(err) => {
+                volume.abort(transaction);
+                Err(err)
+            }
+        }
+    }
+
+    fn inode(self: &Arc<Self>, number: u64) -> Arc<TrashInode> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&number).and_then(Weak::upgrade) {
+            return inode;
+        }
+
+        let inode = Arc::new(TrashInode {
+            shared: self.clone(),
+            number,
+            orphan: AtomicBool::new(false),
+        });
+        inodes.insert(number, Arc::downgrade(&inode));
+        inode
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        PAGE_CACHE.invalidate_filesystem(self.cache_id);
+    }
+}
+
+// Writes go through the journal and patch cached pages in place, so pages
+// of this backing are never dirty.
+struct FileBacking {
+    shared: Weak<Shared>,
+    number: u64,
+}
+
+impl PageBacking for FileBacking {
+    fn read_page(&self, offset: u64, buffer: &mut [u8]) -> FsResult<()> {
+        let shared = self.shared.upgrade().ok_or(FsError::NotFound)?;
+        let volume = shared.volume.lock();
+        let transaction = Transaction::default();
+
+        let inode = volume.load_inode(&transaction, self.number)?;
+        let extents = volume.extents(&transaction, self.number, &inode)?;
+        buffer.fill(0);
+        volume.read_file(&inode, &extents, offset, buffer)?;
+        Ok(())
+    }
+}
+
+pub struct TrashFs {
+    shared: Arc<Shared>,
+}
+
+impl TrashFs {
+    pub fn mount(device: Arc<dyn BlockDevice>) -> FsResult<Arc<Self>> {
+        let volume = Volume::open(device)?;
+        let superblock = &volume.superblock;
+        log::info!(
+            "Mounted TrashFS volume \"{}\" ({} of {} blocks free)",
+            superblock.label(),
+            superblock.free_blocks,
+            superblock.block_count
+        );
+
+        let shared = Arc::new(Shared {
+            volume: Mutex::new(volume),
+            cache_id: new_filesystem_id(),
+            inodes: Mutex::new(BTreeMap::new()),
+        });
+
+        let orphans = find_orphans(&shared.volume.lock())?;
+        if !orphans.is_empty() {
+            log::info!("Freeing {} orphaned TrashFS inodes", orphans.len());
+        }
+        // One transaction per inode keeps each of them within the journal.
+        for number in orphans {
+            shared.transaction(|volume, transaction| release_inode(volume, transaction, number))?;
+        }
+
+        Ok(Arc::new(Self { shared }))
+    }
+}
+
+impl FileSystem for TrashFs {
+    fn name(&self) -> &'static str {
+        "trashfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.shared.inode(ROOT_INODE)
+    }
+}
+
+pub struct TrashInode {
+    shared: Arc<Shared>,
+    number: u64,
+    orphan: AtomicBool,
+}
+
+impl TrashInode {
+    fn load(&self) -> FsResult<DiskInode> {
+        let volume = self.shared.volume.lock();
+        volume.load_inode(&Transaction::default(), self.number)
+    }
+
+    // Drops the directory entry's reference on `number`. The returned handle
+    // must only be dropped after the volume lock is released, since the last
+    // handle of an unlinked inode frees it.
+    fn unlink_inode(
+        &self,
+        volume: &mut Volume,
+        transaction: &mut Transaction,
+        number: u64,
+    ) -> FsResult<Arc<TrashInode>> {
+        let mut inode = volume.load_inode(transaction, number)?;
+        inode.links = match inode.kind() {
+            Some(FileKind::Directory) => 0,
+            _ => inode.links.saturating_sub(1),
+        };
+        volume.store_inode(transaction, number, &inode)?;
+
+        let handle = self.shared.inode(number);
+        handle.orphan.store(inode.links == 0, Ordering::Release);
+        Ok(handle)
+    }
+
+    fn create_child(
+        &self,
+        name: &str,
+        kind: FileKind,
+        mode: u32,
+        data: &[u8],
+    ) -> FsResult<Arc<dyn Inode>> {
+        check_name(name)?;
+
+        let number = self.shared.transaction(|volume, transaction| {
+            let mut directory = load_directory(volume, transaction, self.number)?;
+            if volume
+                .find_entry(transaction, self.number, &directory, name)?
+                .is_some()
+            {
+                return Err(FsError::AlreadyExists);
+            }
+
+            let number = volume.allocate_inode(transaction)?;
+            let mut inode = DiskInode::new(kind, mode);
+            inode.modified = now();
+
+            if kind == FileKind::Directory {
+                inode.links = 2;
+                inode.parent = self.number;
+                inode.tree_root = volume.create_tree(transaction, number)?;
+                directory.links += 1;
+            }
+            if !data.is_empty() {
+                volume.write_file(transaction, number, &mut inode, 0, data)?;
+            }
+
+            let record = DirRecord::new(name, number, kind);
+            volume.insert_entry(transaction, self.number, &mut directory, record)?;
+            directory.modified = inode.modified;
+
+            volume.store_inode(transaction, number, &inode)?;
+            volume.store_inode(transaction, self.number, &directory)?;
+            Ok(number)
+        })?;
+
+        Ok(self.shared.inode(number))
+    }
+}
+
+impl Inode for TrashInode {
+    fn metadata(&self) -> Metadata {
+        let inode = self.load().unwrap_or_default();
+        Metadata {
+            inode: self.number,
+            file_type: inode.kind().map_or(FileType::Regular, file_type),
+            size: inode.size,
+            mode: inode.mode,
+            uid: inode.uid,
+            gid: inode.gid,
+            links: inode.links,
+            modified: inode.modified,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let volume = self.shared.volume.lock();
+        let transaction = Transaction::default();
+        let directory = load_directory(&volume, &transaction, self.number)?;
+
+        let record = volume
+            .find_entry(&transaction, self.number, &directory, name)?
+            .ok_or(FsError::NotFound)?;
+        Ok(self.shared.inode(record.inode))
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        let volume = self.shared.volume.lock();
+        let transaction = Transaction::default();
+        let directory = load_directory(&volume, &transaction, self.number)?;
+
+        let records = volume.entries(&transaction, self.number, &directory)?;
+        Ok(records
+            .into_iter()
+            .map(|record| DirEntry {
+                name: record.name,
+                inode: record.inode,
+                file_type: file_type(record.kind),
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let inode = self.load()?;
+        if inode.kind() == Some(FileKind::Directory) {
+            return Err(FsError::IsADirectory);
+        }
+
+        let length = inode.size.saturating_sub(offset);
+        let length = length.min(buffer.len() as u64) as usize;
+        if length == 0 {
+            return Ok(0);
+        }
+
+        let backing: Arc<dyn PageBacking> = Arc::new(FileBacking {
+            shared: Arc::downgrade(&self.shared),
+            number: self.number,
+        });
+
+        let read = PAGE_CACHE.read(
+            self.shared.cache_id,
+            self.number,
+            offset,
+            &mut buffer[..length],
+            &backing,
+        )?;
+        Ok(read)
+    }
+
+    // Cached pages are patched only after the transaction committed and the
+    // volume lock is released, since page cache misses take that lock too.
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> FsResult<usize> {
+        let written = self.shared.transaction(|volume, transaction| {
+            let mut inode = load_regular(volume, transaction, self.number)?;
+            let written =
+                volume.write_file(transaction, self.number, &mut inode, offset, buffer)?;
+            inode.modified = now();
+            volume.store_inode(transaction, self.number, &inode)?;
+            Ok(written)
+        })?;
+
+        PAGE_CACHE.update(
+            self.shared.cache_id,
+            self.number,
+            offset,
+            &buffer[..written],
+        );
+        Ok(written)
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        let volume = self.shared.volume.lock();
+        let transaction = Transaction::default();
+        let inode = volume.load_inode(&transaction, self.number)?;
+        if inode.kind() != Some(FileKind::Symlink) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let extents = volume.extents(&transaction, self.number, &inode)?;
+        let mut target = vec![0; inode.size as usize];
+        volume.read_file(&inode, &extents, 0, &mut target)?;
+        String::from_utf8(target).map_err(|_| FsError::Corrupted("symlink target is not UTF-8"))
+    }
+
+    fn create(&self, name: &str, file_type: FileType, mode: u32) -> FsResult<Arc<dyn Inode>> {
+        let kind = match file_type {
+            FileType::Regular => FileKind::Regular,
+            FileType::Directory => FileKind::Directory,
+            _ => return Err(FsError::InvalidArgument),
+        };
+        self.create_child(name, kind, mode, &[])
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> FsResult<Arc<dyn Inode>> {
+        if target.is_empty() || target.len() > BLOCK_SIZE {
+            return Err(FsError::InvalidArgument);
+        }
+        self.create_child(name, FileKind::Symlink, 0o777, target.as_bytes())
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        self.shared.transaction(|volume, transaction| {
+            let mut inode = load_regular(volume, transaction, self.number)?;
+            volume.truncate_file(transaction, self.number, &mut inode, size)?;
+            inode.modified = now();
+            volume.store_inode(transaction, self.number, &inode)
+        })?;
+
+        PAGE_CACHE.discard(self.shared.cache_id, self.number);
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        let handle = self.shared.transaction(|volume, transaction| {
+            let mut directory = load_directory(volume, transaction, self.number)?;
+            let record = volume
+                .find_entry(transaction, self.number, &directory, name)?
+                .ok_or(FsError::NotFound)?;
+
+            if record.kind == FileKind::Directory {
+                if volume.load_inode(transaction, record.inode)?.size != 0 {
+                    return Err(FsError::NotEmpty);
+                }
+                directory.links -= 1;
+            }
+
+            volume.remove_entry(transaction, self.number, &mut directory, name)?;
+            directory.modified = now();
+            volume.store_inode(transaction, self.number, &directory)?;
+            self.unlink_inode(volume, transaction, record.inode)
+        })?;
+
+        drop(handle);
+        Ok(())
+    }
+
+    fn rename(&self, name: &str, target: &Arc<dyn Inode>, new_name: &str) -> FsResult<()> {
+        let target = (target.as_ref() as &dyn Any)
+            .downcast_ref::<TrashInode>()
+            .filter(|target| Arc::ptr_eq(&self.shared, &target.shared))
+            .ok_or(FsError::CrossDevice)?;
+        check_name(new_name)?;
+
+        let same_directory = self.number == target.number;
+        if same_directory && name == new_name {
+            return Ok(());
+        }
+
+        let replaced = self.shared.transaction(|volume, transaction| {
+            let mut source = load_directory(volume, transaction, self.number)?;
+            let mut destination = match same_directory {
+                true => None,
+                false => Some(load_directory(volume, transaction, target.number)?),
+            };
+
+            let record = volume
+                .find_entry(transaction, self.number, &source, name)?
+                .ok_or(FsError::NotFound)?;
+            let moves_directory = record.kind == FileKind::Directory && !same_directory;
+            if moves_directory && is_ancestor(volume, transaction, record.inode, target.number)? {
+                return Err(FsError::InvalidArgument);
+            }
+
+            let timestamp = now();
+            let directory = destination.as_mut().unwrap_or(&mut source);
+            let existing = volume.find_entry(transaction, target.number, directory, new_name)?;
+
+            let mut replaced = None;
+            if let Some(existing) = existing {
+                if existing.inode == record.inode {
+                    return Ok(None);
+                }
+                match (record.kind, existing.kind) {
+                    (FileKind::Directory, FileKind::Directory) => {
+                        if volume.load_inode(transaction, existing.inode)?.size != 0 {
+                            return Err(FsError::NotEmpty);
+                        }
+                        directory.links -= 1;
+                    }
+                    (FileKind::Directory, _) => return Err(FsError::NotADirectory),
+                    (_, FileKind::Directory) => return Err(FsError::IsADirectory),
+                    _ => {}
+                }
+
+                volume.remove_entry(transaction, target.number, directory, new_name)?;
+                replaced = Some(self.unlink_inode(volume, transaction, existing.inode)?);
+            }
+
+            let moved = DirRecord::new(new_name, record.inode, record.kind);
+            volume.insert_entry(transaction, target.number, directory, moved)?;
+            directory.modified = timestamp;
+            if moves_directory {
+                directory.links += 1;
+            }
+
+            volume.remove_entry(transaction, self.number, &mut source, name)?;
+            source.modified = timestamp;
+
+            if moves_directory {
+                source.links -= 1;
+                let mut inode = volume.load_inode(transaction, record.inode)?;
+                inode.parent = target.number;
+                volume.store_inode(transaction, record.inode, &inode)?;
+            }
+
+            volume.store_inode(transaction, self.number, &source)?;
+            if let Some(destination) = destination {
+                volume.store_inode(transaction, target.number, &destination)?;
+            }
+            Ok(replaced)
+        })?;
+
+        drop(replaced);
+        Ok(())
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        self.shared.volume.lock().flush()
+    }
+}
+
+impl Drop for TrashInode {
+    fn drop(&mut self) {
+        let mut inodes = self.shared.inodes.lock();
+        if inodes
+            .get(&self.number)
+            .is_some_and(|inode| inode.strong_count() == 0)
+        {
+            inodes.remove(&self.number);
+        }
+        drop(inodes);
+
+        if self.orphan.load(Ordering::Acquire) {
+            let number = self.number;
+            let result = self
+                .shared
+                .transaction(|volume, transaction| release_inode(volume, transaction, number));
+            if let Err(err) = result {
+                log::warn!("Failed to free TrashFS inode {number}: {err}");
+            }
+
+            // The number may be handed out again, which must not see these.
+            PAGE_CACHE.discard(self.shared.cache_id, number);
+        }
+    }
+}
+
+fn load_directory(volume: &Volume, transaction: &Transaction, number: u64) -> FsResult<DiskInode> {
+    let inode = volume.load_inode(transaction, number)?;
+    match inode.kind() {
+        Some(FileKind::Directory) if inode.links > 0 => Ok(inode),
+        Some(FileKind::Directory) => Err(FsError::NotFound),
+        _ => Err(FsError::NotADirectory),
+    }
+}
+
+fn load_regular(volume: &Volume, transaction: &Transaction, number: u64) -> FsResult<DiskInode> {
+    let inode = volume.load_inode(transaction, number)?;
+    match inode.kind() {
+        Some(FileKind::Regular) => Ok(inode),
+        Some(FileKind::Directory) => Err(FsError::IsADirectory),
+        _ => Err(FsError::InvalidArgument),
+    }
+}
+
+// Walks up from `directory` to the root looking for `ancestor`.
+fn is_ancestor(
+    volume: &Volume,
+    transaction: &Transaction,
+    ancestor: u64,
+    mut directory: u64,
+) -> FsResult<bool> {
+    loop {
+        if directory == ancestor {
+            return Ok(true);
+        }
+        if directory == ROOT_INODE {
+            return Ok(false);
+        }
+        directory = volume.load_inode(transaction, directory)?.parent;
+    }
+}
+
+fn release_inode(volume: &mut Volume, transaction: &mut Transaction, number: u64) -> FsResult<()> {
+    let inode = volume.load_inode(transaction, number)?;
+    for extent in volume.extents(transaction, number, &inode)? {
+        volume.free_blocks(transaction, extent.start, extent.length as u64);
+    }
+    if inode.extent_block != 0 {
+        volume.free_blocks(transaction, inode.extent_block, 1);
+    }
+    if inode.kind() == Some(FileKind::Directory) {
+        volume.free_tree(transaction, number, &inode)?;
+    }
+
+    volume.free_inode(transaction, number);
+    Ok(())
+}
+
+// Inodes that were unlinked while open are still allocated after a crash.
+fn find_orphans(volume: &Volume) -> FsResult<Vec<u64>> {
+    let transaction = Transaction::default();
+    let mut orphans = Vec::new();
+    let mut table = (0, Vec::new());
+
+    for number in ROOT_INODE + 1..volume.superblock.inode_count {
+        if !volume.is_inode_allocated(number) {
+            continue;
+        }
+        let (block, offset) = volume.superblock.inode_location(number);
+        if table.1.is_empty() || table.0 != block {
+            table = (block, volume.read(&transaction, block)?);
+        }
+
+        // Damaged inodes are left alone for fsck to report.
+        let raw = &table.1[offset..offset + INODE_SIZE];
+        if DiskInode::decode(number, raw).is_ok_and(|inode| inode.links == 0) {
+            orphans.push(number);
+        }
+    }
+
+    Ok(orphans)
+}
+
+fn check_name(name: &str) -> FsResult<()> {
+    match name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains('/')
+        || name.len() > NAME_MAX
+    {
+        true => Err(FsError::InvalidPath),
+        false => Ok(()),
+    }
+}
+
+fn file_type(kind: FileKind) -> FileType {
+    match kind {
+        FileKind::Regular => FileType::Regular,
+        FileKind::Directory => FileType::Directory,
+        FileKind::Symlink => FileType::Symlink,
+    }
+}
+
+fn now() -> i64 {
+    RtcDateTime::default()
+        .to_datetime()
+        .map_or(0, |time| time.unix_timestamp())
+}
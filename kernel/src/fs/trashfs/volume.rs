@@ -0,0 +1,402 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use trashfs::journal::{Commit, Descriptor, data_checksum, pending_transaction};
+use trashfs::{BLOCK_SIZE, Extent, FormatError, Inode, Superblock, bitmap, inode};
+
+use crate::fs::{FsError, FsResult};
+use crate::io::block::BlockDevice;
+
+const BITS_PER_BLOCK: u64 = BLOCK_SIZE as u64 * 8;
+
+// Metadata changes are staged here and reach the disk through the journal.
+// Freed blocks and inodes only become reusable once the transaction commits,
+// so nothing still referenced on disk gets overwritten early.
+#[derive(Default)]
+pub struct Transaction {
+    blocks: BTreeMap<u64, Vec<u8>>,
+    allocated_blocks: Vec<(u64, u64)>,
+    freed_blocks: Vec<(u64, u64)>,
+    allocated_inodes: Vec<u64>,
+    freed_inodes: Vec<u64>,
+}
+
+pub struct Volume {
+    device: Arc<dyn BlockDevice>,
+    sectors_per_block: u64,
+    pub superblock: Superblock,
+    block_bitmap: Vec<u8>,
+    inode_bitmap: Vec<u8>,
+    next_block: u64,
+}
+
+impl Volume {
+    pub fn open(device: Arc<dyn BlockDevice>) -> FsResult<Self> {
+        let sector_size = device.block_size();
+        if sector_size == 0 || !BLOCK_SIZE.is_multiple_of(sector_size) {
+            return Err(FsError::Unsupported("device block size"));
+        }
+        let sectors_per_block = (BLOCK_SIZE / sector_size) as u64;
+
+        let mut block = vec![0; BLOCK_SIZE];
+        device.read_block(0, &mut block)?;
+        let superblock = Superblock::decode(&block).map_err(|err| match err {
+            FormatError::BadMagic(_) => FsError::Unsupported("not a TrashFS volume"),
+            err => format_error(err),
+        })?;
+
+        if superblock.block_count * sectors_per_block > device.block_count() {
+            return Err(FsError::Corrupted("volume larger than its device"));
+        }
+
+        let mut volume = Self {
+            device,
+            sectors_per_block,
+            next_block: superblock.data_start,
+            superblock,
+            block_bitmap: Vec::new(),
+            inode_bitmap: Vec::new(),
+        };
+
+        volume.replay()?;
+        volume.block_bitmap = volume.read_region(
+            volume.superblock.block_bitmap_start,
+            volume.superblock.block_bitmap_blocks,
+        )?;
+        volume.inode_bitmap = volume.read_region(
+            volume.superblock.inode_bitmap_start,
+            volume.superblock.inode_bitmap_blocks,
+        )?;
+
+        let superblock = &mut volume.superblock;
+        superblock.free_blocks = superblock.block_count
+            - bitmap::count_set(&volume.block_bitmap, superblock.block_count);
+        superblock.free_inodes = superblock.inode_count
+            - bitmap::count_set(&volume.inode_bitmap, superblock.inode_count);
+
+        Ok(volume)
+    }
+
+    fn replay(&mut self) -> FsResult<()> {
+        let pending = pending_transaction(&self.superblock, |block, buffer| {
+            self.read_raw(block, buffer)
+        })?;
+        let Some(blocks) = pending else {
+            return Ok(());
+        };
+
+        log::info!("Replaying {} journaled TrashFS blocks", blocks.len());
+        for (target, data) in blocks.iter() {
+            self.write_raw(*target, data)?;
+        }
+        self.device.flush()?;
+        self.finish_transaction()
+    }
+
+    fn read_region(&self, start: u64, count: u64) -> FsResult<Vec<u8>> {
+        let mut data = vec![0; count as usize * BLOCK_SIZE];
+        self.read_raw(start, &mut data)?;
+        Ok(data)
+    }
+
+    pub fn read_raw(&self, block: u64, buffer: &mut [u8]) -> FsResult<()> {
+        self.device
+            .read_block(block * self.sectors_per_block, buffer)?;
+        Ok(())
+    }
+
+    pub fn write_raw(&self, block: u64, buffer: &[u8]) -> FsResult<()> {
+        self.device
+            .write_block(block * self.sectors_per_block, buffer)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> FsResult<()> {
+        self.device.flush()?;
+        Ok(())
+    }
+
+    pub fn read(&self, transaction: &Transaction, block: u64) -> FsResult<Vec<u8>> {
+        if let Some(data) = transaction.blocks.get(&block) {
+            return Ok(data.clone());
+        }
+        let mut data = vec![0; BLOCK_SIZE];
+        self.read_raw(block, &mut data)?;
+        Ok(data)
+    }
+
+    pub fn write(&self, transaction: &mut Transaction, block: u64, data: Vec<u8>) {
+        transaction.blocks.insert(block, data);
+    }
+}
+
+impl Volume {
+    pub fn load_inode(&self, transaction: &Transaction, number: u64) -> FsResult<Inode> {
+        if number == 0
+            || number >= self.superblock.inode_count
+            || !bitmap::get(&self.inode_bitmap, number)
+        {
+            return Err(FsError::NotFound);
+        }
+
+        let (block, offset) = self.superblock.inode_location(number);
+        let data = self.read(transaction, block)?;
+        Inode::decode(number, &data[offset..]).map_err(format_error)
+    }
+
+    pub fn store_inode(
+        &self,
+        transaction: &mut Transaction,
+        number: u64,
+        inode: &Inode,
+    ) -> FsResult<()> {
+        let (block, offset) = self.superblock.inode_location(number);
+        let mut data = self.read(transaction, block)?;
+        inode.encode(number, &mut data[offset..]);
+        self.write(transaction, block, data);
+        Ok(())
+    }
+
+    pub fn extents(
+        &self,
+        transaction: &Transaction,
+        number: u64,
+        inode: &Inode,
+    ) -> FsResult<Vec<Extent>> {
+        let mut extents = inode.inline_extents().to_vec();
+        if inode.has_overflow() {
+            let data = self.read(transaction, inode.extent_block)?;
+            extents.extend(inode::decode_extent_block(number, &data).map_err(format_error)?);
+        }
+        Ok(extents)
+    }
+
+    pub fn set_extents(
+        &mut self,
+        transaction: &mut Transaction,
+        number: u64,
+        inode: &mut Inode,
+        extents: &[Extent],
+    ) -> FsResult<()> {
+        let overflow = inode.store_extents(extents).map_err(|_| FsError::NoSpace)?;
+
+        if overflow.is_empty() {
+            if inode.extent_block != 0 {
+                self.free_blocks(transaction, inode.extent_block, 1);
+                inode.extent_block = 0;
+            }
+            return Ok(());
+        }
+
+        if inode.extent_block == 0 {
+            let goal = extents
+                .first()
+                .map_or(self.next_block, |extent| extent.start);
+            inode.extent_block = self.allocate_blocks(transaction, goal, 1)?.0;
+        }
+        let data = inode::encode_extent_block(number, overflow).map_err(format_error)?;
+        self.write(transaction, inode.extent_block, data);
+        Ok(())
+    }
+}
+
+impl Volume {
+    // Returns the longest free run of at most `count` blocks found first when
+    // scanning from `goal`, so callers may receive fewer blocks than asked.
+    pub fn allocate_blocks(
+        &mut self,
+        transaction: &mut Transaction,
+        goal: u64,
+        count: u64,
+    ) -> FsResult<(u64, u64)> {
+        let superblock = &self.superblock;
+        let data_blocks = superblock.block_count - superblock.data_start;
+        let goal = match superblock.is_data_block(goal) {
+            true => goal,
+            false => self.next_block,
+        };
+
+        let start = (0..data_blocks)
+            .map(|index| {
+                superblock.data_start + (goal - superblock.data_start + index) % data_blocks
+            })
+            .find(|&block| !bitmap::get(&self.block_bitmap, block))
+            .ok_or(FsError::NoSpace)?;
+
+        let length = (start..superblock.block_count)
+            .take(count as usize)
+            .take_while(|&block| !bitmap::get(&self.block_bitmap, block))
+            .count() as u64;
+
+        for block in start..start + length {
+            bitmap::set(&mut self.block_bitmap, block, true);
+        }
+        transaction.allocated_blocks.push((start, length));
+        self.superblock.free_blocks -= length;
+        self.next_block = start + length;
+        Ok((start, length))
+    }
+
+    pub fn free_blocks(&mut self, transaction: &mut Transaction, start: u64, count: u64) {
+        transaction.freed_blocks.push((start, count));
+    }
+
+    pub fn allocate_inode(&mut self, transaction: &mut Transaction) -> FsResult<u64> {
+        let number = (1..self.superblock.inode_count)
+            .find(|&number| !bitmap::get(&self.inode_bitmap, number))
+            .ok_or(FsError::NoSpace)?;
+
+        bitmap::set(&mut self.inode_bitmap, number, true);
+        transaction.allocated_inodes.push(number);
+        self.superblock.free_inodes -= 1;
+        Ok(number)
+    }
+
+    pub fn is_inode_allocated(&self, number: u64) -> bool {
+        bitmap::get(&self.inode_bitmap, number)
+    }
+
+    pub fn free_inode(&mut self, transaction: &mut Transaction, number: u64) {
+        transaction.freed_inodes.push(number);
+    }
+
+    pub fn commit(&mut self, mut transaction: Transaction) -> FsResult<()> {
+        for &(start, count) in transaction.freed_blocks.iter() {
+            for block in start..start + count {
+                bitmap::set(&mut self.block_bitmap, block, false);
+            }
+        }
+        for &number in transaction.freed_inodes.iter() {
+            bitmap::set(&mut self.inode_bitmap, number, false);
+        }
+
+        let block_ranges = transaction
+            .allocated_blocks
+            .iter()
+            .chain(transaction.freed_blocks.iter());
+        let dirty_blocks = block_ranges
+            .filter(|(_, count)| *count > 0)
+            .flat_map(|&(start, count)| {
+                start / BITS_PER_BLOCK..=(start + count - 1) / BITS_PER_BLOCK
+            })
+            .collect::<BTreeSet<_>>();
+        let dirty_inodes = transaction
+            .allocated_inodes
+            .iter()
+            .chain(transaction.freed_inodes.iter())
+            .map(|number| number / BITS_PER_BLOCK)
+            .collect::<BTreeSet<_>>();
+
+        let bitmaps = [
+            (
+                dirty_blocks,
+                self.superblock.block_bitmap_start,
+                &self.block_bitmap,
+            ),
+            (
+                dirty_inodes,
+                self.superblock.inode_bitmap_start,
+                &self.inode_bitmap,
+            ),
+        ];
+        for (dirty, start, bitmap) in bitmaps {
+            for index in dirty {
+                let offset = index as usize * BLOCK_SIZE;
+                let data = bitmap[offset..offset + BLOCK_SIZE].to_vec();
+                transaction.blocks.insert(start + index, data);
+            }
+        }
+
+        if let Err(err) = self.write_journal(&transaction) {
+            for &(start, count) in transaction.freed_blocks.iter() {
+                for block in start..start + count {
+                    bitmap::set(&mut self.block_bitmap, block, true);
+                }
+            }
+            for &number in transaction.freed_inodes.iter() {
+                bitmap::set(&mut self.inode_bitmap, number, true);
+            }
+            self.abort(transaction);
+            return Err(err);
+        }
+
+        self.superblock.free_blocks += transaction
+            .freed_blocks
+            .iter()
+            .map(|(_, count)| count)
+            .sum::<u64>();
+        self.superblock.free_inodes += transaction.freed_inodes.len() as u64;
+
+        for (block, data) in transaction.blocks.iter() {
+            self.write_raw(*block, data)?;
+        }
+        self.device.flush()?;
+        self.finish_transaction()
+    }
+
+    fn write_journal(&self, transaction: &Transaction) -> FsResult<()> {
+        if transaction.blocks.is_empty() {
+            return Ok(());
+        }
+        if transaction.blocks.len() > self.superblock.transaction_capacity() {
+            log::error!(
+                "TrashFS transaction of {} blocks exceeds the journal",
+                transaction.blocks.len()
+            );
+            return Err(FsError::NoSpace);
+        }
+
+        let descriptor = Descriptor {
+            sequence: self.superblock.journal_sequence,
+            targets: transaction.blocks.keys().copied().collect(),
+        };
+        let mut journal = descriptor.encode().map_err(format_error)?;
+        for data in transaction.blocks.values() {
+            journal.extend_from_slice(data);
+        }
+        self.write_raw(self.superblock.journal_start, &journal)?;
+        self.device.flush()?;
+
+        // The commit block is only written once everything it covers is
+        // durable, which makes it the point where the transaction takes effect.
+        let commit = Commit {
+            sequence: descriptor.sequence,
+            data_checksum: data_checksum(transaction.blocks.values().map(Vec::as_slice)),
+        };
+        let commit_block = self.superblock.journal_start + 1 + descriptor.targets.len() as u64;
+        self.write_raw(commit_block, &commit.encode())?;
+        self.device.flush()?;
+        Ok(())
+    }
+
+    // Bumping the sequence retires the transaction currently in the journal.
+    fn finish_transaction(&mut self) -> FsResult<()> {
+        self.superblock.journal_sequence += 1;
+        self.write_raw(0, &self.superblock.encode())?;
+        self.device.flush()?;
+        Ok(())
+    }
+
+    pub fn abort(&mut self, transaction: Transaction) {
+        for &(start, count) in transaction.allocated_blocks.iter() {
+            for block in start..start + count {
+                bitmap::set(&mut self.block_bitmap, block, false);
+            }
+            self.superblock.free_blocks += count;
+        }
+        for &number in transaction.allocated_inodes.iter() {
+            bitmap::set(&mut self.inode_bitmap, number, false);
+            self.superblock.free_inodes += 1;
+        }
+    }
+}
+
+pub fn format_error(err: FormatError) -> FsError {
+    match err {
+        FormatError::BadMagic(what) | FormatError::BadChecksum(what) => FsError::Corrupted(what),
+        FormatError::Invalid(what) => FsError::Corrupted(what),
+        FormatError::UnsupportedVersion(_) => FsError::Unsupported("TrashFS version"),
+        FormatError::Overflow(_) => FsError::NoSpace,
+    }
+}
@@ -0,0 +1,129 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::{Lazy, RwLock};
+
+use super::{FileSystem, FileType, FsError, FsResult, Inode};
+
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+pub static VFS: Lazy<RwLock<Vfs>> = Lazy::new(RwLock::default);
+
+#[derive(Default)]
+pub struct Vfs {
+    mounts: BTreeMap<String, Arc<dyn FileSystem>>,
+}
+
+impl Vfs {
+    pub fn mount(&mut self, path: &str, filesystem: Arc<dyn FileSystem>) -> FsResult<()> {
+        let path = join(&normalize(path)?);
+        if self.mounts.contains_key(&path) {
+            return Err(FsError::Busy);
+        }
+
+        if path != "/" && self.lookup(&path)?.metadata().file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        log::info!("Mounted {} filesystem at {path}", filesystem.name());
+        self.mounts.insert(path, filesystem);
+        Ok(())
+    }
+
+    pub fn unmount(&mut self, path: &str) -> FsResult<Arc<dyn FileSystem>> {
+        let path = join(&normalize(path)?);
+        let prefix = if path == "/" {
+            path.clone()
+        } else {
+            path.clone() + "/"
+        };
+        let nested = self
+            .mounts
+            .keys()
+            .any(|mount| *mount != path && mount.starts_with(&prefix));
+
+        if nested {
+            return Err(FsError::Busy);
+        }
+
+        self.mounts.remove(&path).ok_or(FsError::NotFound)
+    }
+
+    pub fn mounts(&self) -> impl Iterator<Item = (&str, &Arc<dyn FileSystem>)> {
+        self.mounts.iter().map(|(path, fs)| (path.as_str(), fs))
+    }
+
+    pub fn lookup(&self, path: &str) -> FsResult<Arc<dyn Inode>> {
+        self.resolve(path, 0)
+    }
+}
+
+impl Vfs {
+    fn resolve(&self, path: &str, depth: usize) -> FsResult<Arc<dyn Inode>> {
+        if depth > MAX_SYMLINK_DEPTH {
+            return Err(FsError::TooManyLinks);
+        }
+
+        let components = normalize(path)?;
+        let mut inode = self.mounts.get("/").ok_or(FsError::NotFound)?.root();
+
+        for (index, component) in components.iter().enumerate() {
+            if inode.metadata().file_type != FileType::Directory {
+                return Err(FsError::NotADirectory);
+            }
+
+            let current = join(&components[..=index]);
+            inode = match self.mounts.get(&current) {
+                Some(filesystem) => filesystem.root(),
+                None => inode.lookup(component)?,
+            };
+
+            if inode.metadata().file_type == FileType::Symlink {
+                let target = inode.read_link()?;
+                let mut path = match target.starts_with('/') {
+                    true => target,
+                    false => join(&components[..index]) + "/" + &target,
+                };
+
+                for rest in components[index + 1..].iter() {
+                    path.push('/');
+                    path.push_str(rest);
+                }
+
+                return self.resolve(&path, depth + 1);
+            }
+        }
+
+        Ok(inode)
+    }
+}
+
+fn normalize(path: &str) -> FsResult<Vec<String>> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component.to_string()),
+        }
+    }
+
+    Ok(components)
+}
+
+fn join(components: &[String]) -> String {
+    if components.is_empty() {
+        return "/".to_string();
+    }
+
+    components
+        .iter()
+        .fold(String::new(), |path, component| path + "/" + component)
+}
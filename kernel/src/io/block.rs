@@ -1,32 +1,66 @@
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
+use bitflags::bitflags;
 use core::any::Any;
 use core::fmt::Debug;
+use core::ops::Range;
 use gpt_disk_io::BlockIo;
 use gpt_disk_types::{BlockSize, Lba};
 use thiserror::Error;
+use x86_64::instructions::interrupts;
 
-use crate::drivers::ahci::{self, AhciBlockDevice};
+use super::request::{BlockOperation, BlockRequest, BlockSegment};
+use crate::drivers::ahci::{Ahci, AhciBlockDevice};
 use crate::drivers::nvme::NvmeBlockDevice;
+use crate::drivers::virtio::{VirtioBlk, VirtioBlockDevice};
 
 #[derive(Error, Debug)]
 pub enum BlockDeviceError {
     #[error("I/O Error: {0}")]
     IoError(String),
-    #[error("NVMe specific error: {0}")]
-    Nvme(#[from] nvme::Error),
+    #[error("NVMe error (status type: {status_type:#x}, status code: {status_code:#x})")]
+    Nvme { status_type: u8, status_code: u8 },
+    #[error("Encryption error: {0}")]
+    Crypt(#[from] trashcrypt::CryptError),
+    #[error("ATA error (status: {status:#x}, error: {error:#x})")]
+    Ata { status: u8, error: u8 },
     #[error("Access out of bounds")]
     OutOfBounds,
     #[error("Block device not found")]
     DeviceNotFound,
     #[error("Invalid input argument")]
     InvalidInput,
+    #[error("Device is read-only")]
+    ReadOnly,
+    #[error("Operation not supported by device")]
+    Unsupported,
     #[error("Device naming error: {0}")]
     NamingError(String),
 }
 
 pub type BlockDeviceResult<T> = Result<T, BlockDeviceError>;
 
+const ZERO_FILL_SIZE: usize = 64 * 1024;
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct BlockCapabilities: u32 {
+        const DISCARD = 1 << 0;
+        const WRITE_ZEROES = 1 << 1;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DeviceDetails {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    pub temperature: Option<i16>,
+    pub percentage_used: Option<u8>,
+    pub power_on_hours: Option<u64>,
+}
+
 pub trait BlockDevice: Send + Sync + Any {
     fn block_size(&self) -> usize;
     fn block_count(&self) -> u64;
@@ -34,11 +68,55 @@ pub trait BlockDevice: Send + Sync + Any {
     fn flush(&self) -> BlockDeviceResult<()>;
     fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()>;
     fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()>;
+
+    fn queue_depth(&self) -> usize {
+        1
+    }
+
+    fn details(&self) -> DeviceDetails {
+        DeviceDetails::default()
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        BlockCapabilities::empty()
+    }
+
+    fn discard(&self, _range: Range<u64>) -> BlockDeviceResult<()> {
+        Err(BlockDeviceError::Unsupported)
+    }
+
+    fn write_zeroes(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        zero_fill(self, range)
+    }
+
+    fn submit(&self, mut request: BlockRequest) {
+        let result = request.execute(self);
+        request.complete(result);
+    }
+}
+
+pub fn zero_fill<D: BlockDevice + ?Sized>(device: &D, range: Range<u64>) -> BlockDeviceResult<()> {
+    if range.start > range.end || range.end > device.block_count() {
+        return Err(BlockDeviceError::OutOfBounds);
+    }
+
+    let block_size = device.block_size();
+    let chunk_blocks = (ZERO_FILL_SIZE / block_size).max(1) as u64;
+    let zeroes = vec![0; chunk_blocks as usize * block_size];
+
+    let mut block_id = range.start;
+    while block_id < range.end {
+        let count = (range.end - block_id).min(chunk_blocks);
+        device.write_block(block_id, &zeroes[..count as usize * block_size])?;
+        block_id += count;
+    }
+
+    Ok(())
 }
 
 impl BlockDevice for AhciBlockDevice {
     fn block_size(&self) -> usize {
-        ahci::BLOCK_SIZE
+        self.identify.block_size
     }
 
     fn block_count(&self) -> u64 {
@@ -46,59 +124,204 @@ impl BlockDevice for AhciBlockDevice {
     }
 
     fn flush(&self) -> BlockDeviceResult<()> {
-        Ok(())
+        interrupts::without_interrupts(|| self.device.lock().flush())
     }
 
     fn read_block(&self, lba: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
-        self.device.lock().read_block(lba, buffer);
-        Ok(())
+        // Waited on before the buffer is released.
+        let segment = unsafe { BlockSegment::new(buffer) };
+        self.execute(BlockRequest::read(lba, vec![segment]))
     }
 
     fn write_block(&self, lba: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
-        self.device.lock().write_block(lba, buffer);
-        Ok(())
+        if self.atapi {
+            return Err(BlockDeviceError::ReadOnly);
+        }
+        // Waited on before the buffer is released.
+        let segment = unsafe { BlockSegment::from_slice(buffer) };
+        self.execute(BlockRequest::write(lba, vec![segment]))
+    }
+
+    fn queue_depth(&self) -> usize {
+        interrupts::without_interrupts(|| self.device.lock().queue_depth())
+    }
+
+    fn details(&self) -> DeviceDetails {
+        DeviceDetails {
+            model: self.identify.model_number.clone(),
+            serial: self.identify.serial_number.clone(),
+            firmware: self.identify.firmware_revision.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        match self.identify.trim_supported && !self.atapi {
+            true => BlockCapabilities::DISCARD,
+            false => BlockCapabilities::empty(),
+        }
+    }
+
+    fn discard(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.execute(BlockRequest::discard(range))
+    }
+
+    fn submit(&self, request: BlockRequest) {
+        if let Err(err) = request.validate(self.block_size(), self.block_count()) {
+            return request.complete(Err(err));
+        }
+
+        let result = match request.operation {
+            BlockOperation::Flush => self.flush(),
+            BlockOperation::Write | BlockOperation::WriteZeroes if self.atapi => {
+                Err(BlockDeviceError::ReadOnly)
+            }
+            BlockOperation::Discard
+                if !self.capabilities().contains(BlockCapabilities::DISCARD) =>
+            {
+                Err(BlockDeviceError::Unsupported)
+            }
+            BlockOperation::Discard => {
+                interrupts::without_interrupts(|| self.device.lock().trim(request.range()))
+            }
+            BlockOperation::WriteZeroes => zero_fill(self, request.range()),
+            _ => return Ahci::submit(&self.device, request),
+        };
+
+        request.complete(result);
+    }
+}
+
+impl BlockDevice for VirtioBlockDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn flush(&self) -> BlockDeviceResult<()> {
+        self.execute(BlockRequest::flush())
+    }
+
+    fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
+        // Waited on before the buffer is released.
+        let segment = unsafe { BlockSegment::new(buffer) };
+        self.execute(BlockRequest::read(block_id, vec![segment]))
+    }
+
+    fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
+        if self.read_only {
+            return Err(BlockDeviceError::ReadOnly);
+        }
+        // Waited on before the buffer is released.
+        let segment = unsafe { BlockSegment::from_slice(buffer) };
+        self.execute(BlockRequest::write(block_id, vec![segment]))
+    }
+
+    fn queue_depth(&self) -> usize {
+        interrupts::without_interrupts(|| self.device.lock().queue_depth())
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.capabilities
+    }
+
+    fn discard(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.execute(BlockRequest::discard(range))
+    }
+
+    fn write_zeroes(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.execute(BlockRequest::write_zeroes(range))
+    }
+
+    fn submit(&self, request: BlockRequest) {
+        if let Err(err) = request.validate(self.block_size, self.block_count) {
+            return request.complete(Err(err));
+        }
+
+        let result = match request.operation {
+            BlockOperation::Discard if !self.capabilities.contains(BlockCapabilities::DISCARD) => {
+                Err(BlockDeviceError::Unsupported)
+            }
+            BlockOperation::WriteZeroes
+                if !self.read_only
+                    && !self.capabilities.contains(BlockCapabilities::WRITE_ZEROES) =>
+            {
+                zero_fill(self, request.range())
+            }
+            _ => return VirtioBlk::submit(&self.device, request),
+        };
+
+        request.complete(result);
     }
 }
 
 impl BlockDevice for NvmeBlockDevice {
     fn block_size(&self) -> usize {
-        self.namespace.block_size() as usize
+        self.namespace.block_size
     }
 
     fn block_count(&self) -> u64 {
-        self.namespace.block_count()
+        self.namespace.block_count
     }
 
     fn flush(&self) -> BlockDeviceResult<()> {
-        let qpair = self
-            .qpairs
-            .get(&1)
-            .ok_or(BlockDeviceError::DeviceNotFound)?;
-        qpair.lock().flush().map_err(BlockDeviceError::from)
+        self.execute(BlockRequest::flush())
     }
 
-    fn read_block(&self, lba: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
-        let qpair = self
-            .qpairs
-            .get(&1)
-            .ok_or(BlockDeviceError::DeviceNotFound)?;
-        qpair
-            .lock()
-            .read(buffer.as_mut_ptr(), buffer.len(), lba)
-            .map_err(BlockDeviceError::from)?;
-        qpair.lock().flush().map_err(BlockDeviceError::from)
+    fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
+        // Waited on before the buffer is released.
+        let segment = unsafe { BlockSegment::new(buffer) };
+        self.execute(BlockRequest::read(block_id, vec![segment]))
     }
 
-    fn write_block(&self, lba: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
-        let qpair = self
-            .qpairs
-            .get(&1)
-            .ok_or(BlockDeviceError::DeviceNotFound)?;
-        qpair
-            .lock()
-            .write(buffer.as_ptr(), buffer.len(), lba)
-            .map_err(BlockDeviceError::from)?;
-        qpair.lock().flush().map_err(BlockDeviceError::from)
+    fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
+        // Waited on before the buffer is released.
+        let segment = unsafe { BlockSegment::from_slice(buffer) };
+        self.execute(BlockRequest::write(block_id, vec![segment]))
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.controller.queue_depth()
+    }
+
+    fn details(&self) -> DeviceDetails {
+        self.controller.details()
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.controller.capabilities()
+    }
+
+    fn discard(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.execute(BlockRequest::discard(range))
+    }
+
+    fn write_zeroes(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.execute(BlockRequest::write_zeroes(range))
+    }
+
+    fn submit(&self, request: BlockRequest) {
+        if let Err(err) = request.validate(self.block_size(), self.block_count()) {
+            return request.complete(Err(err));
+        }
+
+        let capabilities = self.capabilities();
+        let result = match request.operation {
+            BlockOperation::Discard if !capabilities.contains(BlockCapabilities::DISCARD) => {
+                Err(BlockDeviceError::Unsupported)
+            }
+            BlockOperation::WriteZeroes
+                if !capabilities.contains(BlockCapabilities::WRITE_ZEROES) =>
+            {
+                zero_fill(self, request.range())
+            }
+            _ => return self.controller.submit(&self.namespace, request),
+        };
+
+        request.complete(result);
     }
 }
 
@@ -169,16 +392,61 @@ impl BlockDevice for PartitionBlockDevice {
     }
 
     fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
-        if block_id >= self.block_count {
-            return Err(BlockDeviceError::OutOfBounds);
-        }
+        self.check_range(block_id, buffer.len())?;
         self.parent.read_block(self.start_block + block_id, buffer)
     }
 
     fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
-        if block_id >= self.block_count {
+        self.check_range(block_id, buffer.len())?;
+        self.parent.write_block(self.start_block + block_id, buffer)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.parent.queue_depth()
+    }
+
+    fn details(&self) -> DeviceDetails {
+        self.parent.details()
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.parent.capabilities()
+    }
+
+    fn discard(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.parent.discard(self.translate(range)?)
+    }
+
+    fn write_zeroes(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.parent.write_zeroes(self.translate(range)?)
+    }
+
+    fn submit(&self, mut request: BlockRequest) {
+        if let Err(err) = request.validate(self.block_size(), self.block_count) {
+            request.complete(Err(err));
+            return;
+        }
+
+        if request.operation != BlockOperation::Flush {
+            request.block_id += self.start_block;
+        }
+        self.parent.submit(request);
+    }
+}
+
+impl PartitionBlockDevice {
+    fn translate(&self, range: Range<u64>) -> BlockDeviceResult<Range<u64>> {
+        if range.start > range.end || range.end > self.block_count {
             return Err(BlockDeviceError::OutOfBounds);
         }
-        self.parent.write_block(self.start_block + block_id, buffer)
+        Ok(self.start_block + range.start..self.start_block + range.end)
+    }
+
+    fn check_range(&self, block_id: u64, length: usize) -> BlockDeviceResult<()> {
+        let block_count = length.div_ceil(self.block_size()).max(1) as u64;
+        if block_id + block_count > self.block_count {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+        Ok(())
     }
 }
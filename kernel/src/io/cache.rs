@@ -0,0 +1,482 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::num::NonZeroUsize;
+use core::ops::Range;
+use lru::LruCache;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+use super::block::DeviceDetails;
+use super::block::{BlockCapabilities, BlockDevice, BlockDeviceError, BlockDeviceResult};
+use super::request::{BlockOperation, BlockRequest};
+
+pub const DEFAULT_CACHE_SIZE: usize = 256 * 1024;
+// Transfers this large go straight to the device instead of churning the LRU
+const BYPASS_SIZE: usize = 64 * 1024;
+const WRITEBACK_INTERVAL_MS: u64 = 1000;
+
+static BLOCK_CACHES: Mutex<Vec<Weak<CachedBlockDevice>>> = Mutex::new(Vec::new());
+
+struct CachedBlock {
+    data: Box<[u8]>,
+    dirty: bool,
+    // Changes on every write, so a write-back only cleans the copy it wrote.
+    version: u64,
+}
+
+struct CacheState {
+    blocks: LruCache<u64, CachedBlock>,
+    // Dirty blocks pushed out of the LRU, kept readable until written back.
+    evicted: BTreeMap<u64, CachedBlock>,
+    // Bumped whenever blocks reach the device or are dropped, so a miss that
+    // raced with that doesn't cache what it read.
+    epoch: u64,
+    next_version: u64,
+}
+
+impl CacheState {
+    fn contains(&self, block_id: u64) -> bool {
+        self.blocks.contains(&block_id) || self.evicted.contains_key(&block_id)
+    }
+
+    fn peek(&self, block_id: u64) -> Option<&CachedBlock> {
+        self.blocks
+            .peek(&block_id)
+            .or_else(|| self.evicted.get(&block_id))
+    }
+
+    // Evicted blocks are moved back into the LRU, which may evict others.
+    fn get_mut(&mut self, block_id: u64, victims: &mut Vec<u64>) -> Option<&mut CachedBlock> {
+        if let Some(block) = self.evicted.remove(&block_id) {
+            victims.extend(self.push(block_id, block));
+        }
+        self.blocks.get_mut(&block_id)
+    }
+
+    fn insert(&mut self, block_id: u64, data: Box<[u8]>) -> Option<u64> {
+        let block = CachedBlock {
+            data,
+            dirty: false,
+            version: 0,
+        };
+        self.push(block_id, block)
+    }
+
+    // Returns the block evicted to make room when it still has to be written.
+    fn push(&mut self, block_id: u64, block: CachedBlock) -> Option<u64> {
+        let (victim_id, victim) = self.blocks.push(block_id, block)?;
+        if victim_id == block_id || !victim.dirty {
+            return None;
+        }
+        self.evicted.insert(victim_id, victim);
+        Some(victim_id)
+    }
+
+    fn remove(&mut self, range: &Range<u64>) {
+        let stale = self
+            .blocks
+            .iter()
+            .map(|(&block_id, _)| block_id)
+            .filter(|block_id| range.contains(block_id))
+            .collect::<Vec<_>>();
+
+        for block_id in stale {
+            self.blocks.pop(&block_id);
+        }
+        self.evicted.retain(|block_id, _| !range.contains(block_id));
+        self.epoch += 1;
+    }
+
+    fn next_version(&mut self) -> u64 {
+        self.next_version += 1;
+        self.next_version
+    }
+}
+
+// Device I/O never runs with the state locked. Write-backs are serialized by
+// their own lock instead, so an older copy of a block can't land on the
+// device after a newer one.
+pub struct CachedBlockDevice {
+    inner: Arc<dyn BlockDevice>,
+    state: Mutex<CacheState>,
+    writeback: Mutex<()>,
+    // Bypassing writes still in flight; misses overlapping them are not cached
+    writes_in_flight: Arc<Mutex<Vec<Range<u64>>>>,
+}
+
+impl CachedBlockDevice {
+    pub fn new(inner: Arc<dyn BlockDevice>, capacity: usize) -> Arc<Self> {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        let state = CacheState {
+            blocks: LruCache::new(capacity),
+            evicted: BTreeMap::new(),
+            epoch: 0,
+            next_version: 0,
+        };
+        let device = Arc::new(Self {
+            inner,
+            state: Mutex::new(state),
+            writeback: Mutex::new(()),
+            writes_in_flight: Arc::new(Mutex::new(Vec::new())),
+        });
+
+        let mut caches = BLOCK_CACHES.lock();
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.push(Arc::downgrade(&device));
+
+        device
+    }
+
+    pub fn with_default_capacity(inner: Arc<dyn BlockDevice>) -> Arc<Self> {
+        let capacity = DEFAULT_CACHE_SIZE / inner.block_size();
+        Self::new(inner, capacity)
+    }
+
+    pub fn sync(&self) -> BlockDeviceResult<()> {
+        if self.write_back(|_| true)? {
+            self.inner.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn forget(&self, range: Range<u64>) {
+        let _writeback = self.writeback.lock();
+        self.state.lock().remove(&range);
+    }
+
+    pub fn invalidate(&self) -> BlockDeviceResult<()> {
+        self.sync()?;
+        self.forget(0..u64::MAX);
+        Ok(())
+    }
+
+    pub fn inner(&self) -> Arc<dyn BlockDevice> {
+        self.inner.clone()
+    }
+}
+
+impl CachedBlockDevice {
+    // Returns the state locked with `block_id` cached, reading it in first
+    // when `load` is set.
+    fn cached_block(
+        &self,
+        block_id: u64,
+        load: bool,
+        victims: &mut Vec<u64>,
+    ) -> BlockDeviceResult<MutexGuard<'_, CacheState>> {
+        if block_id >= self.inner.block_count() {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+
+        loop {
+            let mut state = self.state.lock();
+            if state.contains(block_id) {
+                return Ok(state);
+            }
+
+            let mut data = vec![0; self.inner.block_size()].into_boxed_slice();
+            if !load {
+                victims.extend(state.insert(block_id, data));
+                return Ok(state);
+            }
+
+            let epoch = state.epoch;
+            drop(state);
+            self.inner.read_block(block_id, &mut data)?;
+
+            let mut state = self.state.lock();
+            if state.epoch == epoch && !state.contains(block_id) {
+                victims.extend(state.insert(block_id, data));
+                return Ok(state);
+            }
+        }
+    }
+
+    // Caches blocks read from the device while the state was unlocked. Any
+    // block that was cached meanwhile is newer, so it replaces the data read.
+    fn fill(&self, block_id: u64, epoch: u64, data: &mut [u8]) -> BlockDeviceResult<()> {
+        let block_size = self.block_size();
+        let range = block_id..block_id + (data.len() / block_size) as u64;
+        let mut victims = Vec::new();
+
+        let mut state = self.state.lock();
+        let cacheable = state.epoch == epoch && !self.write_in_flight(&range);
+        for (block_id, chunk) in range.zip(data.chunks_mut(block_size)) {
+            match state.peek(block_id) {
+                Some(block) => chunk.copy_from_slice(&block.data),
+                None if cacheable => victims.extend(state.insert(block_id, chunk.into())),
+                None => {}
+            }
+        }
+        drop(state);
+
+        self.write_evicted(&victims)
+    }
+
+    // Returns whether anything was written.
+    fn write_back(&self, filter: impl Fn(u64) -> bool) -> BlockDeviceResult<bool> {
+        let _writeback = self.writeback.lock();
+
+        let state = self.state.lock();
+        let dirty = state
+            .blocks
+            .iter()
+            .filter(|(_, block)| block.dirty)
+            .chain(state.evicted.iter())
+            .filter(|(block_id, _)| filter(**block_id))
+            .map(|(&block_id, block)| (block_id, block.version, block.data.clone()))
+            .collect::<Vec<_>>();
+        drop(state);
+
+        let mut written = Vec::new();
+        let mut result = Ok(!dirty.is_empty());
+        for (block_id, version, data) in dirty {
+            if let Err(err) = self.inner.write_block(block_id, &data) {
+                result = Err(err);
+                break;
+            }
+            written.push((block_id, version));
+        }
+
+        if written.is_empty() {
+            return result;
+        }
+
+        let mut state = self.state.lock();
+        for (block_id, version) in written {
+            if let Some(block) = state.blocks.peek_mut(&block_id)
+                && block.version == version
+            {
+                block.dirty = false;
+            }
+            if state
+                .evicted
+                .get(&block_id)
+                .is_some_and(|block| block.version == version)
+            {
+                state.evicted.remove(&block_id);
+            }
+        }
+        state.epoch += 1;
+
+        result
+    }
+
+    fn write_evicted(&self, victims: &[u64]) -> BlockDeviceResult<()> {
+        if victims.is_empty() {
+            return Ok(());
+        }
+        self.write_back(|block_id| victims.contains(&block_id))?;
+        Ok(())
+    }
+
+    fn write_in_flight(&self, range: &Range<u64>) -> bool {
+        interrupts::without_interrupts(|| {
+            self.writes_in_flight
+                .lock()
+                .iter()
+                .any(|write| write.start < range.end && range.start < write.end)
+        })
+    }
+
+    fn bypasses(&self, length: usize) -> bool {
+        length >= BYPASS_SIZE && length % self.block_size() == 0
+    }
+}
+
+impl BlockDevice for CachedBlockDevice {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.inner.block_count()
+    }
+
+    fn flush(&self) -> BlockDeviceResult<()> {
+        self.sync()
+    }
+
+    fn details(&self) -> DeviceDetails {
+        self.inner.details()
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn discard(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.forget(range.clone());
+        self.inner.discard(range)
+    }
+
+    fn write_zeroes(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.forget(range.clone());
+        self.inner.write_zeroes(range)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.inner.queue_depth()
+    }
+
+    fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
+        let block_size = self.block_size();
+        let length = buffer.len();
+        let count = length.div_ceil(block_size);
+
+        if self.bypasses(length) {
+            let range = block_id..block_id + count as u64;
+            self.write_back(|block_id| range.contains(&block_id))?;
+            return self.inner.read_block(block_id, buffer);
+        }
+
+        let whole = length / block_size;
+        let mut index = 0;
+        while index < count {
+            let start = block_id + index as u64;
+            let offset = index * block_size;
+            if start >= self.inner.block_count() {
+                return Err(BlockDeviceError::OutOfBounds);
+            }
+
+            let mut state = self.state.lock();
+            if let Some(block) = state.peek(start) {
+                let chunk = &mut buffer[offset..(offset + block_size).min(length)];
+                chunk.copy_from_slice(&block.data[..chunk.len()]);
+                state.blocks.promote(&start);
+                index += 1;
+                continue;
+            }
+
+            // Read the whole run of missing blocks in one request
+            let mut end = index;
+            while end < whole && !state.contains(block_id + end as u64) {
+                end += 1;
+            }
+            let epoch = state.epoch;
+            drop(state);
+
+            if end == index {
+                let mut data = vec![0; block_size];
+                self.inner.read_block(start, &mut data)?;
+                self.fill(start, epoch, &mut data)?;
+
+                let chunk = &mut buffer[offset..];
+                chunk.copy_from_slice(&data[..chunk.len()]);
+                index += 1;
+                continue;
+            }
+
+            let run = &mut buffer[offset..end * block_size];
+            self.inner.read_block(start, run)?;
+            self.fill(start, epoch, run)?;
+            index = end;
+        }
+
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
+        let block_size = self.block_size();
+
+        if self.bypasses(buffer.len()) {
+            let count = (buffer.len() / block_size) as u64;
+            let _writeback = self.writeback.lock();
+            self.state.lock().remove(&(block_id..block_id + count));
+            return self.inner.write_block(block_id, buffer);
+        }
+
+        let mut victims = Vec::new();
+        let mut result = Ok(());
+        for (index, chunk) in buffer.chunks(block_size).enumerate() {
+            let block_id = block_id + index as u64;
+            let load = chunk.len() < block_size;
+            let mut state = match self.cached_block(block_id, load, &mut victims) {
+                Ok(state) => state,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            };
+
+            let version = state.next_version();
+            let block = state.get_mut(block_id, &mut victims).unwrap();
+            block.data[..chunk.len()].copy_from_slice(chunk);
+            block.dirty = true;
+            block.version = version;
+        }
+
+        self.write_evicted(&victims)?;
+        result
+    }
+
+    fn submit(&self, mut request: BlockRequest) {
+        let transfer = matches!(
+            request.operation,
+            BlockOperation::Read | BlockOperation::Write
+        );
+        if !transfer || !self.bypasses(request.length()) {
+            let result = request.execute(self);
+            return request.complete(result);
+        }
+
+        if let Err(err) = request.validate(self.block_size(), self.block_count()) {
+            return request.complete(Err(err));
+        }
+
+        let range = request.block_id..request.block_id + request.block_count(self.block_size());
+
+        // Reads must see dirty cached data, writes supersede it
+        if request.operation == BlockOperation::Read {
+            return match self.write_back(|block_id| range.contains(&block_id)) {
+                Ok(_) => self.inner.submit(request),
+                Err(err) => request.complete(Err(err)),
+            };
+        }
+
+        let writeback = self.writeback.lock();
+        self.state.lock().remove(&range);
+        interrupts::without_interrupts(|| self.writes_in_flight.lock().push(range.clone()));
+        drop(writeback);
+
+        let writes_in_flight = self.writes_in_flight.clone();
+        let callback = request.take_callback();
+        self.inner
+            .submit(request.with_callback(Box::new(move |result| {
+                interrupts::without_interrupts(|| {
+                    let mut writes = writes_in_flight.lock();
+                    if let Some(index) = writes.iter().position(|write| *write == range) {
+                        writes.swap_remove(index);
+                    }
+                });
+
+                if let Some(callback) = callback {
+                    callback(result);
+                }
+            })));
+    }
+}
+
+pub fn sync_all() {
+    let caches = BLOCK_CACHES
+        .lock()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>();
+
+    for cache in caches {
+        if let Err(err) = cache.sync() {
+            log::warn!("Failed to write back block cache: {err}");
+        }
+    }
+}
+
+pub fn writeback_thread() {
+    loop {
+        sync_all();
+        crate::syscall::sleep(WRITEBACK_INTERVAL_MS);
+    }
+}
@@ -0,0 +1,112 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use trashcrypt::{CryptError, HEADER_SIZE, Header, Xts};
+
+use super::block::{BlockDevice, BlockDeviceError, BlockDeviceResult, DeviceDetails};
+
+pub struct CryptBlockDevice {
+    parent: Arc<dyn BlockDevice>,
+    cipher: Xts,
+    sector_size: usize,
+    payload_block: u64,
+    block_count: u64,
+}
+
+impl CryptBlockDevice {
+    pub fn open(parent: Arc<dyn BlockDevice>, passphrase: &str) -> BlockDeviceResult<Self> {
+        let header = read_header(parent.as_ref())?.ok_or(BlockDeviceError::InvalidInput)?;
+
+        let sector_size = header.sector_size as usize;
+        if !sector_size.is_multiple_of(parent.block_size()) {
+            return Err(BlockDeviceError::InvalidInput);
+        }
+
+        let parent_blocks_per_sector = (sector_size / parent.block_size()) as u64;
+        let payload_block = header.payload_offset / parent.block_size() as u64;
+        let block_count =
+            parent.block_count().saturating_sub(payload_block) / parent_blocks_per_sector;
+        if block_count == 0 {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+
+        let cipher = header.unlock(passphrase.as_bytes())?;
+        Ok(Self {
+            parent,
+            cipher,
+            sector_size,
+            payload_block,
+            block_count,
+        })
+    }
+}
+
+impl CryptBlockDevice {
+    fn check_range(&self, block_id: u64, length: usize) -> BlockDeviceResult<u64> {
+        if !length.is_multiple_of(self.sector_size) {
+            return Err(BlockDeviceError::InvalidInput);
+        }
+
+        let block_count = (length / self.sector_size) as u64;
+        if block_id + block_count > self.block_count {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+
+        let parent_blocks_per_sector = (self.sector_size / self.parent.block_size()) as u64;
+        Ok(self.payload_block + block_id * parent_blocks_per_sector)
+    }
+}
+
+impl BlockDevice for CryptBlockDevice {
+    fn block_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn flush(&self) -> BlockDeviceResult<()> {
+        self.parent.flush()
+    }
+
+    fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
+        let parent_block = self.check_range(block_id, buffer.len())?;
+        self.parent.read_block(parent_block, buffer)?;
+
+        for (sector, data) in (block_id..).zip(buffer.chunks_exact_mut(self.sector_size)) {
+            self.cipher.decrypt_sector(sector, data);
+        }
+        Ok(())
+    }
+
+    // Encrypts into a copy, the caller's buffer must stay plaintext.
+    fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
+        let parent_block = self.check_range(block_id, buffer.len())?;
+        let mut encrypted = buffer.to_vec();
+
+        for (sector, data) in (block_id..).zip(encrypted.chunks_exact_mut(self.sector_size)) {
+            self.cipher.encrypt_sector(sector, data);
+        }
+        self.parent.write_block(parent_block, &encrypted)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.parent.queue_depth()
+    }
+
+    fn details(&self) -> DeviceDetails {
+        self.parent.details()
+    }
+}
+
+// Returns None when the device does not start with an encryption header.
+pub fn read_header(device: &dyn BlockDevice) -> BlockDeviceResult<Option<Header>> {
+    let mut block = vec![0; device.block_size().max(HEADER_SIZE)];
+    device.read_block(0, &mut block)?;
+
+    match Header::decode(&block) {
+        Ok(header) => Ok(Some(header)),
+        Err(CryptError::BadMagic) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
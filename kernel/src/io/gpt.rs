@@ -0,0 +1,269 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use anyhow::{Result, anyhow};
+use gpt_disk_io::Disk;
+use gpt_disk_types::{GptHeader, GptPartitionEntry, GptPartitionEntryArray};
+use gpt_disk_types::{GptPartitionName, GptPartitionType, Guid, LbaLe, U32Le};
+
+use super::block::{BlockDevice, BlockDeviceWrapper};
+use crate::arch::random_u64;
+
+const ENTRY_COUNT: u32 = 128;
+const ENTRY_SIZE: u32 = 128;
+const ALIGNMENT: u64 = 1024 * 1024;
+
+pub struct GptTable {
+    header: GptHeader,
+    entries: Vec<u8>,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl GptTable {
+    pub fn new(device: &dyn BlockDevice) -> Self {
+        let block_size = device.block_size();
+        let block_count = device.block_count();
+        let array_blocks = (ENTRY_COUNT * ENTRY_SIZE) as u64 / block_size as u64;
+
+        let header = GptHeader {
+            my_lba: LbaLe::from_u64(1),
+            alternate_lba: LbaLe::from_u64(block_count - 1),
+            first_usable_lba: LbaLe::from_u64(2 + array_blocks),
+            last_usable_lba: LbaLe::from_u64(block_count - 2 - array_blocks),
+            disk_guid: random_guid(),
+            partition_entry_lba: LbaLe::from_u64(2),
+            number_of_partition_entries: U32Le::from_u32(ENTRY_COUNT),
+            size_of_partition_entry: U32Le::from_u32(ENTRY_SIZE),
+            ..GptHeader::default()
+        };
+
+        Self {
+            header,
+            entries: vec![0; (ENTRY_COUNT * ENTRY_SIZE) as usize],
+            block_size,
+            block_count,
+        }
+    }
+
+    pub fn read(device: Arc<dyn BlockDevice>) -> Result<Option<Self>> {
+        let block_size = device.block_size();
+        let block_count = device.block_count();
+        let mut block_buf = vec![0; block_size];
+        let mut disk = Disk::new(BlockDeviceWrapper(device))?;
+
+        let header = disk.read_primary_gpt_header(&mut block_buf)?;
+        if !header.is_signature_valid() {
+            return Ok(None);
+        }
+
+        let layout = header.get_partition_entry_array_layout()?;
+        let length = layout
+            .num_bytes_rounded_to_block(gpt_block_size(block_size)?)
+            .ok_or(anyhow!("GPT entry array is too large"))?;
+        let mut entries = vec![0; length as usize];
+        disk.read_gpt_partition_entry_array(layout, &mut entries)?;
+
+        Ok(Some(Self {
+            header,
+            entries,
+            block_size,
+            block_count,
+        }))
+    }
+
+    pub fn entry(&self, index: usize) -> Option<GptPartitionEntry> {
+        let size = self.header.size_of_partition_entry.to_u32() as usize;
+        let raw = self
+            .entries
+            .get(index * size..index * size + size_of::<GptPartitionEntry>())?;
+        Some(unsafe { raw.as_ptr().cast::<GptPartitionEntry>().read_unaligned() })
+    }
+
+    pub fn find(&self, guid: Guid) -> Option<usize> {
+        (0..self.entry_count()).find(|&index| {
+            self.entry(index)
+                .is_some_and(|entry| entry.is_used() && entry.unique_partition_guid == guid)
+        })
+    }
+
+    pub fn create(
+        &mut self,
+        partition_type: GptPartitionType,
+        start: Option<u64>,
+        block_count: u64,
+        name: &str,
+    ) -> Result<usize> {
+        let index = (0..self.entry_count())
+            .find(|&index| self.entry(index).is_some_and(|entry| !entry.is_used()))
+            .ok_or(anyhow!("GPT entry array is full"))?;
+
+        let start = match start {
+            Some(start) => start,
+            None => self
+                .find_free(block_count)
+                .ok_or(anyhow!("Not enough free space for the partition"))?,
+        };
+        self.check_range(start, block_count, None)?;
+
+        let entry = GptPartitionEntry {
+            partition_type_guid: partition_type,
+            unique_partition_guid: random_guid(),
+            starting_lba: LbaLe::from_u64(start),
+            ending_lba: LbaLe::from_u64(start + block_count - 1),
+            name: name
+                .parse::<GptPartitionName>()
+                .map_err(|err| anyhow!("{err:?}"))?,
+            ..GptPartitionEntry::default()
+        };
+        self.set_entry(index, entry);
+
+        Ok(index)
+    }
+
+    pub fn delete(&mut self, index: usize) -> Result<()> {
+        self.used_entry(index)?;
+        self.set_entry(index, GptPartitionEntry::default());
+        Ok(())
+    }
+
+    pub fn resize(&mut self, index: usize, block_count: u64) -> Result<()> {
+        let mut entry = self.used_entry(index)?;
+        let start = entry.starting_lba.to_u64();
+        self.check_range(start, block_count, Some(index))?;
+
+        entry.ending_lba = LbaLe::from_u64(start + block_count - 1);
+        self.set_entry(index, entry);
+        Ok(())
+    }
+
+    pub fn rename(&mut self, index: usize, name: &str) -> Result<()> {
+        let mut entry = self.used_entry(index)?;
+        entry.name = name.parse().map_err(|err| anyhow!("{err:?}"))?;
+        self.set_entry(index, entry);
+        Ok(())
+    }
+
+    // Writes the backup copy first so an interrupted update still leaves a
+    // valid table behind.
+    pub fn write(&mut self, device: Arc<dyn BlockDevice>) -> Result<()> {
+        let block_size = gpt_block_size(self.block_size)?;
+        let array_blocks = self.entries.len() as u64 / self.block_size as u64;
+
+        let mut primary = self.header;
+        let layout = primary.get_partition_entry_array_layout()?;
+        let array = GptPartitionEntryArray::new(layout, block_size, &mut self.entries)?;
+        primary.partition_entry_array_crc32 = array.calculate_crc32();
+        primary.update_header_crc32();
+
+        let mut secondary = primary;
+        secondary.my_lba = LbaLe::from_u64(self.block_count - 1);
+        secondary.alternate_lba = LbaLe::from_u64(1);
+        secondary.partition_entry_lba = LbaLe::from_u64(self.block_count - 1 - array_blocks);
+        secondary.update_header_crc32();
+
+        let mut block_buf = vec![0; self.block_size];
+        let mut disk = Disk::new(BlockDeviceWrapper(device.clone()))?;
+
+        let secondary_layout = secondary.get_partition_entry_array_layout()?;
+        let array = GptPartitionEntryArray::new(secondary_layout, block_size, &mut self.entries)?;
+        disk.write_gpt_partition_entry_array(&array)?;
+        disk.write_secondary_gpt_header(&secondary, &mut block_buf)?;
+
+        let array = GptPartitionEntryArray::new(layout, block_size, &mut self.entries)?;
+        disk.write_gpt_partition_entry_array(&array)?;
+        disk.write_primary_gpt_header(&primary, &mut block_buf)?;
+        disk.write_protective_mbr(&mut block_buf)?;
+
+        device.flush()?;
+        self.header = primary;
+        Ok(())
+    }
+}
+
+impl GptTable {
+    fn entry_count(&self) -> usize {
+        self.header.number_of_partition_entries.to_u32() as usize
+    }
+
+    fn used_entry(&self, index: usize) -> Result<GptPartitionEntry> {
+        self.entry(index)
+            .filter(|entry| entry.is_used())
+            .ok_or(anyhow!("GPT entry {index} is not in use"))
+    }
+
+    fn set_entry(&mut self, index: usize, entry: GptPartitionEntry) {
+        let size = self.header.size_of_partition_entry.to_u32() as usize;
+        let raw = &mut self.entries[index * size..(index + 1) * size];
+        raw.fill(0);
+        unsafe {
+            raw.as_mut_ptr()
+                .cast::<GptPartitionEntry>()
+                .write_unaligned(entry)
+        };
+    }
+
+    fn used_ranges(&self, skip: Option<usize>) -> Vec<(u64, u64)> {
+        let mut ranges = (0..self.entry_count())
+            .filter(|&index| Some(index) != skip)
+            .filter_map(|index| self.entry(index))
+            .filter(|entry| entry.is_used())
+            .map(|entry| (entry.starting_lba.to_u64(), entry.ending_lba.to_u64()))
+            .collect::<Vec<_>>();
+        ranges.sort_unstable();
+        ranges
+    }
+
+    fn check_range(&self, start: u64, block_count: u64, skip: Option<usize>) -> Result<()> {
+        let end = start
+            .checked_add(block_count)
+            .filter(|_| block_count > 0)
+            .ok_or(anyhow!("Invalid partition length"))?;
+
+        if start < self.header.first_usable_lba.to_u64()
+            || end - 1 > self.header.last_usable_lba.to_u64()
+        {
+            anyhow::bail!("Partition lies outside the usable area");
+        }
+
+        if self
+            .used_ranges(skip)
+            .iter()
+            .any(|&(first, last)| start <= last && first < end)
+        {
+            anyhow::bail!("Partition overlaps an existing partition");
+        }
+
+        Ok(())
+    }
+
+    fn find_free(&self, block_count: u64) -> Option<u64> {
+        let alignment = (ALIGNMENT / self.block_size as u64).max(1);
+        let mut candidate = self
+            .header
+            .first_usable_lba
+            .to_u64()
+            .next_multiple_of(alignment);
+
+        for (first, last) in self.used_ranges(None) {
+            if candidate + block_count <= first {
+                return Some(candidate);
+            }
+            candidate = candidate.max((last + 1).next_multiple_of(alignment));
+        }
+
+        let last_usable = self.header.last_usable_lba.to_u64();
+        (candidate + block_count - 1 <= last_usable).then_some(candidate)
+    }
+}
+
+fn gpt_block_size(block_size: usize) -> Result<gpt_disk_types::BlockSize> {
+    gpt_disk_types::BlockSize::new(block_size as u32).ok_or(anyhow!("Unsupported block size"))
+}
+
+fn random_guid() -> Guid {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&random_u64().to_le_bytes());
+    bytes[8..].copy_from_slice(&random_u64().to_le_bytes());
+    Guid::from_random_bytes(bytes)
+}
@@ -0,0 +1,105 @@
+use alloc::format;
+use alloc::sync::Arc;
+
+use super::block::{BlockDevice, BlockDeviceError, BlockDeviceResult};
+use crate::fs::{FileType, FsError, Inode, VFS};
+
+pub const LOOP_BLOCK_SIZE: usize = 512;
+
+pub struct LoopBlockDevice {
+    inode: Arc<dyn Inode>,
+    block_count: u64,
+}
+
+impl LoopBlockDevice {
+    pub fn open(path: &str) -> Result<Self, FsError> {
+        let inode = VFS.read().lookup(path)?;
+        Self::new(inode)
+    }
+
+    // Trailing bytes that don't fill a whole block are not exposed.
+    pub fn new(inode: Arc<dyn Inode>) -> Result<Self, FsError> {
+        let metadata = inode.metadata();
+        if metadata.file_type != FileType::Regular {
+            return Err(FsError::InvalidArgument);
+        }
+
+        Ok(Self {
+            inode,
+            block_count: metadata.size / LOOP_BLOCK_SIZE as u64,
+        })
+    }
+}
+
+impl LoopBlockDevice {
+    fn offset(&self, block_id: u64, length: usize) -> BlockDeviceResult<u64> {
+        let block_count = length.div_ceil(LOOP_BLOCK_SIZE) as u64;
+        if block_id + block_count > self.block_count {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+        Ok(block_id * LOOP_BLOCK_SIZE as u64)
+    }
+}
+
+impl BlockDevice for LoopBlockDevice {
+    fn block_size(&self) -> usize {
+        LOOP_BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn flush(&self) -> BlockDeviceResult<()> {
+        self.inode.sync().map_err(map_fs_error)
+    }
+
+    fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
+        let mut offset = self.offset(block_id, buffer.len())?;
+        let mut position = 0;
+
+        while position < buffer.len() {
+            let read = self
+                .inode
+                .read_at(offset, &mut buffer[position..])
+                .map_err(map_fs_error)?;
+            if read == 0 {
+                buffer[position..].fill(0);
+                break;
+            }
+            position += read;
+            offset += read as u64;
+        }
+
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
+        let mut offset = self.offset(block_id, buffer.len())?;
+        let mut position = 0;
+
+        while position < buffer.len() {
+            let written = self
+                .inode
+                .write_at(offset, &buffer[position..])
+                .map_err(map_fs_error)?;
+            if written == 0 {
+                return Err(BlockDeviceError::IoError(
+                    "Short write to backing file".into(),
+                ));
+            }
+            position += written;
+            offset += written as u64;
+        }
+
+        Ok(())
+    }
+}
+
+fn map_fs_error(err: FsError) -> BlockDeviceError {
+    match err {
+        FsError::Device(err) => err,
+        FsError::ReadOnly => BlockDeviceError::ReadOnly,
+        err => BlockDeviceError::IoError(format!("{err}")),
+    }
+}
@@ -2,18 +2,33 @@ use alloc::collections::btree_map::BTreeMap;
 use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use anyhow::{Result, anyhow};
+use core::any::Any;
+use core::mem::take;
+use core::ops::{Deref, DerefMut, Range};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use derive_more::Display;
 use gpt_disk_io::Disk;
+use gpt_disk_types::{GptPartitionEntry, GptPartitionType, Guid, guid};
+use spin::{Lazy, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use thiserror::Error;
 use x86_64::structures::paging::{PageSize, Size4KiB};
 
-use super::block::{BlockDevice, BlockDeviceError};
-use super::block::{BlockDeviceWrapper, PartitionBlockDevice};
+use super::block::{BlockCapabilities, BlockDevice, BlockDeviceError, BlockDeviceResult};
+use super::block::{BlockDeviceWrapper, DeviceDetails, PartitionBlockDevice};
+use super::cache::CachedBlockDevice;
+use super::crypt::{CryptBlockDevice, read_header};
+use super::gpt::GptTable;
+use super::mbr::Mbr;
+use super::raid::{MirrorBlockDevice, RaidLevel, StripeBlockDevice};
+use super::request::BlockRequest;
 use crate::{drivers::nvme::NvmeBlockDevice, mem::AlignedBuffer};
 
+pub const LINUX_SWAP_PARTITION: GptPartitionType =
+    GptPartitionType(guid!("0657fd6d-a4ab-43c4-84e5-0933c84b4f4f"));
+
 #[derive(Error, Debug)]
 pub enum DeviceManagerError {
     #[error("Device not found")]
@@ -28,6 +43,18 @@ pub enum DeviceManagerError {
     Other(String),
     #[error("Memory allocation failed: {0}")]
     AllocationError(String),
+    #[error("Device has no GPT partition table")]
+    NoPartitionTable,
+    #[error("Partition is in use")]
+    PartitionBusy,
+    #[error("Device is not encrypted")]
+    NotEncrypted,
+    #[error("Device is already unlocked as {0}")]
+    AlreadyUnlocked(String),
+    #[error("Invalid RAID member list")]
+    InvalidMembers,
+    #[error("Device is not a RAID array")]
+    NotAnArray,
 }
 
 #[derive(Debug, Display, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -44,11 +71,19 @@ impl DeviceId {
 pub enum RootDeviceType {
     ScsiLike,
     NvmeNamespace,
+    Optical,
+    Virtio,
+    Ram,
+    Loop,
 }
 
 pub enum DeviceSource {
     ScsiLike(Arc<dyn BlockDevice>),
     NvmeController(Vec<NvmeBlockDevice>),
+    Optical(Arc<dyn BlockDevice>),
+    Virtio(Arc<dyn BlockDevice>),
+    Ram(Arc<dyn BlockDevice>),
+    Loop(Arc<dyn BlockDevice>),
 }
 
 impl DeviceSource {
@@ -56,6 +91,10 @@ impl DeviceSource {
         match self {
             DeviceSource::ScsiLike(_) => "sd",
             DeviceSource::NvmeController(_) => "nvme",
+            DeviceSource::Optical(_) => "sr",
+            DeviceSource::Virtio(_) => "vd",
+            DeviceSource::Ram(_) => "ram",
+            DeviceSource::Loop(_) => "loop",
         }
     }
 }
@@ -65,22 +104,241 @@ pub enum DeviceKind {
     #[display("Root device")]
     Root(RootDeviceType),
     #[display("Partition device (parent: {parent})")]
-    Partition { parent: DeviceId },
+    Partition {
+        parent: DeviceId,
+        partition_type: PartitionType,
+    },
+    #[display("Encrypted device (parent: {parent})")]
+    Crypt { parent: DeviceId },
+    #[display("{level} array ({} members)", members.len())]
+    Raid {
+        level: RaidLevel,
+        members: Vec<DeviceId>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Gpt(GptPartitionType),
+    Mbr(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionInfo {
+    pub guid: Guid,
+    pub label: String,
 }
 
 #[derive(Clone)]
-#[allow(dead_code)]
 pub struct DeviceInfo {
-    id: DeviceId,
-    name: String,
+    pub id: DeviceId,
+    pub name: String,
+    pub device: Arc<dyn BlockDevice>,
+    pub kind: DeviceKind,
+    pub partition: Option<PartitionInfo>,
+    users: Arc<AtomicUsize>,
+}
+
+impl DeviceInfo {
+    // Mounts, swap, mappings and arrays open the device, which keeps it busy
+    // until the returned handle is dropped.
+    pub fn open(&self) -> Arc<dyn BlockDevice> {
+        self.open_with(self.device.clone())
+    }
+
+    pub fn in_use(&self) -> bool {
+        self.users.load(Ordering::Acquire) > 0
+    }
+
+    fn open_with(&self, device: Arc<dyn BlockDevice>) -> Arc<dyn BlockDevice> {
+        self.users.fetch_add(1, Ordering::AcqRel);
+        Arc::new(OpenDevice {
+            device,
+            users: self.users.clone(),
+        })
+    }
+}
+
+struct OpenDevice {
     device: Arc<dyn BlockDevice>,
-    kind: DeviceKind,
+    users: Arc<AtomicUsize>,
+}
+
+impl Drop for OpenDevice {
+    fn drop(&mut self) {
+        self.users.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl BlockDevice for OpenDevice {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn flush(&self) -> BlockDeviceResult<()> {
+        self.device.flush()
+    }
+
+    fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
+        self.device.read_block(block_id, buffer)
+    }
+
+    fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
+        self.device.write_block(block_id, buffer)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.device.queue_depth()
+    }
+
+    fn details(&self) -> DeviceDetails {
+        self.device.details()
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        self.device.capabilities()
+    }
+
+    fn discard(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.device.discard(range)
+    }
+
+    fn write_zeroes(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.device.write_zeroes(range)
+    }
+
+    fn submit(&self, request: BlockRequest) {
+        self.device.submit(request)
+    }
+}
+
+#[derive(Clone)]
+pub enum DeviceEvent {
+    Registered(DeviceInfo),
+    Unregistered(DeviceInfo),
+}
+
+pub type DeviceListener = fn(&DeviceEvent);
+
+pub static DEVICE_MANAGER: Lazy<DeviceManagerLock> = Lazy::new(DeviceManagerLock::default);
+
+// Events raised while the manager is locked are queued and handed to the
+// listeners once the write guard is dropped, so listeners may lock the
+// manager themselves.
+#[derive(Default)]
+pub struct DeviceManagerLock(RwLock<DeviceManager>);
+
+impl DeviceManagerLock {
+    pub fn read(&self) -> RwLockReadGuard<'_, DeviceManager> {
+        self.0.read()
+    }
+
+    pub fn write(&self) -> DeviceManagerGuard<'_> {
+        DeviceManagerGuard(Some(self.0.write()))
+    }
+}
+
+pub struct DeviceManagerGuard<'a>(Option<RwLockWriteGuard<'a, DeviceManager>>);
+
+impl Deref for DeviceManagerGuard<'_> {
+    type Target = DeviceManager;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for DeviceManagerGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut().unwrap()
+    }
+}
+
+impl Drop for DeviceManagerGuard<'_> {
+    fn drop(&mut self) {
+        let Some(mut manager) = self.0.take() else {
+            return;
+        };
+        let events = take(&mut manager.pending);
+        drop(manager);
+
+        for (listeners, event) in events {
+            for listener in listeners {
+                listener(&event);
+            }
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct DeviceManager {
     devices: BTreeMap<DeviceId, DeviceInfo>,
     names: BTreeMap<String, DeviceId>,
+    listeners: Vec<DeviceListener>,
+    pending: Vec<(Vec<DeviceListener>, DeviceEvent)>,
+}
+
+impl DeviceManager {
+    pub fn get(&self, id: DeviceId) -> Option<&DeviceInfo> {
+        self.devices.get(&id)
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<&DeviceInfo> {
+        self.names.get(name).and_then(|id| self.devices.get(id))
+    }
+
+    pub fn get_by_partition_guid(&self, guid: Guid) -> Option<&DeviceInfo> {
+        self.partitions()
+            .find(|(_, partition)| partition.guid == guid)
+            .map(|(info, _)| info)
+    }
+
+    pub fn get_by_label(&self, label: &str) -> Option<&DeviceInfo> {
+        self.partitions()
+            .find(|(_, partition)| partition.label == label)
+            .map(|(info, _)| info)
+    }
+
+    pub fn details(&self, name: &str) -> Option<DeviceDetails> {
+        self.get_by_name(name).map(|info| info.device.details())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceInfo> {
+        self.devices.values()
+    }
+
+    pub fn children(&self, parent: DeviceId) -> impl Iterator<Item = &DeviceInfo> {
+        self.devices.values().filter(move |info| match &info.kind {
+            DeviceKind::Partition { parent: id, .. } | DeviceKind::Crypt { parent: id } => {
+                *id == parent
+            }
+            DeviceKind::Raid { members, .. } => members.contains(&parent),
+            _ => false,
+        })
+    }
+
+    // The new listener is sent a registration event for every known device.
+    pub fn subscribe(&mut self, listener: DeviceListener) {
+        for info in self.devices.values() {
+            let event = DeviceEvent::Registered(info.clone());
+            self.pending.push((vec![listener], event));
+        }
+        self.listeners.push(listener);
+    }
+
+    fn partitions(&self) -> impl Iterator<Item = (&DeviceInfo, &PartitionInfo)> {
+        self.devices
+            .values()
+            .filter_map(|info| info.partition.as_ref().map(|partition| (info, partition)))
+    }
+
+    fn notify(&mut self, event: DeviceEvent) {
+        self.pending.push((self.listeners.clone(), event));
+    }
 }
 
 impl DeviceManager {
@@ -111,25 +369,61 @@ impl DeviceManager {
         };
 
         match device {
-            DeviceSource::ScsiLike(device) => self.register_internal(
-                find_name(prefix, create_scsi_name),
-                device,
-                DeviceKind::Root(RootDeviceType::ScsiLike),
-            ),
+            DeviceSource::ScsiLike(device) => {
+                self.register_internal(
+                    find_name(prefix, create_scsi_name),
+                    device,
+                    DeviceKind::Root(RootDeviceType::ScsiLike),
+                    None,
+                )?;
+            }
             DeviceSource::NvmeController(devices) => {
                 let id = find_name(prefix, |index| index.to_string());
 
                 for device in devices {
                     self.register_internal(
-                        format!("{}n{}", id, device.namespace.id()),
+                        format!("{}n{}", id, device.namespace.id),
                         Arc::new(device),
                         DeviceKind::Root(RootDeviceType::NvmeNamespace),
+                        None,
                     )?;
                 }
-
-                Ok(())
+            }
+            DeviceSource::Optical(device) => {
+                self.register_internal(
+                    find_name(prefix, |index| index.to_string()),
+                    device,
+                    DeviceKind::Root(RootDeviceType::Optical),
+                    None,
+                )?;
+            }
+            DeviceSource::Virtio(device) => {
+                self.register_internal(
+                    find_name(prefix, create_scsi_name),
+                    device,
+                    DeviceKind::Root(RootDeviceType::Virtio),
+                    None,
+                )?;
+            }
+            DeviceSource::Ram(device) => {
+                self.register_internal(
+                    find_name(prefix, |index| index.to_string()),
+                    device,
+                    DeviceKind::Root(RootDeviceType::Ram),
+                    None,
+                )?;
+            }
+            DeviceSource::Loop(device) => {
+                self.register_internal(
+                    find_name(prefix, |index| index.to_string()),
+                    device,
+                    DeviceKind::Root(RootDeviceType::Loop),
+                    None,
+                )?;
             }
         }
+
+        Ok(())
     }
 
     fn register_internal(
@@ -137,53 +431,65 @@ impl DeviceManager {
         name: String,
         device: Arc<dyn BlockDevice>,
         kind: DeviceKind,
-    ) -> Result<()> {
+        partition: Option<PartitionInfo>,
+    ) -> Result<DeviceId> {
+        if self.names.contains_key(&name) {
+            anyhow::bail!(DeviceManagerError::NameAlreadyExists(name));
+        }
+
         let id = DeviceId::new();
 
+        // RAM disks are already memory, so caching them only doubles the copy.
+        let device: Arc<dyn BlockDevice> = match kind {
+            DeviceKind::Root(RootDeviceType::Ram)
+            | DeviceKind::Partition { .. }
+            | DeviceKind::Crypt { .. }
+            | DeviceKind::Raid { .. } => device,
+            DeviceKind::Root(_) => CachedBlockDevice::with_default_capacity(device),
+        };
+
         let info = DeviceInfo {
             id,
             name: name.clone(),
             device,
             kind: kind.clone(),
+            partition,
+            users: Arc::default(),
         };
 
-        self.devices.insert(id, info);
+        self.devices.insert(id, info.clone());
         self.names.insert(name.clone(), id);
         log::info!("{kind} (name: {name}, id: {id:?}) registered");
+        self.notify(DeviceEvent::Registered(info));
 
-        if matches!(kind, DeviceKind::Root(_)) {
-            self.scan_partitions(id)?;
+        if matches!(kind, DeviceKind::Root(root_type) if root_type != RootDeviceType::Optical) {
+            if let Err(err) = self.scan_partitions(id) {
+                log::warn!("Failed to scan partitions on {name}: {err}");
+            }
         }
 
-        Ok(())
+        Ok(id)
     }
 
-    #[allow(dead_code)]
     pub fn unregister(&mut self, id: DeviceId) -> Result<()> {
         let info = self
             .devices
             .get(&id)
             .ok_or(DeviceManagerError::DeviceNotFound)?;
 
-        if matches!(info.kind, DeviceKind::Root { .. }) {
-            let partitions = self
-                .devices
-                .iter()
-                .filter(|(_, info)| match &info.kind {
-                    DeviceKind::Partition { parent } => *parent == id,
-                    _ => false,
-                })
-                .map(|(part_id, _)| *part_id)
-                .collect::<Vec<DeviceId>>();
-
-            for partition in partitions {
-                self.unregister(partition)?;
-            }
+        let children = self
+            .children(id)
+            .map(|info| info.id)
+            .collect::<Vec<DeviceId>>();
+
+        for child in children {
+            self.unregister(child)?;
         }
 
         if let Some(device) = self.devices.remove(&id) {
             self.names.remove(&device.name);
             log::info!("{} (name: {}) unregistered.", device.kind, device.name);
+            self.notify(DeviceEvent::Unregistered(device));
         }
 
         Ok(())
@@ -200,38 +506,359 @@ impl DeviceManager {
             anyhow::bail!(DeviceManagerError::InvalidParent);
         }
 
+        // A valid GPT takes precedence, so hybrid MBRs only contribute their
+        // entries when the GPT header is missing.
+        if self.scan_gpt(&root_info)? {
+            return Ok(());
+        }
+
+        let device = root_info.device.as_ref();
+        let Some(mbr) = Mbr::read(device)? else {
+            return Ok(());
+        };
+
+        for partition in mbr.partitions(device)? {
+            let entry = partition.entry;
+            let end = entry.start_block + entry.block_count;
+            if end > device.block_count() {
+                log::warn!(
+                    "Skipping MBR partition {} on {}: extends past end of disk",
+                    partition.number,
+                    root_info.name
+                );
+                continue;
+            }
+
+            let partition_device = PartitionBlockDevice::new(
+                root_info.device.clone(),
+                entry.start_block,
+                entry.block_count,
+            )?;
+
+            self.register_internal(
+                partition_name(&root_info, partition.number),
+                Arc::new(partition_device),
+                DeviceKind::Partition {
+                    parent: root_info.id,
+                    partition_type: PartitionType::Mbr(entry.partition_type),
+                },
+                None,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn scan_gpt(&mut self, root_info: &DeviceInfo) -> Result<bool> {
         let mut block_buf = AlignedBuffer::new(512, Size4KiB::SIZE as usize)
             .ok_or(anyhow!("Failed to allocate buffer for GPT scan"))?;
         let mut disk = Disk::new(BlockDeviceWrapper(root_info.device.clone()))?;
 
         let primary_header = disk.read_primary_gpt_header(&mut block_buf)?;
+        if !primary_header.is_signature_valid() {
+            return Ok(false);
+        }
         let layout = primary_header.get_partition_entry_array_layout()?;
 
-        let is_nvme = matches!(
-            root_info.kind,
-            DeviceKind::Root(RootDeviceType::NvmeNamespace)
-        );
-        let part_divider = if is_nvme { "p" } else { "" };
-
-        for (index, entry) in disk
+        let entries = disk
             .gpt_partition_entry_array_iter(layout, &mut block_buf)?
             .enumerate()
             .filter_map(|(i, e)| e.ok().map(|e| (i, e)))
             .filter(|(_, e)| e.is_used())
+            .collect::<Vec<_>>();
+
+        for (index, entry) in entries {
+            self.register_gpt_partition(root_info, index, &entry)?;
+        }
+
+        Ok(true)
+    }
+
+    fn register_gpt_partition(
+        &mut self,
+        root_info: &DeviceInfo,
+        index: usize,
+        entry: &GptPartitionEntry,
+    ) -> Result<DeviceId> {
+        let partition = PartitionBlockDevice::new(
+            root_info.device.clone(),
+            entry.starting_lba.to_u64(),
+            entry.ending_lba.to_u64() - entry.starting_lba.to_u64() + 1,
+        )?;
+
+        let partition_info = PartitionInfo {
+            guid: entry.unique_partition_guid,
+            label: entry.name.to_string(),
+        };
+
+        self.register_internal(
+            partition_name(root_info, index + 1),
+            Arc::new(partition),
+            DeviceKind::Partition {
+                parent: root_info.id,
+                partition_type: PartitionType::Gpt(entry.partition_type_guid),
+            },
+            Some(partition_info),
+        )
+    }
+}
+
+impl DeviceManager {
+    pub fn create_partition_table(&mut self, root_id: DeviceId) -> Result<()> {
+        let root_info = self.root(root_id)?;
+        if self.children(root_id).next().is_some() {
+            anyhow::bail!(DeviceManagerError::PartitionBusy);
+        }
+
+        GptTable::new(root_info.device.as_ref()).write(root_info.device.clone())?;
+        log::info!("Created GPT partition table on {}", root_info.name);
+        Ok(())
+    }
+
+    pub fn create_partition(
+        &mut self,
+        root_id: DeviceId,
+        partition_type: GptPartitionType,
+        start: Option<u64>,
+        block_count: u64,
+        name: &str,
+    ) -> Result<DeviceId> {
+        let root_info = self.root(root_id)?;
+        let mut table = GptTable::read(root_info.device.clone())?
+            .ok_or(DeviceManagerError::NoPartitionTable)?;
+
+        let index = table.create(partition_type, start, block_count, name)?;
+        table.write(root_info.device.clone())?;
+
+        let entry = table.entry(index).unwrap();
+        self.register_gpt_partition(&root_info, index, &entry)
+    }
+
+    pub fn delete_partition(&mut self, id: DeviceId) -> Result<()> {
+        let (root_info, mut table, index) = self.gpt_entry(id)?;
+        self.ensure_unused(id)?;
+
+        table.delete(index)?;
+        table.write(root_info.device.clone())?;
+        self.unregister(id)
+    }
+
+    pub fn resize_partition(&mut self, id: DeviceId, block_count: u64) -> Result<DeviceId> {
+        let (root_info, mut table, index) = self.gpt_entry(id)?;
+        self.ensure_unused(id)?;
+
+        table.resize(index, block_count)?;
+        table.write(root_info.device.clone())?;
+        self.unregister(id)?;
+
+        let entry = table.entry(index).unwrap();
+        self.register_gpt_partition(&root_info, index, &entry)
+    }
+
+    pub fn rename_partition(&mut self, id: DeviceId, name: &str) -> Result<()> {
+        let (root_info, mut table, index) = self.gpt_entry(id)?;
+
+        table.rename(index, name)?;
+        table.write(root_info.device.clone())?;
+
+        if let Some(partition) = self
+            .devices
+            .get_mut(&id)
+            .and_then(|info| info.partition.as_mut())
         {
-            let partition = PartitionBlockDevice::new(
-                root_info.device.clone(),
-                entry.starting_lba.to_u64(),
-                entry.ending_lba.to_u64() - entry.starting_lba.to_u64() + 1,
-            )?;
+            partition.label = name.to_string();
+        }
+        Ok(())
+    }
+}
 
-            self.register_internal(
-                format!("{}{}{}", root_info.name, part_divider, index + 1),
-                Arc::new(partition),
-                DeviceKind::Partition { parent: root_id },
-            )?;
+impl DeviceManagerLock {
+    // The mapping is named after its parent, so sda2 unlocks as sda2-crypt.
+    // Key derivation is slow on purpose, so it runs without the manager
+    // locked and the parent is checked again before registering.
+    pub fn unlock(&self, id: DeviceId, passphrase: &str) -> Result<DeviceId> {
+        let info = self.read().crypt_parent(id)?;
+        if read_header(info.device.as_ref())?.is_none() {
+            anyhow::bail!(DeviceManagerError::NotEncrypted);
         }
 
+        let device = CryptBlockDevice::open(info.open(), passphrase)?;
+
+        let mut manager = self.write();
+        manager.crypt_parent(id)?;
+        manager.register_internal(
+            format!("{}-crypt", info.name),
+            Arc::new(device),
+            DeviceKind::Crypt { parent: id },
+            None,
+        )
+    }
+}
+
+impl DeviceManager {
+    pub fn lock(&mut self, id: DeviceId) -> Result<()> {
+        let info = self.get(id).ok_or(DeviceManagerError::DeviceNotFound)?;
+        if !matches!(info.kind, DeviceKind::Crypt { .. }) {
+            anyhow::bail!(DeviceManagerError::NotEncrypted);
+        }
+
+        self.ensure_unused(id)?;
+        self.unregister(id)
+    }
+}
+
+impl DeviceManager {
+    pub fn create_mirror(&mut self, members: &[DeviceId]) -> Result<DeviceId> {
+        let device = MirrorBlockDevice::new(self.raid_members(members)?)?;
+        self.register_raid(RaidLevel::Mirror, members, Arc::new(device))
+    }
+
+    pub fn create_stripe(&mut self, members: &[DeviceId], chunk_size: usize) -> Result<DeviceId> {
+        let device = StripeBlockDevice::new(self.raid_members(members)?, chunk_size)?;
+        self.register_raid(RaidLevel::Stripe, members, Arc::new(device))
+    }
+
+    pub fn stop_array(&mut self, id: DeviceId) -> Result<()> {
+        let info = self.get(id).ok_or(DeviceManagerError::DeviceNotFound)?;
+        let DeviceKind::Raid { members, .. } = info.kind.clone() else {
+            anyhow::bail!(DeviceManagerError::NotAnArray);
+        };
+
+        self.ensure_unused(id)?;
+        self.unregister(id)?;
+
+        // The array wrote to the members behind their caches.
+        for member in members.iter().filter_map(|id| self.get(*id)) {
+            if let Some(cache) = cached(&member.device) {
+                cache.forget(0..u64::MAX);
+            }
+        }
         Ok(())
     }
+
+    // Resyncing takes a while, so callers get the mirror itself and run it
+    // without holding the manager.
+    pub fn mirror(&self, id: DeviceId) -> Result<Arc<MirrorBlockDevice>> {
+        let info = self.get(id).ok_or(DeviceManagerError::DeviceNotFound)?;
+        let device: Arc<dyn Any + Send + Sync> = info.device.clone();
+        device
+            .downcast::<MirrorBlockDevice>()
+            .map_err(|_| anyhow!(DeviceManagerError::NotAnArray))
+    }
+
+    // Members are used whole and must not be in use by anything else,
+    // including their own partitions. Arrays sit below the member caches, so
+    // a failed write reaches the array and faults the member instead of
+    // being lost in writeback.
+    fn raid_members(&self, ids: &[DeviceId]) -> Result<Vec<Arc<dyn BlockDevice>>> {
+        let mut devices = Vec::new();
+        for (index, id) in ids.iter().enumerate() {
+            if ids[..index].contains(id) {
+                anyhow::bail!(DeviceManagerError::InvalidMembers);
+            }
+
+            let info = self.get(*id).ok_or(DeviceManagerError::DeviceNotFound)?;
+            if matches!(info.kind, DeviceKind::Root(RootDeviceType::Optical)) {
+                anyhow::bail!(DeviceManagerError::InvalidMembers);
+            }
+            self.ensure_unused(*id)?;
+            devices.push(match cached(&info.device) {
+                Some(cache) => {
+                    cache.invalidate()?;
+                    info.open_with(cache.inner())
+                }
+                None => info.open(),
+            });
+        }
+        Ok(devices)
+    }
+
+    fn register_raid(
+        &mut self,
+        level: RaidLevel,
+        members: &[DeviceId],
+        device: Arc<dyn BlockDevice>,
+    ) -> Result<DeviceId> {
+        let name = (0..)
+            .map(|index| format!("md{index}"))
+            .find(|name| !self.names.contains_key(name))
+            .expect("Failed to find an available name");
+
+        self.register_internal(
+            name,
+            device,
+            DeviceKind::Raid {
+                level,
+                members: members.to_vec(),
+            },
+            None,
+        )
+    }
+}
+
+impl DeviceManager {
+    fn root(&self, id: DeviceId) -> Result<DeviceInfo> {
+        let info = self.get(id).ok_or(DeviceManagerError::DeviceNotFound)?;
+        match info.kind {
+            DeviceKind::Root(RootDeviceType::Optical)
+            | DeviceKind::Partition { .. }
+            | DeviceKind::Crypt { .. }
+            | DeviceKind::Raid { .. } => {
+                anyhow::bail!(DeviceManagerError::InvalidParent)
+            }
+            DeviceKind::Root(_) => Ok(info.clone()),
+        }
+    }
+
+    fn gpt_entry(&self, id: DeviceId) -> Result<(DeviceInfo, GptTable, usize)> {
+        let info = self.get(id).ok_or(DeviceManagerError::DeviceNotFound)?;
+        let (DeviceKind::Partition { parent, .. }, Some(partition)) = (&info.kind, &info.partition)
+        else {
+            anyhow::bail!(DeviceManagerError::NoPartitionTable);
+        };
+
+        let root_info = self.root(*parent)?;
+        let table = GptTable::read(root_info.device.clone())?
+            .ok_or(DeviceManagerError::NoPartitionTable)?;
+        let index = table
+            .find(partition.guid)
+            .ok_or(DeviceManagerError::DeviceNotFound)?;
+
+        Ok((root_info, table, index))
+    }
+
+    fn crypt_parent(&self, id: DeviceId) -> Result<DeviceInfo> {
+        let info = self.get(id).ok_or(DeviceManagerError::DeviceNotFound)?;
+        if let Some(child) = self
+            .children(id)
+            .find(|child| matches!(child.kind, DeviceKind::Crypt { .. }))
+        {
+            anyhow::bail!(DeviceManagerError::AlreadyUnlocked(child.name.clone()));
+        }
+        Ok(info.clone())
+    }
+
+    // Anything that opened the device, such as a mounted filesystem, swap or
+    // an array, would keep using the old extent. Arrays open their members'
+    // uncached devices, which still count against the member.
+    fn ensure_unused(&self, id: DeviceId) -> Result<()> {
+        let info = self.get(id).ok_or(DeviceManagerError::DeviceNotFound)?;
+        if info.in_use() || self.children(id).next().is_some() {
+            anyhow::bail!(DeviceManagerError::PartitionBusy);
+        }
+        Ok(())
+    }
+}
+
+fn cached(device: &Arc<dyn BlockDevice>) -> Option<Arc<CachedBlockDevice>> {
+    let device: Arc<dyn Any + Send + Sync> = device.clone();
+    device.downcast::<CachedBlockDevice>().ok()
+}
+
+// Like Linux, names ending in a digit (nvme0n1, ram0, loop0) get a "p"
+// before the partition number.
+fn partition_name(root_info: &DeviceInfo, number: usize) -> String {
+    let ends_in_digit = root_info.name.ends_with(|c: char| c.is_ascii_digit());
+    let part_divider = if ends_in_digit { "p" } else { "" };
+    format!("{}{}{}", root_info.name, part_divider, number)
 }
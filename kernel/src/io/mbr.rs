@@ -0,0 +1,139 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::block::{BlockDevice, BlockDeviceResult};
+
+pub const MBR_PROTECTIVE: u8 = 0xEE;
+pub const MBR_LINUX_SWAP: u8 = 0x82;
+
+const MBR_SIGNATURE: u16 = 0xAA55;
+const MBR_ENTRY_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_PRIMARY_COUNT: usize = 4;
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MbrEntry {
+    pub bootable: bool,
+    pub partition_type: u8,
+    pub start_block: u64,
+    pub block_count: u64,
+}
+
+impl MbrEntry {
+    fn parse(raw: &[u8]) -> Self {
+        Self {
+            bootable: raw[0] & 0x80 != 0,
+            partition_type: raw[4],
+            start_block: u32::from_le_bytes(raw[8..12].try_into().unwrap()) as u64,
+            block_count: u32::from_le_bytes(raw[12..16].try_into().unwrap()) as u64,
+        }
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.partition_type != 0 && self.block_count != 0
+    }
+
+    pub fn is_extended(&self) -> bool {
+        MBR_EXTENDED_TYPES.contains(&self.partition_type)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MbrPartition {
+    pub number: usize,
+    pub entry: MbrEntry,
+}
+
+pub struct Mbr {
+    pub entries: [MbrEntry; MBR_PRIMARY_COUNT],
+}
+
+impl Mbr {
+    pub fn read(device: &dyn BlockDevice) -> BlockDeviceResult<Option<Self>> {
+        let Some(entries) = read_table(device, 0)? else {
+            return Ok(None);
+        };
+        Ok(Some(Self { entries }))
+    }
+
+    pub fn is_protective(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.partition_type == MBR_PROTECTIVE)
+    }
+
+    // Primary partitions are numbered 1-4 by slot and logical partitions
+    // from 5 in chain order, matching Linux.
+    pub fn partitions(&self, device: &dyn BlockDevice) -> BlockDeviceResult<Vec<MbrPartition>> {
+        let mut partitions = Vec::new();
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            if !entry.is_used() || entry.partition_type == MBR_PROTECTIVE {
+                continue;
+            }
+
+            if entry.is_extended() {
+                read_logical(device, entry.start_block, &mut partitions)?;
+            } else {
+                partitions.push(MbrPartition {
+                    number: index + 1,
+                    entry: *entry,
+                });
+            }
+        }
+
+        Ok(partitions)
+    }
+}
+
+fn read_table(
+    device: &dyn BlockDevice,
+    block_id: u64,
+) -> BlockDeviceResult<Option<[MbrEntry; MBR_PRIMARY_COUNT]>> {
+    let mut buffer = vec![0; device.block_size()];
+    device.read_block(block_id, &mut buffer)?;
+
+    if u16::from_le_bytes([buffer[510], buffer[511]]) != MBR_SIGNATURE {
+        return Ok(None);
+    }
+
+    Ok(Some(core::array::from_fn(|index| {
+        let offset = MBR_ENTRY_OFFSET + index * MBR_ENTRY_SIZE;
+        MbrEntry::parse(&buffer[offset..offset + MBR_ENTRY_SIZE])
+    })))
+}
+
+fn read_logical(
+    device: &dyn BlockDevice,
+    extended_start: u64,
+    partitions: &mut Vec<MbrPartition>,
+) -> BlockDeviceResult<()> {
+    let mut ebr = extended_start;
+
+    // Logical starts are relative to their own EBR, while links to the next
+    // EBR are relative to the start of the extended partition.
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+        if ebr >= device.block_count() {
+            break;
+        }
+
+        let Some(entries) = read_table(device, ebr)? else {
+            break;
+        };
+
+        if entries[0].is_used() {
+            let mut entry = entries[0];
+            entry.start_block += ebr;
+            partitions.push(MbrPartition { number, entry });
+        }
+
+        if !entries[1].is_used() || !entries[1].is_extended() {
+            break;
+        }
+        ebr = extended_start + entries[1].start_block;
+    }
+
+    Ok(())
+}
@@ -1,22 +1,108 @@
 use alloc::sync::Arc;
 use anyhow::Result;
-use manager::{DeviceManager, DeviceSource};
+use manager::{DEVICE_MANAGER, DeviceEvent, DeviceKind, DeviceSource, RootDeviceType};
+use manager::{LINUX_SWAP_PARTITION, PartitionType};
+use mbr::MBR_LINUX_SWAP;
 
-use crate::drivers::{ahci, nvme};
+use crate::drivers::{ahci, nvme, virtio};
+use crate::fs::iso9660::Iso9660;
 
 pub mod block;
+pub mod cache;
+pub mod crypt;
+pub mod gpt;
+pub mod loopback;
 pub mod manager;
+pub mod mbr;
+pub mod raid;
+pub mod ram;
+pub mod request;
 
 pub fn init_manager() -> Result<()> {
-    let mut manager = DeviceManager::default();
+    let mut manager = DEVICE_MANAGER.write();
+    manager.subscribe(enable_swap_partition);
+    manager.subscribe(detect_encrypted_device);
+    manager.subscribe(mount_optical_media);
 
     for device in nvme::NVME.iter() {
         manager.register(DeviceSource::NvmeController(device))?;
     }
 
     for device in ahci::AHCI.iter() {
-        manager.register(DeviceSource::ScsiLike(Arc::new(device)))?;
+        let source = if device.atapi {
+            DeviceSource::Optical(Arc::new(device))
+        } else {
+            DeviceSource::ScsiLike(Arc::new(device))
+        };
+        manager.register(source)?;
+    }
+
+    for device in virtio::VIRTIO_BLK.iter() {
+        manager.register(DeviceSource::Virtio(Arc::new(device)))?;
+    }
+
+    for device in ram::module_ram_disks() {
+        manager.register(DeviceSource::Ram(Arc::new(device)))?;
     }
 
     Ok(())
 }
+
+fn enable_swap_partition(event: &DeviceEvent) {
+    let DeviceEvent::Registered(info) = event else {
+        return;
+    };
+
+    let DeviceKind::Partition { partition_type, .. } = info.kind else {
+        return;
+    };
+
+    let is_swap = match partition_type {
+        PartitionType::Gpt(type_guid) => type_guid == LINUX_SWAP_PARTITION,
+        PartitionType::Mbr(type_byte) => type_byte == MBR_LINUX_SWAP,
+    };
+
+    if is_swap {
+        if let Err(err) = crate::mem::enable_swap(info.open()) {
+            log::warn!("Failed to enable swap on {}: {err}", info.name);
+        }
+    }
+}
+
+fn detect_encrypted_device(event: &DeviceEvent) {
+    let DeviceEvent::Registered(info) = event else {
+        return;
+    };
+    if matches!(info.kind, DeviceKind::Root(RootDeviceType::Optical)) {
+        return;
+    }
+
+    if let Ok(Some(header)) = crypt::read_header(info.device.as_ref()) {
+        log::info!(
+            "{} holds an encrypted volume ({} KDF iterations)",
+            info.name,
+            header.iterations
+        );
+    }
+}
+
+fn mount_optical_media(event: &DeviceEvent) {
+    match event {
+        DeviceEvent::Registered(info)
+            if matches!(info.kind, DeviceKind::Root(RootDeviceType::Optical)) =>
+        {
+            let mounted = Iso9660::mount(info.open())
+                .and_then(|filesystem| crate::fs::mount_media(&info.name, filesystem));
+
+            if let Err(err) = mounted {
+                log::warn!("Failed to mount {}: {err}", info.name);
+            }
+        }
+        DeviceEvent::Unregistered(info)
+            if matches!(info.kind, DeviceKind::Root(RootDeviceType::Optical)) =>
+        {
+            let _ = crate::fs::unmount_media(&info.name);
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,389 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use derive_more::Display;
+use spin::{Mutex, RwLock};
+
+use super::block::{BlockDevice, BlockDeviceError, BlockDeviceResult};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+const RESYNC_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum RaidLevel {
+    #[display("RAID-0")]
+    Stripe,
+    #[display("RAID-1")]
+    Mirror,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberState {
+    Active,
+    Faulty,
+    Syncing,
+}
+
+// Members must share a block size; the array is as large as its smallest
+// member allows.
+fn member_geometry(members: &[Arc<dyn BlockDevice>]) -> BlockDeviceResult<(usize, u64)> {
+    let first = members.first().ok_or(BlockDeviceError::InvalidInput)?;
+    let block_size = first.block_size();
+    if members
+        .iter()
+        .any(|member| member.block_size() != block_size)
+    {
+        return Err(BlockDeviceError::InvalidInput);
+    }
+
+    let block_count = members.iter().map(|member| member.block_count()).min();
+    Ok((block_size, block_count.unwrap_or_default()))
+}
+
+fn check_range(
+    block_id: u64,
+    length: usize,
+    block_size: usize,
+    block_count: u64,
+) -> BlockDeviceResult<()> {
+    if !length.is_multiple_of(block_size) {
+        return Err(BlockDeviceError::InvalidInput);
+    }
+    if block_id + (length / block_size) as u64 > block_count {
+        return Err(BlockDeviceError::OutOfBounds);
+    }
+    Ok(())
+}
+
+struct Member {
+    device: Arc<dyn BlockDevice>,
+    state: MemberState,
+}
+
+pub struct MirrorBlockDevice {
+    members: Vec<RwLock<Member>>,
+    block_size: usize,
+    block_count: u64,
+    next_read: AtomicUsize,
+    // Writes hold this shared while resync holds it exclusively for each
+    // chunk, so a chunk is never copied while a write to it is in flight.
+    sync_lock: RwLock<()>,
+    resync_running: Mutex<()>,
+}
+
+impl MirrorBlockDevice {
+    pub fn new(members: Vec<Arc<dyn BlockDevice>>) -> BlockDeviceResult<Self> {
+        if members.len() < 2 {
+            return Err(BlockDeviceError::InvalidInput);
+        }
+        let (block_size, block_count) = member_geometry(&members)?;
+
+        let members = members
+            .into_iter()
+            .map(|device| {
+                RwLock::new(Member {
+                    device,
+                    state: MemberState::Active,
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            members,
+            block_size,
+            block_count,
+            next_read: AtomicUsize::new(0),
+            sync_lock: RwLock::new(()),
+            resync_running: Mutex::new(()),
+        })
+    }
+
+    pub fn member_states(&self) -> Vec<MemberState> {
+        self.members
+            .iter()
+            .map(|member| member.read().state)
+            .collect()
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.member_states()
+            .iter()
+            .any(|state| *state != MemberState::Active)
+    }
+
+    pub fn fail_member(&self, index: usize) -> BlockDeviceResult<()> {
+        let member = self
+            .members
+            .get(index)
+            .ok_or(BlockDeviceError::InvalidInput)?;
+        member.write().state = MemberState::Faulty;
+        Ok(())
+    }
+
+    // Copies the array contents onto every faulty member, bringing it back
+    // into service. The array stays usable while this runs.
+    pub fn resync(&self) -> BlockDeviceResult<()> {
+        let Some(_running) = self.resync_running.try_lock() else {
+            return Err(BlockDeviceError::IoError(String::from(
+                "Resync already running",
+            )));
+        };
+
+        for index in 0..self.members.len() {
+            if self.members[index].read().state == MemberState::Faulty {
+                self.resync_member(index)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn resync_member(&self, index: usize) -> BlockDeviceResult<()> {
+        let target = {
+            let mut member = self.members[index].write();
+            member.state = MemberState::Syncing;
+            member.device.clone()
+        };
+        log::info!("Resyncing mirror member {index}");
+
+        let chunk_blocks = (RESYNC_CHUNK_SIZE / self.block_size).max(1) as u64;
+        let mut buffer = vec![0; chunk_blocks as usize * self.block_size];
+        let mut block_id = 0;
+
+        while block_id < self.block_count {
+            let count = (self.block_count - block_id).min(chunk_blocks);
+            let chunk = &mut buffer[..count as usize * self.block_size];
+
+            let _exclusive = self.sync_lock.write();
+            let result = self
+                .read_any(block_id, chunk)
+                .and_then(|_| target.write_block(block_id, chunk));
+            if let Err(err) = result {
+                self.members[index].write().state = MemberState::Faulty;
+                log::warn!("Resync of mirror member {index} failed: {err}");
+                return Err(err);
+            }
+            block_id += count;
+        }
+
+        target.flush()?;
+        self.members[index].write().state = MemberState::Active;
+        log::info!("Mirror member {index} is back in sync");
+        Ok(())
+    }
+
+    // Reads from the active members in turn, failing over to the next one
+    // when a member errors out.
+    fn read_any(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
+        let start = self.next_read.fetch_add(1, Ordering::Relaxed);
+        let mut last_error = None;
+
+        for offset in 0..self.members.len() {
+            let index = (start + offset) % self.members.len();
+            let Some(device) = self.device_in_state(index, &[MemberState::Active]) else {
+                continue;
+            };
+
+            match device.read_block(block_id, buffer) {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    self.mark_faulty(index, &err);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or(BlockDeviceError::DeviceNotFound))
+    }
+
+    fn device_in_state(
+        &self,
+        index: usize,
+        states: &[MemberState],
+    ) -> Option<Arc<dyn BlockDevice>> {
+        let member = self.members[index].read();
+        states
+            .contains(&member.state)
+            .then(|| member.device.clone())
+    }
+
+    fn mark_faulty(&self, index: usize, err: &BlockDeviceError) {
+        let mut member = self.members[index].write();
+        if member.state != MemberState::Faulty {
+            log::warn!("Mirror member {index} failed: {err}");
+            member.state = MemberState::Faulty;
+        }
+    }
+}
+
+impl BlockDevice for MirrorBlockDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn flush(&self) -> BlockDeviceResult<()> {
+        let mut last_error = None;
+        let mut flushed = false;
+
+        for index in 0..self.members.len() {
+            let Some(device) = self.device_in_state(index, &[MemberState::Active]) else {
+                continue;
+            };
+            match device.flush() {
+                Ok(()) => flushed = true,
+                Err(err) => {
+                    self.mark_faulty(index, &err);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        match (flushed, last_error) {
+            (false, Some(err)) => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
+        check_range(block_id, buffer.len(), self.block_size, self.block_count)?;
+        self.read_any(block_id, buffer)
+    }
+
+    // Succeeds as long as one active member took the write. Members being
+    // resynced get every write too, so they are current once the copy ends.
+    fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
+        check_range(block_id, buffer.len(), self.block_size, self.block_count)?;
+        let _shared = self.sync_lock.read();
+
+        let mut last_error = None;
+        let mut written = false;
+        let states = [MemberState::Active, MemberState::Syncing];
+
+        for index in 0..self.members.len() {
+            let Some(device) = self.device_in_state(index, &states) else {
+                continue;
+            };
+            match device.write_block(block_id, buffer) {
+                Ok(()) => written |= self.members[index].read().state == MemberState::Active,
+                Err(err) => {
+                    self.mark_faulty(index, &err);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        match written {
+            true => Ok(()),
+            false => Err(last_error.unwrap_or(BlockDeviceError::DeviceNotFound)),
+        }
+    }
+}
+
+pub struct StripeBlockDevice {
+    members: Vec<Arc<dyn BlockDevice>>,
+    block_size: usize,
+    chunk_blocks: u64,
+    block_count: u64,
+}
+
+impl StripeBlockDevice {
+    pub fn new(members: Vec<Arc<dyn BlockDevice>>, chunk_size: usize) -> BlockDeviceResult<Self> {
+        if members.len() < 2 {
+            return Err(BlockDeviceError::InvalidInput);
+        }
+        let (block_size, member_blocks) = member_geometry(&members)?;
+        if chunk_size == 0 || !chunk_size.is_multiple_of(block_size) {
+            return Err(BlockDeviceError::InvalidInput);
+        }
+
+        // Only whole chunks are striped, a partial chunk at the end of each
+        // member is left unused.
+        let chunk_blocks = (chunk_size / block_size) as u64;
+        let member_chunks = member_blocks / chunk_blocks;
+        let block_count = member_chunks * chunk_blocks * members.len() as u64;
+        if block_count == 0 {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+
+        Ok(Self {
+            members,
+            block_size,
+            chunk_blocks,
+            block_count,
+        })
+    }
+
+    // Splits a request at chunk boundaries and calls `operation` with the
+    // member, the block on that member and the byte range of each piece.
+    fn for_each_chunk(
+        &self,
+        block_id: u64,
+        length: usize,
+        mut operation: impl FnMut(
+            &Arc<dyn BlockDevice>,
+            u64,
+            core::ops::Range<usize>,
+        ) -> BlockDeviceResult<()>,
+    ) -> BlockDeviceResult<()> {
+        check_range(block_id, length, self.block_size, self.block_count)?;
+        let end = block_id + (length / self.block_size) as u64;
+        let mut block = block_id;
+
+        while block < end {
+            let chunk = block / self.chunk_blocks;
+            let within = block % self.chunk_blocks;
+            let count = (self.chunk_blocks - within).min(end - block);
+
+            let member = &self.members[(chunk % self.members.len() as u64) as usize];
+            let member_block = chunk / self.members.len() as u64 * self.chunk_blocks + within;
+
+            let start = (block - block_id) as usize * self.block_size;
+            operation(
+                member,
+                member_block,
+                start..start + count as usize * self.block_size,
+            )?;
+            block += count;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for StripeBlockDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn flush(&self) -> BlockDeviceResult<()> {
+        self.members.iter().try_for_each(|member| member.flush())
+    }
+
+    fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
+        self.for_each_chunk(block_id, buffer.len(), |member, member_block, range| {
+            member.read_block(member_block, &mut buffer[range])
+        })
+    }
+
+    fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
+        self.for_each_chunk(block_id, buffer.len(), |member, member_block, range| {
+            member.write_block(member_block, &buffer[range])
+        })
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.members
+            .iter()
+            .map(|member| member.queue_depth())
+            .min()
+            .unwrap_or(1)
+    }
+}
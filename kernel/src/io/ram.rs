@@ -0,0 +1,96 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::RwLock;
+
+use super::block::{BlockCapabilities, BlockDevice, BlockDeviceError, BlockDeviceResult};
+
+pub const RAM_BLOCK_SIZE: usize = 512;
+const RAM_DISK_MODULE: &str = "ramdisk";
+
+pub struct RamBlockDevice {
+    data: RwLock<Vec<u8>>,
+    block_count: u64,
+}
+
+impl RamBlockDevice {
+    pub fn new(block_count: u64) -> Self {
+        Self {
+            data: RwLock::new(vec![0; block_count as usize * RAM_BLOCK_SIZE]),
+            block_count,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let block_count = bytes.len().div_ceil(RAM_BLOCK_SIZE) as u64;
+        let mut data = vec![0; block_count as usize * RAM_BLOCK_SIZE];
+        data[..bytes.len()].copy_from_slice(bytes);
+
+        Self {
+            data: RwLock::new(data),
+            block_count,
+        }
+    }
+}
+
+impl RamBlockDevice {
+    fn byte_range(&self, block_id: u64, length: usize) -> BlockDeviceResult<Range<usize>> {
+        let block_count = length.div_ceil(RAM_BLOCK_SIZE) as u64;
+        if block_id + block_count > self.block_count {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+
+        let start = block_id as usize * RAM_BLOCK_SIZE;
+        Ok(start..start + length)
+    }
+}
+
+impl BlockDevice for RamBlockDevice {
+    fn block_size(&self) -> usize {
+        RAM_BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn flush(&self) -> BlockDeviceResult<()> {
+        Ok(())
+    }
+
+    fn read_block(&self, block_id: u64, buffer: &mut [u8]) -> BlockDeviceResult<()> {
+        let range = self.byte_range(block_id, buffer.len())?;
+        buffer.copy_from_slice(&self.data.read()[range]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: u64, buffer: &[u8]) -> BlockDeviceResult<()> {
+        let range = self.byte_range(block_id, buffer.len())?;
+        self.data.write()[range].copy_from_slice(buffer);
+        Ok(())
+    }
+
+    fn capabilities(&self) -> BlockCapabilities {
+        BlockCapabilities::DISCARD | BlockCapabilities::WRITE_ZEROES
+    }
+
+    fn discard(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        self.write_zeroes(range)
+    }
+
+    fn write_zeroes(&self, range: Range<u64>) -> BlockDeviceResult<()> {
+        if range.start > range.end || range.end > self.block_count {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+
+        let start = range.start as usize * RAM_BLOCK_SIZE;
+        let end = range.end as usize * RAM_BLOCK_SIZE;
+        self.data.write()[start..end].fill(0);
+        Ok(())
+    }
+}
+
+// Modules tagged "ramdisk" are copied so the resulting disks are writable.
+pub fn module_ram_disks() -> impl Iterator<Item = RamBlockDevice> {
+    crate::modules::find(RAM_DISK_MODULE).map(RamBlockDevice::from_bytes)
+}
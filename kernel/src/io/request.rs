@@ -0,0 +1,419 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::mem::replace;
+use core::ops::Range;
+use core::pin::Pin;
+use core::slice::{from_raw_parts, from_raw_parts_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{PageSize, Size4KiB, Translate};
+
+use super::block::{BlockDevice, BlockDeviceError, BlockDeviceResult};
+use crate::mem::ref_current_page_table;
+
+pub type BlockCallback = Box<dyn FnOnce(BlockDeviceResult<()>) + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOperation {
+    Read,
+    Write,
+    Flush,
+    Discard,
+    WriteZeroes,
+}
+
+impl BlockOperation {
+    pub fn is_ranged(&self) -> bool {
+        matches!(self, BlockOperation::Discard | BlockOperation::WriteZeroes)
+    }
+}
+
+// Points into a caller's buffer, which the device reads from or writes to
+// by DMA until the request holding the segment completes.
+pub struct BlockSegment {
+    pub buffer: *const u8,
+    pub length: usize,
+    writable: bool,
+}
+
+unsafe impl Send for BlockSegment {}
+
+impl BlockSegment {
+    // The buffer must stay allocated, and must not be accessed by anything
+    // else, until the request this segment is part of has completed.
+    pub unsafe fn new(buffer: &mut [u8]) -> Self {
+        Self {
+            buffer: buffer.as_mut_ptr(),
+            length: buffer.len(),
+            writable: true,
+        }
+    }
+
+    // Can only be the source of a write request. The buffer must stay
+    // allocated, and must not be written to, until the request this segment
+    // is part of has completed.
+    pub unsafe fn from_slice(buffer: &[u8]) -> Self {
+        Self {
+            buffer: buffer.as_ptr(),
+            length: buffer.len(),
+            writable: false,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { from_raw_parts(self.buffer, self.length) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+        self.writable
+            .then(|| unsafe { from_raw_parts_mut(self.buffer.cast_mut(), self.length) })
+    }
+}
+
+pub struct PendingRequest {
+    request: Mutex<Option<BlockRequest>>,
+    result: Mutex<BlockDeviceResult<()>>,
+    remaining: AtomicUsize,
+}
+
+impl PendingRequest {
+    pub fn new(request: BlockRequest, commands: usize) -> Arc<Self> {
+        Arc::new(Self {
+            request: Mutex::new(Some(request)),
+            result: Mutex::new(Ok(())),
+            remaining: AtomicUsize::new(commands),
+        })
+    }
+
+    pub fn finish(&self, result: BlockDeviceResult<()>) {
+        if let Err(err) = result {
+            let mut current = self.result.lock();
+            if current.is_ok() {
+                *current = Err(err);
+            }
+        }
+
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let result = replace(&mut *self.result.lock(), Ok(()));
+            if let Some(request) = self.request.lock().take() {
+                request.complete(result);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DmaLimits {
+    pub block_size: usize,
+    pub max_segments: usize,
+    pub max_segment_size: usize,
+    pub max_transfer_size: usize,
+    // Only the first segment may start and only the last may end inside a
+    // page, as NVMe PRP entries require.
+    pub page_aligned: bool,
+}
+
+pub struct DmaTransfer {
+    pub block_id: u64,
+    pub block_count: usize,
+    pub segments: Vec<(u64, usize)>,
+}
+
+pub struct BlockRequest {
+    pub operation: BlockOperation,
+    pub block_id: u64,
+    pub segments: Vec<BlockSegment>,
+    range_length: u64,
+    callback: Option<BlockCallback>,
+}
+
+impl BlockRequest {
+    pub fn read(block_id: u64, segments: Vec<BlockSegment>) -> Self {
+        Self::new(BlockOperation::Read, block_id, segments)
+    }
+
+    pub fn write(block_id: u64, segments: Vec<BlockSegment>) -> Self {
+        Self::new(BlockOperation::Write, block_id, segments)
+    }
+
+    pub fn flush() -> Self {
+        Self::new(BlockOperation::Flush, 0, Vec::new())
+    }
+
+    pub fn discard(range: Range<u64>) -> Self {
+        Self::ranged(BlockOperation::Discard, range)
+    }
+
+    pub fn write_zeroes(range: Range<u64>) -> Self {
+        Self::ranged(BlockOperation::WriteZeroes, range)
+    }
+
+    pub fn with_callback(mut self, callback: BlockCallback) -> Self {
+        self.callback = Some(callback);
+        self
+    }
+
+    pub fn take_callback(&mut self) -> Option<BlockCallback> {
+        self.callback.take()
+    }
+
+    pub fn length(&self) -> usize {
+        self.segments.iter().map(|segment| segment.length).sum()
+    }
+
+    pub fn block_count(&self, block_size: usize) -> u64 {
+        match self.operation.is_ranged() {
+            true => self.range_length,
+            false => (self.length() / block_size) as u64,
+        }
+    }
+
+    pub fn range(&self) -> Range<u64> {
+        self.block_id..self.block_id + self.range_length
+    }
+
+    pub fn complete(mut self, result: BlockDeviceResult<()>) {
+        if let Some(callback) = self.callback.take() {
+            callback(result);
+        }
+    }
+
+    pub fn validate(&self, block_size: usize, device_blocks: u64) -> BlockDeviceResult<()> {
+        let reading = self.operation == BlockOperation::Read;
+        if self
+            .segments
+            .iter()
+            .any(|segment| segment.length % block_size != 0 || (reading && !segment.writable))
+        {
+            return Err(BlockDeviceError::InvalidInput);
+        }
+
+        let end = self.block_id + self.block_count(block_size);
+        if end > device_blocks {
+            return Err(BlockDeviceError::OutOfBounds);
+        }
+
+        Ok(())
+    }
+
+    pub fn dma_transfers(&self, limits: &DmaLimits) -> BlockDeviceResult<Vec<DmaTransfer>> {
+        let page_table = ref_current_page_table();
+        let mut chunks: VecDeque<(u64, usize)> = VecDeque::new();
+
+        for segment in self.segments.iter() {
+            let mut offset = 0;
+            while offset < segment.length {
+                let address = VirtAddr::from_ptr(segment.buffer) + offset as u64;
+                let page_remaining = Size4KiB::SIZE - address.as_u64() % Size4KiB::SIZE;
+                let length = (segment.length - offset).min(page_remaining as usize);
+
+                let physical_address = page_table
+                    .translate_addr(address)
+                    .ok_or(BlockDeviceError::InvalidInput)?
+                    .as_u64();
+
+                match chunks.back_mut() {
+                    Some((last_address, last_length))
+                        if *last_address + *last_length as u64 == physical_address
+                            && *last_length + length <= limits.max_segment_size =>
+                    {
+                        *last_length += length
+                    }
+                    _ => chunks.push_back((physical_address, length)),
+                }
+                offset += length;
+            }
+        }
+
+        let mut transfers = Vec::new();
+        let mut block_id = self.block_id;
+
+        while !chunks.is_empty() {
+            let mut segments = Vec::new();
+            let mut length = 0;
+
+            while let Some((address, chunk_length)) = chunks.pop_front() {
+                if limits.page_aligned
+                    && let Some(&(last_address, last_length)) = segments.last()
+                    && (address % Size4KiB::SIZE != 0
+                        || (last_address + last_length as u64) % Size4KiB::SIZE != 0)
+                {
+                    chunks.push_front((address, chunk_length));
+                    break;
+                }
+
+                let taken = chunk_length.min(limits.max_transfer_size - length);
+                if taken < chunk_length {
+                    chunks.push_front((address + taken as u64, chunk_length - taken));
+                }
+
+                segments.push((address, taken));
+                length += taken;
+
+                if segments.len() == limits.max_segments || length == limits.max_transfer_size {
+                    break;
+                }
+            }
+
+            // A transfer must end on a block boundary, so push any partial
+            // block back to be transferred by the next one.
+            let mut remainder = length % limits.block_size;
+            length -= remainder;
+            while remainder > 0 {
+                let (address, segment_length) = segments.pop().unwrap();
+                if segment_length > remainder {
+                    let kept = segment_length - remainder;
+                    segments.push((address, kept));
+                    chunks.push_front((address + kept as u64, remainder));
+                    remainder = 0;
+                } else {
+                    chunks.push_front((address, segment_length));
+                    remainder -= segment_length;
+                }
+            }
+
+            if length == 0 {
+                return Err(BlockDeviceError::InvalidInput);
+            }
+
+            let block_count = length / limits.block_size;
+            transfers.push(DmaTransfer {
+                block_id,
+                block_count,
+                segments,
+            });
+            block_id += block_count as u64;
+        }
+
+        Ok(transfers)
+    }
+
+    pub fn execute<D: BlockDevice + ?Sized>(&mut self, device: &D) -> BlockDeviceResult<()> {
+        let block_size = device.block_size();
+        self.validate(block_size, device.block_count())?;
+
+        match self.operation {
+            BlockOperation::Discard => return device.discard(self.range()),
+            BlockOperation::WriteZeroes => return device.write_zeroes(self.range()),
+            _ => {}
+        }
+
+        let mut block_id = self.block_id;
+        for segment in self.segments.iter_mut() {
+            match self.operation {
+                BlockOperation::Read => {
+                    let buffer = segment
+                        .as_mut_slice()
+                        .ok_or(BlockDeviceError::InvalidInput)?;
+                    device.read_block(block_id, buffer)?
+                }
+                BlockOperation::Write => device.write_block(block_id, segment.as_slice())?,
+                _ => (),
+            }
+            block_id += (segment.length / block_size) as u64;
+        }
+
+        if self.operation == BlockOperation::Flush {
+            device.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BlockRequest {
+    fn new(operation: BlockOperation, block_id: u64, segments: Vec<BlockSegment>) -> Self {
+        Self {
+            operation,
+            block_id,
+            segments,
+            range_length: 0,
+            callback: None,
+        }
+    }
+
+    fn ranged(operation: BlockOperation, range: Range<u64>) -> Self {
+        Self {
+            range_length: range.end.saturating_sub(range.start),
+            ..Self::new(operation, range.start, Vec::new())
+        }
+    }
+}
+
+#[derive(Default)]
+struct RequestState {
+    result: Option<BlockDeviceResult<()>>,
+    waker: Option<Waker>,
+}
+
+pub struct BlockRequestHandle(Arc<Mutex<RequestState>>);
+
+impl BlockRequestHandle {
+    pub fn submit<D: BlockDevice + ?Sized>(device: &D, request: BlockRequest) -> Self {
+        let state = Arc::new(Mutex::new(RequestState::default()));
+        let completion_state = state.clone();
+
+        device.submit(request.with_callback(Box::new(move |result| {
+            let mut state = completion_state.lock();
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        })));
+
+        Self(state)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        interrupts::without_interrupts(|| self.0.lock().result.is_some())
+    }
+
+    pub fn wait(self) -> BlockDeviceResult<()> {
+        self.wait_with(|| ())
+    }
+
+    pub fn wait_with(self, poll: impl Fn()) -> BlockDeviceResult<()> {
+        loop {
+            if let Some(result) = self.take_result() {
+                return result;
+            }
+
+            poll();
+
+            if interrupts::are_enabled() {
+                crate::syscall::r#yield();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl BlockRequestHandle {
+    fn take_result(&self) -> Option<BlockDeviceResult<()>> {
+        interrupts::without_interrupts(|| self.0.lock().result.take())
+    }
+}
+
+impl Future for BlockRequestHandle {
+    type Output = BlockDeviceResult<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        interrupts::without_interrupts(|| {
+            let mut state = self.0.lock();
+            match state.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
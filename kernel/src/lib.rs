@@ -8,8 +8,10 @@
 
 pub mod arch;
 pub mod drivers;
+pub mod fs;
 pub mod io;
 pub mod mem;
+pub mod modules;
 pub mod syscall;
 pub mod tasks;
 pub mod unwind;
@@ -26,9 +28,12 @@ pub fn init() {
     arch::smp::CPUS.write().load(*BSP_LAPIC_ID);
     arch::interrupts::IDT.load();
     arch::init_sse();
+    arch::init_pat();
     arch::smp::CPUS.write().init_ap();
     arch::apic::init();
+    arch::shootdown::init();
     drivers::mouse::init();
     syscall::init();
     tasks::scheduler::init();
+    fs::init();
 }
@@ -17,6 +17,8 @@ static BASE_REVISION: BaseRevision = BaseRevision::new();
 extern "C" fn kmain() -> ! {
     catch_unwind(kernel::init).unwrap();
     Thread::new_kernel_thread(terminal_thread);
+    Thread::new_kernel_thread(kernel::mem::reclaim_thread);
+    Thread::new_kernel_thread(kernel::io::cache::writeback_thread);
     log::info!("Boot time: {:?}", HPET.elapsed());
 
     (40..=47).for_each(|index| kernel::print!("\x1b[{}m   \x1b[0m", index));
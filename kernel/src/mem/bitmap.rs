@@ -91,4 +91,11 @@ impl Bitmap {
 
         None
     }
+
+    pub fn find_aligned_range(&self, length: usize, align: usize, value: bool) -> Option<usize> {
+        (0..self.len())
+            .step_by(align)
+            .take_while(|start| start + length <= self.len())
+            .find(|&start| (start..start + length).all(|index| self.get(index) == value))
+    }
 }
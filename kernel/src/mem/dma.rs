@@ -72,7 +72,7 @@ impl DmaManager {
 
     pub fn deallocate(address: VirtAddr) {
         let physical_address = convert_virtual_to_physical(address);
-        let address = PhysFrame::containing_address(physical_address);
+        let address: PhysFrame = PhysFrame::containing_address(physical_address);
         unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(address) };
     }
 }
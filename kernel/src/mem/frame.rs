@@ -4,7 +4,7 @@ use limine::memory_map::EntryType;
 use limine::response::MemoryMapResponse;
 use x86_64::PhysAddr;
 use x86_64::structures::paging::{FrameAllocator, PhysFrame};
-use x86_64::structures::paging::{FrameDeallocator, Size4KiB};
+use x86_64::structures::paging::{FrameDeallocator, PageSize, Size4KiB};
 
 use super::bitmap::Bitmap;
 use super::convert_physical_to_virtual;
@@ -91,11 +91,23 @@ impl BitmapFrameAllocator {
         }
     }
 
+    pub fn free_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.origin_frames
+    }
+
     pub fn allocate_frames(&mut self, count: usize) -> Option<PhysFrame> {
-        let index = self
-            .bitmap
-            .find_range(count, true)
-            .expect("No more usable frames!");
+        self.allocate_aligned_frames(count, 1)
+    }
+
+    pub fn allocate_aligned_frames(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        let index = match align {
+            1 => self.bitmap.find_range(count, true),
+            _ => self.bitmap.find_aligned_range(count, align, true),
+        }?;
 
         self.bitmap.set_range(index, index + count, false);
         self.usable_frames -= count;
@@ -103,18 +115,26 @@ impl BitmapFrameAllocator {
         let address = PhysAddr::new(index as u64 * 4096);
         Some(PhysFrame::containing_address(address))
     }
+
+    pub fn deallocate_frames(&mut self, frame: PhysFrame, count: usize) {
+        let index = (frame.start_address().as_u64() / 4096) as usize;
+        self.bitmap.set_range(index, index + count, true);
+        self.usable_frames += count;
+    }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        self.allocate_frames(1)
+unsafe impl<S: PageSize> FrameAllocator<S> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let count = (S::SIZE / Size4KiB::SIZE) as usize;
+        let frame = self.allocate_aligned_frames(count, count)?;
+        PhysFrame::from_start_address(frame.start_address()).ok()
     }
 }
 
-impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
-    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        let index = frame.start_address().as_u64() / 4096;
-        self.bitmap.set(index as usize, true);
-        self.usable_frames += 1;
+impl<S: PageSize> FrameDeallocator<S> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let count = (S::SIZE / Size4KiB::SIZE) as usize;
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_frames(frame, count);
     }
 }
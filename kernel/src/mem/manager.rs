@@ -1,17 +1,26 @@
-use core::marker::PhantomData;
-use x86_64::VirtAddr;
+use bit_field::BitField;
+use core::arch::x86_64::__cpuid;
+use spin::Lazy;
+use thiserror::Error;
 use x86_64::instructions::interrupts;
-use x86_64::structures::paging::mapper::MapToError;
-use x86_64::structures::paging::{FrameAllocator, PhysFrame};
-use x86_64::structures::paging::{Mapper, OffsetPageTable, PageTableFlags};
-use x86_64::structures::paging::{Page, PageSize, Size4KiB};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
+use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+use x86_64::structures::paging::{Mapper, OffsetPageTable, PageTable, PageTableFlags};
+use x86_64::structures::paging::{Page, PageSize, Size1GiB, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
 
-use super::BitmapFrameAllocator;
+use super::{BitmapFrameAllocator, ExtendedPageTable, convert_physical_to_virtual};
+use crate::arch::shootdown;
+
+static GIGANTIC_PAGE_SUPPORTED: Lazy<bool> = Lazy::new(|| __cpuid(0x8000_0001).edx.get_bit(26));
 
 pub enum MappingType {
     UserCode,
     KernelData,
     UserData,
+    WriteCombining,
+    Uncached,
 }
 
 #[rustfmt::skip]
@@ -28,40 +37,114 @@ impl MappingType {
                 | PageTableFlags::WRITABLE
                 | PageTableFlags::USER_ACCESSIBLE
                 | PageTableFlags::NO_EXECUTE,
+            Self::WriteCombining => PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_EXECUTE
+                | PageTableFlags::WRITE_THROUGH,
+            Self::Uncached => PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_EXECUTE
+                | PageTableFlags::WRITE_THROUGH
+                | PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MappingError {
+    #[error("Failed to allocate frame")]
+    FrameAllocationFailed,
+    #[error("Parent entry is a huge page")]
+    ParentEntryHugePage,
+    #[error("Page already mapped to {0:?}")]
+    PageAlreadyMapped(PhysAddr),
+    #[error("Page not mapped")]
+    PageNotMapped,
+    #[error("Invalid frame address {0:?}")]
+    InvalidFrameAddress(PhysAddr),
+    #[error("Virtual address space exhausted")]
+    AddressSpaceExhausted,
+}
+
+impl<S: PageSize> From<MapToError<S>> for MappingError {
+    fn from(error: MapToError<S>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => Self::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => Self::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(frame) => Self::PageAlreadyMapped(frame.start_address()),
         }
     }
 }
 
-pub struct MemoryManager<S: PageSize = Size4KiB> {
-    size: PhantomData<S>,
+impl From<FlagUpdateError> for MappingError {
+    fn from(error: FlagUpdateError) -> Self {
+        match error {
+            FlagUpdateError::PageNotMapped => Self::PageNotMapped,
+            FlagUpdateError::ParentEntryHugePage => Self::ParentEntryHugePage,
+        }
+    }
+}
+
+impl From<UnmapError> for MappingError {
+    fn from(error: UnmapError) -> Self {
+        match error {
+            UnmapError::ParentEntryHugePage => Self::ParentEntryHugePage,
+            UnmapError::PageNotMapped => Self::PageNotMapped,
+            UnmapError::InvalidFrameAddress(address) => Self::InvalidFrameAddress(address),
+        }
+    }
 }
 
-impl<S: PageSize> MemoryManager<S> {
+pub struct MemoryManager;
+
+impl MemoryManager {
     pub fn alloc_range(
         start_address: VirtAddr,
         length: u64,
         flags: PageTableFlags,
         page_table: &mut OffsetPageTable<'static>,
-    ) -> Result<(), MapToError<S>>
-    where
-        OffsetPageTable<'static>: Mapper<S>,
-        BitmapFrameAllocator: FrameAllocator<S>,
-    {
+    ) -> Result<(), MappingError> {
+        super::ensure_free_frames(length.div_ceil(Size4KiB::SIZE) as usize);
+
         interrupts::without_interrupts(|| unsafe {
-            let page_range = {
-                let start_page = Page::containing_address(start_address);
-                let end_page = Page::containing_address(start_address + length - 1u64);
-                Page::range_inclusive(start_page, end_page)
-            };
+            let end_address = (start_address + length).align_up(Size4KiB::SIZE);
+            let mut address = start_address.align_down(Size4KiB::SIZE);
+            let mut fallback_end = address;
             let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
 
-            for page in page_range {
-                let frame = frame_allocator
-                    .allocate_frame()
-                    .ok_or(MapToError::FrameAllocationFailed)?;
-                page_table
-                    .map_to(page, frame, flags, &mut *frame_allocator)?
-                    .flush();
+            while address < end_address {
+                let fit_size = if address < fallback_end {
+                    Size4KiB::SIZE
+                } else {
+                    Self::fit_page_size(address, None, end_address - address)
+                };
+
+                let (size, frame) = Self::PAGE_SIZES
+                    .into_iter()
+                    .filter(|&size| size <= fit_size)
+                    .find_map(|size| {
+                        let count = (size / Size4KiB::SIZE) as usize;
+                        let frame = frame_allocator.allocate_aligned_frames(count, count)?;
+                        Some((size, frame))
+                    })
+                    .ok_or(MappingError::FrameAllocationFailed)?;
+
+                match Self::map_page(
+                    size,
+                    address,
+                    frame.start_address(),
+                    flags,
+                    page_table,
+                    &mut frame_allocator,
+                ) {
+                    Ok(()) => address += size,
+                    Err(MappingError::PageAlreadyMapped(_)) if size > Size4KiB::SIZE => {
+                        let count = (size / Size4KiB::SIZE) as usize;
+                        frame_allocator.deallocate_frames(frame, count);
+                        fallback_end = address + size;
+                    }
+                    Err(err) => return Err(err),
+                }
             }
 
             Ok(())
@@ -70,33 +153,303 @@ impl<S: PageSize> MemoryManager<S> {
 
     pub fn map_range_to(
         start_address: VirtAddr,
-        start_frame: PhysFrame<S>,
+        start_frame: PhysFrame,
         length: u64,
         flags: PageTableFlags,
         page_table: &mut OffsetPageTable<'static>,
-    ) -> Result<(), MapToError<S>>
-    where
-        OffsetPageTable<'static>: Mapper<S>,
-        BitmapFrameAllocator: FrameAllocator<S>,
-    {
+    ) -> Result<(), MappingError> {
         interrupts::without_interrupts(|| unsafe {
-            let page_range = {
-                let start_page = Page::containing_address(start_address);
-                let end_page = Page::containing_address(start_address + length - 1u64);
-                Page::range_inclusive(start_page, end_page)
-            };
+            let end_address = (start_address + length).align_up(Size4KiB::SIZE);
+            let start_address = start_address.align_down(Size4KiB::SIZE);
             let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
 
-            for (index, page) in page_range.enumerate() {
-                let frame = start_frame + index as u64;
-                match page_table.map_to(page, frame, flags, &mut *frame_allocator) {
-                    Ok(flush) => flush.flush(),
-                    Err(MapToError::PageAlreadyMapped(_)) => (),
+            let mut address = start_address;
+            let mut fallback_end = start_address;
+
+            while address < end_address {
+                let frame_address = start_frame.start_address() + (address - start_address);
+
+                let size = if address < fallback_end {
+                    Size4KiB::SIZE
+                } else {
+                    Self::fit_page_size(address, Some(frame_address), end_address - address)
+                };
+
+                match Self::map_page(
+                    size,
+                    address,
+                    frame_address,
+                    flags,
+                    page_table,
+                    &mut frame_allocator,
+                ) {
+                    Ok(()) => (),
+                    Err(MappingError::PageAlreadyMapped(_)) if size > Size4KiB::SIZE => {
+                        fallback_end = address + size;
+                        continue;
+                    }
+                    Err(MappingError::PageAlreadyMapped(_) | MappingError::ParentEntryHugePage)
+                        if Self::is_mapped_to(address, frame_address, flags, page_table) => {}
                     Err(err) => return Err(err),
                 }
+
+                address += size;
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn unmap_range(
+        start_address: VirtAddr,
+        length: u64,
+        page_table: &mut OffsetPageTable<'static>,
+    ) -> Result<(), MappingError> {
+        interrupts::without_interrupts(|| {
+            let end_address = (start_address + length).align_up(Size4KiB::SIZE);
+            let mut address = start_address.align_down(Size4KiB::SIZE);
+
+            // Huge pages sticking out of either end of the range are split
+            // first, so memory outside the range stays mapped.
+            unsafe {
+                Self::split_huge_pages_at(address, page_table)?;
+                Self::split_huge_pages_at(end_address, page_table)?;
+            }
+
+            while address < end_address {
+                let size = match page_table.translate(address) {
+                    TranslateResult::Mapped { frame, .. } => frame.size(),
+                    _ => Size4KiB::SIZE,
+                };
+
+                match size {
+                    Size1GiB::SIZE => Self::unmap_sized_page::<Size1GiB>(address, page_table)?,
+                    Size2MiB::SIZE => Self::unmap_sized_page::<Size2MiB>(address, page_table)?,
+                    _ => match Self::unmap_sized_page::<Size4KiB>(address, page_table) {
+                        Ok(()) | Err(MappingError::PageNotMapped) => (),
+                        Err(err) => return Err(err),
+                    },
+                }
+
+                address = address.align_down(size) + size;
+            }
+
+            shootdown::shootdown(start_address..end_address, page_table.physical_address());
+            Ok(())
+        })
+    }
+
+    pub fn protect_range(
+        start_address: VirtAddr,
+        length: u64,
+        flags: PageTableFlags,
+        page_table: &mut OffsetPageTable<'static>,
+    ) -> Result<(), MappingError> {
+        interrupts::without_interrupts(|| unsafe {
+            let end_address = (start_address + length).align_up(Size4KiB::SIZE);
+            let mut address = start_address.align_down(Size4KiB::SIZE);
+
+            // Huge pages sticking out of either end of the range are split
+            // first, so memory outside the range keeps its flags.
+            Self::split_huge_pages_at(address, page_table)?;
+            Self::split_huge_pages_at(end_address, page_table)?;
+
+            while address < end_address {
+                let size = match page_table.translate(address) {
+                    TranslateResult::Mapped { frame, .. } => frame.size(),
+                    _ => return Err(MappingError::PageNotMapped),
+                };
+
+                match size {
+                    Size1GiB::SIZE => {
+                        Self::protect_sized_page::<Size1GiB>(address, flags, page_table)?
+                    }
+                    Size2MiB::SIZE => {
+                        Self::protect_sized_page::<Size2MiB>(address, flags, page_table)?
+                    }
+                    _ => Self::protect_sized_page::<Size4KiB>(address, flags, page_table)?,
+                }
+
+                address = address.align_down(size) + size;
             }
 
+            shootdown::shootdown(start_address..end_address, page_table.physical_address());
             Ok(())
         })
     }
 }
+
+impl MemoryManager {
+    const PAGE_SIZES: [u64; 3] = [Size1GiB::SIZE, Size2MiB::SIZE, Size4KiB::SIZE];
+
+    fn fit_page_size(
+        virtual_address: VirtAddr,
+        physical_address: Option<PhysAddr>,
+        remaining: u64,
+    ) -> u64 {
+        Self::PAGE_SIZES
+            .into_iter()
+            .filter(|&size| size != Size1GiB::SIZE || *GIGANTIC_PAGE_SUPPORTED)
+            .find(|&size| {
+                remaining >= size
+                    && virtual_address.is_aligned(size)
+                    && physical_address.is_none_or(|address| address.is_aligned(size))
+            })
+            .unwrap_or(Size4KiB::SIZE)
+    }
+
+    // An existing mapping is only accepted if it already points at the frame
+    // with the requested access and caching.
+    fn is_mapped_to(
+        address: VirtAddr,
+        frame_address: PhysAddr,
+        flags: PageTableFlags,
+        page_table: &OffsetPageTable<'static>,
+    ) -> bool {
+        let TranslateResult::Mapped {
+            frame,
+            offset,
+            flags: mapped_flags,
+        } = page_table.translate(address)
+        else {
+            return false;
+        };
+
+        let caching = PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE;
+        frame.start_address() + offset == frame_address
+            && mapped_flags.contains(flags.difference(caching))
+            && mapped_flags.intersection(caching) == flags.intersection(caching)
+    }
+
+    unsafe fn map_page(
+        size: u64,
+        address: VirtAddr,
+        frame_address: PhysAddr,
+        flags: PageTableFlags,
+        page_table: &mut OffsetPageTable<'static>,
+        frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<(), MappingError> {
+        match size {
+            Size1GiB::SIZE => Self::map_sized_page::<Size1GiB>(
+                address,
+                frame_address,
+                flags,
+                page_table,
+                frame_allocator,
+            ),
+            Size2MiB::SIZE => Self::map_sized_page::<Size2MiB>(
+                address,
+                frame_address,
+                flags,
+                page_table,
+                frame_allocator,
+            ),
+            _ => Self::map_sized_page::<Size4KiB>(
+                address,
+                frame_address,
+                flags,
+                page_table,
+                frame_allocator,
+            ),
+        }
+    }
+
+    unsafe fn map_sized_page<S: PageSize>(
+        address: VirtAddr,
+        frame_address: PhysAddr,
+        flags: PageTableFlags,
+        page_table: &mut OffsetPageTable<'static>,
+        frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<(), MappingError>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let page = Page::<S>::containing_address(address);
+        let frame = PhysFrame::<S>::containing_address(frame_address);
+        page_table
+            .map_to(page, frame, flags, frame_allocator)?
+            .flush();
+        Ok(())
+    }
+
+    fn unmap_sized_page<S: PageSize>(
+        address: VirtAddr,
+        page_table: &mut OffsetPageTable<'static>,
+    ) -> Result<(), MappingError>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let page = Page::<S>::containing_address(address);
+        let (_, flush) = page_table.unmap(page)?;
+        flush.ignore();
+        Ok(())
+    }
+
+    // Splits the huge pages mapping `address` until it sits on a page
+    // boundary. Translations stay the same, so no TLB flush is needed.
+    unsafe fn split_huge_pages_at(
+        address: VirtAddr,
+        page_table: &mut OffsetPageTable<'static>,
+    ) -> Result<(), MappingError> {
+        let indexes = [address.p4_index(), address.p3_index(), address.p2_index()];
+        let mut table = page_table.level_4_table_mut() as *mut PageTable;
+
+        for (level, index) in (2..=4u8).rev().zip(indexes) {
+            let entry = &mut (*table)[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return Ok(());
+            }
+
+            if level < 4 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                let size = if level == 3 {
+                    Size1GiB::SIZE
+                } else {
+                    Size2MiB::SIZE
+                };
+                if address.is_aligned(size) {
+                    return Ok(());
+                }
+
+                let frame = super::FRAME_ALLOCATOR
+                    .lock()
+                    .allocate_frames(1)
+                    .ok_or(MappingError::FrameAllocationFailed)?;
+                let child: &mut PageTable =
+                    &mut *convert_physical_to_virtual(frame.start_address()).as_mut_ptr();
+
+                // 2 MiB children stay huge pages, 4 KiB ones must not set
+                // the bit since it selects the PAT entry at that level.
+                let mut child_flags = entry.flags();
+                if level == 2 {
+                    child_flags.remove(PageTableFlags::HUGE_PAGE);
+                }
+
+                let child_size = size / 512;
+                for (offset, child_entry) in child.iter_mut().enumerate() {
+                    let child_address = entry.addr() + offset as u64 * child_size;
+                    child_entry.set_addr(child_address, child_flags);
+                }
+
+                let flags = entry.flags() - PageTableFlags::HUGE_PAGE;
+                entry.set_addr(frame.start_address(), flags);
+            }
+
+            table = convert_physical_to_virtual(entry.addr()).as_mut_ptr();
+        }
+
+        Ok(())
+    }
+
+    unsafe fn protect_sized_page<S: PageSize>(
+        address: VirtAddr,
+        flags: PageTableFlags,
+        page_table: &mut OffsetPageTable<'static>,
+    ) -> Result<(), MappingError>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let page = Page::<S>::containing_address(address);
+        page_table.update_flags(page, flags)?.ignore();
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use alloc::collections::BTreeMap;
+use spin::{Lazy, Mutex};
+use x86_64::structures::paging::{OffsetPageTable, PageSize, PageTableFlags, PhysFrame};
+use x86_64::structures::paging::{PageTableIndex, Size1GiB, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr, align_up};
+
+use super::frame_data;
+use super::{FRAME_ALLOCATOR, KERNEL_PAGE_TABLE, MappingError, MappingType, MemoryManager};
+
+const MMIO_START: u64 = 0xffff_f000_0000_0000;
+const MMIO_SIZE: u64 = 64 * Size1GiB::SIZE;
+
+pub static MMIO_ALLOCATOR: Lazy<Mutex<MmioAllocator>> =
+    Lazy::new(|| Mutex::new(MmioAllocator::default()));
+
+// The whole window sits under one PML4 entry, which every page table shares
+// instead of copying, so windows mapped after a process was created still
+// show up in its address space.
+pub(super) fn table_index() -> PageTableIndex {
+    VirtAddr::new(MMIO_START).p4_index()
+}
+
+pub(super) fn reserve_table(page_table: &mut OffsetPageTable) {
+    let entry = &mut page_table.level_4_table_mut()[table_index()];
+    if !entry.is_unused() {
+        return;
+    }
+
+    let frame = FRAME_ALLOCATOR
+        .lock()
+        .allocate_frames(1)
+        .expect("Failed to allocate frame for MMIO page table");
+    frame_data(frame).fill(0);
+    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+}
+
+struct MmioMapping {
+    physical_address: PhysAddr,
+    length: u64,
+    flags: PageTableFlags,
+    references: usize,
+}
+
+pub struct MmioAllocator {
+    free_ranges: BTreeMap<u64, u64>,
+    mappings: BTreeMap<u64, MmioMapping>,
+}
+
+impl Default for MmioAllocator {
+    fn default() -> Self {
+        Self {
+            free_ranges: BTreeMap::from([(MMIO_START, MMIO_SIZE)]),
+            mappings: BTreeMap::new(),
+        }
+    }
+}
+
+impl MmioAllocator {
+    pub fn map(
+        &mut self,
+        physical_address: PhysAddr,
+        length: u64,
+        mapping_type: MappingType,
+    ) -> Result<VirtAddr, MappingError> {
+        let flags = mapping_type.flags();
+        let start = physical_address.align_down(Size4KiB::SIZE);
+        let end = (physical_address + length.max(1)).align_up(Size4KiB::SIZE);
+
+        if let Some((&virtual_start, mapping)) = self.mappings.iter_mut().find(|(_, mapping)| {
+            mapping.flags == flags
+                && mapping.physical_address <= start
+                && mapping.physical_address + mapping.length >= end
+        }) {
+            mapping.references += 1;
+            let offset = physical_address - mapping.physical_address;
+            return Ok(VirtAddr::new(virtual_start + offset));
+        }
+
+        let length = end - start;
+        let align = [Size1GiB::SIZE, Size2MiB::SIZE]
+            .into_iter()
+            .find(|&size| length >= size && start.is_aligned(size))
+            .unwrap_or(Size4KiB::SIZE);
+
+        let virtual_start = self
+            .allocate_range(length, align)
+            .ok_or(MappingError::AddressSpaceExhausted)?;
+
+        if let Err(err) = MemoryManager::map_range_to(
+            VirtAddr::new(virtual_start),
+            PhysFrame::containing_address(start),
+            length,
+            flags,
+            &mut KERNEL_PAGE_TABLE.lock(),
+        ) {
+            self.free_range(virtual_start, length);
+            return Err(err);
+        }
+
+        let mapping = MmioMapping {
+            physical_address: start,
+            length,
+            flags,
+            references: 1,
+        };
+        self.mappings.insert(virtual_start, mapping);
+
+        Ok(VirtAddr::new(virtual_start + (physical_address - start)))
+    }
+
+    pub fn unmap(&mut self, address: VirtAddr) -> Result<(), MappingError> {
+        let (&virtual_start, mapping) = self
+            .mappings
+            .range_mut(..=address.as_u64())
+            .next_back()
+            .filter(|(start, mapping)| address.as_u64() < *start + mapping.length)
+            .ok_or(MappingError::PageNotMapped)?;
+
+        mapping.references -= 1;
+        if mapping.references > 0 {
+            return Ok(());
+        }
+
+        let length = mapping.length;
+        self.mappings.remove(&virtual_start);
+
+        MemoryManager::unmap_range(
+            VirtAddr::new(virtual_start),
+            length,
+            &mut KERNEL_PAGE_TABLE.lock(),
+        )?;
+        self.free_range(virtual_start, length);
+
+        Ok(())
+    }
+}
+
+impl MmioAllocator {
+    fn allocate_range(&mut self, length: u64, align: u64) -> Option<u64> {
+        let (range_start, range_length, start) = self
+            .free_ranges
+            .iter()
+            .map(|(&range_start, &range_length)| {
+                (range_start, range_length, align_up(range_start, align))
+            })
+            .find(|&(range_start, range_length, start)| {
+                start + length <= range_start + range_length
+            })?;
+
+        self.free_ranges.remove(&range_start);
+
+        if start > range_start {
+            self.free_ranges.insert(range_start, start - range_start);
+        }
+
+        let range_end = range_start + range_length;
+        if start + length < range_end {
+            self.free_ranges
+                .insert(start + length, range_end - start - length);
+        }
+
+        Some(start)
+    }
+
+    fn free_range(&mut self, mut start: u64, mut length: u64) {
+        if let Some((&previous_start, &previous_length)) =
+            self.free_ranges.range(..start).next_back()
+        {
+            if previous_start + previous_length == start {
+                self.free_ranges.remove(&previous_start);
+                start = previous_start;
+                length += previous_length;
+            }
+        }
+
+        if let Some(next_length) = self.free_ranges.remove(&(start + length)) {
+            length += next_length;
+        }
+
+        self.free_ranges.insert(start, length);
+    }
+}
@@ -2,6 +2,7 @@ use limine::request::{HhdmRequest, MemoryMapRequest};
 use spin::{Lazy, Mutex};
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::{OffsetPageTable, PageTable};
+use x86_64::structures::paging::{PageSize, PhysFrame, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr};
 
 mod bitmap;
@@ -9,13 +10,23 @@ mod dma;
 mod frame;
 mod kernel_heap;
 mod manager;
+mod mmio;
+mod page_cache;
 mod page_table;
+mod reclaim;
+mod swap;
 
 pub use dma::{AlignedBuffer, DmaManager};
 pub use frame::BitmapFrameAllocator;
 pub use kernel_heap::init_heap;
-pub use manager::{MappingType, MemoryManager};
+pub use manager::{MappingError, MappingType, MemoryManager};
+pub use mmio::{MMIO_ALLOCATOR, MmioAllocator};
+pub use page_cache::{
+    PAGE_CACHE, PageBacking, PageCache, PageCacheError, PageKey, new_filesystem_id,
+};
 pub use page_table::*;
+pub use reclaim::{ensure_free_frames, reclaim, reclaim_thread};
+pub use swap::{SwapError, enable_swap, handle_swap_fault, swap_out_pages};
 
 #[used]
 #[unsafe(link_section = ".requests")]
@@ -28,8 +39,11 @@ static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
 pub static PHYSICAL_MEMORY_OFFSET: Lazy<u64> =
     Lazy::new(|| HHDM_REQUEST.get_response().unwrap().offset());
 
-pub static KERNEL_PAGE_TABLE: Lazy<Mutex<OffsetPageTable>> =
-    Lazy::new(|| Mutex::new(ref_current_page_table()));
+pub static KERNEL_PAGE_TABLE: Lazy<Mutex<OffsetPageTable>> = Lazy::new(|| {
+    let mut page_table = ref_current_page_table();
+    mmio::reserve_table(&mut page_table);
+    Mutex::new(page_table)
+});
 
 pub static FRAME_ALLOCATOR: Lazy<Mutex<BitmapFrameAllocator>> = Lazy::new(|| {
     let memory_map = MEMORY_MAP_REQUEST.get_response().unwrap();
@@ -44,6 +58,11 @@ pub fn convert_virtual_to_physical(virtual_address: VirtAddr) -> PhysAddr {
     PhysAddr::new(virtual_address.as_u64() - *PHYSICAL_MEMORY_OFFSET)
 }
 
+pub fn frame_data(frame: PhysFrame) -> &'static mut [u8] {
+    let address = convert_physical_to_virtual(frame.start_address());
+    unsafe { core::slice::from_raw_parts_mut(address.as_mut_ptr(), Size4KiB::SIZE as usize) }
+}
+
 pub fn ref_current_page_table() -> OffsetPageTable<'static> {
     let physical_address = Cr3::read().0.start_address();
     let page_table = convert_physical_to_virtual(physical_address).as_mut_ptr::<PageTable>();
@@ -0,0 +1,355 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lru::LruCache;
+use spin::{Lazy, Mutex};
+use thiserror::Error;
+use x86_64::structures::paging::{PageSize, PhysFrame, Size4KiB};
+
+use super::{FRAME_ALLOCATOR, frame_data};
+use crate::fs::{FsError, FsResult};
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+
+pub static PAGE_CACHE: Lazy<PageCache> = Lazy::new(PageCache::default);
+
+// Each mounted filesystem gets its own id, so inode numbers of different
+// mounts never share cached pages.
+pub fn new_filesystem_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub trait PageBacking: Send + Sync {
+    fn read_page(&self, offset: u64, buffer: &mut [u8]) -> FsResult<()>;
+
+    fn write_page(&self, _offset: u64, _buffer: &[u8]) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PageCacheError {
+    #[error("Failed to allocate frame for cached page")]
+    FrameAllocationFailed,
+    #[error("Backing I/O failed: {0}")]
+    Backing(#[from] FsError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PageKey {
+    pub filesystem: u64,
+    pub inode: u64,
+    pub offset: u64,
+}
+
+// Pages are read in and written back without the cache locked. While that
+// I/O runs the page is busy: accesses wait for it, and removals only mark it
+// discarded for the I/O owner to drop once it is done.
+struct CachedPage {
+    frame: PhysFrame,
+    dirty: bool,
+    busy: bool,
+    discarded: bool,
+    backing: Arc<dyn PageBacking>,
+}
+
+impl CachedPage {
+    fn data(&self) -> &'static mut [u8] {
+        frame_data(self.frame)
+    }
+}
+
+pub struct PageCache {
+    pages: Mutex<LruCache<PageKey, CachedPage>>,
+}
+
+impl Default for PageCache {
+    fn default() -> Self {
+        Self {
+            pages: Mutex::new(LruCache::unbounded()),
+        }
+    }
+}
+
+impl PageCache {
+    pub fn read(
+        &self,
+        filesystem: u64,
+        inode: u64,
+        offset: u64,
+        buffer: &mut [u8],
+        backing: &Arc<dyn PageBacking>,
+    ) -> Result<usize, PageCacheError> {
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let page_offset = (position % Size4KiB::SIZE) as usize;
+            let chunk_size = (PAGE_SIZE - page_offset).min(buffer.len() - done);
+
+            let key = page_key(filesystem, inode, position);
+            self.with_page(key, backing, |page| {
+                let data = &page.data()[page_offset..page_offset + chunk_size];
+                buffer[done..done + chunk_size].copy_from_slice(data);
+            })?;
+            done += chunk_size;
+        }
+
+        Ok(done)
+    }
+
+    pub fn write(
+        &self,
+        filesystem: u64,
+        inode: u64,
+        offset: u64,
+        buffer: &[u8],
+        backing: &Arc<dyn PageBacking>,
+    ) -> Result<usize, PageCacheError> {
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let page_offset = (position % Size4KiB::SIZE) as usize;
+            let chunk_size = (PAGE_SIZE - page_offset).min(buffer.len() - done);
+
+            let key = page_key(filesystem, inode, position);
+            self.with_page(key, backing, |page| {
+                let data = &mut page.data()[page_offset..page_offset + chunk_size];
+                data.copy_from_slice(&buffer[done..done + chunk_size]);
+                page.dirty = true;
+            })?;
+            done += chunk_size;
+        }
+
+        Ok(done)
+    }
+
+    pub fn sync(&self) -> Result<(), PageCacheError> {
+        self.write_back(|_| true)
+    }
+
+    // Copies freshly written data into pages that are already cached, for
+    // filesystems that write through to their own storage.
+    pub fn update(&self, filesystem: u64, inode: u64, offset: u64, buffer: &[u8]) {
+        let mut pages = self.pages.lock();
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let page_offset = (position % Size4KiB::SIZE) as usize;
+            let chunk_size = (PAGE_SIZE - page_offset).min(buffer.len() - done);
+
+            match pages.peek_mut(&page_key(filesystem, inode, position)) {
+                // A read in progress may have fetched the old contents.
+                Some(page) if page.busy => page.discarded = true,
+                Some(page) => {
+                    let data = &mut page.data()[page_offset..page_offset + chunk_size];
+                    data.copy_from_slice(&buffer[done..done + chunk_size]);
+                }
+                None => {}
+            }
+            done += chunk_size;
+        }
+    }
+
+    pub fn sync_inode(&self, filesystem: u64, inode: u64) -> Result<(), PageCacheError> {
+        self.write_back(|key| key.filesystem == filesystem && key.inode == inode)
+    }
+
+    pub fn invalidate(&self, filesystem: u64, inode: u64) -> Result<(), PageCacheError> {
+        self.sync_inode(filesystem, inode)?;
+        self.remove(|key| key.filesystem == filesystem && key.inode == inode);
+        Ok(())
+    }
+
+    // Drops pages without writing them back, for deleted or truncated files.
+    pub fn discard(&self, filesystem: u64, inode: u64) {
+        self.remove(|key| key.filesystem == filesystem && key.inode == inode);
+    }
+
+    // Drops the pages of an unmounted filesystem without writing them back.
+    pub fn invalidate_filesystem(&self, filesystem: u64) {
+        self.remove(|key| key.filesystem == filesystem);
+    }
+
+    // Gives up when the cache is locked, since reclaim may run from an
+    // allocation made while this CPU holds it.
+    pub fn evict(&self, count: usize) -> usize {
+        let mut evicted = 0;
+
+        while evicted < count {
+            let Some(mut pages) = self.pages.try_lock() else {
+                break;
+            };
+            let Some((&key, page)) = pages.iter().rev().find(|(_, page)| !page.busy) else {
+                break;
+            };
+
+            if !page.dirty {
+                let page = pages.pop(&key).unwrap();
+                drop(pages);
+                FRAME_ALLOCATOR.lock().deallocate_frames(page.frame, 1);
+                evicted += 1;
+                continue;
+            }
+
+            let (frame, backing) = (page.frame, page.backing.clone());
+            pages.peek_mut(&key).unwrap().busy = true;
+            drop(pages);
+
+            let result = backing.write_page(key.offset, frame_data(frame));
+
+            let mut pages = self.pages.lock();
+            let page = pages.peek_mut(&key).unwrap();
+            page.busy = false;
+            if let Err(err) = result {
+                log::warn!("Failed to write back cached page {key:?}: {err}");
+                if !page.discarded {
+                    // Keep the page and its data, the next sync retries it.
+                    pages.demote(&key);
+                    break;
+                }
+            }
+
+            pages.pop(&key);
+            drop(pages);
+            FRAME_ALLOCATOR.lock().deallocate_frames(frame, 1);
+            evicted += 1;
+        }
+
+        evicted
+    }
+}
+
+impl PageCache {
+    // Runs `access` on the cached page under the lock, reading the page in
+    // first if it is missing.
+    fn with_page<T>(
+        &self,
+        key: PageKey,
+        backing: &Arc<dyn PageBacking>,
+        access: impl FnOnce(&mut CachedPage) -> T,
+    ) -> Result<T, PageCacheError> {
+        loop {
+            let mut pages = self.pages.lock();
+            match pages.get_mut(&key) {
+                Some(page) if !page.busy => return Ok(access(page)),
+                Some(_) => {
+                    drop(pages);
+                    core::hint::spin_loop();
+                    continue;
+                }
+                None => {}
+            }
+            drop(pages);
+
+            let frame = self.allocate_frame()?;
+            let mut pages = self.pages.lock();
+            if pages.contains(&key) {
+                drop(pages);
+                FRAME_ALLOCATOR.lock().deallocate_frames(frame, 1);
+                continue;
+            }
+            pages.put(
+                key,
+                CachedPage {
+                    frame,
+                    dirty: false,
+                    busy: true,
+                    discarded: false,
+                    backing: backing.clone(),
+                },
+            );
+            drop(pages);
+
+            let result = backing.read_page(key.offset, frame_data(frame));
+
+            let mut pages = self.pages.lock();
+            let page = pages.peek_mut(&key).unwrap();
+            if result.is_ok() && !page.discarded {
+                page.busy = false;
+                continue;
+            }
+
+            pages.pop(&key);
+            drop(pages);
+            FRAME_ALLOCATOR.lock().deallocate_frames(frame, 1);
+            result?;
+        }
+    }
+
+    // Writes dirty pages back one at a time, each marked busy so it cannot be
+    // changed or dropped while the lock is released for the I/O.
+    fn write_back(&self, filter: impl Fn(&PageKey) -> bool) -> Result<(), PageCacheError> {
+        loop {
+            let mut pages = self.pages.lock();
+            let Some((&key, page)) = pages
+                .iter_mut()
+                .find(|(key, page)| page.dirty && !page.busy && filter(key))
+            else {
+                return Ok(());
+            };
+
+            page.busy = true;
+            let (frame, backing) = (page.frame, page.backing.clone());
+            drop(pages);
+
+            let result = backing.write_page(key.offset, frame_data(frame));
+
+            let mut pages = self.pages.lock();
+            let page = pages.peek_mut(&key).unwrap();
+            page.busy = false;
+            if result.is_ok() {
+                page.dirty = false;
+            }
+            if page.discarded {
+                pages.pop(&key);
+                drop(pages);
+                FRAME_ALLOCATOR.lock().deallocate_frames(frame, 1);
+            }
+            result?;
+        }
+    }
+
+    fn remove(&self, filter: impl Fn(&PageKey) -> bool) {
+        let mut pages = self.pages.lock();
+        let keys = pages
+            .iter()
+            .filter(|(key, _)| filter(key))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        for key in keys {
+            let page = pages.peek_mut(&key).unwrap();
+            if page.busy {
+                page.discarded = true;
+            } else {
+                frame_allocator.deallocate_frames(page.frame, 1);
+                pages.pop(&key);
+            }
+        }
+    }
+
+    fn allocate_frame(&self) -> Result<PhysFrame, PageCacheError> {
+        loop {
+            if let Some(frame) = FRAME_ALLOCATOR.lock().allocate_frames(1) {
+                return Ok(frame);
+            }
+
+            if self.evict(1) == 0 {
+                return Err(PageCacheError::FrameAllocationFailed);
+            }
+        }
+    }
+}
+
+fn page_key(filesystem: u64, inode: u64, position: u64) -> PageKey {
+    PageKey {
+        filesystem,
+        inode,
+        offset: position - position % Size4KiB::SIZE,
+    }
+}
@@ -1,23 +1,27 @@
 use alloc::vec;
 use alloc::vec::Vec;
-use x86_64::structures::paging::FrameAllocator;
-use x86_64::structures::paging::FrameDeallocator;
 use x86_64::structures::paging::PageSize;
 use x86_64::structures::paging::PhysFrame;
 use x86_64::structures::paging::Size4KiB;
 use x86_64::structures::paging::mapper::*;
+use x86_64::structures::paging::page_table::PageTableEntry;
 use x86_64::structures::paging::{PageTable, PageTableFlags};
 use x86_64::{PhysAddr, VirtAddr};
 
-use super::MappingType;
+use super::swap::{SWAPPED, duplicate_swap_entry, free_swap_entry};
 use super::{FRAME_ALLOCATOR, PHYSICAL_MEMORY_OFFSET};
+use super::{MappingType, mmio};
 use super::{convert_physical_to_virtual, convert_virtual_to_physical};
+use crate::arch::shootdown;
+
+pub(super) const USER_SPACE_END: u64 = 0x0000_7fff_ffff_ffff;
 
 pub trait ExtendedPageTable {
     fn physical_address(&self) -> PhysAddr;
     unsafe fn write_to_mapped(&self, buffer: &[u8], address: VirtAddr);
     unsafe fn deep_copy(&self) -> OffsetPageTable<'static>;
     unsafe fn free_user_pages(&mut self);
+    unsafe fn leaf_entry_mut(&mut self, address: VirtAddr) -> Option<&'static mut PageTableEntry>;
 }
 
 impl ExtendedPageTable for OffsetPageTable<'_> {
@@ -50,9 +54,12 @@ impl ExtendedPageTable for OffsetPageTable<'_> {
     }
 
     unsafe fn free_user_pages(&mut self) {
+        let user_space = VirtAddr::zero()..VirtAddr::new(USER_SPACE_END);
+        shootdown::shootdown(user_space, self.physical_address());
+
         let frame_allocator = &mut FRAME_ALLOCATOR.lock();
         let mut table_frames_to_free: Vec<PhysFrame> = Vec::new();
-        let mut stack = vec![(self.level_4_table_mut() as *mut PageTable, 4)];
+        let mut stack = vec![(self.level_4_table_mut() as *mut PageTable, 4u8)];
 
         while let Some((table_ptr, current_level)) = stack.pop() {
             let table = &mut *table_ptr;
@@ -62,14 +69,18 @@ impl ExtendedPageTable for OffsetPageTable<'_> {
             let table_frame = PhysFrame::containing_address(table_paddr);
             table_frames_to_free.push(table_frame);
 
-            for entry in table.iter_mut().filter(|entry| {
-                !entry.is_unused() && !entry.flags().contains(PageTableFlags::HUGE_PAGE)
-            }) {
-                if current_level == 1 {
+            for (index, entry) in table.iter_mut().enumerate() {
+                if entry.is_unused() || is_shared_entry(index, current_level) {
+                    continue;
+                }
+
+                if is_leaf_entry(entry, current_level) {
                     if entry.flags().contains(MappingType::UserCode.flags()) {
-                        if let Ok(frame) = entry.frame() {
-                            frame_allocator.deallocate_frame(frame);
-                        }
+                        let frame = PhysFrame::containing_address(entry.addr());
+                        let count = 1 << (9 * (current_level - 1));
+                        frame_allocator.deallocate_frames(frame, count);
+                    } else if entry.flags().contains(SWAPPED) {
+                        free_swap_entry(entry);
                     }
                 } else {
                     let child_address = convert_physical_to_virtual(entry.addr());
@@ -79,15 +90,39 @@ impl ExtendedPageTable for OffsetPageTable<'_> {
         }
 
         for frame in table_frames_to_free.into_iter().rev() {
-            frame_allocator.deallocate_frame(frame);
+            frame_allocator.deallocate_frames(frame, 1);
         }
     }
 
+    unsafe fn leaf_entry_mut(&mut self, address: VirtAddr) -> Option<&'static mut PageTableEntry> {
+        let mut table = self.level_4_table_mut() as *mut PageTable;
+        let indexes = [
+            address.p4_index(),
+            address.p3_index(),
+            address.p2_index(),
+            address.p1_index(),
+        ];
+
+        for (level, index) in (1..=4u8).rev().zip(indexes) {
+            let entry = &mut (*table)[index];
+            if level == 1 {
+                return Some(entry);
+            }
+
+            if !entry.flags().contains(PageTableFlags::PRESENT) || is_leaf_entry(entry, level) {
+                return None;
+            }
+            table = convert_physical_to_virtual(entry.addr()).as_mut_ptr();
+        }
+
+        None
+    }
+
     unsafe fn deep_copy(&self) -> OffsetPageTable<'static> {
         let frame_allocator = &mut FRAME_ALLOCATOR.lock();
 
         let root_table_frame = frame_allocator
-            .allocate_frame()
+            .allocate_frames(1)
             .expect("Failed to allocate frame for root page table")
             .start_address();
 
@@ -104,11 +139,16 @@ impl ExtendedPageTable for OffsetPageTable<'_> {
                 .enumerate()
                 .filter(|(_, entry)| !entry.is_unused())
             {
-                if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                if is_shared_entry(index, level) {
+                    (&mut *target_table)[index].set_addr(entry.addr(), entry.flags());
+                } else if is_leaf_entry(entry, level) {
+                    if entry.flags().contains(SWAPPED) {
+                        duplicate_swap_entry(entry);
+                    }
                     (&mut *target_table)[index].set_addr(entry.addr(), entry.flags());
                 } else {
                     let target_child_frame = frame_allocator
-                        .allocate_frame()
+                        .allocate_frames(1)
                         .expect("Failed to allocate frame for child page table")
                         .start_address();
 
@@ -126,3 +166,11 @@ impl ExtendedPageTable for OffsetPageTable<'_> {
         OffsetPageTable::new(root_table, VirtAddr::new(*PHYSICAL_MEMORY_OFFSET))
     }
 }
+
+fn is_shared_entry(index: usize, level: u8) -> bool {
+    level == 4 && index == usize::from(mmio::table_index())
+}
+
+fn is_leaf_entry(entry: &PageTableEntry, level: u8) -> bool {
+    level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+}
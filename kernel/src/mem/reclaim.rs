@@ -0,0 +1,31 @@
+use super::FRAME_ALLOCATOR;
+use super::page_cache::PAGE_CACHE;
+use crate::tasks::process::Process;
+
+const LOW_WATERMARK: usize = 1024;
+const HIGH_WATERMARK: usize = 4096;
+const RECLAIM_INTERVAL_MS: u64 = 500;
+
+pub fn reclaim(target: usize) -> usize {
+    let mut reclaimed = PAGE_CACHE.evict(target);
+
+    if reclaimed < target {
+        reclaimed += Process::swap_out(target - reclaimed);
+    }
+
+    reclaimed
+}
+
+pub fn ensure_free_frames(count: usize) {
+    let free_frames = FRAME_ALLOCATOR.lock().free_frames();
+    if free_frames < count + LOW_WATERMARK {
+        reclaim(count + HIGH_WATERMARK - free_frames);
+    }
+}
+
+pub fn reclaim_thread() {
+    loop {
+        ensure_free_frames(0);
+        crate::syscall::sleep(RECLAIM_INTERVAL_MS);
+    }
+}
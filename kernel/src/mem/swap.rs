@@ -0,0 +1,334 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use thiserror::Error;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{OffsetPageTable, PageTable, PageTableFlags};
+use x86_64::structures::paging::{PageSize, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::page_table::USER_SPACE_END;
+use super::{ExtendedPageTable, FRAME_ALLOCATOR};
+use super::{convert_physical_to_virtual, frame_data, ref_current_page_table};
+use crate::arch::shootdown;
+use crate::io::block::{BlockDevice, BlockDeviceError};
+use crate::tasks::process::Process;
+
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_9;
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+const USER_TABLE_ENTRIES: usize = 256;
+const FRAME_WAIT_ATTEMPTS: usize = 20;
+const FRAME_WAIT_MS: u64 = 50;
+
+// Linux version 1 swap header, as written by mkswap.
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+const HEADER_VERSION: u32 = 1;
+const VERSION_OFFSET: usize = 1024;
+const LAST_PAGE_OFFSET: usize = 1028;
+const BAD_PAGE_COUNT_OFFSET: usize = 1032;
+const BAD_PAGES_OFFSET: usize = 1536;
+
+static SWAP: Mutex<Option<SwapSpace>> = Mutex::new(None);
+
+#[derive(Error, Debug)]
+pub enum SwapError {
+    #[error("Unsupported block size {0}")]
+    UnsupportedBlockSize(usize),
+    #[error("Swap device is too small")]
+    DeviceTooSmall,
+    #[error("No swap signature found")]
+    MissingSignature,
+    #[error("Unsupported swap header version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Swap device error: {0}")]
+    DeviceError(#[from] BlockDeviceError),
+}
+
+#[derive(Clone)]
+struct SwapDevice {
+    device: Arc<dyn BlockDevice>,
+    blocks_per_page: u64,
+}
+
+impl SwapDevice {
+    fn write_page(&self, slot: u64, frame: PhysFrame) -> Result<(), SwapError> {
+        let block_id = slot * self.blocks_per_page;
+        self.device.write_block(block_id, frame_data(frame))?;
+        Ok(())
+    }
+
+    fn read_page(&self, slot: u64, frame: PhysFrame) -> Result<(), SwapError> {
+        let block_id = slot * self.blocks_per_page;
+        self.device.read_block(block_id, frame_data(frame))?;
+        Ok(())
+    }
+}
+
+// Page I/O runs on a clone of the device without holding the lock, slots
+// are only handed out and freed under it. Copied page tables share swapped
+// entries, so each slot counts the entries referring to it.
+struct SwapSpace {
+    device: SwapDevice,
+    references: Vec<u16>,
+}
+
+impl SwapSpace {
+    fn allocate_slot(&mut self) -> Option<u64> {
+        let slot = self.references.iter().position(|&count| count == 0)?;
+        self.references[slot] = 1;
+        Some(slot as u64)
+    }
+
+    fn duplicate_slot(&mut self, slot: u64) {
+        if let Some(count) = self.references.get_mut(slot as usize) {
+            *count += 1;
+        }
+    }
+
+    fn free_slot(&mut self, slot: u64) {
+        if let Some(count) = self.references.get_mut(slot as usize) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+pub fn enable_swap(device: Arc<dyn BlockDevice>) -> Result<(), SwapError> {
+    let block_size = device.block_size();
+    if block_size == 0 || PAGE_SIZE % block_size != 0 {
+        return Err(SwapError::UnsupportedBlockSize(block_size));
+    }
+
+    // Only devices carrying a swap header are used, so a mislabeled
+    // partition or a hibernation image is never overwritten.
+    let mut header = vec![0; PAGE_SIZE];
+    device.read_block(0, &mut header)?;
+    if !header.ends_with(SWAP_MAGIC) {
+        return Err(SwapError::MissingSignature);
+    }
+
+    let read_u32 =
+        |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let version = read_u32(VERSION_OFFSET);
+    if version != HEADER_VERSION {
+        return Err(SwapError::UnsupportedVersion(version));
+    }
+
+    let blocks_per_page = (PAGE_SIZE / block_size) as u64;
+    let slot_count = (device.block_count() / blocks_per_page)
+        .min(read_u32(LAST_PAGE_OFFSET) as u64 + 1) as usize;
+    if slot_count < 2 {
+        return Err(SwapError::DeviceTooSmall);
+    }
+
+    // The first page holds the header, bad pages are never handed out.
+    let mut references = vec![0; slot_count];
+    references[0] = 1;
+
+    let max_bad_pages = (PAGE_SIZE - SWAP_MAGIC.len() - BAD_PAGES_OFFSET) / 4;
+    let bad_pages = (read_u32(BAD_PAGE_COUNT_OFFSET) as usize).min(max_bad_pages);
+    for index in 0..bad_pages {
+        let page = read_u32(BAD_PAGES_OFFSET + index * 4) as usize;
+        if let Some(count) = references.get_mut(page) {
+            *count = 1;
+        }
+    }
+
+    let usable = references.iter().filter(|&&count| count == 0).count();
+    *SWAP.lock() = Some(SwapSpace {
+        device: SwapDevice {
+            device,
+            blocks_per_page,
+        },
+        references,
+    });
+
+    log::info!("Swap enabled with {usable} pages");
+    Ok(())
+}
+
+// Callers must hold the page table's owner locked, which keeps the swap-in
+// path away from the victims until their contents are on the device.
+pub fn swap_out_pages(page_table: &mut OffsetPageTable<'static>, count: usize) -> usize {
+    let mut swap = SWAP.lock();
+    let Some(space) = swap.as_mut().filter(|_| count > 0) else {
+        return 0;
+    };
+    let device = space.device.clone();
+
+    let mut victims = Vec::new();
+    for entry in unsafe { select_victims(page_table, count) } {
+        let Some(slot) = space.allocate_slot() else {
+            break;
+        };
+
+        let (frame, flags) = (entry.frame().unwrap(), entry.flags());
+        let swap_flags = (flags - PageTableFlags::PRESENT) | SWAPPED;
+        entry.set_addr(PhysAddr::new(slot * Size4KiB::SIZE), swap_flags);
+        victims.push((entry, frame, flags, slot));
+    }
+    drop(swap);
+
+    if victims.is_empty() {
+        return 0;
+    }
+
+    let user_space = VirtAddr::zero()..VirtAddr::new(USER_SPACE_END);
+    shootdown::shootdown(user_space, page_table.physical_address());
+
+    let mut freed = Vec::new();
+    for (entry, frame, flags, slot) in victims {
+        match device.write_page(slot, frame) {
+            Ok(()) => freed.push(frame),
+            Err(err) => {
+                log::warn!("Failed to swap out page: {err}");
+                entry.set_addr(frame.start_address(), flags);
+                free_slot(slot);
+            }
+        }
+    }
+
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for &frame in freed.iter() {
+        frame_allocator.deallocate_frames(frame, 1);
+    }
+
+    freed.len()
+}
+
+// Runs in the page fault handler on the faulting thread's kernel stack. If
+// the faulting code could be preempted, so can this, so interrupts are
+// turned back on and the I/O and frame waits sleep instead of spinning.
+// Reclaim is left to its own thread, it may need locks held around here.
+pub fn handle_swap_fault(address: VirtAddr, preemptible: bool) -> bool {
+    // Kernel addresses and pages that were never swapped out, like NULL
+    // dereferences, fail here before any lock is taken or frame allocated.
+    if address.as_u64() >= USER_SPACE_END || !is_swapped(address) {
+        return false;
+    }
+
+    if preemptible {
+        interrupts::enable();
+    }
+
+    let Some(process) = Process::current() else {
+        return false;
+    };
+    let Some(frame) = allocate_frame(preemptible) else {
+        log::error!("No memory to swap in page at {address:#x}");
+        return false;
+    };
+
+    // The owner's lock keeps swap_out_pages and other faulting threads off
+    // this page table until the entry is resolved.
+    let mut process = process.write();
+    let Some(entry) = (unsafe { process.page_table.leaf_entry_mut(address) }) else {
+        FRAME_ALLOCATOR.lock().deallocate_frames(frame, 1);
+        return false;
+    };
+
+    let flags = entry.flags();
+    if flags.contains(PageTableFlags::PRESENT) || !flags.contains(SWAPPED) {
+        FRAME_ALLOCATOR.lock().deallocate_frames(frame, 1);
+        // Another thread may have swapped the page in while we waited.
+        return flags.contains(PageTableFlags::PRESENT);
+    }
+
+    let slot = entry.addr().as_u64() / Size4KiB::SIZE;
+    let device = SWAP.lock().as_ref().map(|space| space.device.clone());
+    let result = match device {
+        Some(device) => device.read_page(slot, frame),
+        None => Err(SwapError::DeviceError(BlockDeviceError::DeviceNotFound)),
+    };
+
+    if let Err(err) = result {
+        log::error!("Failed to swap in page at {address:#x}: {err}");
+        FRAME_ALLOCATOR.lock().deallocate_frames(frame, 1);
+        return false;
+    }
+
+    free_slot(slot);
+    let present_flags = (flags - SWAPPED) | PageTableFlags::PRESENT;
+    entry.set_addr(frame.start_address(), present_flags);
+    true
+}
+
+// Peeks at the faulting CPU's page table without locking its owner, the
+// entry is checked again under the lock.
+fn is_swapped(address: VirtAddr) -> bool {
+    let mut page_table = ref_current_page_table();
+    unsafe { page_table.leaf_entry_mut(address) }.is_some_and(|entry| {
+        let flags = entry.flags();
+        flags.contains(SWAPPED) && !flags.contains(PageTableFlags::PRESENT)
+    })
+}
+
+pub fn free_swap_entry(entry: &PageTableEntry) {
+    free_slot(entry.addr().as_u64() / Size4KiB::SIZE);
+}
+
+// Called for each swapped entry copied into another page table. The slot
+// stays allocated until every copy was swapped in or freed.
+pub fn duplicate_swap_entry(entry: &PageTableEntry) {
+    if let Some(space) = SWAP.lock().as_mut() {
+        space.duplicate_slot(entry.addr().as_u64() / Size4KiB::SIZE);
+    }
+}
+
+fn free_slot(slot: u64) {
+    if let Some(space) = SWAP.lock().as_mut() {
+        space.free_slot(slot);
+    }
+}
+
+fn allocate_frame(preemptible: bool) -> Option<PhysFrame> {
+    for _ in 0..FRAME_WAIT_ATTEMPTS {
+        if let Some(frame) = FRAME_ALLOCATOR.lock().allocate_frames(1) {
+            return Some(frame);
+        }
+        if !preemptible {
+            break;
+        }
+        crate::syscall::sleep(FRAME_WAIT_MS);
+    }
+    None
+}
+
+unsafe fn select_victims(
+    page_table: &mut OffsetPageTable<'static>,
+    count: usize,
+) -> Vec<&'static mut PageTableEntry> {
+    let user_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let mut victims = Vec::new();
+    let mut stack = vec![(page_table.level_4_table_mut() as *mut PageTable, 4u8)];
+
+    while let Some((table_ptr, level)) = stack.pop() {
+        let table = &mut *table_ptr;
+        let entry_count = if level == 4 { USER_TABLE_ENTRIES } else { 512 };
+
+        for entry in table.iter_mut().take(entry_count) {
+            let flags = entry.flags();
+            if !flags.contains(user_flags) {
+                continue;
+            }
+
+            if level > 1 {
+                if !flags.contains(PageTableFlags::HUGE_PAGE) {
+                    let child_address = convert_physical_to_virtual(entry.addr());
+                    stack.push((child_address.as_mut_ptr(), level - 1));
+                }
+            } else if flags.contains(PageTableFlags::ACCESSED) {
+                entry.set_flags(flags - PageTableFlags::ACCESSED);
+            } else {
+                victims.push(entry);
+                if victims.len() >= count {
+                    return victims;
+                }
+            }
+        }
+    }
+
+    victims
+}
@@ -0,0 +1,16 @@
+use core::slice::from_raw_parts;
+use limine::request::ModuleRequest;
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
+
+// Modules are matched on their `module_string` in limine.conf.
+pub fn find(string: &str) -> impl Iterator<Item = &'static [u8]> {
+    MODULE_REQUEST
+        .get_response()
+        .into_iter()
+        .flat_map(|response| response.modules().iter())
+        .filter(move |module| module.string() == string.as_bytes())
+        .map(|module| unsafe { from_raw_parts(module.addr(), module.size() as _) })
+}
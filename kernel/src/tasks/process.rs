@@ -8,10 +8,11 @@ use spin::{Lazy, RwLock};
 use x86_64::VirtAddr;
 use x86_64::structures::paging::OffsetPageTable;
 
+use super::scheduler::SCHEDULER;
 use super::thread::{SharedThread, Thread};
 use crate::mem::{ExtendedPageTable, ref_current_page_table};
 use crate::mem::{FRAME_ALLOCATOR, KERNEL_PAGE_TABLE};
-use crate::mem::{MappingType, MemoryManager};
+use crate::mem::{MappingType, MemoryManager, swap_out_pages};
 
 pub(super) type SharedProcess = Arc<RwLock<Process>>;
 pub(super) type WeakSharedProcess = Weak<RwLock<Process>>;
@@ -63,6 +64,34 @@ impl Process {
         }
     }
 
+    pub fn all() -> Vec<SharedProcess> {
+        PROCESSES.read().clone()
+    }
+
+    pub fn current() -> Option<SharedProcess> {
+        let thread = SCHEDULER.lock().current().upgrade()?;
+        let process = thread.read().process.upgrade();
+        process
+    }
+
+    pub fn swap_out(count: usize) -> usize {
+        let Some(processes) = PROCESSES.try_read() else {
+            return 0;
+        };
+        let mut swapped = 0;
+
+        for process in processes.iter() {
+            if swapped >= count {
+                break;
+            }
+            if let Some(mut process) = process.try_write() {
+                swapped += swap_out_pages(&mut process.page_table, count - swapped);
+            }
+        }
+
+        swapped
+    }
+
     pub fn create(name: &str, elf_data: &'static [u8]) {
         let binary = ProcessBinary::parse(elf_data);
         let mut page_table = unsafe { KERNEL_PAGE_TABLE.lock().deep_copy() };
@@ -6,7 +6,7 @@ use x86_64::VirtAddr;
 
 use super::context::Context;
 use super::thread::{Thread, WeakSharedThread};
-use crate::arch::apic::LAPIC;
+use crate::arch::apic;
 use crate::arch::smp::CPUS;
 
 pub static SCHEDULER_INIT: AtomicBool = AtomicBool::new(false);
@@ -52,14 +52,14 @@ impl Scheduler {
 
     #[inline]
     pub fn current(&self) -> WeakSharedThread {
-        let lapic_id = unsafe { LAPIC.lock().id() };
+        let lapic_id = apic::current_id();
         self.current_threads[&lapic_id].clone()
     }
 }
 
 impl Scheduler {
     pub fn schedule(&mut self, context: VirtAddr) -> VirtAddr {
-        let lapic_id = unsafe { LAPIC.lock().id() };
+        let lapic_id = apic::current_id();
 
         if let Some(weak) = self.current_threads.get(&lapic_id) {
             if let Some(thread) = weak.upgrade() {
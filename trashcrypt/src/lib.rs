@@ -0,0 +1,165 @@
+#![no_std]
+
+//! Format of TrashOS encrypted block devices, shared by the kernel and the
+//! image builder.
+//!
+//! The device starts with a header followed by the payload, which is
+//! encrypted sector by sector with AES-256-XTS under a random master key.
+//! The header stores that master key encrypted with a key derived from the
+//! passphrase using PBKDF2-HMAC-SHA256, plus a digest of the master key to
+//! tell a wrong passphrase apart from a valid one.
+
+pub mod xts;
+
+#[cfg(test)]
+mod tests;
+
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use thiserror::Error;
+
+pub use xts::Xts;
+
+pub const MAGIC: [u8; 8] = *b"TCRYPT\0\0";
+pub const VERSION: u32 = 1;
+
+pub const KEY_SIZE: usize = 64;
+pub const SALT_SIZE: usize = 32;
+pub const DIGEST_SIZE: usize = 32;
+
+// The encoded header fits in the smallest sector, the payload starts at the
+// next 4 KiB boundary so it stays aligned on 4K-native drives.
+pub const HEADER_SIZE: usize = 512;
+pub const PAYLOAD_OFFSET: u64 = 4096;
+
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+const DIGEST_ITERATIONS: u32 = 1000;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptError {
+    #[error("Not an encrypted device")]
+    BadMagic,
+    #[error("Unsupported header version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Invalid {0}")]
+    Invalid(&'static str),
+    #[error("Wrong passphrase")]
+    WrongPassphrase,
+}
+
+pub type CryptResult<T> = Result<T, CryptError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub iterations: u32,
+    pub sector_size: u32,
+    pub payload_offset: u64,
+    pub uuid: [u8; 16],
+    salt: [u8; SALT_SIZE],
+    wrapped_key: [u8; KEY_SIZE],
+    key_digest: [u8; DIGEST_SIZE],
+}
+
+impl Header {
+    pub fn new(
+        passphrase: &[u8],
+        master_key: &[u8; KEY_SIZE],
+        salt: [u8; SALT_SIZE],
+        uuid: [u8; 16],
+        iterations: u32,
+        sector_size: u32,
+    ) -> CryptResult<Self> {
+        let mut header = Self {
+            iterations,
+            sector_size,
+            payload_offset: PAYLOAD_OFFSET.next_multiple_of(sector_size as u64),
+            uuid,
+            salt,
+            wrapped_key: *master_key,
+            key_digest: [0; DIGEST_SIZE],
+        };
+        header.validate()?;
+
+        header
+            .wrapping_key(passphrase)
+            .encrypt_sector(0, &mut header.wrapped_key);
+        header.key_digest = header.digest(master_key);
+        Ok(header)
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut data = [0; HEADER_SIZE];
+        data[0..8].copy_from_slice(&MAGIC);
+        data[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        data[12..16].copy_from_slice(&self.iterations.to_le_bytes());
+        data[16..20].copy_from_slice(&self.sector_size.to_le_bytes());
+        data[24..32].copy_from_slice(&self.payload_offset.to_le_bytes());
+        data[32..48].copy_from_slice(&self.uuid);
+        data[48..80].copy_from_slice(&self.salt);
+        data[80..144].copy_from_slice(&self.wrapped_key);
+        data[144..176].copy_from_slice(&self.key_digest);
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> CryptResult<Self> {
+        if data.len() < HEADER_SIZE || data[0..8] != MAGIC {
+            return Err(CryptError::BadMagic);
+        }
+
+        let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(CryptError::UnsupportedVersion(version));
+        }
+
+        let header = Self {
+            iterations: u32::from_le_bytes(data[12..16].try_into().unwrap()),
+            sector_size: u32::from_le_bytes(data[16..20].try_into().unwrap()),
+            payload_offset: u64::from_le_bytes(data[24..32].try_into().unwrap()),
+            uuid: data[32..48].try_into().unwrap(),
+            salt: data[48..80].try_into().unwrap(),
+            wrapped_key: data[80..144].try_into().unwrap(),
+            key_digest: data[144..176].try_into().unwrap(),
+        };
+        header.validate()?;
+        Ok(header)
+    }
+
+    // Runs the full key derivation, so expect it to take a while.
+    pub fn unlock(&self, passphrase: &[u8]) -> CryptResult<Xts> {
+        let mut master_key = self.wrapped_key;
+        self.wrapping_key(passphrase)
+            .decrypt_sector(0, &mut master_key);
+
+        match self.digest(&master_key) == self.key_digest {
+            true => Ok(Xts::new(&master_key)),
+            false => Err(CryptError::WrongPassphrase),
+        }
+    }
+
+    fn validate(&self) -> CryptResult<()> {
+        let sector_size = self.sector_size as u64;
+        if self.iterations == 0 {
+            return Err(CryptError::Invalid("iteration count"));
+        }
+        if !self.sector_size.is_power_of_two() || (self.sector_size as usize) < HEADER_SIZE {
+            return Err(CryptError::Invalid("sector size"));
+        }
+        if self.payload_offset < PAYLOAD_OFFSET || !self.payload_offset.is_multiple_of(sector_size)
+        {
+            return Err(CryptError::Invalid("payload offset"));
+        }
+        Ok(())
+    }
+
+    fn wrapping_key(&self, passphrase: &[u8]) -> Xts {
+        let mut key = [0; KEY_SIZE];
+        pbkdf2_hmac::<Sha256>(passphrase, &self.salt, self.iterations, &mut key);
+        Xts::new(&key)
+    }
+
+    fn digest(&self, master_key: &[u8; KEY_SIZE]) -> [u8; DIGEST_SIZE] {
+        let mut digest = [0; DIGEST_SIZE];
+        pbkdf2_hmac::<Sha256>(master_key, &self.salt, DIGEST_ITERATIONS, &mut digest);
+        digest
+    }
+}
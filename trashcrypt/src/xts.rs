@@ -0,0 +1,62 @@
+//! AES-256-XTS with the sector number as the tweak, like dm-crypt's
+//! `aes-xts-plain64`. Sectors must be a multiple of the AES block size, so
+//! ciphertext stealing is never needed.
+
+use aes::Aes256;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+
+use super::KEY_SIZE;
+
+pub const AES_BLOCK_SIZE: usize = 16;
+
+pub struct Xts {
+    data: Aes256,
+    tweak: Aes256,
+}
+
+impl Xts {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        let (data, tweak) = key.split_at(KEY_SIZE / 2);
+        Self {
+            data: Aes256::new(data.into()),
+            tweak: Aes256::new(tweak.into()),
+        }
+    }
+
+    pub fn encrypt_sector(&self, sector: u64, data: &mut [u8]) {
+        self.process(sector, data, |block| self.data.encrypt_block(block.into()));
+    }
+
+    pub fn decrypt_sector(&self, sector: u64, data: &mut [u8]) {
+        self.process(sector, data, |block| self.data.decrypt_block(block.into()));
+    }
+
+    fn process(&self, sector: u64, data: &mut [u8], cipher: impl Fn(&mut [u8; AES_BLOCK_SIZE])) {
+        assert!(data.len().is_multiple_of(AES_BLOCK_SIZE));
+
+        let mut tweak = [0; AES_BLOCK_SIZE];
+        tweak[..8].copy_from_slice(&sector.to_le_bytes());
+        self.tweak.encrypt_block((&mut tweak).into());
+        let mut tweak = u128::from_le_bytes(tweak);
+
+        for chunk in data.chunks_exact_mut(AES_BLOCK_SIZE) {
+            let block: &mut [u8; AES_BLOCK_SIZE] = chunk.try_into().unwrap();
+            let mask = tweak.to_le_bytes();
+
+            xor(block, &mask);
+            cipher(block);
+            xor(block, &mask);
+
+            // Multiply by the primitive element of GF(2^128).
+            let carry = tweak >> 127;
+            tweak = (tweak << 1) ^ (carry * 0x87);
+        }
+    }
+}
+
+fn xor(block: &mut [u8; AES_BLOCK_SIZE], mask: &[u8; AES_BLOCK_SIZE]) {
+    block
+        .iter_mut()
+        .zip(mask)
+        .for_each(|(byte, mask)| *byte ^= mask);
+}
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use argh::FromArgs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use trashfs_tools::Image;
+use trashfs_tools::fsck::check;
+
+#[derive(FromArgs)]
+#[argh(description = "Verify a TrashFS filesystem image")]
+struct Args {
+    #[argh(positional)]
+    image: PathBuf,
+
+    #[argh(switch, short = 'v')]
+    #[argh(description = "list every inode while walking the tree")]
+    verbose: bool,
+}
+
+fn main() -> Result<ExitCode> {
+    let args: Args = argh::from_env();
+    let mut image = Image::open(&args.image, false)?;
+    let summary = check(&mut image, args.verbose)?;
+
+    let superblock = &summary.superblock;
+    println!(
+        "{}: {}/{} inodes, {}/{} blocks",
+        match superblock.label() {
+            "" => args.image.display().to_string(),
+            label => label.to_string(),
+        },
+        summary.used_inodes,
+        superblock.inode_count,
+        summary.used_blocks,
+        superblock.block_count
+    );
+
+    if summary.errors.is_empty() {
+        println!("Filesystem is clean");
+        Ok(ExitCode::SUCCESS)
+    } else {
+        println!("Found {} problems", summary.errors.len());
+        Ok(ExitCode::FAILURE)
+    }
+}
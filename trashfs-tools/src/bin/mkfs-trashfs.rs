@@ -0,0 +1,84 @@
+use anyhow::{Result, bail};
+use argh::FromArgs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use trashfs::{BLOCK_SIZE, Superblock, checksum};
+use trashfs_tools::mkfs::format;
+use trashfs_tools::{Image, parse_size};
+
+#[derive(FromArgs)]
+#[argh(description = "Create a TrashFS filesystem on an image file")]
+struct Args {
+    #[argh(positional)]
+    image: PathBuf,
+
+    #[argh(option, short = 's', from_str_fn(parse_size))]
+    #[argh(description = "resize the image to this size, e.g. 64M")]
+    size: Option<u64>,
+
+    #[argh(option, short = 'L')]
+    #[argh(description = "volume label")]
+    label: Option<String>,
+
+    #[argh(option, short = 'i', from_str_fn(parse_size))]
+    #[argh(description = "bytes of data per inode")]
+    bytes_per_inode: Option<u64>,
+
+    #[argh(switch, short = 'f')]
+    #[argh(description = "overwrite an existing TrashFS filesystem")]
+    force: bool,
+}
+
+fn main() -> Result<()> {
+    let args: Args = argh::from_env();
+    let mut image = Image::create(&args.image, args.size)?;
+    let block_count = image.block_count();
+
+    let mut block = vec![0; BLOCK_SIZE];
+    image.read_block(0, &mut block)?;
+    if !args.force && Superblock::decode(&block).is_ok() {
+        bail!(
+            "{} already contains TrashFS, use --force",
+            args.image.display()
+        );
+    }
+
+    let inode_count = match args.bytes_per_inode {
+        Some(0) => bail!("Bytes per inode must not be zero"),
+        Some(bytes) => block_count * BLOCK_SIZE as u64 / bytes,
+        None => Superblock::default_inode_count(block_count),
+    };
+
+    let created = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let mut superblock = Superblock::new(block_count, inode_count)?;
+    superblock.set_label(args.label.as_deref().unwrap_or(""))?;
+    superblock.created = created.as_secs() as i64;
+    superblock.uuid = generate_uuid(created.as_nanos(), &args.image);
+
+    let superblock = format(&mut image, superblock)?;
+
+    println!(
+        "Created TrashFS on {}: {} blocks of {} bytes, {} inodes, {} journal blocks",
+        args.image.display(),
+        block_count,
+        BLOCK_SIZE,
+        superblock.inode_count,
+        superblock.journal_blocks
+    );
+    Ok(())
+}
+
+fn generate_uuid(seed: u128, image: &std::path::Path) -> [u8; 16] {
+    let path = image.to_string_lossy();
+    let mut uuid = [0; 16];
+    for (index, chunk) in uuid.chunks_mut(4).enumerate() {
+        let crc = checksum::crc32c_append(index as u32, &seed.to_le_bytes());
+        let crc = checksum::crc32c_append(crc, path.as_bytes());
+        chunk.copy_from_slice(&crc.to_le_bytes());
+    }
+
+    // Random UUID, variant 1.
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
@@ -0,0 +1,439 @@
+use anyhow::{Result, bail};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use trashfs::bitmap;
+use trashfs::btree::MAX_DEPTH;
+use trashfs::inode::decode_extent_block;
+use trashfs::journal::pending_transaction;
+use trashfs::{BLOCK_SIZE, DirRecord, Extent, FileKind, Inode, Node, ROOT_INODE, Superblock};
+
+use super::{Image, Storage};
+
+// Only the first few offenders of each bitmap mismatch are listed.
+const MAX_LISTED: usize = 8;
+
+pub struct Summary {
+    pub superblock: Superblock,
+    pub used_blocks: u64,
+    pub used_inodes: u64,
+    pub errors: Vec<String>,
+}
+
+// Problems are printed as they are found and collected in the summary.
+pub fn check<S: Storage>(image: &mut Image<S>, verbose: bool) -> Result<Summary> {
+    let mut block = vec![0; BLOCK_SIZE];
+    image.read_block(0, &mut block)?;
+    let superblock = Superblock::decode(&block)?;
+    if superblock.block_count > image.block_count() {
+        bail!(
+            "Filesystem has {} blocks but the image only holds {}",
+            superblock.block_count,
+            image.block_count()
+        );
+    }
+
+    let pending =
+        pending_transaction(&superblock, |block, buffer| image.read_block(block, buffer))?;
+    let overlay = pending
+        .unwrap_or_default()
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+    if !overlay.is_empty() {
+        println!(
+            "Journal holds committed transaction {} ({} blocks), checking as if replayed",
+            superblock.journal_sequence,
+            overlay.len()
+        );
+    }
+
+    let mut checker = Checker {
+        image,
+        superblock: superblock.clone(),
+        overlay,
+        block_bitmap: Vec::new(),
+        inode_bitmap: Vec::new(),
+        used_blocks: vec![0; superblock.block_count.div_ceil(8) as usize],
+        inodes: BTreeMap::new(),
+        references: BTreeMap::new(),
+        subdirectories: BTreeMap::new(),
+        parents: BTreeMap::new(),
+        errors: Vec::new(),
+        verbose,
+    };
+    checker.block_bitmap = checker.read_region(
+        superblock.block_bitmap_start,
+        superblock.block_bitmap_blocks,
+    )?;
+    checker.inode_bitmap = checker.read_region(
+        superblock.inode_bitmap_start,
+        superblock.inode_bitmap_blocks,
+    )?;
+    checker.check()?;
+
+    Ok(Summary {
+        used_blocks: bitmap::count_set(&checker.block_bitmap, superblock.block_count),
+        used_inodes: bitmap::count_set(&checker.inode_bitmap, superblock.inode_count),
+        errors: checker.errors,
+        superblock,
+    })
+}
+
+struct Checker<'a, S: Storage> {
+    image: &'a mut Image<S>,
+    superblock: Superblock,
+    // Committed journal blocks that haven't been checkpointed yet.
+    overlay: BTreeMap<u64, Vec<u8>>,
+    block_bitmap: Vec<u8>,
+    inode_bitmap: Vec<u8>,
+    used_blocks: Vec<u8>,
+    inodes: BTreeMap<u64, Inode>,
+    references: BTreeMap<u64, u32>,
+    subdirectories: BTreeMap<u64, u32>,
+    parents: BTreeMap<u64, u64>,
+    errors: Vec<String>,
+    verbose: bool,
+}
+
+impl<S: Storage> Checker<'_, S> {
+    fn read(&mut self, block: u64) -> Result<Vec<u8>> {
+        if let Some(data) = self.overlay.get(&block) {
+            return Ok(data.clone());
+        }
+        let mut data = vec![0; BLOCK_SIZE];
+        self.image.read_block(block, &mut data)?;
+        Ok(data)
+    }
+
+    fn read_region(&mut self, start: u64, count: u64) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(count as usize * BLOCK_SIZE);
+        for block in start..start + count {
+            data.extend(self.read(block)?);
+        }
+        Ok(data)
+    }
+
+    fn error(&mut self, message: String) {
+        println!("error: {message}");
+        self.errors.push(message);
+    }
+
+    fn claim(&mut self, block: u64, owner: u64) {
+        if !self.superblock.is_data_block(block) {
+            self.error(format!(
+                "Inode {owner} references block {block} outside the data area"
+            ));
+        } else if bitmap::get(&self.used_blocks, block) {
+            self.error(format!(
+                "Block {block} of inode {owner} is claimed more than once"
+            ));
+        } else {
+            bitmap::set(&mut self.used_blocks, block, true);
+        }
+    }
+
+    fn load_inode(&mut self, number: u64) -> Result<Option<Inode>> {
+        if number == 0 || number >= self.superblock.inode_count {
+            self.error(format!("Inode number {number} is out of range"));
+            return Ok(None);
+        }
+        if !bitmap::get(&self.inode_bitmap, number) {
+            self.error(format!("Inode {number} is referenced but marked free"));
+        }
+
+        let (block, offset) = self.superblock.inode_location(number);
+        let data = self.read(block)?;
+        match Inode::decode(number, &data[offset..]) {
+            Ok(inode) => Ok(Some(inode)),
+            Err(err) => {
+                self.error(format!("Inode {number}: {err}"));
+                Ok(None)
+            }
+        }
+    }
+
+    fn check(&mut self) -> Result<()> {
+        for block in 0..self.superblock.data_start {
+            bitmap::set(&mut self.used_blocks, block, true);
+        }
+
+        let mut queue = VecDeque::from([ROOT_INODE]);
+        let mut directories = BTreeSet::from([ROOT_INODE]);
+        *self.references.entry(ROOT_INODE).or_default() += 1;
+
+        while let Some(number) = queue.pop_front() {
+            let Some(inode) = self.load_inode(number)? else {
+                continue;
+            };
+            if self.verbose {
+                println!("inode {number}: mode {:o}, size {}", inode.mode, inode.size);
+            }
+
+            let extents = self.check_extents(number, &inode)?;
+            match inode.kind() {
+                Some(FileKind::Directory) => {
+                    if inode.extent_count != 0 {
+                        self.error(format!("Directory {number} has data extents"));
+                    }
+
+                    let records = self.check_directory(number, &inode)?;
+                    if inode.size != records.len() as u64 {
+                        self.error(format!(
+                            "Directory {number} has {} entries but records size {}",
+                            records.len(),
+                            inode.size
+                        ));
+                    }
+
+                    for record in records {
+                        *self.references.entry(record.inode).or_default() += 1;
+                        if record.kind != FileKind::Directory {
+                            if let Entry::Vacant(entry) = self.inodes.entry(record.inode) {
+                                entry.insert(Inode::default());
+                                queue.push_back(record.inode);
+                            }
+                            continue;
+                        }
+
+                        *self.subdirectories.entry(number).or_default() += 1;
+                        if directories.insert(record.inode) {
+                            self.parents.insert(record.inode, number);
+                            queue.push_back(record.inode);
+                        } else {
+                            self.error(format!(
+                                "Directory {} is linked more than once",
+                                record.inode
+                            ));
+                        }
+                    }
+                }
+                Some(FileKind::Symlink) => {
+                    if inode.size == 0 || inode.size > BLOCK_SIZE as u64 {
+                        self.error(format!(
+                            "Symlink {number} has invalid length {}",
+                            inode.size
+                        ));
+                    } else if let Some(extent) = extents.first() {
+                        let data = self.read(extent.start)?;
+                        if std::str::from_utf8(&data[..inode.size as usize]).is_err() {
+                            self.error(format!("Symlink {number} target is not UTF-8"));
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            self.inodes.insert(number, inode);
+        }
+
+        self.check_links(&directories);
+        self.check_bitmaps();
+        Ok(())
+    }
+
+    fn check_extents(&mut self, number: u64, inode: &Inode) -> Result<Vec<Extent>> {
+        let mut extents = inode.inline_extents().to_vec();
+        if inode.has_overflow() {
+            self.claim(inode.extent_block, number);
+            let data = self.read(inode.extent_block)?;
+            match decode_extent_block(number, &data) {
+                Ok(overflow) => extents.extend(overflow),
+                Err(err) => self.error(format!("Inode {number}: {err}")),
+            }
+            if extents.len() != inode.extent_count as usize {
+                self.error(format!(
+                    "Inode {number} extent count does not match its extents"
+                ));
+            }
+        }
+
+        let mut previous_end = 0;
+        for extent in extents.iter() {
+            if extent.length == 0 || extent.logical < previous_end {
+                self.error(format!("Inode {number} has overlapping or empty extents"));
+            }
+            previous_end = extent.end();
+
+            let in_bounds = extent
+                .start
+                .checked_add(extent.length as u64)
+                .is_some_and(|end| end <= self.superblock.block_count);
+            if !in_bounds {
+                self.error(format!(
+                    "Inode {number} has an extent past the end of the volume"
+                ));
+                continue;
+            }
+
+            for block in extent.start..extent.start + extent.length as u64 {
+                self.claim(block, number);
+            }
+        }
+
+        if previous_end > inode.size.div_ceil(BLOCK_SIZE as u64) {
+            self.error(format!(
+                "Inode {number} has extents past the end of the file"
+            ));
+        }
+        Ok(extents)
+    }
+
+    fn check_directory(&mut self, number: u64, inode: &Inode) -> Result<Vec<DirRecord>> {
+        let mut records = Vec::new();
+        if inode.parent == 0 || (number == ROOT_INODE && inode.parent != ROOT_INODE) {
+            self.error(format!(
+                "Directory {number} has invalid parent {}",
+                inode.parent
+            ));
+        }
+        self.walk_node(number, inode.tree_root, None, (0, None), &mut records)?;
+        Ok(records)
+    }
+
+    fn walk_node(
+        &mut self,
+        owner: u64,
+        block: u64,
+        expected_level: Option<u8>,
+        (lower, upper): (u64, Option<u64>),
+        records: &mut Vec<DirRecord>,
+    ) -> Result<()> {
+        self.claim(block, owner);
+        if !self.superblock.is_data_block(block) {
+            return Ok(());
+        }
+
+        let data = self.read(block)?;
+        let node = match Node::decode(owner, &data) {
+            Ok(node) => node,
+            Err(err) => {
+                self.error(format!("Directory {owner}, node {block}: {err}"));
+                return Ok(());
+            }
+        };
+
+        if expected_level.is_some_and(|level| level != node.level()) {
+            self.error(format!(
+                "Directory {owner}, node {block} has an unexpected level"
+            ));
+            return Ok(());
+        }
+
+        let in_range = |hash: u64| hash >= lower && upper.is_none_or(|upper| hash < upper);
+        match node {
+            Node::Leaf(leaf) => {
+                if leaf.iter().any(|record| !in_range(record.hash)) {
+                    self.error(format!(
+                        "Directory {owner}, node {block} has misplaced records"
+                    ));
+                }
+                records.extend(leaf);
+            }
+            Node::Internal { level, children } => {
+                if level > MAX_DEPTH {
+                    return Ok(());
+                }
+                for (index, child) in children.iter().enumerate() {
+                    let child_lower = if index == 0 { lower } else { child.key };
+                    if index > 0 && !in_range(child.key) {
+                        self.error(format!(
+                            "Directory {owner}, node {block} has misplaced keys"
+                        ));
+                    }
+                    let child_upper = children.get(index + 1).map(|next| next.key).or(upper);
+                    self.walk_node(
+                        owner,
+                        child.block,
+                        Some(level - 1),
+                        (child_lower, child_upper),
+                        records,
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_links(&mut self, directories: &BTreeSet<u64>) {
+        let inodes = std::mem::take(&mut self.inodes);
+        for (&number, inode) in inodes.iter() {
+            if inode.kind().is_none() {
+                continue;
+            }
+
+            let expected = match directories.contains(&number) {
+                true => 2 + self.subdirectories.get(&number).copied().unwrap_or(0),
+                false => self.references.get(&number).copied().unwrap_or(0),
+            };
+            if inode.links != expected {
+                self.error(format!(
+                    "Inode {number} has link count {} but {expected} references",
+                    inode.links
+                ));
+            }
+            if number != ROOT_INODE
+                && directories.contains(&number)
+                && self.parents.get(&number) != Some(&inode.parent)
+            {
+                self.error(format!(
+                    "Directory {number} has a wrong parent {}",
+                    inode.parent
+                ));
+            }
+        }
+        self.inodes = inodes;
+    }
+
+    fn check_bitmaps(&mut self) {
+        let mut unreachable = Vec::new();
+        let mut unmarked = Vec::new();
+        for number in 1..self.superblock.inode_count {
+            match (
+                bitmap::get(&self.inode_bitmap, number),
+                self.inodes.contains_key(&number),
+            ) {
+                (true, false) => unreachable.push(number),
+                (false, true) => unmarked.push(number),
+                _ => {}
+            }
+        }
+        if !bitmap::get(&self.inode_bitmap, 0) {
+            self.error("Reserved inode 0 is marked free".to_string());
+        }
+        self.report("inodes allocated but unreachable", &unreachable);
+        self.report("inodes in use but marked free", &unmarked);
+
+        let mut leaked = Vec::new();
+        let mut unmarked = Vec::new();
+        for block in 0..self.superblock.block_count {
+            match (
+                bitmap::get(&self.block_bitmap, block),
+                bitmap::get(&self.used_blocks, block),
+            ) {
+                (true, false) => leaked.push(block),
+                (false, true) => unmarked.push(block),
+                _ => {}
+            }
+        }
+        self.report("blocks allocated but unreferenced", &leaked);
+        self.report("blocks in use but marked free", &unmarked);
+    }
+
+    fn report(&mut self, what: &str, items: &[u64]) {
+        if items.is_empty() {
+            return;
+        }
+        let listed = items
+            .iter()
+            .take(MAX_LISTED)
+            .map(u64::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let more = if items.len() > MAX_LISTED {
+            ", ..."
+        } else {
+            ""
+        };
+        self.error(format!("{} {what}: {listed}{more}", items.len()));
+    }
+}
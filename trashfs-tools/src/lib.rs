@@ -0,0 +1,127 @@
+pub mod fsck;
+pub mod mkfs;
+
+#[cfg(test)]
+mod tests;
+
+use anyhow::{Result, bail};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use trashfs::BLOCK_SIZE;
+
+pub trait Storage: Read + Write + Seek {
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl Storage for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl Storage for Cursor<Vec<u8>> {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Image<S: Storage = File> {
+    file: S,
+    block_count: u64,
+}
+
+impl Image {
+    pub fn open(path: &Path, writable: bool) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        Self::new(file)
+    }
+
+    // Creates the image if needed and resizes it when `size` is given.
+    pub fn create(path: &Path, size: Option<u64>) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        if let Some(size) = size {
+            file.set_len(size - size % BLOCK_SIZE as u64)?;
+        }
+        Self::new(file)
+    }
+
+    fn new(file: File) -> Result<Self> {
+        let length = file.metadata()?.len();
+        Image::with_storage(file, length)
+    }
+}
+
+impl Image<Cursor<Vec<u8>>> {
+    pub fn in_memory(block_count: u64) -> Result<Self> {
+        let length = block_count * BLOCK_SIZE as u64;
+        Image::with_storage(Cursor::new(vec![0; length as usize]), length)
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.file.get_mut()
+    }
+}
+
+impl<S: Storage> Image<S> {
+    fn with_storage(file: S, length: u64) -> Result<Self> {
+        if length < BLOCK_SIZE as u64 {
+            bail!("Image is smaller than a single block");
+        }
+
+        Ok(Self {
+            file,
+            block_count: length / BLOCK_SIZE as u64,
+        })
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    pub fn read_block(&mut self, block: u64, buffer: &mut [u8]) -> Result<()> {
+        self.seek(block, buffer.len())?;
+        self.file.read_exact(buffer)?;
+        Ok(())
+    }
+
+    pub fn write_block(&mut self, block: u64, buffer: &[u8]) -> Result<()> {
+        self.seek(block, buffer.len())?;
+        self.file.write_all(buffer)?;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync()?;
+        Ok(())
+    }
+
+    fn seek(&mut self, block: u64, length: usize) -> Result<()> {
+        if block * BLOCK_SIZE as u64 + length as u64 > self.block_count * BLOCK_SIZE as u64 {
+            bail!("Block {block} is outside of the image");
+        }
+        self.file.seek(SeekFrom::Start(block * BLOCK_SIZE as u64))?;
+        Ok(())
+    }
+}
+
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&value[..index], 1 << 10),
+        Some((index, 'M' | 'm')) => (&value[..index], 1 << 20),
+        Some((index, 'G' | 'g')) => (&value[..index], 1 << 30),
+        _ => (value, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size: {value}"))
+}
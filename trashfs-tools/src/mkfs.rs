@@ -0,0 +1,56 @@
+use anyhow::Result;
+use trashfs::inode::INODE_SIZE;
+use trashfs::{BLOCK_SIZE, FileKind, Inode, Node, ROOT_INODE, Superblock, bitmap};
+
+use super::{Image, Storage};
+
+// Inode table and bitmaps are zeroed in chunks of this many blocks.
+const ZERO_CHUNK_BLOCKS: u64 = 256;
+
+// Writes an empty filesystem laid out by `superblock`, returning it with the
+// free counts filled in.
+pub fn format<S: Storage>(image: &mut Image<S>, mut superblock: Superblock) -> Result<Superblock> {
+    // Everything before the data area is zeroed so stale journal or inode
+    // contents can't leak into the new filesystem.
+    let zeroes = vec![0; BLOCK_SIZE * ZERO_CHUNK_BLOCKS as usize];
+    let mut next = 1;
+    while next < superblock.data_start {
+        let count = ZERO_CHUNK_BLOCKS.min(superblock.data_start - next);
+        image.write_block(next, &zeroes[..count as usize * BLOCK_SIZE])?;
+        next += count;
+    }
+
+    let root_node = superblock.data_start;
+    let encoded = Node::Leaf(Vec::new()).encode(ROOT_INODE)?;
+    image.write_block(root_node, &encoded)?;
+
+    let mut root = Inode::new(FileKind::Directory, 0o755);
+    root.links = 2;
+    root.parent = ROOT_INODE;
+    root.tree_root = root_node;
+    root.modified = superblock.created;
+
+    let (inode_block, offset) = superblock.inode_location(ROOT_INODE);
+    let mut table = vec![0; BLOCK_SIZE];
+    root.encode(ROOT_INODE, &mut table[offset..offset + INODE_SIZE]);
+    image.write_block(inode_block, &table)?;
+
+    // Metadata regions and the root directory node are the only used blocks.
+    let mut blocks = vec![0; superblock.block_bitmap_blocks as usize * BLOCK_SIZE];
+    for index in 0..=root_node {
+        bitmap::set(&mut blocks, index, true);
+    }
+    image.write_block(superblock.block_bitmap_start, &blocks)?;
+
+    let mut inodes = vec![0; superblock.inode_bitmap_blocks as usize * BLOCK_SIZE];
+    bitmap::set(&mut inodes, 0, true);
+    bitmap::set(&mut inodes, ROOT_INODE, true);
+    image.write_block(superblock.inode_bitmap_start, &inodes)?;
+
+    superblock.free_blocks = superblock.block_count - root_node - 1;
+    superblock.free_inodes = superblock.inode_count - 2;
+    image.write_block(0, &superblock.encode())?;
+    image.sync()?;
+
+    Ok(superblock)
+}
@@ -0,0 +1,15 @@
+pub fn get(bitmap: &[u8], index: u64) -> bool {
+    bitmap[(index / 8) as usize] & (1 << (index % 8)) != 0
+}
+
+pub fn set(bitmap: &mut [u8], index: u64, value: bool) {
+    let byte = &mut bitmap[(index / 8) as usize];
+    match value {
+        true => *byte |= 1 << (index % 8),
+        false => *byte &= !(1 << (index % 8)),
+    }
+}
+
+pub fn count_set(bitmap: &[u8], limit: u64) -> u64 {
+    (0..limit).filter(|&index| get(bitmap, index)).count() as u64
+}
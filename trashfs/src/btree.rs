@@ -0,0 +1,251 @@
+//! Directory B-tree nodes.
+//!
+//! Each directory owns a B-tree keyed by the 64-bit hash of entry names. Leaf
+//! records are ordered by `(hash, name)` and records sharing a hash always
+//! live in the same leaf. Internal nodes hold `(key, child)` pairs where the
+//! child covers hashes from its key up to the next one; the first key of an
+//! internal node is ignored when searching.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::inode::FileKind;
+use super::{BLOCK_SIZE, FormatError, FormatResult, NAME_MAX, checksum};
+use super::{read_u16, read_u64, write_u16, write_u32, write_u64};
+
+const NODE_MAGIC: u32 = u32::from_le_bytes(*b"TBTN");
+const HEADER_SIZE: usize = 24;
+const CHECKSUM_OFFSET: usize = 16;
+const CHILD_SIZE: usize = 16;
+const RECORD_HEADER: usize = 18;
+
+pub const INTERNAL_CAPACITY: usize = (BLOCK_SIZE - HEADER_SIZE) / CHILD_SIZE;
+pub const MAX_DEPTH: u8 = 8;
+
+// FNV-1a, which spreads short names well enough for directory lookups.
+pub fn name_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirRecord {
+    pub hash: u64,
+    pub inode: u64,
+    pub kind: FileKind,
+    pub name: String,
+}
+
+impl DirRecord {
+    pub fn new(name: &str, inode: u64, kind: FileKind) -> Self {
+        Self {
+            hash: name_hash(name),
+            inode,
+            kind,
+            name: String::from(name),
+        }
+    }
+
+    pub fn key(&self) -> (u64, &str) {
+        (self.hash, &self.name)
+    }
+
+    fn encoded_len(&self) -> usize {
+        RECORD_HEADER + self.name.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Child {
+    pub key: u64,
+    pub block: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Leaf(Vec<DirRecord>),
+    Internal { level: u8, children: Vec<Child> },
+}
+
+impl Node {
+    pub fn level(&self) -> u8 {
+        match self {
+            Node::Leaf(_) => 0,
+            Node::Internal { level, .. } => *level,
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Node::Leaf(records) => {
+                HEADER_SIZE + records.iter().map(DirRecord::encoded_len).sum::<usize>()
+            }
+            Node::Internal { children, .. } => HEADER_SIZE + children.len() * CHILD_SIZE,
+        }
+    }
+
+    pub fn fits(&self) -> bool {
+        self.encoded_len() <= BLOCK_SIZE
+    }
+
+    pub fn min_key(&self) -> Option<u64> {
+        match self {
+            Node::Leaf(records) => records.first().map(|record| record.hash),
+            Node::Internal { children, .. } => children.first().map(|child| child.key),
+        }
+    }
+
+    // Index of the child whose range covers `hash`.
+    pub fn child_index(children: &[Child], hash: u64) -> usize {
+        children
+            .iter()
+            .skip(1)
+            .take_while(|child| child.key <= hash)
+            .count()
+    }
+
+    pub fn encode(&self, owner: u64) -> FormatResult<Vec<u8>> {
+        if !self.fits() {
+            return Err(FormatError::Overflow("directory node"));
+        }
+
+        let mut block = vec![0; BLOCK_SIZE];
+        write_u32(&mut block, 0, NODE_MAGIC);
+        block[4] = self.level();
+        write_u64(&mut block, 8, owner);
+
+        let mut offset = HEADER_SIZE;
+        let count = match self {
+            Node::Leaf(records) => {
+                for record in records.iter() {
+                    write_u64(&mut block, offset, record.hash);
+                    write_u64(&mut block, offset + 8, record.inode);
+                    block[offset + 16] = record.kind as u8;
+                    block[offset + 17] = record.name.len() as u8;
+                    block[offset + RECORD_HEADER..offset + record.encoded_len()]
+                        .copy_from_slice(record.name.as_bytes());
+                    offset += record.encoded_len();
+                }
+                records.len()
+            }
+            Node::Internal { children, .. } => {
+                for child in children.iter() {
+                    write_u64(&mut block, offset, child.key);
+                    write_u64(&mut block, offset + 8, child.block);
+                    offset += CHILD_SIZE;
+                }
+                children.len()
+            }
+        };
+
+        write_u16(&mut block, 6, count as u16);
+        checksum::seal(&mut block, CHECKSUM_OFFSET);
+        Ok(block)
+    }
+
+    pub fn decode(owner: u64, block: &[u8]) -> FormatResult<Self> {
+        let block = &block[..BLOCK_SIZE];
+        if super::read_u32(block, 0) != NODE_MAGIC {
+            return Err(FormatError::BadMagic("directory node"));
+        }
+        if !checksum::verify(block, CHECKSUM_OFFSET) {
+            return Err(FormatError::BadChecksum("directory node"));
+        }
+        if read_u64(block, 8) != owner {
+            return Err(FormatError::Invalid("directory node owner"));
+        }
+
+        let level = block[4];
+        let count = read_u16(block, 6) as usize;
+        if level > MAX_DEPTH {
+            return Err(FormatError::Invalid("directory node level"));
+        }
+
+        let node = match level {
+            0 => Node::Leaf(decode_records(block, count)?),
+            _ => {
+                if count == 0 || count > INTERNAL_CAPACITY {
+                    return Err(FormatError::Invalid("directory node child count"));
+                }
+                let children = (0..count)
+                    .map(|index| {
+                        let offset = HEADER_SIZE + index * CHILD_SIZE;
+                        Child {
+                            key: read_u64(block, offset),
+                            block: read_u64(block, offset + 8),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                if children.windows(2).any(|pair| pair[0].key >= pair[1].key) {
+                    return Err(FormatError::Invalid("directory node key order"));
+                }
+                Node::Internal { level, children }
+            }
+        };
+
+        Ok(node)
+    }
+}
+
+fn decode_records(block: &[u8], count: usize) -> FormatResult<Vec<DirRecord>> {
+    let mut records: Vec<DirRecord> = Vec::with_capacity(count);
+    let mut offset = HEADER_SIZE;
+
+    for _ in 0..count {
+        let header = block
+            .get(offset..offset + RECORD_HEADER)
+            .ok_or(FormatError::Invalid("directory record length"))?;
+        let kind = FileKind::from_u8(header[16])
+            .ok_or(FormatError::Invalid("directory record file type"))?;
+        let length = header[17] as usize;
+
+        let name = block
+            .get(offset + RECORD_HEADER..offset + RECORD_HEADER + length)
+            .ok_or(FormatError::Invalid("directory record length"))?;
+        let name = core::str::from_utf8(name)
+            .map_err(|_| FormatError::Invalid("directory record name"))?;
+
+        let record = DirRecord {
+            hash: read_u64(header, 0),
+            inode: read_u64(header, 8),
+            kind,
+            name: String::from(name),
+        };
+
+        if name.is_empty() || name.len() > NAME_MAX || record.hash != name_hash(name) {
+            return Err(FormatError::Invalid("directory record name"));
+        }
+        if records
+            .last()
+            .is_some_and(|last| last.key() >= record.key())
+        {
+            return Err(FormatError::Invalid("directory record order"));
+        }
+
+        offset += record.encoded_len();
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+// Splits an overfull leaf roughly in half by size without separating records
+// that share a hash. Returns `None` when no such split point exists.
+pub fn split_leaf(records: &mut Vec<DirRecord>) -> Option<Vec<DirRecord>> {
+    let total = records.iter().map(DirRecord::encoded_len).sum::<usize>();
+    let mut size = 0;
+    let mut candidates = Vec::new();
+
+    for index in 1..records.len() {
+        size += records[index - 1].encoded_len();
+        if records[index - 1].hash != records[index].hash {
+            candidates.push((size.abs_diff(total - size), index));
+        }
+    }
+
+    let (_, index) = candidates.into_iter().min()?;
+    Some(records.split_off(index))
+}
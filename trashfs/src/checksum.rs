@@ -0,0 +1,45 @@
+const POLYNOMIAL: u32 = 0x82f6_3b78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ POLYNOMIAL,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_append(0, data)
+}
+
+pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
+    let crc = data.iter().fold(!crc, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
+// Checksums cover the whole structure with the checksum field itself zeroed.
+pub fn seal(data: &mut [u8], offset: usize) {
+    data[offset..offset + 4].fill(0);
+    let checksum = crc32c(data);
+    data[offset..offset + 4].copy_from_slice(&checksum.to_le_bytes());
+}
+
+pub fn verify(data: &[u8], offset: usize) -> bool {
+    let crc = crc32c_append(0, &data[..offset]);
+    let crc = crc32c_append(crc, &[0; 4]);
+    let crc = crc32c_append(crc, &data[offset + 4..]);
+    crc == super::read_u32(data, offset)
+}
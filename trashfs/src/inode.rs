@@ -0,0 +1,251 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{BLOCK_SIZE, FormatError, FormatResult, checksum};
+use super::{read_u32, read_u64, write_u32, write_u64};
+
+pub const INODE_SIZE: usize = 256;
+pub const INODES_PER_BLOCK: u64 = (BLOCK_SIZE / INODE_SIZE) as u64;
+
+pub const INLINE_EXTENTS: usize = 7;
+pub const EXTENT_BLOCK_CAPACITY: usize = (BLOCK_SIZE - EXTENT_BLOCK_HEADER) / EXTENT_SIZE;
+pub const MAX_EXTENTS: usize = INLINE_EXTENTS + EXTENT_BLOCK_CAPACITY;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+const EXTENT_SIZE: usize = 24;
+const EXTENTS_OFFSET: usize = 64;
+const INODE_CHECKSUM_OFFSET: usize = 252;
+
+const EXTENT_BLOCK_MAGIC: u32 = u32::from_le_bytes(*b"TEXT");
+const EXTENT_BLOCK_HEADER: usize = 24;
+const EXTENT_BLOCK_CHECKSUM_OFFSET: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FileKind {
+    Regular = 1,
+    Directory = 2,
+    Symlink = 3,
+}
+
+impl FileKind {
+    pub fn from_mode(mode: u32) -> Option<Self> {
+        match mode & S_IFMT {
+            S_IFREG => Some(FileKind::Regular),
+            S_IFDIR => Some(FileKind::Directory),
+            S_IFLNK => Some(FileKind::Symlink),
+            _ => None,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(FileKind::Regular),
+            2 => Some(FileKind::Directory),
+            3 => Some(FileKind::Symlink),
+            _ => None,
+        }
+    }
+
+    pub fn mode_bits(&self) -> u32 {
+        match self {
+            FileKind::Regular => S_IFREG,
+            FileKind::Directory => S_IFDIR,
+            FileKind::Symlink => S_IFLNK,
+        }
+    }
+}
+
+// Maps `length` file blocks starting at `logical` to device blocks at `start`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u64,
+    pub start: u64,
+    pub length: u32,
+}
+
+impl Extent {
+    pub fn end(&self) -> u64 {
+        self.logical + self.length as u64
+    }
+
+    pub fn map(&self, logical: u64) -> Option<u64> {
+        (self.logical..self.end())
+            .contains(&logical)
+            .then(|| self.start + (logical - self.logical))
+    }
+
+    fn encode(&self, data: &mut [u8]) {
+        write_u64(data, 0, self.logical);
+        write_u64(data, 8, self.start);
+        write_u32(data, 16, self.length);
+        write_u32(data, 20, 0);
+    }
+
+    fn decode(data: &[u8]) -> Self {
+        Self {
+            logical: read_u64(data, 0),
+            start: read_u64(data, 8),
+            length: read_u32(data, 16),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inode {
+    pub mode: u32,
+    pub links: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub modified: i64,
+    pub extent_count: u32,
+    pub extent_block: u64,
+    pub tree_root: u64,
+    pub parent: u64,
+    pub extents: [Extent; INLINE_EXTENTS],
+}
+
+impl Inode {
+    pub fn new(kind: FileKind, permissions: u32) -> Self {
+        Self {
+            mode: kind.mode_bits() | (permissions & !S_IFMT),
+            links: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn kind(&self) -> Option<FileKind> {
+        FileKind::from_mode(self.mode)
+    }
+
+    pub fn inline_extents(&self) -> &[Extent] {
+        &self.extents[..(self.extent_count as usize).min(INLINE_EXTENTS)]
+    }
+
+    pub fn has_overflow(&self) -> bool {
+        self.extent_count as usize > INLINE_EXTENTS
+    }
+
+    // Stores the leading extents inline and returns the ones that belong in
+    // the overflow extent block.
+    pub fn store_extents<'a>(&mut self, extents: &'a [Extent]) -> FormatResult<&'a [Extent]> {
+        if extents.len() > MAX_EXTENTS {
+            return Err(FormatError::Overflow("extent list"));
+        }
+
+        let inline = extents.len().min(INLINE_EXTENTS);
+        self.extents = [Extent::default(); INLINE_EXTENTS];
+        self.extents[..inline].copy_from_slice(&extents[..inline]);
+        self.extent_count = extents.len() as u32;
+        Ok(&extents[inline..])
+    }
+
+    pub fn encode(&self, number: u64, data: &mut [u8]) {
+        let data = &mut data[..INODE_SIZE];
+        data.fill(0);
+
+        write_u32(data, 0, self.mode);
+        write_u32(data, 4, self.links);
+        write_u32(data, 8, self.uid);
+        write_u32(data, 12, self.gid);
+        write_u64(data, 16, self.size);
+        write_u64(data, 24, self.modified as u64);
+        write_u32(data, 32, self.extent_count);
+        write_u64(data, 40, self.extent_block);
+        write_u64(data, 48, self.tree_root);
+        write_u64(data, 56, self.parent);
+
+        for (index, extent) in self.extents.iter().enumerate() {
+            extent.encode(&mut data[EXTENTS_OFFSET + index * EXTENT_SIZE..]);
+        }
+
+        let checksum = inode_checksum(number, data);
+        write_u32(data, INODE_CHECKSUM_OFFSET, checksum);
+    }
+
+    pub fn decode(number: u64, data: &[u8]) -> FormatResult<Self> {
+        let data = &data[..INODE_SIZE];
+        if inode_checksum(number, data) != read_u32(data, INODE_CHECKSUM_OFFSET) {
+            return Err(FormatError::BadChecksum("inode"));
+        }
+
+        let mut extents = [Extent::default(); INLINE_EXTENTS];
+        for (index, extent) in extents.iter_mut().enumerate() {
+            *extent = Extent::decode(&data[EXTENTS_OFFSET + index * EXTENT_SIZE..]);
+        }
+
+        let inode = Self {
+            mode: read_u32(data, 0),
+            links: read_u32(data, 4),
+            uid: read_u32(data, 8),
+            gid: read_u32(data, 12),
+            size: read_u64(data, 16),
+            modified: read_u64(data, 24) as i64,
+            extent_count: read_u32(data, 32),
+            extent_block: read_u64(data, 40),
+            tree_root: read_u64(data, 48),
+            parent: read_u64(data, 56),
+            extents,
+        };
+
+        if inode.kind().is_none() {
+            return Err(FormatError::Invalid("inode file type"));
+        }
+        if inode.extent_count as usize > MAX_EXTENTS
+            || inode.has_overflow() != (inode.extent_block != 0)
+        {
+            return Err(FormatError::Invalid("inode extent count"));
+        }
+        Ok(inode)
+    }
+}
+
+// Seeding with the inode number catches inodes written to the wrong slot.
+fn inode_checksum(number: u64, data: &[u8]) -> u32 {
+    let crc = checksum::crc32c(&number.to_le_bytes());
+    checksum::crc32c_append(crc, &data[..INODE_CHECKSUM_OFFSET])
+}
+
+pub fn encode_extent_block(owner: u64, extents: &[Extent]) -> FormatResult<Vec<u8>> {
+    if extents.len() > EXTENT_BLOCK_CAPACITY {
+        return Err(FormatError::Overflow("extent block"));
+    }
+
+    let mut block = vec![0; BLOCK_SIZE];
+    write_u32(&mut block, 0, EXTENT_BLOCK_MAGIC);
+    write_u32(&mut block, 4, extents.len() as u32);
+    write_u64(&mut block, 8, owner);
+
+    for (index, extent) in extents.iter().enumerate() {
+        extent.encode(&mut block[EXTENT_BLOCK_HEADER + index * EXTENT_SIZE..]);
+    }
+
+    checksum::seal(&mut block, EXTENT_BLOCK_CHECKSUM_OFFSET);
+    Ok(block)
+}
+
+pub fn decode_extent_block(owner: u64, block: &[u8]) -> FormatResult<Vec<Extent>> {
+    if read_u32(block, 0) != EXTENT_BLOCK_MAGIC {
+        return Err(FormatError::BadMagic("extent block"));
+    }
+    if !checksum::verify(&block[..BLOCK_SIZE], EXTENT_BLOCK_CHECKSUM_OFFSET) {
+        return Err(FormatError::BadChecksum("extent block"));
+    }
+    if read_u64(block, 8) != owner {
+        return Err(FormatError::Invalid("extent block owner"));
+    }
+
+    let count = read_u32(block, 4) as usize;
+    if count > EXTENT_BLOCK_CAPACITY {
+        return Err(FormatError::Invalid("extent block count"));
+    }
+
+    Ok((0..count)
+        .map(|index| Extent::decode(&block[EXTENT_BLOCK_HEADER + index * EXTENT_SIZE..]))
+        .collect())
+}
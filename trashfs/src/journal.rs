@@ -0,0 +1,154 @@
+//! Metadata write-ahead journal.
+//!
+//! The journal holds at most one transaction at a time, always starting at
+//! the first journal block: a descriptor listing the target blocks, a copy of
+//! each of those blocks, then a commit block. A transaction is only replayed
+//! when its sequence matches the superblock and the commit checksum covers
+//! every copied block. After checkpointing, the superblock sequence is bumped
+//! so the old transaction is never replayed again.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::superblock::Superblock;
+use super::{BLOCK_SIZE, FormatError, FormatResult, checksum};
+use super::{read_u32, read_u64, write_u32, write_u64};
+
+const DESCRIPTOR_MAGIC: u32 = u32::from_le_bytes(*b"TJDB");
+const COMMIT_MAGIC: u32 = u32::from_le_bytes(*b"TJCB");
+const HEADER_SIZE: usize = 24;
+const DESCRIPTOR_CHECKSUM_OFFSET: usize = 16;
+const COMMIT_CHECKSUM_OFFSET: usize = 20;
+
+pub const MAX_TRANSACTION_BLOCKS: usize = (BLOCK_SIZE - HEADER_SIZE) / 8;
+
+// Target block numbers paired with the contents to write there.
+pub type JournalBlocks = Vec<(u64, Vec<u8>)>;
+
+pub fn transaction_capacity(journal_blocks: u64) -> usize {
+    MAX_TRANSACTION_BLOCKS.min(journal_blocks.saturating_sub(2) as usize)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Descriptor {
+    pub sequence: u64,
+    pub targets: Vec<u64>,
+}
+
+impl Descriptor {
+    pub fn encode(&self) -> FormatResult<Vec<u8>> {
+        if self.targets.len() > MAX_TRANSACTION_BLOCKS {
+            return Err(FormatError::Overflow("journal descriptor"));
+        }
+
+        let mut block = vec![0; BLOCK_SIZE];
+        write_u32(&mut block, 0, DESCRIPTOR_MAGIC);
+        write_u32(&mut block, 4, self.targets.len() as u32);
+        write_u64(&mut block, 8, self.sequence);
+        for (index, target) in self.targets.iter().enumerate() {
+            write_u64(&mut block, HEADER_SIZE + index * 8, *target);
+        }
+
+        checksum::seal(&mut block, DESCRIPTOR_CHECKSUM_OFFSET);
+        Ok(block)
+    }
+
+    pub fn decode(block: &[u8]) -> FormatResult<Self> {
+        let block = &block[..BLOCK_SIZE];
+        if read_u32(block, 0) != DESCRIPTOR_MAGIC {
+            return Err(FormatError::BadMagic("journal descriptor"));
+        }
+        if !checksum::verify(block, DESCRIPTOR_CHECKSUM_OFFSET) {
+            return Err(FormatError::BadChecksum("journal descriptor"));
+        }
+
+        let count = read_u32(block, 4) as usize;
+        if count > MAX_TRANSACTION_BLOCKS {
+            return Err(FormatError::Invalid("journal descriptor count"));
+        }
+
+        Ok(Self {
+            sequence: read_u64(block, 8),
+            targets: (0..count)
+                .map(|index| read_u64(block, HEADER_SIZE + index * 8))
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Commit {
+    pub sequence: u64,
+    pub data_checksum: u32,
+}
+
+impl Commit {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut block = vec![0; BLOCK_SIZE];
+        write_u32(&mut block, 0, COMMIT_MAGIC);
+        write_u64(&mut block, 8, self.sequence);
+        write_u32(&mut block, 16, self.data_checksum);
+        checksum::seal(&mut block, COMMIT_CHECKSUM_OFFSET);
+        block
+    }
+
+    pub fn decode(block: &[u8]) -> FormatResult<Self> {
+        let block = &block[..BLOCK_SIZE];
+        if read_u32(block, 0) != COMMIT_MAGIC {
+            return Err(FormatError::BadMagic("journal commit"));
+        }
+        if !checksum::verify(block, COMMIT_CHECKSUM_OFFSET) {
+            return Err(FormatError::BadChecksum("journal commit"));
+        }
+
+        Ok(Self {
+            sequence: read_u64(block, 8),
+            data_checksum: read_u32(block, 16),
+        })
+    }
+}
+
+pub fn data_checksum<'a>(blocks: impl IntoIterator<Item = &'a [u8]>) -> u32 {
+    blocks.into_iter().fold(0, checksum::crc32c_append)
+}
+
+// Reads back the committed transaction waiting to be checkpointed, if any.
+// Torn or stale transactions are reported as `None`.
+pub fn pending_transaction<E>(
+    superblock: &Superblock,
+    mut read: impl FnMut(u64, &mut [u8]) -> Result<(), E>,
+) -> Result<Option<JournalBlocks>, E> {
+    let mut block = vec![0; BLOCK_SIZE];
+    read(superblock.journal_start, &mut block)?;
+
+    let Ok(descriptor) = Descriptor::decode(&block) else {
+        return Ok(None);
+    };
+    let capacity = superblock.transaction_capacity();
+    if descriptor.sequence != superblock.journal_sequence || descriptor.targets.len() > capacity {
+        return Ok(None);
+    }
+
+    let mut blocks = Vec::with_capacity(descriptor.targets.len());
+    for (index, &target) in descriptor.targets.iter().enumerate() {
+        if target == 0 || target >= superblock.block_count {
+            return Ok(None);
+        }
+        let mut data = vec![0; BLOCK_SIZE];
+        read(superblock.journal_start + 1 + index as u64, &mut data)?;
+        blocks.push((target, data));
+    }
+
+    let commit_block = superblock.journal_start + 1 + descriptor.targets.len() as u64;
+    read(commit_block, &mut block)?;
+
+    let checksum = data_checksum(blocks.iter().map(|(_, data)| data.as_slice()));
+    match Commit::decode(&block) {
+        Ok(commit)
+            if commit.sequence == descriptor.sequence && commit.data_checksum == checksum =>
+        {
+            Ok(Some(blocks))
+        }
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,70 @@
+#![no_std]
+
+//! On-disk format of TrashFS, shared by the kernel driver and the host tools.
+//!
+//! A volume is split into fixed regions: the superblock, the metadata
+//! journal, the block and inode bitmaps, the inode table and finally the data
+//! area holding file extents, extent overflow blocks and directory B-tree
+//! nodes. Every metadata structure carries a CRC-32C checksum.
+
+extern crate alloc;
+
+pub mod bitmap;
+pub mod btree;
+pub mod checksum;
+pub mod inode;
+pub mod journal;
+pub mod superblock;
+
+#[cfg(test)]
+mod tests;
+
+use thiserror::Error;
+
+pub use btree::{Child, DirRecord, Node};
+pub use inode::{Extent, FileKind, Inode};
+pub use superblock::Superblock;
+
+pub const BLOCK_SIZE: usize = 4096;
+pub const ROOT_INODE: u64 = 1;
+pub const NAME_MAX: usize = 255;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatError {
+    #[error("Bad magic number in {0}")]
+    BadMagic(&'static str),
+    #[error("Checksum mismatch in {0}")]
+    BadChecksum(&'static str),
+    #[error("Unsupported format version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Invalid {0}")]
+    Invalid(&'static str),
+    #[error("{0} does not fit in a block")]
+    Overflow(&'static str),
+}
+
+pub type FormatResult<T> = Result<T, FormatError>;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
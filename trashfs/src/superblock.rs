@@ -0,0 +1,222 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::inode::{INODE_SIZE, INODES_PER_BLOCK};
+use super::{BLOCK_SIZE, FormatError, FormatResult, checksum};
+use super::{read_u32, read_u64, write_u32, write_u64};
+
+pub const MAGIC: [u8; 8] = *b"TRASHFS\0";
+pub const VERSION: u32 = 1;
+pub const LABEL_SIZE: usize = 32;
+
+pub const MIN_JOURNAL_BLOCKS: u64 = 64;
+pub const MAX_JOURNAL_BLOCKS: u64 = 4096;
+pub const MIN_DATA_BLOCKS: u64 = 16;
+pub const DEFAULT_BYTES_PER_INODE: u64 = 16 * 1024;
+
+const BITS_PER_BLOCK: u64 = BLOCK_SIZE as u64 * 8;
+const CHECKSUM_OFFSET: usize = 184;
+const SUPERBLOCK_SIZE: usize = CHECKSUM_OFFSET + 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Superblock {
+    pub block_count: u64,
+    pub inode_count: u64,
+    pub journal_start: u64,
+    pub journal_blocks: u64,
+    pub block_bitmap_start: u64,
+    pub block_bitmap_blocks: u64,
+    pub inode_bitmap_start: u64,
+    pub inode_bitmap_blocks: u64,
+    pub inode_table_start: u64,
+    pub inode_table_blocks: u64,
+    pub data_start: u64,
+    // Free counts are hints refreshed on mount, the bitmaps are authoritative.
+    pub free_blocks: u64,
+    pub free_inodes: u64,
+    pub journal_sequence: u64,
+    pub uuid: [u8; 16],
+    pub label: [u8; LABEL_SIZE],
+    pub created: i64,
+}
+
+impl Superblock {
+    pub fn new(block_count: u64, inode_count: u64) -> FormatResult<Self> {
+        let inode_count = inode_count
+            .max(INODES_PER_BLOCK)
+            .next_multiple_of(INODES_PER_BLOCK);
+        let journal_blocks = (block_count / 64).clamp(MIN_JOURNAL_BLOCKS, MAX_JOURNAL_BLOCKS);
+
+        let journal_start = 1;
+        let block_bitmap_start = journal_start + journal_blocks;
+        let block_bitmap_blocks = block_count.div_ceil(BITS_PER_BLOCK);
+        let inode_bitmap_start = block_bitmap_start + block_bitmap_blocks;
+        let inode_bitmap_blocks = inode_count.div_ceil(BITS_PER_BLOCK);
+        let inode_table_start = inode_bitmap_start + inode_bitmap_blocks;
+        let inode_table_blocks = inode_count / INODES_PER_BLOCK;
+        let data_start = inode_table_start + inode_table_blocks;
+
+        if data_start + MIN_DATA_BLOCKS > block_count {
+            return Err(FormatError::Invalid("volume size, too small for TrashFS"));
+        }
+
+        Ok(Self {
+            block_count,
+            inode_count,
+            journal_start,
+            journal_blocks,
+            block_bitmap_start,
+            block_bitmap_blocks,
+            inode_bitmap_start,
+            inode_bitmap_blocks,
+            inode_table_start,
+            inode_table_blocks,
+            data_start,
+            free_blocks: block_count - data_start,
+            free_inodes: inode_count,
+            journal_sequence: 1,
+            uuid: [0; 16],
+            label: [0; LABEL_SIZE],
+            created: 0,
+        })
+    }
+
+    pub fn default_inode_count(block_count: u64) -> u64 {
+        block_count * BLOCK_SIZE as u64 / DEFAULT_BYTES_PER_INODE
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut block = vec![0; BLOCK_SIZE];
+        block[0..8].copy_from_slice(&MAGIC);
+        write_u32(&mut block, 8, VERSION);
+        write_u32(&mut block, 12, BLOCK_SIZE as u32);
+
+        let fields = [
+            self.block_count,
+            self.inode_count,
+            self.journal_start,
+            self.journal_blocks,
+            self.block_bitmap_start,
+            self.block_bitmap_blocks,
+            self.inode_bitmap_start,
+            self.inode_bitmap_blocks,
+            self.inode_table_start,
+            self.inode_table_blocks,
+            self.data_start,
+            self.free_blocks,
+            self.free_inodes,
+            self.journal_sequence,
+        ];
+        for (index, value) in fields.into_iter().enumerate() {
+            write_u64(&mut block, 16 + index * 8, value);
+        }
+
+        block[128..144].copy_from_slice(&self.uuid);
+        block[144..176].copy_from_slice(&self.label);
+        write_u64(&mut block, 176, self.created as u64);
+
+        checksum::seal(&mut block[..SUPERBLOCK_SIZE], CHECKSUM_OFFSET);
+        block
+    }
+
+    pub fn decode(block: &[u8]) -> FormatResult<Self> {
+        if block.len() < SUPERBLOCK_SIZE || block[0..8] != MAGIC {
+            return Err(FormatError::BadMagic("superblock"));
+        }
+        if !checksum::verify(&block[..SUPERBLOCK_SIZE], CHECKSUM_OFFSET) {
+            return Err(FormatError::BadChecksum("superblock"));
+        }
+
+        let version = read_u32(block, 8);
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        if read_u32(block, 12) != BLOCK_SIZE as u32 {
+            return Err(FormatError::Invalid("block size"));
+        }
+
+        let field = |index: usize| read_u64(block, 16 + index * 8);
+        let superblock = Self {
+            block_count: field(0),
+            inode_count: field(1),
+            journal_start: field(2),
+            journal_blocks: field(3),
+            block_bitmap_start: field(4),
+            block_bitmap_blocks: field(5),
+            inode_bitmap_start: field(6),
+            inode_bitmap_blocks: field(7),
+            inode_table_start: field(8),
+            inode_table_blocks: field(9),
+            data_start: field(10),
+            free_blocks: field(11),
+            free_inodes: field(12),
+            journal_sequence: field(13),
+            uuid: block[128..144].try_into().unwrap(),
+            label: block[144..176].try_into().unwrap(),
+            created: read_u64(block, 176) as i64,
+        };
+
+        superblock.validate()?;
+        Ok(superblock)
+    }
+
+    fn validate(&self) -> FormatResult<()> {
+        let regions = [
+            (self.journal_start, self.journal_blocks),
+            (self.block_bitmap_start, self.block_bitmap_blocks),
+            (self.inode_bitmap_start, self.inode_bitmap_blocks),
+            (self.inode_table_start, self.inode_table_blocks),
+        ];
+
+        // Regions must be laid out back to back right after the superblock.
+        let mut next = 1;
+        for (start, length) in regions {
+            if start != next {
+                return Err(FormatError::Invalid("superblock layout"));
+            }
+            next = start + length;
+        }
+
+        let layout_ok = next == self.data_start
+            && self.data_start < self.block_count
+            && self.journal_blocks >= 3
+            && self.block_bitmap_blocks >= self.block_count.div_ceil(BITS_PER_BLOCK)
+            && self.inode_bitmap_blocks >= self.inode_count.div_ceil(BITS_PER_BLOCK)
+            && self.inode_table_blocks * INODES_PER_BLOCK >= self.inode_count;
+
+        match layout_ok {
+            true => Ok(()),
+            false => Err(FormatError::Invalid("superblock layout")),
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        let length = self.label.iter().position(|&byte| byte == 0);
+        let label = &self.label[..length.unwrap_or(LABEL_SIZE)];
+        core::str::from_utf8(label).unwrap_or("")
+    }
+
+    pub fn set_label(&mut self, label: &str) -> FormatResult<()> {
+        if label.len() > LABEL_SIZE {
+            return Err(FormatError::Invalid("label, longer than 32 bytes"));
+        }
+        self.label = [0; LABEL_SIZE];
+        self.label[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(())
+    }
+
+    // Returns the inode table block and the byte offset within it.
+    pub fn inode_location(&self, inode: u64) -> (u64, usize) {
+        let block = self.inode_table_start + inode / INODES_PER_BLOCK;
+        let offset = (inode % INODES_PER_BLOCK) as usize * INODE_SIZE;
+        (block, offset)
+    }
+
+    pub fn is_data_block(&self, block: u64) -> bool {
+        (self.data_start..self.block_count).contains(&block)
+    }
+
+    pub fn transaction_capacity(&self) -> usize {
+        super::journal::transaction_capacity(self.journal_blocks)
+    }
+}
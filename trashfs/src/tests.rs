@@ -0,0 +1,421 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::btree::{INTERNAL_CAPACITY, name_hash, split_leaf};
+use crate::inode::{INODE_SIZE, decode_extent_block, encode_extent_block};
+use crate::journal::{Commit, Descriptor, data_checksum, pending_transaction};
+use crate::{BLOCK_SIZE, Child, DirRecord, Extent, FileKind, FormatError, Inode, Node};
+use crate::{Superblock, checksum};
+
+const OWNER: u64 = 7;
+const BLOCK_COUNT: u64 = 1024;
+
+// Directory tree kept in memory as encoded blocks, grown the same way the
+// kernel driver does it: split full nodes and add a root level on top.
+struct Tree {
+    blocks: BTreeMap<u64, Vec<u8>>,
+    root: u64,
+    next_block: u64,
+}
+
+impl Tree {
+    fn new() -> Self {
+        let mut tree = Self {
+            blocks: BTreeMap::new(),
+            root: 0,
+            next_block: 0,
+        };
+        tree.root = tree.allocate(&Node::Leaf(Vec::new()));
+        tree
+    }
+
+    fn read(&self, block: u64) -> Node {
+        Node::decode(OWNER, &self.blocks[&block]).unwrap()
+    }
+
+    fn write(&mut self, block: u64, node: &Node) {
+        self.blocks.insert(block, node.encode(OWNER).unwrap());
+    }
+
+    fn allocate(&mut self, node: &Node) -> u64 {
+        let block = self.next_block;
+        self.next_block += 1;
+        self.write(block, node);
+        block
+    }
+
+    fn insert(&mut self, record: DirRecord) {
+        if let Some(sibling) = self.insert_into(self.root, record) {
+            let level = self.read(self.root).level() + 1;
+            let children = vec![
+                Child {
+                    key: 0,
+                    block: self.root,
+                },
+                sibling,
+            ];
+            self.root = self.allocate(&Node::Internal { level, children });
+        }
+    }
+
+    fn insert_into(&mut self, block: u64, record: DirRecord) -> Option<Child> {
+        let mut node = self.read(block);
+        match &mut node {
+            Node::Leaf(records) => {
+                let index = records
+                    .binary_search_by(|existing| existing.key().cmp(&record.key()))
+                    .unwrap_err();
+                records.insert(index, record);
+            }
+            Node::Internal { children, .. } => {
+                let index = Node::child_index(children, record.hash);
+                if let Some(sibling) = self.insert_into(children[index].block, record) {
+                    children.insert(index + 1, sibling);
+                }
+            }
+        }
+
+        if node.fits() {
+            self.write(block, &node);
+            return None;
+        }
+
+        let right = match &mut node {
+            Node::Leaf(records) => Node::Leaf(split_leaf(records).unwrap()),
+            Node::Internal { level, children } => Node::Internal {
+                level: *level,
+                children: children.split_off(children.len() / 2),
+            },
+        };
+        self.write(block, &node);
+        let key = right.min_key().unwrap();
+        Some(Child {
+            key,
+            block: self.allocate(&right),
+        })
+    }
+
+    fn leaf_for(&self, name: &str) -> (u64, Vec<DirRecord>) {
+        let mut block = self.root;
+        loop {
+            match self.read(block) {
+                Node::Leaf(records) => return (block, records),
+                Node::Internal { children, .. } => {
+                    block = children[Node::child_index(&children, name_hash(name))].block;
+                }
+            }
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<DirRecord> {
+        let (_, records) = self.leaf_for(name);
+        records.into_iter().find(|record| record.name == name)
+    }
+
+    fn remove(&mut self, name: &str) -> Option<DirRecord> {
+        let (block, mut records) = self.leaf_for(name);
+        let index = records.iter().position(|record| record.name == name)?;
+        let record = records.remove(index);
+        self.write(block, &Node::Leaf(records));
+        Some(record)
+    }
+}
+
+fn record(index: u64) -> DirRecord {
+    DirRecord::new(&format!("file-{index:05}"), index + 2, FileKind::Regular)
+}
+
+fn corrupt(data: &mut [u8], offset: usize) {
+    data[offset] ^= 0x01;
+}
+
+#[test]
+fn crc32c_check_value() {
+    assert_eq!(checksum::crc32c(b"123456789"), 0xe306_9283);
+    assert_eq!(
+        checksum::crc32c_append(checksum::crc32c(b"1234"), b"56789"),
+        0xe306_9283
+    );
+}
+
+#[test]
+fn superblock_round_trip() {
+    let mut superblock = Superblock::new(BLOCK_COUNT, 512).unwrap();
+    superblock.set_label("scratch").unwrap();
+    superblock.uuid = [0xab; 16];
+    superblock.created = 1_700_000_000;
+    superblock.journal_sequence = 42;
+
+    let decoded = Superblock::decode(&superblock.encode()).unwrap();
+    assert_eq!(decoded, superblock);
+    assert_eq!(decoded.label(), "scratch");
+    assert!(decoded.is_data_block(decoded.data_start));
+    assert!(!decoded.is_data_block(decoded.inode_table_start));
+}
+
+#[test]
+fn superblock_rejects_bad_layout() {
+    assert_eq!(
+        Superblock::new(16, 16),
+        Err(FormatError::Invalid("volume size, too small for TrashFS"))
+    );
+
+    let mut superblock = Superblock::new(BLOCK_COUNT, 512).unwrap();
+    superblock.data_start += 1;
+    assert_eq!(
+        Superblock::decode(&superblock.encode()),
+        Err(FormatError::Invalid("superblock layout"))
+    );
+}
+
+#[test]
+fn inode_round_trip() {
+    let mut inode = Inode::new(FileKind::Regular, 0o644);
+    inode.size = 3 * BLOCK_SIZE as u64;
+    let extents = [Extent {
+        logical: 0,
+        start: 100,
+        length: 3,
+    }];
+    assert!(inode.store_extents(&extents).unwrap().is_empty());
+
+    let mut data = vec![0; INODE_SIZE];
+    inode.encode(12, &mut data);
+    assert_eq!(Inode::decode(12, &data).unwrap(), inode);
+
+    // The checksum is seeded with the inode number.
+    assert_eq!(
+        Inode::decode(13, &data),
+        Err(FormatError::BadChecksum("inode"))
+    );
+}
+
+#[test]
+fn checksum_mismatches_are_detected() {
+    let mut superblock = Superblock::new(BLOCK_COUNT, 512).unwrap().encode();
+    corrupt(&mut superblock, 20);
+    assert_eq!(
+        Superblock::decode(&superblock),
+        Err(FormatError::BadChecksum("superblock"))
+    );
+
+    let mut inode = vec![0; INODE_SIZE];
+    Inode::new(FileKind::Directory, 0o755).encode(1, &mut inode);
+    corrupt(&mut inode, 4);
+    assert_eq!(
+        Inode::decode(1, &inode),
+        Err(FormatError::BadChecksum("inode"))
+    );
+
+    let mut node = Node::Leaf(vec![record(1)]).encode(OWNER).unwrap();
+    corrupt(&mut node, 40);
+    assert_eq!(
+        Node::decode(OWNER, &node),
+        Err(FormatError::BadChecksum("directory node"))
+    );
+
+    let extent = Extent {
+        logical: 7,
+        start: 300,
+        length: 1,
+    };
+    let mut extents = encode_extent_block(OWNER, &[extent]).unwrap();
+    assert_eq!(decode_extent_block(OWNER, &extents).unwrap(), [extent]);
+    corrupt(&mut extents, 30);
+    assert_eq!(
+        decode_extent_block(OWNER, &extents),
+        Err(FormatError::BadChecksum("extent block"))
+    );
+
+    let mut commit = Commit {
+        sequence: 3,
+        data_checksum: 0x1234,
+    }
+    .encode();
+    corrupt(&mut commit, 8);
+    assert_eq!(
+        Commit::decode(&commit),
+        Err(FormatError::BadChecksum("journal commit"))
+    );
+}
+
+#[test]
+fn node_round_trip() {
+    let mut records = vec![record(1), record(2)];
+    records.sort_by(|a, b| a.key().cmp(&b.key()));
+    let leaf = Node::Leaf(records);
+    assert_eq!(
+        Node::decode(OWNER, &leaf.encode(OWNER).unwrap()).unwrap(),
+        leaf
+    );
+
+    let internal = Node::Internal {
+        level: 1,
+        children: vec![Child { key: 0, block: 10 }, Child { key: 50, block: 11 }],
+    };
+    let encoded = internal.encode(OWNER).unwrap();
+    assert_eq!(Node::decode(OWNER, &encoded).unwrap(), internal);
+    assert_eq!(
+        Node::decode(OWNER + 1, &encoded),
+        Err(FormatError::Invalid("directory node owner"))
+    );
+}
+
+#[test]
+fn unordered_records_are_rejected() {
+    let mut records = vec![record(1), record(2)];
+    records.sort_by(|a, b| b.key().cmp(&a.key()));
+    let encoded = Node::Leaf(records).encode(OWNER).unwrap();
+    assert_eq!(
+        Node::decode(OWNER, &encoded),
+        Err(FormatError::Invalid("directory record order"))
+    );
+}
+
+#[test]
+fn child_index_follows_keys() {
+    let children = [
+        Child { key: 0, block: 1 },
+        Child { key: 100, block: 2 },
+        Child { key: 200, block: 3 },
+    ];
+    assert_eq!(Node::child_index(&children, 0), 0);
+    assert_eq!(Node::child_index(&children, 99), 0);
+    assert_eq!(Node::child_index(&children, 100), 1);
+    assert_eq!(Node::child_index(&children, u64::MAX), 2);
+}
+
+#[test]
+fn split_leaf_balances_by_size() {
+    let mut left = (0..150).map(record).collect::<Vec<_>>();
+    left.sort_by(|a, b| a.key().cmp(&b.key()));
+    assert!(!Node::Leaf(left.clone()).fits());
+
+    let right = split_leaf(&mut left).unwrap();
+    assert!(Node::Leaf(left.clone()).fits());
+    assert!(Node::Leaf(right.clone()).fits());
+    assert_eq!(left.len() + right.len(), 150);
+    assert!(left.last().unwrap().key() < right.first().unwrap().key());
+
+    assert!(split_leaf(&mut vec![record(1)]).is_none());
+}
+
+#[test]
+fn btree_insert_split_and_delete() {
+    let mut tree = Tree::new();
+    let count = 3000;
+    for index in 0..count {
+        tree.insert(record(index));
+    }
+
+    // Enough entries to split leaves and grow the tree past a single level.
+    assert!(tree.read(tree.root).level() >= 1);
+    assert!(tree.blocks.len() > 2);
+    for index in 0..count {
+        assert_eq!(tree.find(&record(index).name), Some(record(index)));
+    }
+    assert_eq!(tree.find("missing"), None);
+
+    for index in (0..count).step_by(2) {
+        assert_eq!(tree.remove(&record(index).name), Some(record(index)));
+    }
+    assert_eq!(tree.remove(&record(0).name), None);
+
+    for index in 0..count {
+        let expected = (index % 2 == 1).then(|| record(index));
+        assert_eq!(tree.find(&record(index).name), expected);
+    }
+
+    // Every node still decodes and stays within its block.
+    for block in tree.blocks.keys() {
+        let node = tree.read(*block);
+        assert!(node.fits());
+        if let Node::Internal { children, .. } = node {
+            assert!(children.len() <= INTERNAL_CAPACITY);
+        }
+    }
+}
+
+// Journal blocks of a single transaction over an in-memory volume.
+fn journal(superblock: &Superblock, blocks: &[(u64, Vec<u8>)]) -> BTreeMap<u64, Vec<u8>> {
+    let descriptor = Descriptor {
+        sequence: superblock.journal_sequence,
+        targets: blocks.iter().map(|(target, _)| *target).collect(),
+    };
+    let commit = Commit {
+        sequence: superblock.journal_sequence,
+        data_checksum: data_checksum(blocks.iter().map(|(_, data)| data.as_slice())),
+    };
+
+    let start = superblock.journal_start;
+    let mut disk = BTreeMap::new();
+    disk.insert(start, descriptor.encode().unwrap());
+    for (index, (_, data)) in blocks.iter().enumerate() {
+        disk.insert(start + 1 + index as u64, data.clone());
+    }
+    disk.insert(start + 1 + blocks.len() as u64, commit.encode());
+    disk
+}
+
+fn pending(superblock: &Superblock, disk: &BTreeMap<u64, Vec<u8>>) -> Option<Vec<(u64, Vec<u8>)>> {
+    pending_transaction::<()>(superblock, |block, buffer| {
+        match disk.get(&block) {
+            Some(data) => buffer.copy_from_slice(data),
+            None => buffer.fill(0),
+        }
+        Ok(())
+    })
+    .unwrap()
+}
+
+#[test]
+fn journal_replays_committed_transaction() {
+    let superblock = Superblock::new(BLOCK_COUNT, 512).unwrap();
+    let data_start = superblock.data_start;
+    let blocks = vec![
+        (data_start, vec![0x11; BLOCK_SIZE]),
+        (data_start + 5, vec![0x22; BLOCK_SIZE]),
+    ];
+
+    // The crash hits after the commit block is written but before the
+    // blocks reach their final location, so replay has to restore them.
+    let mut disk = journal(&superblock, &blocks);
+    assert_eq!(pending(&superblock, &disk), Some(blocks.clone()));
+
+    for (target, data) in pending(&superblock, &disk).unwrap() {
+        disk.insert(target, data);
+    }
+    for (target, data) in blocks.iter() {
+        assert_eq!(&disk[target], data);
+    }
+
+    // Checkpointing bumps the sequence, so the transaction is not replayed
+    // a second time.
+    let mut checkpointed = superblock.clone();
+    checkpointed.journal_sequence += 1;
+    assert_eq!(pending(&checkpointed, &disk), None);
+}
+
+#[test]
+fn journal_ignores_torn_transactions() {
+    let superblock = Superblock::new(BLOCK_COUNT, 512).unwrap();
+    let target = superblock.data_start;
+    let blocks = vec![(target, vec![0x33; BLOCK_SIZE])];
+    let start = superblock.journal_start;
+
+    // Crash before the commit block was written.
+    let mut disk = journal(&superblock, &blocks);
+    disk.remove(&(start + 2));
+    assert_eq!(pending(&superblock, &disk), None);
+
+    // Crash while a copied block was only partially written.
+    let mut disk = journal(&superblock, &blocks);
+    corrupt(disk.get_mut(&(start + 1)).unwrap(), 100);
+    assert_eq!(pending(&superblock, &disk), None);
+
+    // A descriptor pointing outside the volume is never trusted.
+    let disk = journal(&superblock, &[(BLOCK_COUNT, vec![0; BLOCK_SIZE])]);
+    assert_eq!(pending(&superblock, &disk), None);
+}